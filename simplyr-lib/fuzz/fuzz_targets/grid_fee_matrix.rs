@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simplyr_lib::*;
+
+// Parse arbitrary bytes as grid fee matrix and look up all fees.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(raw) = serde_json::from_slice::<GridFeeMatrixRaw>(data) {
+        if let Ok(grid_fee_matrix) = GridFeeMatrix::from_raw(&raw) {
+            for source in 0..=grid_fee_matrix.size {
+                for dest in 0..=grid_fee_matrix.size {
+                    grid_fee_matrix.get(source, dest);
+                }
+            }
+        }
+    }
+});
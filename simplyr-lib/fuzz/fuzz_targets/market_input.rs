@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simplyr_lib::*;
+use std::collections::BTreeSet;
+
+// Parse arbitrary bytes as market input and match the orders with Pay-as-Bid.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(market_input) = serde_json::from_slice::<MarketInput>(data) {
+        let market_output = pay_as_bid_matching(&market_input);
+        let report =
+            verify_market_output(&market_input, &market_output, None, &PriceLimits::default());
+        // Duplicate order IDs are always reported and Pay-as-Bid matches a single time slot
+        let mut ids = BTreeSet::new();
+        let unique_ids = market_input
+            .orders
+            .iter()
+            .map(|order| order.id)
+            .chain(
+                market_input
+                    .curve_orders
+                    .iter()
+                    .map(|curve_order| curve_order.id),
+            )
+            .all(|id| ids.insert(id));
+        if unique_ids && market_input.split_time_slots().len() <= 1 {
+            assert!(report.is_valid(), "{report:?}");
+        }
+    }
+});
@@ -0,0 +1,140 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::Deserialize;
+use simplyr_lib::*;
+use std::collections::BTreeSet;
+
+/// Everything that is needed to run all matching algorithms.
+#[derive(Deserialize)]
+struct FuzzCase {
+    market_input: MarketInput,
+    grid_fee_matrix: GridFeeMatrixRaw,
+    energy_unit_kwh: f64,
+    #[serde(default)]
+    config: MatchingConfig,
+}
+
+/// Return `true` if no two orders of the input have the same ID.
+///
+/// Duplicate order IDs are always reported, so those inputs are only checked for panics.
+fn has_unique_ids(input: &MarketInput) -> bool {
+    let mut ids = BTreeSet::new();
+    input
+        .orders
+        .iter()
+        .map(|order| order.id)
+        .chain(input.curve_orders.iter().map(|curve_order| curve_order.id))
+        .all(|id| ids.insert(id))
+}
+
+/// Verify a market output and assert that it is valid if `assert_valid` is set.
+fn check(
+    input: &MarketInput,
+    output: &MarketOutput,
+    grid_fee_matrix: Option<&GridFeeMatrix>,
+    config: &MatchingConfig,
+    assert_valid: bool,
+) {
+    let report = verify_market_output(input, output, grid_fee_matrix, &config.price_limits);
+    if assert_valid {
+        assert!(report.is_valid(), "{report:?}");
+    }
+}
+
+// Parse arbitrary bytes and run them through every algorithm.
+fuzz_target!(|data: &[u8]| {
+    let case = match serde_json::from_slice::<FuzzCase>(data) {
+        Ok(case) => case,
+        Err(_) => return,
+    };
+    let grid_fee_matrix = match GridFeeMatrix::from_raw(&case.grid_fee_matrix) {
+        Ok(grid_fee_matrix) => grid_fee_matrix,
+        Err(_) => return,
+    };
+    let market_input = &case.market_input;
+    let config = &case.config;
+    let unique_ids = has_unique_ids(market_input);
+    // Except for the multi-slot matching, the algorithms match a single time slot
+    let checkable = unique_ids && market_input.split_time_slots().len() <= 1;
+    // McAfee and VCG ignore the forbidden counterparties
+    let checkable_without_rules = checkable
+        && !market_input.orders.iter().any(
+            |order| matches!(&order.counterparties, Some(rules) if !rules.forbidden.is_empty()),
+        );
+
+    let market_output = pay_as_bid_matching_with_config(market_input, config);
+    check(market_input, &market_output, None, config, checkable);
+
+    let market_output = uniform_price_matching_with_config(market_input, config);
+    check(market_input, &market_output, None, config, checkable);
+
+    let market_output = preference_matching_with_config(market_input, config);
+    check(market_input, &market_output, None, config, checkable);
+
+    let market_output = custom_fair_matching_with_config(
+        market_input,
+        case.energy_unit_kwh,
+        &grid_fee_matrix,
+        config,
+    );
+    check(
+        market_input,
+        &market_output,
+        Some(&grid_fee_matrix),
+        config,
+        checkable,
+    );
+
+    let market_output = local_first_matching_with_config(
+        market_input,
+        case.energy_unit_kwh,
+        &grid_fee_matrix,
+        config,
+    );
+    check(
+        market_input,
+        &market_output,
+        Some(&grid_fee_matrix),
+        config,
+        checkable,
+    );
+
+    let mcafee_output = mcafee_matching_with_config(market_input, case.energy_unit_kwh, config);
+    check(
+        market_input,
+        &mcafee_output.market_output,
+        None,
+        config,
+        checkable_without_rules,
+    );
+
+    let vcg_output = vcg_matching_with_config(market_input, &grid_fee_matrix, config);
+    check(
+        market_input,
+        &vcg_output.market_output,
+        Some(&grid_fee_matrix),
+        config,
+        checkable_without_rules,
+    );
+
+    let outputs = multi_slot_matching(
+        market_input,
+        |input| pay_as_bid_matching_with_config(input, config),
+        |output| output,
+    );
+    // Each output only contains the orders of its own time slot
+    for (time_slot, slot_input) in market_input.split_time_slots() {
+        check(&slot_input, &outputs[&time_slot], None, config, unique_ids);
+    }
+
+    // Submit the orders one by one to the continuous market and cancel every third order
+    let mut order_book = OrderBook::with_self_trade_prevention(config.self_trade_prevention);
+    for (idx, order) in market_input.orders.iter().enumerate() {
+        let _ = order_book.add_order(order.clone());
+        if idx % 3 == 2 {
+            let _ = order_book.cancel_order(order.id);
+        }
+        let _ = order_book.depth(&order.time_slot, 3);
+    }
+});
@@ -0,0 +1,124 @@
+//! Allocation of scarce energy among orders with the same price.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ENERGY_EPS;
+
+/// Decides how the available energy is shared among bids at the marginal price.
+///
+/// The marginal price is the lowest bid price that still gets (some) energy. If the bids at this
+/// price want more energy than is left, the allocation rule decides who gets how much.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Allocation {
+    /// Bids are filled completely one after another in the order given by the
+    /// [`TieBreak`](crate::TieBreak) rule.
+    #[default]
+    PriceTime,
+    /// Every bid gets a share of the available energy that is proportional to its size.
+    ///
+    /// Shares are multiples of the smallest energy value ([`ENERGY_EPS`] in Pay-as-Bid, the
+    /// energy unit in the custom fair matching). Quanta that are left after rounding down are
+    /// given to the bids with the largest rounding remainders (ties by the tie-break rule), so the
+    /// shares add up exactly to the available energy.
+    ProRata,
+}
+
+/// Split `total` quanta proportionally to `weights` using the largest remainder method.
+///
+/// The result always sums up to `total` if `total` does not exceed the sum of the weights. Ties
+/// are given to the entries that come first.
+pub(crate) fn pro_rata_shares(total: u64, weights: &[u64]) -> Vec<u64> {
+    let weight_sum: u128 = weights.iter().map(|&w| w as u128).sum();
+    if weight_sum == 0 {
+        return vec![0; weights.len()];
+    }
+    let total = (total as u128).min(weight_sum);
+
+    let mut shares = Vec::with_capacity(weights.len());
+    let mut remainders = Vec::with_capacity(weights.len());
+    for (idx, &weight) in weights.iter().enumerate() {
+        let product = total * weight as u128;
+        // The share is never larger than the weight, so it fits into an u64
+        shares.push((product / weight_sum) as u64);
+        remainders.push((product % weight_sum, idx));
+    }
+
+    let distributed: u128 = shares.iter().map(|&s| s as u128).sum();
+    let missing = (total - distributed) as usize;
+    // Largest remainder first, then by position
+    remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for &(_, idx) in remainders.iter().take(missing) {
+        shares[idx] += 1;
+    }
+    shares
+}
+
+/// Split `supply_kwh` among orders with the given energy values in multiples of [`ENERGY_EPS`].
+///
+/// Returns the energy values unchanged if the supply is sufficient.
+pub(crate) fn pro_rata_energy(supply_kwh: f64, energies_kwh: &[f64]) -> Vec<f64> {
+    let demand_kwh: f64 = energies_kwh.iter().sum();
+    if supply_kwh >= demand_kwh {
+        return energies_kwh.to_vec();
+    }
+    let to_quanta = |energy_kwh: f64| libm::floor(energy_kwh / ENERGY_EPS + 1e-6).max(0.0) as u64;
+    let weights: Vec<u64> = energies_kwh.iter().map(|&e| to_quanta(e)).collect();
+    pro_rata_shares(to_quanta(supply_kwh), &weights)
+        .into_iter()
+        .map(|quanta| quanta as f64 * ENERGY_EPS)
+        .collect()
+}
+
+/// Split a slice that is sorted by price into runs of elements with the same price.
+pub(crate) fn equal_price_runs<T, F>(items: &[T], price: F) -> impl Iterator<Item = &[T]>
+where
+    F: Fn(&T) -> f64,
+{
+    let mut remaining = items;
+    core::iter::from_fn(move || {
+        let first_price = price(remaining.first()?);
+        let count = remaining
+            .iter()
+            .take_while(|item| price(item).total_cmp(&first_price).is_eq())
+            .count();
+        let (run, rest) = remaining.split_at(count);
+        remaining = rest;
+        Some(run)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pro_rata_shares() {
+        assert_eq!(pro_rata_shares(10, &[4, 4, 4]), vec![4, 3, 3]);
+        assert_eq!(pro_rata_shares(10, &[2, 3, 5]), vec![2, 3, 5]);
+        assert_eq!(pro_rata_shares(7, &[10, 20, 5]), vec![2, 4, 1]);
+        assert_eq!(pro_rata_shares(100, &[1, 2]), vec![1, 2]);
+        assert_eq!(pro_rata_shares(5, &[0, 0]), vec![0, 0]);
+        assert_eq!(pro_rata_shares(u64::MAX, &[u64::MAX, u64::MAX]).len(), 2);
+        for total in 0..50 {
+            let weights = [3, 17, 1, 8, 8];
+            let shares = pro_rata_shares(total, &weights);
+            assert_eq!(shares.iter().sum::<u64>(), total.min(37));
+            assert!(shares.iter().zip(weights).all(|(&s, w)| s <= w));
+        }
+    }
+
+    #[test]
+    fn test_pro_rata_energy() {
+        assert_eq!(pro_rata_energy(5.0, &[1.0, 2.0]), vec![1.0, 2.0]);
+        let shares = pro_rata_energy(1.0, &[1.0, 1.0, 1.0]);
+        let quanta: Vec<u64> = shares
+            .iter()
+            .map(|s| (s / ENERGY_EPS).round() as u64)
+            .collect();
+        assert_eq!(quanta, vec![334, 333, 333]);
+    }
+}
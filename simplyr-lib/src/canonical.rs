@@ -0,0 +1,485 @@
+//! A canonical byte encoding of market inputs and outputs.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::ser::{self, Serialize};
+
+use crate::{Digest, MarketInput, MarketOutput};
+
+impl MarketInput {
+    /// Return the canonical encoding of the market input.
+    ///
+    /// The encoding is derived from the serialization of the types, so it contains the same
+    /// fields as the JSON serialization, but it doesn't depend on whitespace, the order of the
+    /// JSON keys or the formatting of numbers:
+    ///
+    /// - Integers are written as 8 big-endian bytes, floats as the 8 big-endian bytes of their
+    ///   IEEE 754 double precision representation (all NaNs are written as the same quiet NaN).
+    /// - Strings are written as their length (8 big-endian bytes) followed by their UTF-8 bytes.
+    /// - `None` is written as the byte 0, `Some` as the byte 1 followed by the value.
+    /// - Lists and maps are written as the number of elements (8 big-endian bytes) followed by
+    ///   the elements, the keys of a map before their values. Maps are sorted by their keys.
+    /// - Structs are written as the number of fields followed by the name and the value of every
+    ///   field in the order of declaration. Fields that are omitted in JSON are omitted as well.
+    /// - Enum variants are written as their name, followed by their content.
+    ///
+    /// Floats are parsed exactly from JSON, so the encoding doesn't change when a value is
+    /// written to JSON and read again.
+    ///
+    /// ```
+    /// # use simplyr_lib::*;
+    /// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+    ///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a",
+    ///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.1}
+    /// ]}"#).unwrap();
+    /// let reformatted: MarketInput = serde_json::from_str(r#"{"curve_orders": [], "orders": [
+    ///     {"price_euro_per_kwh": 1e-1, "energy_kwh": 2, "cluster_index": 0, "actor_id": "a",
+    ///      "time_slot": "t", "order_type": "ask", "id": 1}
+    /// ]}"#).unwrap();
+    /// assert_eq!(input.canonical_bytes(), reformatted.canonical_bytes());
+    /// assert_eq!(input.digest(), reformatted.digest());
+    /// ```
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        canonical_bytes(self)
+    }
+
+    /// Return the SHA-256 digest of the [canonical encoding](MarketInput::canonical_bytes).
+    pub fn digest(&self) -> Digest {
+        Digest::of(&self.canonical_bytes())
+    }
+}
+
+impl MarketOutput {
+    /// Return the canonical encoding of the market output, see
+    /// [`MarketInput::canonical_bytes`].
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        canonical_bytes(self)
+    }
+
+    /// Return the SHA-256 digest of the [canonical encoding](MarketOutput::canonical_bytes).
+    pub fn digest(&self) -> Digest {
+        Digest::of(&self.canonical_bytes())
+    }
+}
+
+/// Encode a value canonically, see [`MarketInput::canonical_bytes`].
+pub(crate) fn canonical_bytes<T: Serialize + ?Sized>(value: &T) -> Vec<u8> {
+    let mut encoder = Encoder { bytes: Vec::new() };
+    // The encoder itself never fails and the types of this crate don't either
+    match value.serialize(&mut encoder) {
+        Ok(()) => encoder.bytes,
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The bits of the NaN that all NaNs are encoded as.
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+#[derive(Debug)]
+struct EncodeError(String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl ser::StdError for EncodeError {}
+
+impl ser::Error for EncodeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        EncodeError(msg.to_string())
+    }
+}
+
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Start a list, map or struct whose number of elements is written when it ends.
+    fn start(&mut self) -> Compound<'_> {
+        let count_position = self.bytes.len();
+        self.write_u64(0);
+        Compound {
+            encoder: self,
+            count_position,
+            count: 0,
+        }
+    }
+}
+
+/// A list, map or struct that is being encoded.
+struct Compound<'a> {
+    encoder: &'a mut Encoder,
+    count_position: usize,
+    count: u64,
+}
+
+impl<'a> Compound<'a> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.count += 1;
+        value.serialize(&mut *self.encoder)
+    }
+
+    fn field<T: Serialize + ?Sized>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.encoder.write_bytes(name.as_bytes());
+        self.element(value)
+    }
+
+    fn finish(self) -> Result<(), EncodeError> {
+        let position = self.count_position;
+        self.encoder.bytes[position..position + 8].copy_from_slice(&self.count.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Encoder {
+    type Ok = ();
+    type Error = EncodeError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), EncodeError> {
+        self.bytes.push(u8::from(v));
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), EncodeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), EncodeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), EncodeError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), EncodeError> {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), EncodeError> {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), EncodeError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), EncodeError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), EncodeError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), EncodeError> {
+        self.write_u64(v);
+        Ok(())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), EncodeError> {
+        self.bytes.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), EncodeError> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), EncodeError> {
+        let bits = if v.is_nan() {
+            CANONICAL_NAN
+        } else {
+            v.to_bits()
+        };
+        self.write_u64(bits);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), EncodeError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), EncodeError> {
+        self.write_bytes(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), EncodeError> {
+        self.write_bytes(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), EncodeError> {
+        self.bytes.push(0);
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), EncodeError> {
+        self.bytes.push(1);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), EncodeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), EncodeError> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), EncodeError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.write_bytes(variant.as_bytes());
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, EncodeError> {
+        Ok(self.start())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>, EncodeError> {
+        Ok(self.start())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EncodeError> {
+        Ok(self.start())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EncodeError> {
+        self.write_bytes(variant.as_bytes());
+        Ok(self.start())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, EncodeError> {
+        Ok(self.start())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EncodeError> {
+        Ok(self.start())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EncodeError> {
+        self.write_bytes(variant.as_bytes());
+        Ok(self.start())
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), EncodeError> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EncodeError> {
+        // The entry was counted with its key
+        value.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = EncodeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), EncodeError> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<(), EncodeError> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+
+    #[test]
+    fn test_canonical_bytes() {
+        assert_eq!(canonical_bytes(&7_u8), 7_u64.to_be_bytes());
+        assert_eq!(canonical_bytes(&0.5_f64), 0.5_f64.to_bits().to_be_bytes());
+        assert_eq!(
+            canonical_bytes(&f64::NAN),
+            canonical_bytes(&f64::from_bits(0xfff8_0000_0000_0001))
+        );
+        assert_eq!(canonical_bytes(&"ab"), [0, 0, 0, 0, 0, 0, 0, 2, b'a', b'b']);
+        assert_eq!(canonical_bytes(&None::<u64>), [0]);
+        assert_eq!(canonical_bytes(&Some(true)), [1, 1]);
+
+        let mut map = BTreeMap::new();
+        map.insert("b", 2_u64);
+        map.insert("a", 1_u64);
+        let mut expected = vec![];
+        expected.extend_from_slice(&2_u64.to_be_bytes());
+        for (key, value) in [("a", 1_u64), ("b", 2)] {
+            expected.extend_from_slice(&canonical_bytes(key));
+            expected.extend_from_slice(&value.to_be_bytes());
+        }
+        assert_eq!(canonical_bytes(&map), expected);
+    }
+
+    #[test]
+    fn test_market_output_digest() {
+        let json = r#"{"matches": [{"bid_id": 2, "ask_id": 1, "energy_kwh": 1.0,
+            "price_euro_per_kwh": 0.3}]}"#;
+        let output: MarketOutput = serde_json::from_str(json).unwrap();
+        let mut changed = output.clone();
+        assert_eq!(output.digest(), changed.digest());
+        changed.matches[0].energy_kwh = 1.0 + f64::EPSILON;
+        assert_ne!(output.digest(), changed.digest());
+        // Empty lists are omitted in JSON and in the canonical encoding
+        let reparsed: MarketOutput =
+            serde_json::from_str(&serde_json::to_string(&output).unwrap()).unwrap();
+        assert_eq!(output.canonical_bytes(), reparsed.canonical_bytes());
+    }
+}
@@ -0,0 +1,257 @@
+//! Sealed-bid commitments to orders.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::canonical::canonical_bytes;
+use crate::{Diagnostic, Digest, MarketInput, Order};
+
+/// A commitment to an order that is submitted before the gate closure.
+///
+/// The hash is the SHA-256 [`Digest`] of the canonical encoding of the order (see
+/// [`MarketInput::canonical_bytes`]) followed by the UTF-8 bytes of a nonce. The nonce keeps the
+/// order secret until it is revealed, so it should be random and long enough not to be guessed
+/// (e.g. 32 random hex digits). After the gate closure, the order and the nonce are revealed as
+/// an [`OrderReveal`] and checked with [`open_commitments`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderCommitment {
+    pub order_id: u64,
+    pub hash: Digest,
+}
+
+/// An order together with the nonce of its [`OrderCommitment`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderReveal {
+    pub order: Order,
+    pub nonce: String,
+}
+
+impl OrderCommitment {
+    /// Create the commitment to an order.
+    pub fn new(order: &Order, nonce: &str) -> Self {
+        OrderCommitment {
+            order_id: order.id,
+            hash: commitment_hash(order, nonce),
+        }
+    }
+
+    /// Return `true` if the revealed order and nonce are the ones that were committed to.
+    pub fn is_revealed_by(&self, reveal: &OrderReveal) -> bool {
+        self.order_id == reveal.order.id
+            && self.hash == commitment_hash(&reveal.order, &reveal.nonce)
+    }
+}
+
+fn commitment_hash(order: &Order, nonce: &str) -> Digest {
+    let mut bytes = canonical_bytes(order);
+    bytes.extend_from_slice(nonce.as_bytes());
+    Digest::of(&bytes)
+}
+
+/// Why an order was not accepted by [`open_commitments`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommitmentError {
+    /// The order was committed to, but not revealed
+    Unrevealed,
+    /// The revealed order or nonce doesn't match the commitment
+    Mismatch,
+    /// The order was revealed without a commitment
+    Uncommitted,
+    /// There is more than one commitment or reveal with the order ID
+    Duplicate,
+}
+
+/// Check the revealed orders against the commitments and return the orders that can be matched.
+///
+/// Orders that were not revealed, that don't match their commitment, that were not committed to
+/// or whose ID occurs in more than one commitment or reveal are rejected and reported in the
+/// diagnostics. The accepted orders are returned in the order of the commitments; they should be
+/// matched as usual and the diagnostics added to the output.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let orders: Vec<Order> = serde_json::from_str(r#"[
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 2.0, "price_euro_per_kwh": 0.1},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.3}
+/// ]"#).unwrap();
+/// // Before the gate closure
+/// let commitments: Vec<OrderCommitment> = orders
+///     .iter()
+///     .map(|order| OrderCommitment::new(order, "5f0c8e2d41a97b36"))
+///     .collect();
+/// // After the gate closure, the bid is revealed with a higher price
+/// let mut bid = orders[1].clone();
+/// bid.price_euro_per_kwh = 0.5;
+/// let reveals = vec![
+///     OrderReveal { order: orders[0].clone(), nonce: "5f0c8e2d41a97b36".to_string() },
+///     OrderReveal { order: bid, nonce: "5f0c8e2d41a97b36".to_string() },
+/// ];
+/// let (input, diagnostics) = open_commitments(&commitments, &reveals);
+/// let mut output = pay_as_bid_matching(&input);
+/// output.diagnostics.extend(diagnostics);
+/// assert!(output.matches.is_empty());
+/// assert_eq!(
+///     output.diagnostics,
+///     vec![Diagnostic::CommitmentRejected { order_id: 2, reason: CommitmentError::Mismatch }]
+/// );
+/// ```
+pub fn open_commitments(
+    commitments: &[OrderCommitment],
+    reveals: &[OrderReveal],
+) -> (MarketInput, Vec<Diagnostic>) {
+    let mut commitment_counts: BTreeMap<u64, usize> = BTreeMap::new();
+    for commitment in commitments {
+        *commitment_counts.entry(commitment.order_id).or_insert(0) += 1;
+    }
+    let mut reveals_by_id: BTreeMap<u64, Vec<&OrderReveal>> = BTreeMap::new();
+    for reveal in reveals {
+        reveals_by_id
+            .entry(reveal.order.id)
+            .or_default()
+            .push(reveal);
+    }
+
+    let mut orders = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut reject = |order_id, reason| {
+        diagnostics.push(Diagnostic::CommitmentRejected { order_id, reason });
+    };
+    let mut duplicates = BTreeSet::new();
+    for commitment in commitments {
+        let order_id = commitment.order_id;
+        let revealed = reveals_by_id.get(&order_id).map_or(&[][..], Vec::as_slice);
+        if commitment_counts[&order_id] > 1 || revealed.len() > 1 {
+            if duplicates.insert(order_id) {
+                reject(order_id, CommitmentError::Duplicate);
+            }
+            continue;
+        }
+        match revealed.first() {
+            None => reject(order_id, CommitmentError::Unrevealed),
+            Some(reveal) if commitment.is_revealed_by(reveal) => orders.push(reveal.order.clone()),
+            Some(_) => reject(order_id, CommitmentError::Mismatch),
+        }
+    }
+    for &order_id in reveals_by_id.keys() {
+        if !commitment_counts.contains_key(&order_id) {
+            reject(order_id, CommitmentError::Uncommitted);
+        }
+    }
+
+    let input = MarketInput {
+        orders,
+        curve_orders: Vec::new(),
+    };
+    (input, diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OrderType;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn order(id: u64, order_type: OrderType, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor".to_string() + &id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 1.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    fn reveal(order: Order, nonce: &str) -> OrderReveal {
+        OrderReveal {
+            order,
+            nonce: nonce.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_commitment() {
+        let order = order(1, OrderType::Bid, 0.3);
+        let commitment = OrderCommitment::new(&order, "nonce");
+        assert!(commitment.is_revealed_by(&reveal(order.clone(), "nonce")));
+        assert!(!commitment.is_revealed_by(&reveal(order.clone(), "other nonce")));
+        let mut changed = order.clone();
+        changed.energy_kwh = 1.5;
+        assert!(!commitment.is_revealed_by(&reveal(changed, "nonce")));
+
+        // The hash is stable and serialized as hex
+        let json = serde_json::to_string(&commitment).unwrap();
+        assert_eq!(
+            serde_json::from_str::<OrderCommitment>(&json).unwrap(),
+            commitment
+        );
+        assert_eq!(OrderCommitment::new(&order, "nonce"), commitment);
+        assert!(
+            serde_json::from_str::<OrderCommitment>(r#"{"order_id": 1, "hash": "ab"}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_open_commitments() {
+        let orders: Vec<Order> = (1..=6)
+            .map(|id| order(id, OrderType::Ask, 0.1 * id as f64))
+            .collect();
+        let commitments = vec![
+            OrderCommitment::new(&orders[0], "a"),
+            OrderCommitment::new(&orders[1], "b"),
+            OrderCommitment::new(&orders[2], "c"),
+            OrderCommitment::new(&orders[3], "d"),
+            OrderCommitment::new(&orders[3], "e"),
+        ];
+        let reveals = vec![
+            reveal(orders[0].clone(), "a"),
+            reveal(orders[1].clone(), "wrong"),
+            reveal(orders[3].clone(), "d"),
+            reveal(orders[4].clone(), "f"),
+        ];
+        let (input, diagnostics) = open_commitments(&commitments, &reveals);
+        let accepted: Vec<u64> = input.orders.iter().map(|order| order.id).collect();
+        assert_eq!(accepted, vec![1]);
+        let rejected: Vec<(u64, CommitmentError)> = diagnostics
+            .iter()
+            .map(|diagnostic| match diagnostic {
+                Diagnostic::CommitmentRejected { order_id, reason } => (*order_id, *reason),
+                _ => panic!("unexpected diagnostic {diagnostic:?}"),
+            })
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (2, CommitmentError::Mismatch),
+                (3, CommitmentError::Unrevealed),
+                (4, CommitmentError::Duplicate),
+                (5, CommitmentError::Uncommitted),
+            ]
+        );
+
+        // Two reveals for one commitment
+        let reveals = vec![
+            reveal(orders[0].clone(), "a"),
+            reveal(orders[0].clone(), "a"),
+        ];
+        let (input, diagnostics) = open_commitments(&commitments[..1], &reveals);
+        assert!(input.orders.is_empty());
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic::CommitmentRejected {
+                order_id: 1,
+                reason: CommitmentError::Duplicate
+            }]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Preferred and forbidden trading partners.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MarketInput, Order};
+
+/// Rules for the counterparties of an order, by their `actor_id`.
+///
+/// Matches between a bid and an ask are not allowed if one of them forbids the actor of the
+/// other one. Among the asks a bid can afford, the asks with the highest preference weight are
+/// matched first; the weight of a pair is the weight of the ask's actor for the bid plus the
+/// weight of the bid's actor for the ask. Only positive weights count, so neighbours can prefer
+/// each other, but no actor is pushed back.
+///
+/// The rules are honored by [`pay_as_bid_matching`](crate::pay_as_bid_matching),
+/// [`uniform_price_matching`](crate::uniform_price_matching),
+/// [`custom_fair_matching`](crate::custom_fair_matching),
+/// [`local_first_matching`](crate::local_first_matching) (within each stage) and
+/// [`preference_matching`](crate::preference_matching) (only the forbidden counterparties). The
+/// other algorithms ignore them, since skipping asks would break their incentive properties.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "utility",
+///      "cluster_index": 0, "energy_kwh": 5.0, "price_euro_per_kwh": 0.2},
+///     {"id": 2, "order_type": "ask", "time_slot": "t", "actor_id": "neighbour",
+///      "cluster_index": 0, "energy_kwh": 5.0, "price_euro_per_kwh": 0.25},
+///     {"id": 3, "order_type": "ask", "time_slot": "t", "actor_id": "subsidiary",
+///      "cluster_index": 0, "energy_kwh": 5.0, "price_euro_per_kwh": 0.1},
+///     {"id": 4, "order_type": "bid", "time_slot": "t", "actor_id": "home",
+///      "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.3,
+///      "counterparties": {"preferred": {"neighbour": 1.0}, "forbidden": ["subsidiary"]}}
+/// ]}"#).unwrap();
+/// let output = pay_as_bid_matching(&input);
+/// assert_eq!(output.matches[0].ask_id, 2);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CounterpartyRules {
+    /// Preferred actors with their priority weight
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub preferred: BTreeMap<String, f64>,
+    /// Actors that may never be the counterparty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forbidden: Vec<String>,
+}
+
+impl CounterpartyRules {
+    /// Return the positive preference weight of an actor, or 0.
+    fn weight(&self, actor_id: &str) -> f64 {
+        match self.preferred.get(actor_id) {
+            Some(&weight) if weight.is_finite() && weight > 0.0 => weight,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Return `true` if the bid or the ask forbids the other one as counterparty.
+pub(crate) fn forbidden(bid: &Order, ask: &Order) -> bool {
+    let forbids = |order: &Order, other: &Order| match &order.counterparties {
+        Some(rules) => rules.forbidden.contains(&other.actor_id),
+        None => false,
+    };
+    forbids(bid, ask) || forbids(ask, bid)
+}
+
+/// Return the preference weight of a pair of orders.
+pub(crate) fn preference_weight(bid: &Order, ask: &Order) -> f64 {
+    let weight = |order: &Order, other: &Order| {
+        order
+            .counterparties
+            .as_ref()
+            .map_or(0.0, |rules| rules.weight(&other.actor_id))
+    };
+    weight(bid, ask) + weight(ask, bid)
+}
+
+/// Return `true` if any order has preferred counterparties.
+pub(crate) fn has_preferences(input: &MarketInput) -> bool {
+    input
+        .orders
+        .iter()
+        .any(|order| matches!(&order.counterparties, Some(rules) if !rules.preferred.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{custom_fair_matching, pay_as_bid_matching, GridFeeMatrix, OrderType};
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn order(id: u64, order_type: OrderType, actor_id: &str, cluster: usize, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: actor_id.to_string(),
+            cluster_index: Some(cluster),
+            energy_kwh: 2.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    fn rules(preferred: &[(&str, f64)], forbidden: &[&str]) -> Option<CounterpartyRules> {
+        Some(CounterpartyRules {
+            preferred: preferred
+                .iter()
+                .map(|&(actor_id, weight)| (actor_id.to_string(), weight))
+                .collect(),
+            forbidden: forbidden
+                .iter()
+                .map(|actor_id| actor_id.to_string())
+                .collect(),
+        })
+    }
+
+    fn pairs(input: &MarketInput, grid_fee_matrix: Option<&GridFeeMatrix>) -> Vec<(u64, u64)> {
+        let output = match grid_fee_matrix {
+            Some(grid_fee_matrix) => custom_fair_matching(input, 1.0, grid_fee_matrix),
+            None => pay_as_bid_matching(input),
+        };
+        output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id))
+            .collect()
+    }
+
+    #[test]
+    fn test_forbidden_counterparties() {
+        let mut input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, "parent", 0, 0.1),
+                order(2, OrderType::Ask, "pv", 0, 0.2),
+                order(3, OrderType::Bid, "subsidiary", 0, 0.3),
+                order(4, OrderType::Bid, "home", 0, 0.25),
+            ],
+            curve_orders: vec![],
+        };
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        assert_eq!(pairs(&input, None), vec![(3, 1), (4, 2)]);
+
+        // Either side may forbid the trade
+        input.orders[0].counterparties = rules(&[], &["subsidiary"]);
+        assert_eq!(pairs(&input, None), vec![(3, 2), (4, 1)]);
+        assert_eq!(pairs(&input, Some(&grid_fee_matrix)), vec![(3, 2), (4, 1)]);
+        input.orders[0].counterparties = None;
+        input.orders[2].counterparties = rules(&[], &["parent"]);
+        assert_eq!(pairs(&input, None), vec![(3, 2), (4, 1)]);
+        assert_eq!(pairs(&input, Some(&grid_fee_matrix)), vec![(3, 2), (4, 1)]);
+
+        // The next market maker is used if the cheapest one is forbidden
+        input.orders.truncate(3);
+        input.orders[2].counterparties = rules(&[], &["parent", "pv", "utility"]);
+        let mut market_maker = order(5, OrderType::Ask, "utility", 0, 0.15);
+        market_maker.cluster_index = None;
+        market_maker.energy_kwh = 1e12;
+        input.orders.push(market_maker.clone());
+        market_maker.id = 6;
+        market_maker.actor_id = "supplier".to_string();
+        market_maker.price_euro_per_kwh = 0.28;
+        input.orders.push(market_maker);
+        assert_eq!(pairs(&input, Some(&grid_fee_matrix)), vec![(3, 6)]);
+    }
+
+    #[test]
+    fn test_preferred_counterparties() {
+        let mut input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, "utility", 0, 0.1),
+                order(2, OrderType::Ask, "neighbour", 1, 0.2),
+                order(3, OrderType::Ask, "friend", 1, 0.22),
+                order(4, OrderType::Ask, "expensive", 0, 0.5),
+                order(5, OrderType::Bid, "home", 0, 0.3),
+            ],
+            curve_orders: vec![],
+        };
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.05], [0.05, 0]]").unwrap();
+        assert_eq!(pairs(&input, None), vec![(5, 1)]);
+
+        // Higher weights first, unaffordable asks are skipped
+        input.orders[4].counterparties = rules(
+            &[("neighbour", 1.0), ("friend", 2.0), ("expensive", 5.0)],
+            &[],
+        );
+        assert_eq!(pairs(&input, None), vec![(5, 3)]);
+        assert_eq!(pairs(&input, Some(&grid_fee_matrix)), vec![(5, 3)]);
+
+        // The weights of both sides are added
+        input.orders[1].counterparties = rules(&[("home", 1.5)], &[]);
+        assert_eq!(pairs(&input, None), vec![(5, 2)]);
+        assert_eq!(pairs(&input, Some(&grid_fee_matrix)), vec![(5, 2)]);
+
+        // Negative weights are ignored
+        input.orders[1].counterparties = None;
+        input.orders[4].counterparties = rules(&[("utility", -1.0)], &[]);
+        assert_eq!(pairs(&input, None), vec![(5, 1)]);
+    }
+}
@@ -0,0 +1,319 @@
+//! Orders with price-elastic bid and ask curves.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MarketInput, Order, OrderType};
+
+/// Linear segments of a curve are split into this many steps for matching.
+pub const LINEAR_CURVE_STEPS: usize = 10;
+
+/// How the price changes between the points of a curve.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    /// The price is constant within a segment (piecewise-constant curve)
+    #[default]
+    Step,
+    /// The price changes linearly between two points (piecewise-linear curve)
+    Linear,
+}
+
+/// A point of a price/quantity curve.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CurvePoint {
+    /// The cumulative amount of energy in kWh
+    pub energy_kwh: f64,
+    /// The price in € / kWh
+    pub price_euro_per_kwh: f64,
+}
+
+/// A bid or an ask whose price depends on the amount of energy.
+///
+/// The curve is given by points with increasing cumulative energy. Segment `i` covers the energy
+/// between point `i - 1` (or zero) and point `i`:
+///
+/// - With [`Interpolation::Step`], the price of segment `i` is the price of point `i`.
+/// - With [`Interpolation::Linear`], segment 0 has the price of point 0 and the price of
+///   segment `i > 0` changes linearly from point `i - 1` to point `i`. Such segments are matched
+///   in [`LINEAR_CURVE_STEPS`] steps that have the price of their midpoint.
+///
+/// The prices of a bid curve may not increase and the prices of an ask curve may not decrease
+/// with the energy, so cheaper energy is always bought first and more expensive energy is always
+/// sold last. Matches reference the ID of the curve order and the segment.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let curve_order: CurveOrder = serde_json::from_str(r#"{
+///     "id": 7, "order_type": "bid", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///     "interpolation": "step",
+///     "points": [
+///         {"energy_kwh": 1.0, "price_euro_per_kwh": 0.4},
+///         {"energy_kwh": 3.0, "price_euro_per_kwh": 0.2}
+///     ]
+/// }"#).unwrap();
+/// assert!(curve_order.validate().is_ok());
+/// // The second segment covers 2 kWh at 0.2 € / kWh
+/// let segment = curve_order.segment(1).unwrap();
+/// assert_eq!((segment.energy_kwh, segment.price_euro_per_kwh), (2.0, 0.2));
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CurveOrder {
+    /// The order ID, which must be different from the IDs of all other orders
+    pub id: u64,
+    /// bid or ask
+    pub order_type: OrderType,
+    pub time_slot: String,
+    pub actor_id: String,
+    pub cluster_index: Option<usize>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    pub points: Vec<CurvePoint>,
+    /// The time the order was submitted, see [`Order::submitted_at`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted_at: Option<u64>,
+}
+
+impl CurveOrder {
+    /// Check that the curve has at least one point, the energy increases and the prices are
+    /// monotonic.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.points.is_empty() {
+            return Err(format!("curve order {} has no points", self.id));
+        }
+        let mut previous: Option<&CurvePoint> = None;
+        for point in &self.points {
+            if !(point.energy_kwh.is_finite() && point.price_euro_per_kwh.is_finite()) {
+                return Err(format!("curve order {} has a non-finite point", self.id));
+            }
+            let previous_energy = previous.map_or(0.0, |p| p.energy_kwh);
+            if point.energy_kwh <= previous_energy {
+                return Err(format!(
+                    "the energy of curve order {} has to increase",
+                    self.id
+                ));
+            }
+            if let Some(previous) = previous {
+                let monotonic = match self.order_type {
+                    OrderType::Bid => point.price_euro_per_kwh <= previous.price_euro_per_kwh,
+                    OrderType::Ask => point.price_euro_per_kwh >= previous.price_euro_per_kwh,
+                };
+                if !monotonic {
+                    return Err(format!(
+                        "the prices of curve order {} have to be monotonic",
+                        self.id
+                    ));
+                }
+            }
+            previous = Some(point);
+        }
+        Ok(())
+    }
+
+    /// Return a segment as an order with the ID of the curve order.
+    ///
+    /// The price is the highest price of a bid segment or the lowest price of an ask segment,
+    /// i.e. the limit that no match of the segment may exceed.
+    pub fn segment(&self, index: usize) -> Option<Order> {
+        let point = self.points.get(index)?;
+        let start = match index {
+            0 => None,
+            _ => self.points.get(index - 1),
+        };
+        let price_euro_per_kwh = match (self.interpolation, start) {
+            (Interpolation::Linear, Some(start)) => start.price_euro_per_kwh,
+            _ => point.price_euro_per_kwh,
+        };
+        let energy_kwh = point.energy_kwh - start.map_or(0.0, |start| start.energy_kwh);
+        Some(self.order(energy_kwh, price_euro_per_kwh))
+    }
+
+    /// Split the curve into orders with a constant price, together with their segment index.
+    pub(crate) fn steps(&self) -> Vec<(Order, usize)> {
+        let mut steps = Vec::new();
+        for (index, point) in self.points.iter().enumerate() {
+            let start = match index {
+                0 => None,
+                _ => self.points.get(index - 1),
+            };
+            match (self.interpolation, start) {
+                (Interpolation::Linear, Some(start)) => {
+                    let energy_kwh =
+                        (point.energy_kwh - start.energy_kwh) / LINEAR_CURVE_STEPS as f64;
+                    let price_change = point.price_euro_per_kwh - start.price_euro_per_kwh;
+                    for step in 0..LINEAR_CURVE_STEPS {
+                        let midpoint = (step as f64 + 0.5) / LINEAR_CURVE_STEPS as f64;
+                        let price = start.price_euro_per_kwh + price_change * midpoint;
+                        steps.push((self.order(energy_kwh, price), index));
+                    }
+                }
+                _ => {
+                    if let Some(segment) = self.segment(index) {
+                        steps.push((segment, index));
+                    }
+                }
+            }
+        }
+        steps
+    }
+
+    fn order(&self, energy_kwh: f64, price_euro_per_kwh: f64) -> Order {
+        Order {
+            id: self.id,
+            order_type: self.order_type,
+            time_slot: self.time_slot.clone(),
+            actor_id: self.actor_id.clone(),
+            cluster_index: self.cluster_index,
+            energy_kwh,
+            price_euro_per_kwh,
+            submitted_at: self.submitted_at,
+            ..Default::default()
+        }
+    }
+}
+
+/// All orders of a market input that can be matched, together with the curve segment they
+/// belong to. Invalid curve orders are skipped.
+pub(crate) fn order_steps(input: &MarketInput) -> Vec<(Order, Option<usize>)> {
+    let mut steps: Vec<(Order, Option<usize>)> = input
+        .orders
+        .iter()
+        .map(|order| (order.clone(), None))
+        .collect();
+    for curve_order in &input.curve_orders {
+        if curve_order.validate().is_ok() {
+            steps.extend(
+                curve_order
+                    .steps()
+                    .into_iter()
+                    .map(|(order, segment)| (order, Some(segment))),
+            );
+        }
+    }
+    steps
+}
+
+/// Orders and curve segments keyed by order ID and segment (`None` for plain orders).
+pub(crate) type SegmentMap = BTreeMap<(u64, Option<usize>), Order>;
+
+/// All orders and curve segments of a market input.
+///
+/// The first order with an ID wins; the IDs that occur more than once are returned as well.
+pub(crate) fn orders_by_segment(input: &MarketInput) -> (SegmentMap, Vec<u64>) {
+    let mut orders = BTreeMap::new();
+    let mut duplicates = Vec::new();
+    let mut ids = BTreeSet::new();
+    for order in &input.orders {
+        if !ids.insert(order.id) {
+            duplicates.push(order.id);
+            continue;
+        }
+        orders.insert((order.id, None), order.clone());
+    }
+    for curve_order in &input.curve_orders {
+        if !ids.insert(curve_order.id) {
+            duplicates.push(curve_order.id);
+            continue;
+        }
+        for index in 0..curve_order.points.len() {
+            if let Some(segment) = curve_order.segment(index) {
+                orders.insert((curve_order.id, Some(index)), segment);
+            }
+        }
+    }
+    (orders, duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn curve_order(
+        order_type: OrderType,
+        interpolation: Interpolation,
+        points: &[(f64, f64)],
+    ) -> CurveOrder {
+        CurveOrder {
+            id: 1,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor_1".to_string(),
+            cluster_index: Some(0),
+            interpolation,
+            points: points
+                .iter()
+                .map(|&(energy_kwh, price_euro_per_kwh)| CurvePoint {
+                    energy_kwh,
+                    price_euro_per_kwh,
+                })
+                .collect(),
+            submitted_at: None,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = curve_order(
+            OrderType::Bid,
+            Interpolation::Step,
+            &[(1.0, 0.5), (2.0, 0.4)],
+        );
+        assert!(valid.validate().is_ok());
+        // Bids may not get more expensive
+        let invalid = curve_order(
+            OrderType::Bid,
+            Interpolation::Step,
+            &[(1.0, 0.4), (2.0, 0.5)],
+        );
+        assert!(invalid.validate().is_err());
+        let invalid = curve_order(
+            OrderType::Ask,
+            Interpolation::Step,
+            &[(1.0, 0.5), (1.0, 0.6)],
+        );
+        assert!(invalid.validate().is_err());
+        let invalid = curve_order(OrderType::Ask, Interpolation::Step, &[(1.0, f64::NAN)]);
+        assert!(invalid.validate().is_err());
+        assert!(curve_order(OrderType::Ask, Interpolation::Step, &[])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_steps() {
+        let step = curve_order(
+            OrderType::Ask,
+            Interpolation::Step,
+            &[(1.0, 0.2), (3.0, 0.3)],
+        );
+        let steps: Vec<(f64, f64, usize)> = step
+            .steps()
+            .into_iter()
+            .map(|(order, segment)| (order.energy_kwh, order.price_euro_per_kwh, segment))
+            .collect();
+        assert_eq!(steps, vec![(1.0, 0.2, 0), (2.0, 0.3, 1)]);
+
+        let linear = curve_order(
+            OrderType::Bid,
+            Interpolation::Linear,
+            &[(1.0, 0.5), (2.0, 0.3)],
+        );
+        let steps = linear.steps();
+        assert_eq!(steps.len(), 1 + LINEAR_CURVE_STEPS);
+        assert_eq!(steps[0].0.price_euro_per_kwh, 0.5);
+        assert!((steps[1].0.price_euro_per_kwh - 0.49).abs() < 1e-9);
+        assert!((steps[LINEAR_CURVE_STEPS].0.price_euro_per_kwh - 0.31).abs() < 1e-9);
+        let energy: f64 = steps.iter().map(|(order, _)| order.energy_kwh).sum();
+        assert!((energy - 2.0).abs() < 1e-9);
+        // The limit of the linear segment is its highest price
+        assert_eq!(linear.segment(1).unwrap().price_euro_per_kwh, 0.5);
+        assert!(linear.segment(2).is_none());
+    }
+}
@@ -0,0 +1,60 @@
+//! Notes about changes to the input that the matching algorithms made.
+
+use serde::{Deserialize, Serialize};
+
+use crate::CommitmentError;
+
+/// A change to the orders, the grid fees or the matches that was made by the matching algorithm.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Diagnostic {
+    /// An order was not matched because its price is outside the
+    /// [`PriceLimits`](crate::PriceLimits) of its cluster
+    OrderRejected {
+        order_id: u64,
+        price_euro_per_kwh: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_price_euro_per_kwh: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_price_euro_per_kwh: Option<f64>,
+    },
+    /// The price of an order was moved into the [`PriceLimits`](crate::PriceLimits) of its
+    /// cluster. Curve orders are reported once with the first point that was moved, all other
+    /// points were moved into the same bounds.
+    PriceClipped {
+        order_id: u64,
+        original_price_euro_per_kwh: f64,
+        price_euro_per_kwh: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        min_price_euro_per_kwh: Option<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_price_euro_per_kwh: Option<f64>,
+    },
+    /// A grid fee was reduced to the maximum grid fee of the
+    /// [`PriceLimits`](crate::PriceLimits)
+    GridFeeCapped {
+        source_cluster: usize,
+        dest_cluster: usize,
+        original_grid_fee_euro_per_kwh: f64,
+        grid_fee_euro_per_kwh: f64,
+    },
+    /// An order was removed because it crossed an order of the same actor, see
+    /// [`SelfTradePrevention`](crate::SelfTradePrevention)
+    SelfTradeCancelled { order_id: u64, other_order_id: u64 },
+    /// The energy of a bid and an ask of the same actor was reduced, see
+    /// [`SelfTradePrevention`](crate::SelfTradePrevention)
+    SelfTradeDecremented {
+        bid_id: u64,
+        ask_id: u64,
+        energy_kwh: f64,
+    },
+    /// A bid and an ask of the same actor were not matched with each other, see
+    /// [`SelfTradePrevention`](crate::SelfTradePrevention)
+    SelfTradeSkipped { bid_id: u64, ask_id: u64 },
+    /// A committed or revealed order was rejected by
+    /// [`open_commitments`](crate::open_commitments)
+    CommitmentRejected {
+        order_id: u64,
+        reason: CommitmentError,
+    },
+}
@@ -0,0 +1,85 @@
+//! SHA-256 digests and other fixed-size byte strings that are serialized as hex.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+
+use sha2::{Digest as _, Sha256};
+
+/// Implement `Display`, `FromStr`, `Serialize` and `Deserialize` with lowercase hex digits for a
+/// newtype around a byte array.
+macro_rules! hex_bytes {
+    ($name:ident) => {
+        impl core::fmt::Display for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                $crate::digest::write_hex(f, &self.0)
+            }
+        }
+
+        impl core::str::FromStr for $name {
+            type Err = alloc::string::String;
+
+            fn from_str(hex: &str) -> Result<Self, Self::Err> {
+                $crate::digest::parse_hex(hex).map($name)
+            }
+        }
+
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let hex = alloc::string::String::deserialize(deserializer)?;
+                hex.parse().map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+pub(crate) use hex_bytes;
+
+/// Write bytes as lowercase hex digits.
+pub(crate) fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, "{byte:02x}")?;
+    }
+    Ok(())
+}
+
+/// Parse exactly `2 * N` hex digits.
+pub(crate) fn parse_hex<const N: usize>(hex: &str) -> Result<[u8; N], String> {
+    if hex.len() != 2 * N || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(format!("{hex:?} is not a string of {} hex digits", 2 * N));
+    }
+    let mut bytes = [0; N];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * index..2 * index + 2], 16)
+            .map_err(|err| format!("{hex:?} is not valid hex: {err}"))?;
+    }
+    Ok(bytes)
+}
+
+/// A SHA-256 digest, serialized as 64 lowercase hex digits.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let digest = Digest::of(b"abc");
+/// assert_eq!(
+///     digest.to_string(),
+///     "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+/// );
+/// assert_eq!(digest.to_string().parse::<Digest>(), Ok(digest));
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Digest(pub [u8; 32]);
+
+hex_bytes!(Digest);
+
+impl Digest {
+    /// Return the digest of some bytes.
+    pub fn of(bytes: &[u8]) -> Self {
+        Digest(Sha256::digest(bytes).into())
+    }
+}
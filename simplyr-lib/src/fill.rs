@@ -0,0 +1,355 @@
+//! Fill constraints for orders that can't be matched partially.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MarketInput, MarketOutput, Order, ENERGY_EPS};
+
+/// Restricts how an order may be filled, e.g. for producers that can only run at full output.
+///
+/// An order with a fill constraint is either not matched at all or matched according to all rules
+/// that are set. Matched energy is rounded to [`ENERGY_EPS`], so the rules allow for a rounding
+/// error of half of `ENERGY_EPS` per match.
+///
+/// The batch algorithms honor fill constraints with the following heuristic:
+///
+/// 1. Pay-as-Bid, uniform-price and custom fair matching skip pairs of orders whose match would
+///    be smaller than the minimum match size of the bid or the ask and try the next one instead.
+/// 2. The orders whose constraints are violated by the result are removed from the input and
+///    reported in the [residual book](MarketOutput::residual_book). The matching is repeated
+///    until no constraint is violated. Every repetition removes at least one order.
+///
+/// Orders with the same [`link_id`](Order::link_id) are treated like a single block: if one of
+/// them can't be matched, the others are removed as well.
+///
+/// The heuristic does not search for the best set of orders to accept, so an order may be left
+/// out although there is a solution that includes it. The [`OrderBook`](crate::OrderBook) ignores
+/// fill constraints.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "chp", "cluster_index": 0,
+///      "energy_kwh": 5.0, "price_euro_per_kwh": 0.2,
+///      "fill_constraint": {"all_or_nothing": true}},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 3.0, "price_euro_per_kwh": 0.4}
+/// ]}"#).unwrap();
+/// let output = pay_as_bid_matching(&input);
+/// // The ask can't be filled completely, so it is not matched at all
+/// assert!(output.matches.is_empty());
+/// assert_eq!(output.residual_book[0].order_id, 1);
+/// assert_eq!(output.residual_book[0].rule, FillRule::MinimumFill);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FillConstraint {
+    /// The order has to be matched completely (a block order)
+    #[serde(default)]
+    pub all_or_nothing: bool,
+    /// The matched share of the energy has to be at least this ratio (between 0 and 1)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_acceptance_ratio: Option<f64>,
+    /// Every match of the order has to contain at least this amount of energy in kWh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_match_kwh: Option<f64>,
+}
+
+impl FillConstraint {
+    /// Return the smallest amount of energy (in kWh) that has to be matched if the order with
+    /// the given energy is matched at all.
+    pub fn min_fill_kwh(&self, energy_kwh: f64) -> f64 {
+        if self.all_or_nothing {
+            return energy_kwh;
+        }
+        match self.min_acceptance_ratio {
+            Some(ratio) if ratio > 0.0 => ratio.min(1.0) * energy_kwh,
+            // Also ignores NaN values
+            _ => 0.0,
+        }
+    }
+
+    /// Return the smallest amount of energy (in kWh) of a single match.
+    pub fn min_match_kwh(&self) -> f64 {
+        // NaN values are ignored
+        self.min_match_kwh.unwrap_or(0.0).max(0.0)
+    }
+}
+
+/// The rules of a [`FillConstraint`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FillRule {
+    /// The order has to be matched completely or with a minimum acceptance ratio
+    MinimumFill,
+    /// Every match of the order has to have a minimum size
+    MinimumMatchSize,
+    /// The order is linked to an order that could not be matched (see [`Order::link_id`])
+    Link,
+}
+
+/// An order that was not matched because its fill constraint could not be met.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ResidualOrder {
+    /// The order ID
+    pub order_id: u64,
+    /// The amount of energy in kWh that was not matched
+    pub energy_kwh: f64,
+    /// The rule that prevented the trade
+    pub rule: FillRule,
+}
+
+/// Return the minimum match size of an order (zero if it has no fill constraint).
+pub(crate) fn min_match_kwh(order: &Order) -> f64 {
+    order
+        .fill_constraint
+        .as_ref()
+        .map_or(0.0, FillConstraint::min_match_kwh)
+}
+
+/// Return the orders whose fill constraints are violated by a market output, together with the
+/// first rule that is violated.
+///
+/// Only plain orders can have fill constraints, so matches of curve segments are ignored. The
+/// first order with an ID wins. Linked orders that are matched although another order of the
+/// link is not are reported with [`FillRule::Link`]; links to orders that are not part of the
+/// input are ignored.
+pub(crate) fn fill_violations(input: &MarketInput, output: &MarketOutput) -> Vec<(u64, FillRule)> {
+    // Map from order ID -> energy of all matches
+    let mut matched: BTreeMap<u64, Vec<f64>> = BTreeMap::new();
+    for m in &output.matches {
+        if m.bid_segment.is_none() {
+            matched.entry(m.bid_id).or_default().push(m.energy_kwh);
+        }
+        if m.ask_segment.is_none() {
+            matched.entry(m.ask_id).or_default().push(m.energy_kwh);
+        }
+    }
+
+    let mut violations = vec![];
+    let mut ids = BTreeSet::new();
+    for order in &input.orders {
+        if !ids.insert(order.id) {
+            continue;
+        }
+        let (constraint, energies) = match (&order.fill_constraint, matched.get(&order.id)) {
+            (Some(constraint), Some(energies)) => (constraint, energies),
+            _ => continue,
+        };
+        let rounding = ENERGY_EPS / 2.0 + 1e-9;
+        let total: f64 = energies.iter().sum();
+        if total < constraint.min_fill_kwh(order.energy_kwh) - rounding * energies.len() as f64 {
+            violations.push((order.id, FillRule::MinimumFill));
+        } else if energies
+            .iter()
+            .any(|&energy| energy < constraint.min_match_kwh() - rounding)
+        {
+            violations.push((order.id, FillRule::MinimumMatchSize));
+        }
+    }
+
+    // Map from link ID -> (order ID, accepted) of all orders of the link
+    let violated: BTreeSet<u64> = violations.iter().map(|&(order_id, _)| order_id).collect();
+    let mut links: BTreeMap<u64, Vec<(u64, bool)>> = BTreeMap::new();
+    let mut ids = BTreeSet::new();
+    for order in &input.orders {
+        if let (true, Some(link_id)) = (ids.insert(order.id), order.link_id) {
+            let accepted = matched.contains_key(&order.id) && !violated.contains(&order.id);
+            links.entry(link_id).or_default().push((order.id, accepted));
+        }
+    }
+    for orders in links.values() {
+        if orders.iter().any(|&(_, accepted)| !accepted) {
+            for &(order_id, accepted) in orders {
+                if accepted {
+                    violations.push((order_id, FillRule::Link));
+                }
+            }
+        }
+    }
+    violations
+}
+
+/// Run a matching algorithm repeatedly until no fill constraint is violated (see
+/// [`FillConstraint`]).
+///
+/// `market_output` gives access to the matches of the algorithm's result. The removed orders are
+/// stored in its residual book.
+pub(crate) fn with_fill_constraints<T, F, M>(
+    input: &MarketInput,
+    matching: F,
+    market_output: M,
+) -> T
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    if input
+        .orders
+        .iter()
+        .all(|order| order.fill_constraint.is_none() && order.link_id.is_none())
+    {
+        return matching(input);
+    }
+
+    // Removed orders are still checked, so the orders linked to them are removed as well
+    let mut remaining = input.clone();
+    let mut residual_book = vec![];
+    loop {
+        let mut output = matching(&remaining);
+        let violations = fill_violations(input, market_output(&mut output));
+        if violations.is_empty() {
+            market_output(&mut output).residual_book = residual_book;
+            return output;
+        }
+        for &(order_id, rule) in &violations {
+            if let Some(order) = input.orders.iter().find(|order| order.id == order_id) {
+                residual_book.push(ResidualOrder {
+                    order_id,
+                    energy_kwh: order.energy_kwh,
+                    rule,
+                });
+            }
+        }
+        let removed: BTreeSet<u64> = violations.iter().map(|&(order_id, _)| order_id).collect();
+        remaining
+            .orders
+            .retain(|order| !removed.contains(&order.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{custom_fair_matching, pay_as_bid_matching, GridFeeMatrix, OrderType};
+    use alloc::string::ToString;
+
+    fn order(
+        id: u64,
+        order_type: OrderType,
+        energy_kwh: f64,
+        price_euro_per_kwh: f64,
+        fill_constraint: Option<FillConstraint>,
+    ) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor".to_string() + &id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh,
+            price_euro_per_kwh,
+            fill_constraint,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_min_fill() {
+        let constraint = FillConstraint {
+            min_acceptance_ratio: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(constraint.min_fill_kwh(4.0), 2.0);
+        let constraint = FillConstraint {
+            all_or_nothing: true,
+            min_acceptance_ratio: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(constraint.min_fill_kwh(4.0), 4.0);
+        let constraint = FillConstraint {
+            min_acceptance_ratio: Some(f64::NAN),
+            min_match_kwh: Some(f64::NAN),
+            ..Default::default()
+        };
+        assert_eq!(constraint.min_fill_kwh(4.0), 0.0);
+        assert_eq!(constraint.min_match_kwh(), 0.0);
+    }
+
+    #[test]
+    fn test_block_order() {
+        let all_or_nothing = Some(FillConstraint {
+            all_or_nothing: true,
+            ..Default::default()
+        });
+        // The block ask of 5 kWh needs both bids
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 5.0, 0.2, all_or_nothing.clone()),
+                order(2, OrderType::Ask, 2.0, 0.3, None),
+                order(3, OrderType::Bid, 3.0, 0.5, None),
+                order(4, OrderType::Bid, 2.0, 0.4, None),
+            ],
+            curve_orders: vec![],
+        };
+        let output = pay_as_bid_matching(&input);
+        let matched: f64 = output
+            .matches
+            .iter()
+            .filter(|m| m.ask_id == 1)
+            .map(|m| m.energy_kwh)
+            .sum();
+        assert_eq!(matched, 5.0);
+        assert!(output.residual_book.is_empty());
+
+        // Without the second bid, the block ask is removed and the other ask is matched instead
+        let mut input = input;
+        input.orders.pop();
+        let output = pay_as_bid_matching(&input);
+        assert_eq!(output.matches.len(), 1);
+        assert_eq!(
+            (output.matches[0].ask_id, output.matches[0].energy_kwh),
+            (2, 2.0)
+        );
+        assert_eq!(
+            output.residual_book,
+            vec![ResidualOrder {
+                order_id: 1,
+                energy_kwh: 5.0,
+                rule: FillRule::MinimumFill,
+            }]
+        );
+
+        // The custom fair matching can't split the block ask into energy units either
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0]]).unwrap();
+        let output = custom_fair_matching(&input, 1.0, &gfm);
+        assert!(output.matches.iter().all(|m| m.ask_id == 2));
+        assert_eq!(output.residual_book.len(), 1);
+    }
+
+    #[test]
+    fn test_min_match_size() {
+        let min_match = Some(FillConstraint {
+            min_match_kwh: Some(2.0),
+            ..Default::default()
+        });
+        // The small bid is skipped instead of removing the ask
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 4.0, 0.2, min_match),
+                order(2, OrderType::Bid, 1.0, 0.5, None),
+                order(3, OrderType::Bid, 3.0, 0.4, None),
+            ],
+            curve_orders: vec![],
+        };
+        for output in [
+            pay_as_bid_matching(&input),
+            custom_fair_matching(
+                &input,
+                0.5,
+                &GridFeeMatrix::from_raw(&vec![vec![0.0]]).unwrap(),
+            ),
+        ] {
+            assert_eq!(output.matches.len(), 1);
+            assert_eq!(
+                (output.matches[0].bid_id, output.matches[0].energy_kwh),
+                (3, 3.0)
+            );
+            assert!(output.residual_book.is_empty());
+            assert!(fill_violations(&input, &output).is_empty());
+        }
+    }
+}
@@ -13,21 +13,87 @@ use crate::alloc::string::ToString;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
-// We use this instead of [`HashMap`] in `no_std` because we don't have access to a secure source
-// of random numbers to avoid hash collision attacks.
-use alloc::collections::btree_map::BTreeMap;
-// We use this instead of [`HashSet`]. See above.
-use alloc::collections::btree_set::BTreeSet;
 
 // We can annotate our structs with custom derives of these traits.
 // Code for serializing and deserializing will then be generated for us.
 use serde::{Deserialize, Serialize};
 
+mod allocation;
+mod canonical;
+mod commitment;
+mod counterparty;
+mod curve;
+mod diagnostic;
+mod digest;
+mod fill;
+mod limits;
+mod mcafee;
+mod merkle;
+mod metrics;
+mod multi_slot;
+mod order_book;
+mod pseudonym;
+pub mod rng;
+mod scenario;
+mod self_trade;
+mod signature;
+mod source;
+mod storage;
+mod tie_break;
+mod vcg;
+mod verify;
+
+use allocation::{equal_price_runs, pro_rata_energy, pro_rata_shares};
+use counterparty::{forbidden, has_preferences, preference_weight};
+use curve::order_steps;
+use fill::{min_match_kwh, with_fill_constraints};
+use limits::with_price_limits;
+use self_trade::with_self_trade_prevention;
+
+pub use allocation::Allocation;
+pub use commitment::{open_commitments, CommitmentError, OrderCommitment, OrderReveal};
+pub use counterparty::CounterpartyRules;
+pub use curve::{CurveOrder, CurvePoint, Interpolation, LINEAR_CURVE_STEPS};
+pub use diagnostic::Diagnostic;
+pub use digest::Digest;
+pub use fill::{FillConstraint, FillRule, ResidualOrder};
+pub use limits::{LimitPolicy, PriceBounds, PriceLimits};
+pub use mcafee::{mcafee_matching, mcafee_matching_with_config, McAfeeOutput};
+pub use merkle::{MerkleProof, MerkleTree};
+pub use metrics::{market_metrics, ActorMetrics, ClusterMetrics, MarketMetrics, PriceStatistics};
+pub use multi_slot::multi_slot_matching;
+pub use order_book::{Depth, OrderBook, PriceLevel};
+pub use pseudonym::{depseudonymize, pseudonymize, PseudonymKey};
+pub use scenario::{generate_scenario, PriceDistribution, Scenario, ScenarioConfig};
+pub use self_trade::SelfTradePrevention;
+pub use signature::{PublicKey, SecretKey, Signature, SignedMarketOutput};
+pub use source::{
+    preference_matching, preference_matching_with_config, EnergySource, SourceInfo,
+    SourcePreference,
+};
+pub use storage::{simulate_battery, Battery, BatterySimulation, BatterySlot};
+pub use tie_break::TieBreak;
+pub use vcg::{vcg_matching, vcg_matching_with_config, VcgOutput, VcgPayment};
+pub use verify::{verify_market_output, VerificationReport, Violation};
+
 /// Smallest energy value (in kWh) that is used for a match.
-const ENERGY_EPS: f64 = 0.001;
+pub const ENERGY_EPS: f64 = 0.001;
 
 fn round_energy_value(energy: f64) -> f64 {
-    (energy * 1000.0).round() / 1000.0
+    libm::round(energy * 1000.0) / 1000.0
+}
+
+/// Energy units smaller than the smallest energy value of a match make no sense.
+fn is_valid_energy_unit(energy_unit_kwh: f64) -> bool {
+    energy_unit_kwh.is_finite() && energy_unit_kwh >= ENERGY_EPS
+}
+
+/// Return the number of full energy units in an amount of energy.
+fn energy_units(energy_kwh: f64, energy_unit_kwh: f64) -> u64 {
+    // Allow for rounding errors when quantizing the energy to full energy units
+    let units = libm::floor((energy_kwh + ENERGY_EPS / 2.0) / energy_unit_kwh);
+    // Saturates for huge values and returns 0 for NaN
+    units.max(0.0) as u64
 }
 
 /// A enumeration of the two possible order types.
@@ -54,16 +120,75 @@ pub struct Order {
     pub energy_kwh: f64,
     /// The price in € / kWh
     pub price_euro_per_kwh: f64,
+    /// The time the order was submitted (e.g. a Unix timestamp in milliseconds). It is only used
+    /// to prioritize orders with equal prices, see [`TieBreak::SubmissionTime`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted_at: Option<u64>,
+    /// Restricts how the order may be filled, see [`FillConstraint`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fill_constraint: Option<FillConstraint>,
+    /// Orders with the same link ID (e.g. in different time slots) are only matched together,
+    /// see [`multi_slot_matching`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_id: Option<u64>,
+    /// Where the energy of an ask comes from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy_source: Option<SourceInfo>,
+    /// The energy a bid wants to buy, see [`preference_matching`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_preference: Option<SourcePreference>,
+    /// Preferred and forbidden counterparties, see [`CounterpartyRules`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparties: Option<CounterpartyRules>,
+}
+
+/// The default order is a bid with ID 0, an empty time slot and actor ID, no cluster, no energy,
+/// a price of 0 and none of the optional settings. Useful to only set some fields:
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let ask = Order {
+///     id: 1,
+///     order_type: OrderType::Ask,
+///     energy_kwh: 2.0,
+///     price_euro_per_kwh: 0.3,
+///     ..Default::default()
+/// };
+/// assert!(ask.time_slot.is_empty() && ask.cluster_index.is_none());
+/// ```
+impl Default for Order {
+    fn default() -> Self {
+        Order {
+            id: 0,
+            order_type: OrderType::Bid,
+            time_slot: String::new(),
+            actor_id: String::new(),
+            cluster_index: None,
+            energy_kwh: 0.0,
+            price_euro_per_kwh: 0.0,
+            submitted_at: None,
+            fill_constraint: None,
+            link_id: None,
+            energy_source: None,
+            source_preference: None,
+            counterparties: None,
+        }
+    }
 }
 
-/// The market input contains all orders of a time slot.
+/// The market input contains all orders of a time slot (or of several time slots, see
+/// [`multi_slot_matching`]).
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MarketInput {
     pub orders: Vec<Order>,
+    /// Orders with price/quantity curves. They are matched by Pay-as-Bid and uniform-price
+    /// matching and ignored by the other algorithms.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub curve_orders: Vec<CurveOrder>,
 }
 
 /// A match between a bid and an ask.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct Match {
     /// The order ID of the bid
     pub bid_id: u64,
@@ -73,12 +198,43 @@ pub struct Match {
     pub energy_kwh: f64,
     /// The price in € / kWh
     pub price_euro_per_kwh: f64,
+    /// The segment of the bid if it is a [`CurveOrder`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bid_segment: Option<usize>,
+    /// The segment of the ask if it is a [`CurveOrder`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ask_segment: Option<usize>,
+    /// The stage of the [`local_first_matching`] that produced the match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<MatchStage>,
+    /// The source of the energy, set by the [`preference_matching`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub energy_source: Option<SourceInfo>,
+}
+
+/// The stages of the [`local_first_matching`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchStage {
+    /// Bid and ask belong to the same cluster
+    Local,
+    /// The bid was matched with an ask of any cluster after all local matches
+    CrossCluster,
+    /// Bid or ask belong to the market maker
+    MarketMaker,
 }
 
 /// The market output contains all matches of a time slot.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct MarketOutput {
     pub matches: Vec<Match>,
+    /// Orders that were not matched because their [`FillConstraint`] could not be met
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub residual_book: Vec<ResidualOrder>,
+    /// Orders, grid fees and matches that were changed or rejected because of the
+    /// [`PriceLimits`] or the [`SelfTradePrevention`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 /// This type is only used to interface with JSON and may not be useful in a public interface.
@@ -90,7 +246,7 @@ pub type GridFeeMatrixRaw = Vec<Vec<f64>>;
 /// add a source of confusion.
 ///
 /// ```
-/// # use rust_matching_lib::*;
+/// # use simplyr_lib::*;
 /// # fn foo() -> Result<(), String> {
 /// let json_str = "[
 ///   [0, 1, 1.2],
@@ -123,11 +279,15 @@ impl GridFeeMatrix {
     /// Create a `GridFeeMatrix` from a `GridFeeMatrixRaw`.
     pub fn from_raw(raw: &GridFeeMatrixRaw) -> Result<Self, String> {
         let size = raw.len();
+        // Check the shape before allocating, so a long list of empty rows can't exhaust memory
+        if raw.iter().any(|vec_a| vec_a.len() != size) {
+            return Err(
+                "matrix needs to be square -> every row/column array has to have the same size."
+                    .into(),
+            );
+        }
         let mut flat_matrix = vec![0.0; size * size];
         for (source_cluster_idx, vec_a) in raw.iter().enumerate() {
-            if vec_a.len() != size {
-                return Err("matrix needs to be square -> every row/column array has to have the same size.".into());
-            }
             for (dest_cluster_idx, &value) in vec_a.iter().enumerate() {
                 let flat_index = (source_cluster_idx * size) + dest_cluster_idx;
                 flat_matrix[flat_index] = value;
@@ -138,246 +298,763 @@ impl GridFeeMatrix {
 
     /// Return the fee between a source cluster and a destination cluster.
     /// Indices are zero-based.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the clusters is not part of the matrix. Use [`GridFeeMatrix::get`] for
+    /// indices that come from untrusted input.
     pub fn lookup(&self, source_cluster_idx: usize, dest_cluster_idx: usize) -> f64 {
         assert!(source_cluster_idx < self.size);
         assert!(dest_cluster_idx < self.size);
         self.flat_matrix[(source_cluster_idx * self.size) + dest_cluster_idx]
     }
+
+    /// Return the fee between a source cluster and a destination cluster, or `None` if one of
+    /// the clusters is not part of the matrix. Indices are zero-based.
+    pub fn get(&self, source_cluster_idx: usize, dest_cluster_idx: usize) -> Option<f64> {
+        if source_cluster_idx >= self.size || dest_cluster_idx >= self.size {
+            return None;
+        }
+        let flat_index = source_cluster_idx
+            .checked_mul(self.size)?
+            .checked_add(dest_cluster_idx)?;
+        self.flat_matrix.get(flat_index).copied()
+    }
+}
+
+/// Settings that are shared by all matching algorithms.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MatchingConfig {
+    /// How orders with equal prices are prioritized
+    #[serde(default)]
+    pub tie_break: TieBreak,
+    /// How energy is shared among bids at the marginal price
+    #[serde(default)]
+    pub allocation: Allocation,
+    /// Regulated price bounds and the maximum grid fee
+    #[serde(default)]
+    pub price_limits: PriceLimits,
+    /// What happens to crossing orders of the same actor
+    #[serde(default)]
+    pub self_trade_prevention: SelfTradePrevention,
+}
+
+impl MatchingConfig {
+    /// Return `true` if the bid and the ask may be matched with each other because of their
+    /// [`CounterpartyRules`] and the self-trade prevention.
+    pub(crate) fn allows(&self, bid: &Order, ask: &Order) -> bool {
+        !forbidden(bid, ask) && !self.self_trade_prevention.prevents(bid, ask)
+    }
+}
+
+/// Run a matching algorithm with the fill constraints, the price limits and the self-trade
+/// prevention of the config.
+pub(crate) fn with_matching_config<T, F, M>(
+    input: &MarketInput,
+    config: &MatchingConfig,
+    matching: F,
+    market_output: M,
+) -> T
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    with_fill_constraints(
+        input,
+        |input| {
+            with_price_limits(
+                input,
+                &config.price_limits,
+                |input| {
+                    with_self_trade_prevention(
+                        input,
+                        config.self_trade_prevention,
+                        &matching,
+                        &market_output,
+                    )
+                },
+                &market_output,
+            )
+        },
+        &market_output,
+    )
 }
 
 /// A very simple (and flawed) implementation of Pay-as-Bid matching.
+///
+/// The segments of [`CurveOrder`]s are matched like separate orders. Bids are matched with the
+/// asks of preferred counterparties first and never with forbidden ones, see
+/// [`CounterpartyRules`].
 pub fn pay_as_bid_matching(input: &MarketInput) -> MarketOutput {
-    let mut bids: Vec<Order> = vec![];
-    let mut asks: Vec<Order> = vec![];
+    pay_as_bid_matching_with_config(input, &MatchingConfig::default())
+}
+
+/// Pay-as-Bid matching with explicit settings. See [`pay_as_bid_matching`].
+pub fn pay_as_bid_matching_with_config(
+    input: &MarketInput,
+    config: &MatchingConfig,
+) -> MarketOutput {
+    with_matching_config(
+        input,
+        config,
+        |input| {
+            let matches = merit_order_matching(input, config, None)
+                .into_iter()
+                .map(|(m, _)| m)
+                .collect();
+            MarketOutput {
+                matches,
+                residual_book: vec![],
+                diagnostics: vec![],
+            }
+        },
+        |output| output,
+    )
+}
+
+/// Uniform-price matching.
+///
+/// The energy is allocated like in [`pay_as_bid_matching`], but all matches get the same price:
+/// the mean of the lowest matched bid price and the highest matched ask price. So no buyer pays
+/// more than their bid and no seller gets less than their ask.
+///
+/// Like in [`pay_as_bid_matching`], pairs of orders that may not trade with each other or whose
+/// match would be too small are skipped. If that leaves no price that suits all matches, only the
+/// bids at or above and the asks at or below a common price are matched, choosing the price with
+/// the most traded energy.
+pub fn uniform_price_matching(input: &MarketInput) -> MarketOutput {
+    uniform_price_matching_with_config(input, &MatchingConfig::default())
+}
+
+/// Uniform-price matching with explicit settings. See [`uniform_price_matching`].
+pub fn uniform_price_matching_with_config(
+    input: &MarketInput,
+    config: &MatchingConfig,
+) -> MarketOutput {
+    with_matching_config(
+        input,
+        config,
+        |input| uniform_price(input, config),
+        |output| output,
+    )
+}
 
-    // Gather bids and asks
-    for order in input.orders.iter().cloned() {
+/// Uniform-price matching without the repetitions for fill constraints and the price limits.
+fn uniform_price(input: &MarketInput, config: &MatchingConfig) -> MarketOutput {
+    let mut matches = merit_order_matching(input, config, None);
+    // Skipping asks (for minimum match sizes, counterparty rules or self-trade prevention) can
+    // match a low bid with a cheap ask while a higher bid gets an expensive ask, so there is no
+    // price that suits all of them. Then only the bids and asks that accept a common price are
+    // matched, using the price with the most traded energy.
+    if lowest_bid_price(&matches) < highest_ask_price(&matches) {
+        let mut prices: Vec<f64> = order_steps(input)
+            .into_iter()
+            .map(|(order, _)| order.price_euro_per_kwh)
+            .filter(|price| price.is_finite())
+            .collect();
+        prices.sort_by(f64::total_cmp);
+        prices.dedup();
+        let mut traded_energy = 0.0;
+        matches = vec![];
+        for price in prices {
+            let candidate = merit_order_matching(input, config, Some(price));
+            let energy: f64 = candidate.iter().map(|(m, _)| m.energy_kwh).sum();
+            if energy > traded_energy {
+                traded_energy = energy;
+                matches = candidate;
+            }
+        }
+    }
+    // Without skipped asks, bids are matched with the cheapest asks first, so the highest
+    // matched ask price is never above the lowest matched bid price
+    let clearing_price = (lowest_bid_price(&matches) + highest_ask_price(&matches)) / 2.0;
+
+    let matches = matches
+        .into_iter()
+        .map(|(m, _)| Match {
+            price_euro_per_kwh: clearing_price,
+            ..m
+        })
+        .collect();
+    MarketOutput {
+        matches,
+        residual_book: vec![],
+        diagnostics: vec![],
+    }
+}
+
+/// The lowest bid price of the result of [`merit_order_matching`].
+fn lowest_bid_price(matches: &[(Match, f64)]) -> f64 {
+    matches
+        .iter()
+        .map(|(m, _)| m.price_euro_per_kwh)
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// The highest ask price of the result of [`merit_order_matching`].
+fn highest_ask_price(matches: &[(Match, f64)]) -> f64 {
+    matches
+        .iter()
+        .map(|&(_, ask_price)| ask_price)
+        .fold(f64::NEG_INFINITY, f64::max)
+}
+
+/// Match the highest bids with the cheapest asks. Returns the matches at the bid price together
+/// with the ask price.
+///
+/// Asks are skipped if the match would be smaller than the minimum match size of the bid or the
+/// ask or if the config doesn't allow the pair (forbidden counterparties or self-trade
+/// prevention). Asks of preferred counterparties are matched first. If a `price` is given, only
+/// the bids with at least this price and the asks with at most this price are matched.
+fn merit_order_matching(
+    input: &MarketInput,
+    config: &MatchingConfig,
+    price: Option<f64>,
+) -> Vec<(Match, f64)> {
+    // Orders and curve segments with their segment index
+    let mut bids: Vec<(Order, Option<usize>)> = vec![];
+    let mut asks: Vec<(Order, Option<usize>)> = vec![];
+
+    let (min_bid_price, max_ask_price) =
+        price.map_or((f64::NEG_INFINITY, f64::INFINITY), |price| (price, price));
+    // Gather bids and asks, orders with invalid values can't be matched
+    for (order, segment) in order_steps(input)
+        .into_iter()
+        .filter(|(order, _)| order.energy_kwh.is_finite() && order.price_euro_per_kwh.is_finite())
+    {
         match order.order_type {
-            OrderType::Bid => {
-                bids.push(order);
+            OrderType::Bid if order.price_euro_per_kwh >= min_bid_price => {
+                bids.push((order, segment));
             }
-            OrderType::Ask => {
-                asks.push(order);
+            OrderType::Ask if order.price_euro_per_kwh <= max_ask_price => {
+                asks.push((order, segment));
             }
+            _ => {}
         }
     }
 
     let mut matches = vec![];
 
-    // Sort by price
-    bids.sort_by(|a, b| {
+    // Sort by price, orders with equal prices by the tie-break rule. The sort is stable, so the
+    // segments of a curve order stay in order.
+    bids.sort_by(|(a, _), (b, _)| {
         a.price_euro_per_kwh
             .total_cmp(&b.price_euro_per_kwh)
             .reverse()
+            .then_with(|| config.tie_break.compare(a, b))
+    });
+    asks.sort_by(|(a, _), (b, _)| {
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .then_with(|| config.tie_break.compare(a, b))
     });
-    asks.sort_by(|a, b| a.price_euro_per_kwh.total_cmp(&b.price_euro_per_kwh));
 
     // Make bids immutable to avoid accidentally changing them
     let bids = bids;
+    let prioritize = has_preferences(input);
+
+    // match, one group of bids with equal price after another
+    for level in equal_price_runs(&bids, |(bid, _)| bid.price_euro_per_kwh) {
+        let level_price = level[0].0.price_euro_per_kwh;
+        let energies: Vec<f64> = level.iter().map(|(bid, _)| bid.energy_kwh).collect();
+        let budgets = match config.allocation {
+            Allocation::PriceTime => energies,
+            Allocation::ProRata => {
+                // Only asks that can be matched with at least one of the bids are available
+                let matchable = |ask: &Order| {
+                    level.iter().any(|(bid, _)| {
+                        ask.energy_kwh.min(bid.energy_kwh)
+                            >= min_match_kwh(bid).max(min_match_kwh(ask))
+                            && config.allows(bid, ask)
+                    })
+                };
+                let supply: f64 = asks
+                    .iter()
+                    .filter(|(ask, _)| {
+                        level_price >= ask.price_euro_per_kwh
+                            && ask.energy_kwh > ENERGY_EPS
+                            && matchable(ask)
+                    })
+                    .map(|(ask, _)| ask.energy_kwh)
+                    .sum();
+                pro_rata_energy(supply, &energies)
+            }
+        };
 
-    // match
-    for bid in &bids {
-        let mut remaining_energy = bid.energy_kwh;
-        for ask in asks.iter_mut() {
-            if (bid.price_euro_per_kwh >= ask.price_euro_per_kwh) && (ask.energy_kwh > ENERGY_EPS) {
-                let matched_energy = ask.energy_kwh.min(remaining_energy);
-                matches.push(Match {
-                    bid_id: bid.id,
-                    ask_id: ask.id,
-                    energy_kwh: round_energy_value(matched_energy),
-                    price_euro_per_kwh: bid.price_euro_per_kwh,
+        for ((bid, bid_segment), budget) in level.iter().zip(budgets) {
+            let mut remaining_energy = budget;
+            if remaining_energy < ENERGY_EPS {
+                continue;
+            }
+            // Asks of preferred counterparties first, the sort is stable
+            let mut ask_order: Vec<usize> = (0..asks.len()).collect();
+            if prioritize {
+                ask_order.sort_by(|&a, &b| {
+                    preference_weight(bid, &asks[b].0)
+                        .total_cmp(&preference_weight(bid, &asks[a].0))
                 });
-                ask.energy_kwh -= matched_energy;
-                remaining_energy -= matched_energy;
-                if remaining_energy < ENERGY_EPS {
-                    break;
+            }
+            for ask_idx in ask_order {
+                let (ask, ask_segment) = &mut asks[ask_idx];
+                if (bid.price_euro_per_kwh >= ask.price_euro_per_kwh)
+                    && (ask.energy_kwh > ENERGY_EPS)
+                {
+                    let matched_energy = ask.energy_kwh.min(remaining_energy);
+                    // Try the next ask if the match would be too small for one of the orders or
+                    // if they may not trade with each other
+                    if matched_energy < min_match_kwh(bid).max(min_match_kwh(ask))
+                        || !config.allows(bid, ask)
+                    {
+                        continue;
+                    }
+                    let m = Match {
+                        bid_id: bid.id,
+                        ask_id: ask.id,
+                        energy_kwh: round_energy_value(matched_energy),
+                        price_euro_per_kwh: bid.price_euro_per_kwh,
+                        bid_segment: *bid_segment,
+                        ask_segment: *ask_segment,
+                        stage: None,
+                        energy_source: None,
+                    };
+                    matches.push((m, ask.price_euro_per_kwh));
+                    ask.energy_kwh -= matched_energy;
+                    remaining_energy -= matched_energy;
+                    if remaining_energy < ENERGY_EPS {
+                        break;
+                    }
                 }
             }
         }
     }
 
-    MarketOutput { matches }
+    matches
 }
 
-struct FairMatchingOrder {
-    orig_id: u64,
+/// Orders with at least this amount of energy (in kWh) are not matched within the clusters.
+const LARGE_ORDER_THRESHOLD: f64 = 2_u64.pow(32) as f64;
+
+/// Orders with at least this amount of energy (in kWh) are treated as orders of the market maker.
+//NOTE: 2^63 - 1 is too large to be represented by a f64 correctly, so I chose a smaller value.
+const MARKET_MAKER_THRESHOLD: f64 = (2_u64.pow(36)) as f64;
+
+/// An order of the custom fair matching that is divided into energy units.
+///
+/// We only store the number of units instead of one entry per unit, so the memory usage does not
+/// depend on the amount of energy in the orders.
+struct FairMatchingOrder<'a> {
+    order: &'a Order,
     cluster_index: usize,
-    price_euro_per_kwh: f64,
-    adjusted_price: f64,
+    /// Number of energy units that are not matched yet
+    remaining_units: u64,
 }
 
 /// An implementation of our custom BEST matching algorithm.
+///
+/// Orders are divided into units of `energy_unit_kwh` (at least [`ENERGY_EPS`], otherwise there
+/// are no matches); remainders that are smaller than one unit are not matched. The clusters are
+/// processed in ascending order. The bids of a cluster (highest price first) are matched with the
+/// cheapest asks of all clusters, where the price of an ask is adjusted by the grid fee between
+/// the two clusters. The matched energy is no longer available to the following clusters.
+/// Finally, the remaining bids and asks are matched with the market maker. The price of a match
+/// is the adjusted price of the ask. Like in the [`pay_as_bid_matching`], the
+/// [`CounterpartyRules`] of the orders are honored.
 pub fn custom_fair_matching(
     input: &MarketInput,
     energy_unit_kwh: f64,
     grid_fee_matrix: &GridFeeMatrix,
 ) -> MarketOutput {
-    // TODO: Check time_slot of all orders is equal
-    // TODO: Quantize energy values to energy unit
-    // TODO: Check if cluster exists
-    // TODO: Check that order id is unique
+    custom_fair_matching_with_config(
+        input,
+        energy_unit_kwh,
+        grid_fee_matrix,
+        &MatchingConfig::default(),
+    )
+}
+
+/// Custom fair matching with explicit settings. See [`custom_fair_matching`].
+pub fn custom_fair_matching_with_config(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    grid_fee_matrix: &GridFeeMatrix,
+    config: &MatchingConfig,
+) -> MarketOutput {
+    let (grid_fee_matrix, grid_fee_diagnostics) =
+        config.price_limits.cap_grid_fees(grid_fee_matrix);
+    let mut output = with_matching_config(
+        input,
+        config,
+        |input| fair_matching(input, energy_unit_kwh, &grid_fee_matrix, config, false),
+        |output| output,
+    );
+    output.diagnostics.extend(grid_fee_diagnostics);
+    output
+}
+
+/// Hierarchical matching that prefers trades within energy communities.
+///
+/// Works like the [`custom_fair_matching`], but in three stages: first, the bids of every cluster
+/// are only matched with the asks of the same cluster, regardless of cheaper asks in other
+/// clusters. Then the remaining bids are matched with the remaining asks of all clusters, where
+/// the price of an ask is adjusted by the grid fee. Finally, the remaining bids and asks are
+/// matched with the market maker. The stage is stored in [`Match::stage`].
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "neighbour",
+///      "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.25},
+///     {"id": 2, "order_type": "ask", "time_slot": "t", "actor_id": "wind park",
+///      "cluster_index": 1, "energy_kwh": 5.0, "price_euro_per_kwh": 0.1},
+///     {"id": 3, "order_type": "bid", "time_slot": "t", "actor_id": "home",
+///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.3}
+/// ]}"#).unwrap();
+/// let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.05], [0.05, 0]]").unwrap();
+/// let output = local_first_matching(&input, 1.0, &grid_fee_matrix);
+/// assert_eq!((output.matches[0].ask_id, output.matches[0].stage), (1, Some(MatchStage::Local)));
+/// assert_eq!(
+///     (output.matches[1].ask_id, output.matches[1].stage),
+///     (2, Some(MatchStage::CrossCluster))
+/// );
+/// ```
+pub fn local_first_matching(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    grid_fee_matrix: &GridFeeMatrix,
+) -> MarketOutput {
+    local_first_matching_with_config(
+        input,
+        energy_unit_kwh,
+        grid_fee_matrix,
+        &MatchingConfig::default(),
+    )
+}
 
-    const LARGE_ORDER_THRESHOLD: f64 = 2_u64.pow(32) as f64;
+/// Local-first matching with explicit settings. See [`local_first_matching`].
+pub fn local_first_matching_with_config(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    grid_fee_matrix: &GridFeeMatrix,
+    config: &MatchingConfig,
+) -> MarketOutput {
+    let (grid_fee_matrix, grid_fee_diagnostics) =
+        config.price_limits.cap_grid_fees(grid_fee_matrix);
+    let mut output = with_matching_config(
+        input,
+        config,
+        |input| fair_matching(input, energy_unit_kwh, &grid_fee_matrix, config, true),
+        |output| output,
+    );
+    output.diagnostics.extend(grid_fee_diagnostics);
+    output
+}
 
-    //NOTE: 2^63 - 1 is too large to be represented by a f64 correctly, so I chose a smaller value.
-    const MARKET_MAKER_THRESHOLD: f64 = (2_u64.pow(36)) as f64;
+/// Custom fair matching without the repetitions for fill constraints and the price limits.
+///
+/// If `local_first` is set, the bids are matched with the asks of their own cluster first and
+/// the stage of every match is recorded.
+fn fair_matching(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    grid_fee_matrix: &GridFeeMatrix,
+    config: &MatchingConfig,
+    local_first: bool,
+) -> MarketOutput {
+    // TODO: Check time_slot of all orders is equal
+    // TODO: Check that order id is unique
 
-    // Filter orders by their type and energy
+    if !is_valid_energy_unit(energy_unit_kwh) {
+        return MarketOutput {
+            matches: vec![],
+            residual_book: vec![],
+            diagnostics: vec![],
+        };
+    }
 
-    // Asks by the market maker
-    let asks_mm: Vec<Order> = input
+    // Asks by the market maker, cheapest first
+    let mut asks_mm: Vec<&Order> = input
         .orders
         .iter()
-        .cloned()
         .filter(|order| {
-            order.order_type == OrderType::Ask && order.energy_kwh >= MARKET_MAKER_THRESHOLD
+            order.order_type == OrderType::Ask
+                && order.energy_kwh >= MARKET_MAKER_THRESHOLD
+                && order.price_euro_per_kwh.is_finite()
         })
         .collect();
+    asks_mm.sort_by(|a, b| {
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .then_with(|| config.tie_break.compare(a, b))
+    });
 
-    // Bids by the market maker
-    let bids_mm: Vec<Order> = input
+    // Bids by the market maker, highest price first
+    let mut bids_mm: Vec<&Order> = input
         .orders
         .iter()
-        .cloned()
         .filter(|order| {
-            order.order_type == OrderType::Bid && order.energy_kwh >= MARKET_MAKER_THRESHOLD
+            order.order_type == OrderType::Bid
+                && order.energy_kwh >= MARKET_MAKER_THRESHOLD
+                && order.price_euro_per_kwh.is_finite()
         })
         .collect();
-
-    // Are there "normal" asks with a resonable energy value?
-    let any_normal_asks: bool = input.orders.iter().any(|order| {
-        order.order_type == OrderType::Ask && order.energy_kwh < LARGE_ORDER_THRESHOLD
-    });
-
-    // Are there "normal" bids with a resonable energy value?
-    let any_normal_bids: bool = input.orders.iter().any(|order| {
-        order.order_type == OrderType::Bid && order.energy_kwh < LARGE_ORDER_THRESHOLD
+    bids_mm.sort_by(|a, b| {
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .reverse()
+            .then_with(|| config.tie_break.compare(a, b))
     });
 
-    if (!any_normal_asks && !any_normal_bids)
-        || (!any_normal_asks && asks_mm.is_empty())
-        || (!any_normal_bids && bids_mm.is_empty())
-    {
-        // No asks or no bids -> No matches
-        return MarketOutput { matches: vec![] };
-    }
-
     // Utility function for filtering orders and converting to FairMatchingOrders
-    fn get_fair_orders<F>(
-        market_input: &MarketInput,
+    fn get_fair_orders<'a>(
+        market_input: &'a MarketInput,
         energy_unit_kwh: f64,
-        filter_fn: F,
-    ) -> Vec<FairMatchingOrder>
-    where
-        F: Fn(&Order) -> bool,
-    {
+        grid_fee_matrix: &GridFeeMatrix,
+        order_type: OrderType,
+    ) -> Vec<FairMatchingOrder<'a>> {
         let mut forders = vec![];
         for order in market_input.orders.iter().filter(|order| {
-            order.energy_kwh < LARGE_ORDER_THRESHOLD
-                && order.cluster_index.is_some()
-                && filter_fn(order)
+            order.order_type == order_type
+                && order.energy_kwh < LARGE_ORDER_THRESHOLD
+                && order.price_euro_per_kwh.is_finite()
         }) {
-            let num_entries = (order.energy_kwh / energy_unit_kwh).trunc() as usize;
-            forders.reserve(num_entries);
-            // Create multiple entries - one for each full energy unit
-            for _ in 0..num_entries {
+            // Orders without a known cluster can only be matched with the market maker
+            let cluster_index = match order.cluster_index {
+                Some(cluster_index) if cluster_index < grid_fee_matrix.size => cluster_index,
+                _ => continue,
+            };
+            let units = energy_units(order.energy_kwh, energy_unit_kwh);
+            if units > 0 {
                 forders.push(FairMatchingOrder {
-                    orig_id: order.id,
-                    cluster_index: order.cluster_index.unwrap(),
-                    price_euro_per_kwh: order.price_euro_per_kwh,
-                    adjusted_price: f64::NAN,
+                    order,
+                    cluster_index,
+                    remaining_units: units,
                 });
             }
         }
         forders
     }
 
-    let matches = vec![];
-
-    // Keep track of clusters to match. Initial value: all cluster indices
-    let mut clusters_to_match: BTreeSet<usize> = BTreeSet::from_iter(0..grid_fee_matrix.size);
-
-    // Map from cluster index -> set of order indices
-    // TODO: Add `mut` again, so exclude can actually be used
-    let exclude: BTreeMap<usize, BTreeSet<u64>> =
-        BTreeMap::from_iter((0..grid_fee_matrix.size).map(|x| (x, BTreeSet::new())));
-
-    loop {
-        // Get cluster ID or break loop if there are none
-        let cluster_idx = match clusters_to_match.pop_first() {
-            Some(cluster_idx) => cluster_idx,
-            None => break,
+    let mut matches = vec![];
+    let mut push_match =
+        |bid: &Order, ask: &Order, units: u64, price_euro_per_kwh: f64, stage: MatchStage| {
+            matches.push(Match {
+                bid_id: bid.id,
+                ask_id: ask.id,
+                energy_kwh: round_energy_value(units as f64 * energy_unit_kwh),
+                price_euro_per_kwh,
+                bid_segment: None,
+                ask_segment: None,
+                stage: if local_first { Some(stage) } else { None },
+                energy_source: None,
+            });
         };
 
-        // local bids
-        let mut fair_bids: Vec<_> = get_fair_orders(input, energy_unit_kwh, |x| {
-            x.order_type == OrderType::Bid && x.cluster_index == Some(cluster_idx)
-        });
+    // Matches that are too small for the minimum match size of the bid or the ask are skipped
+    let too_small = |bid: &Order, ask: &Order, units: u64| {
+        round_energy_value(units as f64 * energy_unit_kwh)
+            < min_match_kwh(bid).max(min_match_kwh(ask))
+    };
+
+    let prioritize = has_preferences(input);
+    let mut fair_bids = get_fair_orders(input, energy_unit_kwh, grid_fee_matrix, OrderType::Bid);
+    let mut fair_asks = get_fair_orders(input, energy_unit_kwh, grid_fee_matrix, OrderType::Ask);
+
+    // Sort by price, descending
+    fair_bids.sort_by(|a, b| {
+        a.order
+            .price_euro_per_kwh
+            .total_cmp(&b.order.price_euro_per_kwh)
+            .reverse()
+            .then_with(|| config.tie_break.compare(a.order, b.order))
+    });
 
-        if fair_bids.is_empty() {
-            // Nothing to do in this cluster
-            continue;
+    // Without local_first, all asks are used in a single stage
+    let stages: &[MatchStage] = if local_first {
+        &[MatchStage::Local, MatchStage::CrossCluster]
+    } else {
+        &[MatchStage::CrossCluster]
+    };
+    for &stage in stages {
+        for cluster_idx in 0..grid_fee_matrix.size {
+            // Indices of all asks (of the same cluster in the local stage), sorted by adjusted
+            // price (price + grid fee), ascending, then by price, descending
+            let adjusted_price = |ask: &FairMatchingOrder| {
+                // Only asks of clusters in the matrix are used, so the fee is always found
+                let grid_fee = grid_fee_matrix
+                    .get(cluster_idx, ask.cluster_index)
+                    .unwrap_or(f64::NAN);
+                ask.order.price_euro_per_kwh + grid_fee
+            };
+            let mut ask_indices: Vec<usize> = (0..fair_asks.len())
+                .filter(|&ask_idx| {
+                    let ask = &fair_asks[ask_idx];
+                    ask.remaining_units > 0
+                        && (stage != MatchStage::Local || ask.cluster_index == cluster_idx)
+                })
+                .collect();
+            ask_indices.sort_by(|&a, &b| {
+                let (a, b) = (&fair_asks[a], &fair_asks[b]);
+                adjusted_price(a)
+                    .total_cmp(&adjusted_price(b))
+                    .then(
+                        a.order
+                            .price_euro_per_kwh
+                            .total_cmp(&b.order.price_euro_per_kwh)
+                            .reverse(),
+                    )
+                    .then_with(|| config.tie_break.compare(a.order, b.order))
+            });
+            // Asks before this position are completely matched
+            let mut first_ask = 0;
+
+            // Local bids, one group of bids with equal price after another
+            let local_bids: Vec<usize> = (0..fair_bids.len())
+                .filter(|&bid_idx| fair_bids[bid_idx].cluster_index == cluster_idx)
+                .collect();
+            let levels: Vec<&[usize]> = equal_price_runs(&local_bids, |&bid_idx| {
+                fair_bids[bid_idx].order.price_euro_per_kwh
+            })
+            .collect();
+            for level in levels {
+                let level_price = fair_bids[level[0]].order.price_euro_per_kwh;
+                let demand: Vec<u64> = level
+                    .iter()
+                    .map(|&bid_idx| fair_bids[bid_idx].remaining_units)
+                    .collect();
+                let budgets = match config.allocation {
+                    Allocation::PriceTime => demand,
+                    Allocation::ProRata => {
+                        // Only asks that can be matched with at least one of the bids are
+                        // available
+                        let matchable = |ask: &FairMatchingOrder| {
+                            level.iter().map(|&bid_idx| &fair_bids[bid_idx]).any(|bid| {
+                                config.allows(bid.order, ask.order)
+                                    && !too_small(
+                                        bid.order,
+                                        ask.order,
+                                        ask.remaining_units.min(bid.remaining_units),
+                                    )
+                            })
+                        };
+                        let supply: u64 = ask_indices[first_ask..]
+                            .iter()
+                            .map(|&ask_idx| &fair_asks[ask_idx])
+                            .take_while(|ask| adjusted_price(ask) <= level_price)
+                            .filter(|ask| matchable(ask))
+                            .fold(0, |sum: u64, ask| sum.saturating_add(ask.remaining_units));
+                        pro_rata_shares(supply, &demand)
+                    }
+                };
+
+                for (&bid_idx, mut budget) in level.iter().zip(budgets) {
+                    let bid = &mut fair_bids[bid_idx];
+                    // Asks of preferred counterparties first, the sort is stable
+                    let mut candidates = ask_indices[first_ask..].to_vec();
+                    if prioritize {
+                        candidates.sort_by(|&a, &b| {
+                            let weight = |ask_idx: usize| {
+                                preference_weight(bid.order, fair_asks[ask_idx].order)
+                            };
+                            weight(b).total_cmp(&weight(a))
+                        });
+                    }
+                    for ask_idx in candidates {
+                        let ask = &mut fair_asks[ask_idx];
+                        if budget == 0 {
+                            break;
+                        }
+                        if ask.remaining_units == 0 || !config.allows(bid.order, ask.order) {
+                            continue;
+                        }
+                        let price = adjusted_price(ask);
+                        // Written this way so NaN values don't produce a match
+                        #[allow(clippy::neg_cmp_op_on_partial_ord)]
+                        if !(price <= bid.order.price_euro_per_kwh) {
+                            if preference_weight(bid.order, ask.order) > 0.0 {
+                                // Other asks may be cheaper
+                                continue;
+                            }
+                            // All other asks are even more expensive
+                            break;
+                        }
+                        let units = budget.min(ask.remaining_units);
+                        if too_small(bid.order, ask.order, units) {
+                            continue;
+                        }
+                        push_match(bid.order, ask.order, units, price, stage);
+                        budget -= units;
+                        bid.remaining_units -= units;
+                        ask.remaining_units -= units;
+                    }
+                    while first_ask < ask_indices.len()
+                        && fair_asks[ask_indices[first_ask]].remaining_units == 0
+                    {
+                        first_ask += 1;
+                    }
+                }
+            }
         }
+    }
 
-        let exclude_set = &exclude[&cluster_idx];
-
-        // Get all asks that are not excluded and set the adjusted price (price + grid fee)
-        let mut fair_asks: Vec<_> = {
-            let mut asks = get_fair_orders(input, energy_unit_kwh, |x| {
-                x.order_type == OrderType::Ask && !exclude_set.contains(&x.id)
-            });
-            for ask in &mut asks {
-                ask.adjusted_price =
-                    ask.price_euro_per_kwh + grid_fee_matrix.lookup(cluster_idx, ask.cluster_index);
+    // Match the remaining bids with the cheapest ask of the market maker they may trade with
+    for bid in fair_bids.iter().filter(|bid| bid.remaining_units > 0) {
+        let ask_mm = asks_mm
+            .iter()
+            .find(|ask_mm| config.allows(bid.order, ask_mm));
+        if let Some(ask_mm) = ask_mm {
+            if ask_mm.price_euro_per_kwh <= bid.order.price_euro_per_kwh
+                && !too_small(bid.order, ask_mm, bid.remaining_units)
+            {
+                push_match(
+                    bid.order,
+                    ask_mm,
+                    bid.remaining_units,
+                    ask_mm.price_euro_per_kwh,
+                    MatchStage::MarketMaker,
+                );
             }
-            asks
-        };
+        }
+    }
 
-        // Sort by price, descending
-        fair_bids.sort_by(|a, b| {
-            a.price_euro_per_kwh
-                .total_cmp(&b.price_euro_per_kwh)
-                .reverse()
-        });
-        // Sort by adjusted price, ascending, then by price, descending
+    // Match the remaining asks with the highest bid of the market maker they may trade with
+    if !bids_mm.is_empty() {
         fair_asks.sort_by(|a, b| {
-            a.adjusted_price.total_cmp(&b.adjusted_price).then(
-                a.price_euro_per_kwh
-                    .total_cmp(&b.price_euro_per_kwh)
-                    .reverse(),
-            )
+            a.order
+                .price_euro_per_kwh
+                .total_cmp(&b.order.price_euro_per_kwh)
+                .then_with(|| config.tie_break.compare(a.order, b.order))
         });
-
-        {
-            let mut _matches = vec![];
-            let mut bids_iter = fair_bids.iter();
-            let bid = bids_iter.next();
-
-            for ask in fair_asks {
-                if let Some(bid) = bid {
-                    if ask.adjusted_price <= bid.price_euro_per_kwh {
-                        _matches.push(Match {
-                            bid_id: bid.orig_id,
-                            ask_id: ask.orig_id,
-                            energy_kwh: energy_unit_kwh,
-                            price_euro_per_kwh: ask.adjusted_price,
-                        });
-                    }
-                } else {
-                    break;
+        for ask in fair_asks.iter().filter(|ask| ask.remaining_units > 0) {
+            let bid_mm = bids_mm
+                .iter()
+                .find(|bid_mm| config.allows(bid_mm, ask.order));
+            if let Some(bid_mm) = bid_mm {
+                if ask.order.price_euro_per_kwh <= bid_mm.price_euro_per_kwh
+                    && !too_small(bid_mm, ask.order, ask.remaining_units)
+                {
+                    push_match(
+                        bid_mm,
+                        ask.order,
+                        ask.remaining_units,
+                        ask.order.price_euro_per_kwh,
+                        MatchStage::MarketMaker,
+                    );
                 }
             }
         }
-
-        //TODO ...
     }
 
-    MarketOutput { matches }
+    MarketOutput {
+        matches,
+        residual_book: vec![],
+        diagnostics: vec![],
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::collections::btree_set::BTreeSet;
 
     #[test]
     fn test_grid_matrix() {
@@ -390,7 +1067,7 @@ mod tests {
         ]\
         ";
 
-        let raw: GridFeeMatrixRaw = serde_json::from_str(&matrix_json).unwrap();
+        let raw: GridFeeMatrixRaw = serde_json::from_str(matrix_json).unwrap();
         let matrix = GridFeeMatrix::from_raw(&raw).unwrap();
         assert_eq!(matrix.lookup(0, 0), 0.0);
         assert_eq!(matrix.lookup(2, 0), 2.0);
@@ -398,6 +1075,59 @@ mod tests {
         assert_eq!(matrix.lookup(2, 2), 0.0);
     }
 
+    #[test]
+    fn test_grid_matrix_invalid() {
+        // Not square
+        assert!(GridFeeMatrix::from_json_str("[[0, 1], [1]]").is_err());
+        // Many empty rows must not allocate a huge matrix
+        let raw: GridFeeMatrixRaw = vec![vec![]; 1_000_000];
+        assert!(GridFeeMatrix::from_raw(&raw).is_err());
+
+        let matrix = GridFeeMatrix::from_json_str("[[0, 1], [2, 0]]").unwrap();
+        assert_eq!(matrix.get(1, 0), Some(2.0));
+        assert_eq!(matrix.get(2, 0), None);
+        assert_eq!(matrix.get(0, usize::MAX), None);
+    }
+
+    #[test]
+    fn test_invalid_order_values() {
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(0), 2.0, 0.10),
+                order(2, OrderType::Bid, Some(0), f64::NAN, 0.30),
+                order(3, OrderType::Bid, Some(0), 1.0, f64::INFINITY),
+                order(4, OrderType::Bid, Some(0), 1.0, 0.20),
+            ],
+            curve_orders: vec![],
+        };
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        let expected = vec![(4, 1, 1.0)];
+
+        // Invalid orders are skipped, as are energy units that cannot be matched at all
+        for (market_output, expected) in [
+            (pay_as_bid_matching(&market_input), expected.clone()),
+            (
+                custom_fair_matching(&market_input, 0.5, &grid_fee_matrix),
+                expected,
+            ),
+            (
+                custom_fair_matching(&market_input, f64::NAN, &grid_fee_matrix),
+                vec![],
+            ),
+            (
+                custom_fair_matching(&market_input, 1e-300, &grid_fee_matrix),
+                vec![],
+            ),
+        ] {
+            let matches: Vec<_> = market_output
+                .matches
+                .iter()
+                .map(|m| (m.bid_id, m.ask_id, m.energy_kwh))
+                .collect();
+            assert_eq!(matches, expected);
+        }
+    }
+
     #[test]
     fn test_custom_fair_matching() {
         let order_1 = Order {
@@ -408,6 +1138,7 @@ mod tests {
             cluster_index: Some(0),
             energy_kwh: 2.0,
             price_euro_per_kwh: 0.30,
+            ..Default::default()
         };
 
         let order_2 = Order {
@@ -418,15 +1149,480 @@ mod tests {
             cluster_index: Some(0),
             energy_kwh: 2.0,
             price_euro_per_kwh: 0.30,
+            ..Default::default()
         };
 
         let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 1], [1, 0]]").unwrap();
 
         let market_input = MarketInput {
             orders: vec![order_1, order_2],
+            curve_orders: vec![],
+        };
+
+        let market_output = custom_fair_matching(&market_input, 1.0, &grid_fee_matrix);
+
+        assert_eq!(market_output.matches.len(), 1);
+        let m = &market_output.matches[0];
+        assert_eq!((m.bid_id, m.ask_id), (2, 1));
+        assert_eq!(m.energy_kwh, 2.0);
+        assert_eq!(m.price_euro_per_kwh, 0.3);
+    }
+
+    /// Shorthand for creating orders in tests
+    fn order(
+        id: u64,
+        order_type: OrderType,
+        cluster_index: Option<usize>,
+        energy_kwh: f64,
+        price_euro_per_kwh: f64,
+    ) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: alloc::format!("actor_{id}"),
+            cluster_index,
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_custom_fair_matching_grid_fees() {
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.1], [0.1, 0]]").unwrap();
+        let market_input = MarketInput {
+            orders: vec![
+                // The cheapest ask is in the other cluster, but the grid fee makes it expensive
+                order(1, OrderType::Ask, Some(1), 2.0, 0.20),
+                order(2, OrderType::Ask, Some(0), 1.0, 0.25),
+                order(3, OrderType::Bid, Some(0), 2.0, 0.35),
+                order(4, OrderType::Bid, Some(1), 3.5, 0.22),
+                // Market maker
+                order(5, OrderType::Ask, None, MARKET_MAKER_THRESHOLD, 0.40),
+                order(6, OrderType::Bid, None, MARKET_MAKER_THRESHOLD, 0.05),
+            ],
+            curve_orders: vec![],
+        };
+
+        let market_output = custom_fair_matching(&market_input, 1.0, &grid_fee_matrix);
+        let matches: Vec<_> = market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.energy_kwh, m.price_euro_per_kwh))
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                (3, 2, 1.0, 0.25),
+                (3, 1, 1.0, 0.20 + 0.1),
+                (4, 1, 1.0, 0.20),
+                // Half units are not matched, the rest doesn't reach the market maker price
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_fair_matching_market_maker() {
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, Some(0), 2.0, 0.35),
+                order(2, OrderType::Ask, Some(0), 3.0, 0.10),
+                order(3, OrderType::Bid, Some(0), 2.0, 0.30),
+                order(4, OrderType::Ask, None, MARKET_MAKER_THRESHOLD, 0.32),
+                order(5, OrderType::Bid, None, MARKET_MAKER_THRESHOLD, 0.05),
+                order(6, OrderType::Bid, None, MARKET_MAKER_THRESHOLD, 0.08),
+                order(7, OrderType::Ask, Some(0), 1.0, 0.07),
+            ],
+            curve_orders: vec![],
+        };
+
+        let market_output = custom_fair_matching(&market_input, 0.5, &grid_fee_matrix);
+        let matches: Vec<_> = market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.energy_kwh, m.price_euro_per_kwh))
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                (1, 7, 1.0, 0.07),
+                (1, 2, 1.0, 0.10),
+                (3, 2, 2.0, 0.10),
+                // Nothing left for the market maker
+            ]
+        );
+
+        // Without local asks, the first bid buys from the market maker
+        let market_input = MarketInput {
+            orders: market_input
+                .orders
+                .into_iter()
+                .filter(|order| order.id != 2 && order.id != 7)
+                .collect(),
+            curve_orders: vec![],
         };
+        let market_output = custom_fair_matching(&market_input, 0.5, &grid_fee_matrix);
+        assert_eq!(market_output.matches.len(), 1);
+        let m = &market_output.matches[0];
+        assert_eq!((m.bid_id, m.ask_id, m.energy_kwh), (1, 4, 2.0));
+    }
 
+    #[test]
+    fn test_local_first_matching() {
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.1], [0.1, 0]]").unwrap();
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(1), 3.0, 0.10),
+                order(2, OrderType::Ask, Some(0), 1.0, 0.30),
+                order(3, OrderType::Bid, Some(0), 2.0, 0.35),
+                order(4, OrderType::Bid, Some(1), 1.0, 0.22),
+                // Market maker
+                order(5, OrderType::Ask, None, MARKET_MAKER_THRESHOLD, 0.50),
+                order(6, OrderType::Bid, None, MARKET_MAKER_THRESHOLD, 0.12),
+            ],
+            curve_orders: vec![],
+        };
+
+        let market_output = local_first_matching(&market_input, 1.0, &grid_fee_matrix);
+        let matches: Vec<_> = market_output
+            .matches
+            .iter()
+            .map(|m| {
+                (
+                    m.bid_id,
+                    m.ask_id,
+                    m.energy_kwh,
+                    m.price_euro_per_kwh,
+                    m.stage,
+                )
+            })
+            .collect();
+        assert_eq!(
+            matches,
+            vec![
+                // The local ask is used although the other one is cheaper with the grid fee
+                (3, 2, 1.0, 0.30, Some(MatchStage::Local)),
+                (4, 1, 1.0, 0.10, Some(MatchStage::Local)),
+                (3, 1, 1.0, 0.10 + 0.1, Some(MatchStage::CrossCluster)),
+                (6, 1, 1.0, 0.10, Some(MatchStage::MarketMaker)),
+            ]
+        );
+
+        // The custom fair matching only looks at the adjusted prices and records no stages
         let market_output = custom_fair_matching(&market_input, 1.0, &grid_fee_matrix);
+        let m = &market_output.matches[0];
+        assert_eq!(
+            (m.bid_id, m.ask_id, m.energy_kwh, m.stage),
+            (3, 1, 2.0, None)
+        );
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let mut orders = vec![
+            order(1, OrderType::Ask, Some(0), 1.0, 0.20),
+            order(2, OrderType::Bid, Some(0), 1.0, 0.30),
+            order(3, OrderType::Bid, Some(0), 1.0, 0.30),
+            order(4, OrderType::Bid, Some(0), 1.0, 0.30),
+        ];
+        orders[1].submitted_at = Some(300);
+        orders[2].submitted_at = Some(200);
+        let market_input = MarketInput {
+            orders,
+            curve_orders: vec![],
+        };
+
+        let winner = |tie_break| {
+            let config = MatchingConfig {
+                tie_break,
+                ..Default::default()
+            };
+            let market_output = pay_as_bid_matching_with_config(&market_input, &config);
+            assert_eq!(market_output.matches.len(), 1);
+            market_output.matches[0].bid_id
+        };
+        assert_eq!(winner(TieBreak::OrderId), 2);
+        assert_eq!(winner(TieBreak::SubmissionTime), 3);
+        // Different seeds give different winners, the same seed the same winner
+        let random_winners: BTreeSet<u64> = (0..20)
+            .map(|seed| winner(TieBreak::Random { seed }))
+            .collect();
+        assert!(random_winners.len() > 1);
+        assert_eq!(
+            winner(TieBreak::Random { seed: 7 }),
+            winner(TieBreak::Random { seed: 7 })
+        );
+    }
+
+    #[test]
+    fn test_pro_rata_allocation() {
+        let config = MatchingConfig {
+            allocation: Allocation::ProRata,
+            ..Default::default()
+        };
+        let matched_energy = |market_output: &MarketOutput, bid_id: u64| -> f64 {
+            market_output
+                .matches
+                .iter()
+                .filter(|m| m.bid_id == bid_id)
+                .map(|m| m.energy_kwh)
+                .sum()
+        };
+
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(0), 0.5, 0.10),
+                order(2, OrderType::Ask, Some(0), 0.5, 0.20),
+                order(3, OrderType::Bid, Some(0), 0.5, 0.40),
+                // The marginal bids share the remaining 0.5 kWh
+                order(4, OrderType::Bid, Some(0), 1.0, 0.30),
+                order(5, OrderType::Bid, Some(0), 2.0, 0.30),
+                order(6, OrderType::Bid, Some(0), 1.0, 0.25),
+            ],
+            curve_orders: vec![],
+        };
+        let market_output = pay_as_bid_matching_with_config(&market_input, &config);
+        assert_eq!(matched_energy(&market_output, 3), 0.5);
+        assert_eq!(matched_energy(&market_output, 4), 0.167);
+        assert_eq!(matched_energy(&market_output, 5), 0.333);
+        assert_eq!(matched_energy(&market_output, 6), 0.0);
+
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        let market_output =
+            custom_fair_matching_with_config(&market_input, 0.1, &grid_fee_matrix, &config);
+        assert_eq!(matched_energy(&market_output, 3), 0.5);
+        assert_eq!(matched_energy(&market_output, 4), 0.2);
+        assert_eq!(matched_energy(&market_output, 5), 0.3);
+        assert_eq!(matched_energy(&market_output, 6), 0.0);
+
+        // Without pro-rata allocation, the bid with the lower ID gets everything
+        let market_output = pay_as_bid_matching(&market_input);
+        assert_eq!(matched_energy(&market_output, 4), 0.5);
+        assert_eq!(matched_energy(&market_output, 5), 0.0);
+
+        // An ask that the marginal bids may not trade with is not part of their supply
+        let mut market_input = market_input;
+        let mut forbidding_ask = order(7, OrderType::Ask, Some(0), 0.5, 0.25);
+        forbidding_ask.counterparties = Some(CounterpartyRules {
+            forbidden: vec!["actor_4".to_string(), "actor_5".to_string()],
+            ..Default::default()
+        });
+        market_input.orders.push(forbidding_ask);
+        let market_output = pay_as_bid_matching_with_config(&market_input, &config);
+        assert_eq!(matched_energy(&market_output, 4), 0.167);
+        assert_eq!(matched_energy(&market_output, 5), 0.333);
+        assert_eq!(matched_energy(&market_output, 6), 0.5);
+        let market_output =
+            custom_fair_matching_with_config(&market_input, 0.1, &grid_fee_matrix, &config);
+        assert_eq!(matched_energy(&market_output, 4), 0.2);
+        assert_eq!(matched_energy(&market_output, 5), 0.3);
+        assert_eq!(matched_energy(&market_output, 6), 0.5);
+    }
+
+    #[test]
+    fn test_uniform_price() {
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(0), 1.0, 0.10),
+                order(2, OrderType::Ask, Some(0), 1.0, 0.20),
+                order(3, OrderType::Ask, Some(0), 1.0, 0.50),
+                order(4, OrderType::Bid, Some(0), 1.0, 0.40),
+                order(5, OrderType::Bid, Some(0), 1.0, 0.30),
+                order(6, OrderType::Bid, Some(0), 1.0, 0.15),
+            ],
+            curve_orders: vec![],
+        };
+        let market_output = uniform_price_matching(&market_input);
+        let matches: Vec<(u64, u64, f64)> = market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.price_euro_per_kwh))
+            .collect();
+        // The same allocation as Pay-as-Bid at the mean of the marginal bid and ask
+        assert_eq!(matches, vec![(4, 1, 0.25), (5, 2, 0.25)]);
+
+        let market_input = MarketInput {
+            orders: vec![],
+            curve_orders: vec![],
+        };
+        assert!(uniform_price_matching(&market_input).matches.is_empty());
+    }
+
+    #[test]
+    fn test_uniform_price_counterparty_rules() {
+        let forbid_actor_1 = Some(CounterpartyRules {
+            forbidden: vec!["actor_1".to_string()],
+            ..Default::default()
+        });
+        let mut market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(0), 1.0, 0.10),
+                order(2, OrderType::Ask, Some(0), 1.0, 0.20),
+                order(4, OrderType::Bid, Some(0), 1.0, 0.40),
+                order(5, OrderType::Bid, Some(0), 1.0, 0.30),
+            ],
+            curve_orders: vec![],
+        };
+        market_input.orders[2].counterparties = forbid_actor_1;
+        let matches = |market_input: &MarketInput| -> Vec<(u64, u64, f64)> {
+            uniform_price_matching(market_input)
+                .matches
+                .iter()
+                .map(|m| (m.bid_id, m.ask_id, m.price_euro_per_kwh))
+                .collect()
+        };
+        // The forbidden ask is skipped, so the other bid gets it
+        assert_eq!(matches(&market_input), vec![(4, 2, 0.25), (5, 1, 0.25)]);
+
+        // Now the lower bid can't pay the ask of the higher bid, so only one of them is matched
+        market_input.orders[1].price_euro_per_kwh = 0.35;
+        assert_eq!(matches(&market_input), vec![(5, 1, 0.2)]);
+    }
+
+    #[test]
+    fn test_curve_orders() {
+        let points = |points: &[(f64, f64)]| -> Vec<CurvePoint> {
+            points
+                .iter()
+                .map(|&(energy_kwh, price_euro_per_kwh)| CurvePoint {
+                    energy_kwh,
+                    price_euro_per_kwh,
+                })
+                .collect()
+        };
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, Some(0), 1.5, 0.20),
+                order(2, OrderType::Ask, Some(0), 2.0, 0.40),
+            ],
+            curve_orders: vec![
+                CurveOrder {
+                    id: 10,
+                    order_type: OrderType::Bid,
+                    time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                    actor_id: "actor_10".to_string(),
+                    cluster_index: Some(0),
+                    interpolation: Interpolation::Step,
+                    points: points(&[(1.0, 0.5), (3.0, 0.3)]),
+                    submitted_at: None,
+                },
+                // Invalid curve orders are ignored
+                CurveOrder {
+                    id: 11,
+                    order_type: OrderType::Bid,
+                    time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                    actor_id: "actor_11".to_string(),
+                    cluster_index: Some(0),
+                    interpolation: Interpolation::Step,
+                    points: points(&[(1.0, 0.5), (3.0, 0.6)]),
+                    submitted_at: None,
+                },
+            ],
+        };
+
+        let market_output = pay_as_bid_matching(&market_input);
+        let matches: Vec<(u64, Option<usize>, u64, f64, f64)> = market_output
+            .matches
+            .iter()
+            .map(|m| {
+                let price = m.price_euro_per_kwh;
+                (m.bid_id, m.bid_segment, m.ask_id, m.energy_kwh, price)
+            })
+            .collect();
+        assert_eq!(
+            matches,
+            vec![(10, Some(0), 1, 1.0, 0.5), (10, Some(1), 1, 0.5, 0.3)]
+        );
+        assert!(
+            verify_market_output(&market_input, &market_output, None, &PriceLimits::default())
+                .is_valid()
+        );
+
+        let market_output = uniform_price_matching(&market_input);
+        assert!(market_output
+            .matches
+            .iter()
+            .all(|m| m.price_euro_per_kwh == 0.25));
+        assert!(
+            verify_market_output(&market_input, &market_output, None, &PriceLimits::default())
+                .is_valid()
+        );
+
+        // Segments are checked on their own
+        let mut market_output = market_output;
+        market_output.matches[1].energy_kwh = 2.5;
+        let report =
+            verify_market_output(&market_input, &market_output, None, &PriceLimits::default());
+        assert!(report.violations.iter().any(|v| matches!(
+            v,
+            Violation::OverAllocated {
+                order_id: 10,
+                segment: Some(1),
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn test_tie_break_permutation_invariance() {
+        let mut orders = vec![
+            order(1, OrderType::Ask, Some(0), 2.0, 0.20),
+            order(2, OrderType::Ask, Some(1), 2.0, 0.20),
+            order(3, OrderType::Ask, Some(0), 2.0, 0.10),
+            order(4, OrderType::Ask, Some(1), 3.0, 0.20),
+            order(5, OrderType::Bid, Some(0), 2.0, 0.30),
+            order(6, OrderType::Bid, Some(0), 2.0, 0.30),
+            order(7, OrderType::Bid, Some(1), 2.0, 0.30),
+            order(8, OrderType::Bid, Some(1), 3.0, 0.30),
+            order(9, OrderType::Bid, Some(0), 1.0, 0.25),
+        ];
+        for (i, order) in orders.iter_mut().enumerate() {
+            order.submitted_at = Some(1000 - (i as u64 % 3));
+        }
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0], [0, 0]]").unwrap();
+
+        let tie_breaks = [
+            TieBreak::OrderId,
+            TieBreak::SubmissionTime,
+            TieBreak::Random { seed: 1 },
+            TieBreak::Random { seed: 2 },
+        ];
+        let allocations = [Allocation::PriceTime, Allocation::ProRata];
+        for (tie_break, allocation) in tie_breaks
+            .into_iter()
+            .flat_map(|t| allocations.into_iter().map(move |a| (t, a)))
+        {
+            let config = MatchingConfig {
+                tie_break,
+                allocation,
+                ..Default::default()
+            };
+            let run = |orders: &[Order]| {
+                let market_input = MarketInput {
+                    orders: orders.to_vec(),
+                    curve_orders: vec![],
+                };
+                (
+                    pay_as_bid_matching_with_config(&market_input, &config),
+                    custom_fair_matching_with_config(&market_input, 1.0, &grid_fee_matrix, &config),
+                )
+            };
+
+            // Both algorithms have to match something, otherwise the outputs are trivially equal
+            let expected = run(&orders);
+            assert!(!expected.0.matches.is_empty());
+            assert!(!expected.1.matches.is_empty());
+            let mut permuted = orders.clone();
+            for i in 0..orders.len() {
+                permuted.rotate_left(1);
+                permuted.swap(0, i);
+                assert_eq!(run(&permuted), expected);
+            }
+            permuted.reverse();
+            assert_eq!(run(&permuted), expected);
+        }
     }
 
     #[test]
@@ -440,6 +1636,7 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 2.0,
                 price_euro_per_kwh: 0.30,
+                ..Default::default()
             };
 
             let order_2 = Order {
@@ -450,10 +1647,12 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 2.0,
                 price_euro_per_kwh: 0.30,
+                ..Default::default()
             };
 
             let market_input = MarketInput {
                 orders: vec![order_1, order_2],
+                curve_orders: vec![],
             };
 
             let market_output = pay_as_bid_matching(&market_input);
@@ -473,6 +1672,7 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 3.0,
                 price_euro_per_kwh: 0.30,
+                ..Default::default()
             };
 
             let order_2 = Order {
@@ -483,6 +1683,7 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 2.0,
                 price_euro_per_kwh: 0.40,
+                ..Default::default()
             };
 
             let order_3 = Order {
@@ -493,10 +1694,12 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 2.0,
                 price_euro_per_kwh: 0.30,
+                ..Default::default()
             };
 
             let market_input = MarketInput {
                 orders: vec![order_1, order_2, order_3],
+                curve_orders: vec![],
             };
 
             let market_output = pay_as_bid_matching(&market_input);
@@ -519,6 +1722,7 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 3.0,
                 price_euro_per_kwh: 0.20,
+                ..Default::default()
             };
 
             let order_2 = Order {
@@ -529,6 +1733,7 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 2.0,
                 price_euro_per_kwh: 0.25,
+                ..Default::default()
             };
 
             let order_3 = Order {
@@ -539,10 +1744,12 @@ mod tests {
                 cluster_index: Some(0),
                 energy_kwh: 4.0,
                 price_euro_per_kwh: 0.30,
+                ..Default::default()
             };
 
             let market_input = MarketInput {
                 orders: vec![order_1, order_2, order_3],
+                curve_orders: vec![],
             };
 
             let market_output = pay_as_bid_matching(&market_input);
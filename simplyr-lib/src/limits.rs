@@ -0,0 +1,405 @@
+//! Regulated price bounds and grid fee caps.
+
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Diagnostic, GridFeeMatrix, MarketInput, MarketOutput};
+
+/// The lowest and the highest allowed price of an order.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceBounds {
+    /// The lowest price in € / kWh, e.g. the feed-in tariff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_price_euro_per_kwh: Option<f64>,
+    /// The highest price in € / kWh, e.g. the retail tariff
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_price_euro_per_kwh: Option<f64>,
+}
+
+impl PriceBounds {
+    /// Return the bounds that satisfy both `self` and `other`.
+    fn intersect(&self, other: &PriceBounds) -> PriceBounds {
+        let combine = |a: Option<f64>, b: Option<f64>, f: fn(f64, f64) -> f64| match (a, b) {
+            (Some(a), Some(b)) => Some(f(a, b)),
+            (a, b) => a.or(b),
+        };
+        PriceBounds {
+            min_price_euro_per_kwh: combine(
+                self.min_price_euro_per_kwh,
+                other.min_price_euro_per_kwh,
+                f64::max,
+            ),
+            max_price_euro_per_kwh: combine(
+                self.max_price_euro_per_kwh,
+                other.max_price_euro_per_kwh,
+                f64::min,
+            ),
+        }
+    }
+
+    /// Return `true` if the price is within the bounds. NaN prices are never within the bounds.
+    fn contains(&self, price_euro_per_kwh: f64) -> bool {
+        !price_euro_per_kwh.is_nan()
+            && !matches!(self.min_price_euro_per_kwh, Some(min) if price_euro_per_kwh < min)
+            && !matches!(self.max_price_euro_per_kwh, Some(max) if price_euro_per_kwh > max)
+    }
+
+    /// Move a price into the bounds. The upper bound wins if the bounds contradict each other.
+    pub(crate) fn clip(&self, price_euro_per_kwh: f64) -> f64 {
+        let mut price = price_euro_per_kwh;
+        if let Some(min) = self.min_price_euro_per_kwh {
+            if price < min {
+                price = min;
+            }
+        }
+        if let Some(max) = self.max_price_euro_per_kwh {
+            if price > max {
+                price = max;
+            }
+        }
+        price
+    }
+}
+
+/// What happens to orders with prices outside the [`PriceLimits`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitPolicy {
+    /// The order is not matched
+    #[default]
+    Reject,
+    /// The price is moved to the nearest bound
+    Clip,
+}
+
+/// Regulatory limits of a market.
+///
+/// The prices of all orders have to be within the global bounds and the bounds of their cluster
+/// (orders without a cluster only have to meet the global bounds). Orders outside the bounds are
+/// handled according to the [`LimitPolicy`], grid fees above the maximum grid fee are reduced to
+/// it. Both are reported in [`MarketOutput::diagnostics`]. Since the price of a match is
+/// between the prices of the bid and the ask (plus grid fee), all match prices are within the
+/// global bounds as well. The only exception is the renewable premium that the
+/// [`preference_matching`](crate::preference_matching) adds to the price of the bid (see
+/// [`SourcePreference`](crate::SourcePreference)): it isn't limited, so such matches may exceed
+/// the upper bound by the premium.
+///
+/// The limits are applied by all algorithms that take a [`MatchingConfig`](crate::MatchingConfig).
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 2.0, "price_euro_per_kwh": 0.05},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.5}
+/// ]}"#).unwrap();
+/// let config: MatchingConfig = serde_json::from_str(r#"{"price_limits": {
+///     "global": {"min_price_euro_per_kwh": 0.08, "max_price_euro_per_kwh": 0.35},
+///     "policy": "clip"
+/// }}"#).unwrap();
+/// let output = pay_as_bid_matching_with_config(&input, &config);
+/// // The buyer pays the retail tariff instead of its bid
+/// assert_eq!(output.matches[0].price_euro_per_kwh, 0.35);
+/// assert_eq!(output.diagnostics.len(), 2);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceLimits {
+    /// Bounds for the orders of all clusters
+    #[serde(default)]
+    pub global: PriceBounds,
+    /// Bounds for the orders of a cluster, indexed by the cluster index
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<PriceBounds>,
+    /// The highest grid fee in € / kWh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_grid_fee_euro_per_kwh: Option<f64>,
+    /// What happens to orders outside the bounds
+    #[serde(default)]
+    pub policy: LimitPolicy,
+}
+
+impl PriceLimits {
+    /// Check that no lower bound is above the corresponding upper bound and that all values are
+    /// numbers.
+    pub fn validate(&self) -> Result<(), String> {
+        let bounds = core::iter::once(&self.global).chain(&self.clusters);
+        for (idx, bounds) in bounds.enumerate() {
+            let values = [bounds.min_price_euro_per_kwh, bounds.max_price_euro_per_kwh];
+            if values.iter().flatten().any(|value| value.is_nan()) {
+                return Err("price limits may not be NaN".into());
+            }
+            if let (Some(min), Some(max)) = values.into() {
+                if min > max {
+                    return Err(match idx {
+                        0 => format!("the global minimum price {min} is above the maximum {max}"),
+                        _ => format!(
+                            "the minimum price {min} of cluster {} is above the maximum {max}",
+                            idx - 1
+                        ),
+                    });
+                }
+            }
+        }
+        if matches!(self.max_grid_fee_euro_per_kwh, Some(fee) if fee.is_nan()) {
+            return Err("the maximum grid fee may not be NaN".into());
+        }
+        Ok(())
+    }
+
+    /// Return the bounds for the orders of a cluster.
+    pub fn bounds(&self, cluster_index: Option<usize>) -> PriceBounds {
+        match cluster_index.and_then(|cluster_index| self.clusters.get(cluster_index)) {
+            Some(cluster_bounds) => self.global.intersect(cluster_bounds),
+            None => self.global.clone(),
+        }
+    }
+
+    /// Return the input with all orders within the bounds, together with the changes that were
+    /// made.
+    pub fn apply<'a>(&self, input: &'a MarketInput) -> (Cow<'a, MarketInput>, Vec<Diagnostic>) {
+        if self.global == PriceBounds::default() && self.clusters.is_empty() {
+            return (Cow::Borrowed(input), vec![]);
+        }
+
+        let mut diagnostics = vec![];
+        let mut result = MarketInput {
+            orders: vec![],
+            curve_orders: vec![],
+        };
+        for order in &input.orders {
+            let bounds = self.bounds(order.cluster_index);
+            // Orders with non-finite prices are never matched, so they are kept as they are
+            if bounds.contains(order.price_euro_per_kwh) || !order.price_euro_per_kwh.is_finite() {
+                result.orders.push(order.clone());
+                continue;
+            }
+            match self.policy {
+                LimitPolicy::Reject => diagnostics.push(Diagnostic::OrderRejected {
+                    order_id: order.id,
+                    price_euro_per_kwh: order.price_euro_per_kwh,
+                    min_price_euro_per_kwh: bounds.min_price_euro_per_kwh,
+                    max_price_euro_per_kwh: bounds.max_price_euro_per_kwh,
+                }),
+                LimitPolicy::Clip => {
+                    let mut clipped = order.clone();
+                    clipped.price_euro_per_kwh = bounds.clip(order.price_euro_per_kwh);
+                    diagnostics.push(Diagnostic::PriceClipped {
+                        order_id: order.id,
+                        original_price_euro_per_kwh: order.price_euro_per_kwh,
+                        price_euro_per_kwh: clipped.price_euro_per_kwh,
+                        min_price_euro_per_kwh: bounds.min_price_euro_per_kwh,
+                        max_price_euro_per_kwh: bounds.max_price_euro_per_kwh,
+                    });
+                    result.orders.push(clipped);
+                }
+            }
+        }
+
+        for curve_order in &input.curve_orders {
+            let bounds = self.bounds(curve_order.cluster_index);
+            let outside = curve_order.points.iter().find(|point| {
+                !bounds.contains(point.price_euro_per_kwh) && point.price_euro_per_kwh.is_finite()
+            });
+            let outside = match outside {
+                Some(point) => point,
+                None => {
+                    result.curve_orders.push(curve_order.clone());
+                    continue;
+                }
+            };
+            match self.policy {
+                LimitPolicy::Reject => diagnostics.push(Diagnostic::OrderRejected {
+                    order_id: curve_order.id,
+                    price_euro_per_kwh: outside.price_euro_per_kwh,
+                    min_price_euro_per_kwh: bounds.min_price_euro_per_kwh,
+                    max_price_euro_per_kwh: bounds.max_price_euro_per_kwh,
+                }),
+                LimitPolicy::Clip => {
+                    diagnostics.push(Diagnostic::PriceClipped {
+                        order_id: curve_order.id,
+                        original_price_euro_per_kwh: outside.price_euro_per_kwh,
+                        price_euro_per_kwh: bounds.clip(outside.price_euro_per_kwh),
+                        min_price_euro_per_kwh: bounds.min_price_euro_per_kwh,
+                        max_price_euro_per_kwh: bounds.max_price_euro_per_kwh,
+                    });
+                    // Clipping keeps the prices monotonic
+                    let mut curve_order = curve_order.clone();
+                    for point in &mut curve_order.points {
+                        point.price_euro_per_kwh = bounds.clip(point.price_euro_per_kwh);
+                    }
+                    result.curve_orders.push(curve_order);
+                }
+            }
+        }
+        (Cow::Owned(result), diagnostics)
+    }
+
+    /// Return the grid fee matrix with all fees reduced to the maximum grid fee, together with
+    /// the changes that were made.
+    pub fn cap_grid_fees<'a>(
+        &self,
+        grid_fee_matrix: &'a GridFeeMatrix,
+    ) -> (Cow<'a, GridFeeMatrix>, Vec<Diagnostic>) {
+        let max_grid_fee = match self.max_grid_fee_euro_per_kwh {
+            Some(max_grid_fee) => max_grid_fee,
+            None => return (Cow::Borrowed(grid_fee_matrix), vec![]),
+        };
+        let mut diagnostics = vec![];
+        let mut capped = grid_fee_matrix.clone();
+        for (flat_index, grid_fee) in capped.flat_matrix.iter_mut().enumerate() {
+            if *grid_fee > max_grid_fee {
+                diagnostics.push(Diagnostic::GridFeeCapped {
+                    source_cluster: flat_index / capped.size,
+                    dest_cluster: flat_index % capped.size,
+                    original_grid_fee_euro_per_kwh: *grid_fee,
+                    grid_fee_euro_per_kwh: max_grid_fee,
+                });
+                *grid_fee = max_grid_fee;
+            }
+        }
+        (Cow::Owned(capped), diagnostics)
+    }
+}
+
+/// Run a matching algorithm on the input after applying the price bounds. The changes are
+/// stored in the diagnostics of the result.
+pub(crate) fn with_price_limits<T, F, M>(
+    input: &MarketInput,
+    price_limits: &PriceLimits,
+    matching: F,
+    market_output: M,
+) -> T
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    let (input, mut diagnostics) = price_limits.apply(input);
+    let mut output = matching(&input);
+    let output_diagnostics = &mut market_output(&mut output).diagnostics;
+    diagnostics.append(output_diagnostics);
+    *output_diagnostics = diagnostics;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{custom_fair_matching_with_config, MatchingConfig, Order, OrderType};
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, cluster_index: usize, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor".to_string() + &id.to_string(),
+            cluster_index: Some(cluster_index),
+            energy_kwh: 1.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    fn limits(policy: LimitPolicy) -> PriceLimits {
+        PriceLimits {
+            global: PriceBounds {
+                min_price_euro_per_kwh: Some(0.1),
+                max_price_euro_per_kwh: Some(0.4),
+            },
+            clusters: vec![
+                PriceBounds::default(),
+                PriceBounds {
+                    min_price_euro_per_kwh: None,
+                    max_price_euro_per_kwh: Some(0.3),
+                },
+            ],
+            max_grid_fee_euro_per_kwh: Some(0.05),
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_bounds() {
+        let limits = limits(LimitPolicy::Reject);
+        assert!(limits.validate().is_ok());
+        assert_eq!(limits.bounds(Some(0)), limits.global);
+        assert_eq!(limits.bounds(None), limits.global);
+        assert_eq!(limits.bounds(Some(1)).max_price_euro_per_kwh, Some(0.3));
+        assert_eq!(limits.bounds(Some(1)).min_price_euro_per_kwh, Some(0.1));
+        assert!(!limits.bounds(None).contains(f64::NAN));
+
+        let mut invalid = limits;
+        invalid.clusters[0].min_price_euro_per_kwh = Some(0.5);
+        invalid.clusters[0].max_price_euro_per_kwh = Some(0.2);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_policies() {
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 0, 0.05),
+                order(2, OrderType::Bid, 1, 0.35),
+                order(3, OrderType::Bid, 0, 0.35),
+            ],
+            curve_orders: vec![],
+        };
+        let (rejected, diagnostics) = limits(LimitPolicy::Reject).apply(&input);
+        assert_eq!(rejected.orders.len(), 1);
+        assert_eq!(diagnostics.len(), 2);
+
+        let (clipped, diagnostics) = limits(LimitPolicy::Clip).apply(&input);
+        let prices: Vec<f64> = clipped
+            .orders
+            .iter()
+            .map(|order| order.price_euro_per_kwh)
+            .collect();
+        assert_eq!(prices, vec![0.1, 0.3, 0.35]);
+        assert_eq!(
+            diagnostics[1],
+            Diagnostic::PriceClipped {
+                order_id: 2,
+                original_price_euro_per_kwh: 0.35,
+                price_euro_per_kwh: 0.3,
+                min_price_euro_per_kwh: Some(0.1),
+                max_price_euro_per_kwh: Some(0.3),
+            }
+        );
+
+        // Without limits, the input is not copied
+        let (unchanged, diagnostics) = PriceLimits::default().apply(&input);
+        assert!(matches!(unchanged, Cow::Borrowed(_)));
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_grid_fee_cap() {
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 1, 0.2),
+                order(2, OrderType::Bid, 0, 0.3),
+            ],
+            curve_orders: vec![],
+        };
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.2], [0.2, 0]]").unwrap();
+        let config = MatchingConfig {
+            price_limits: limits(LimitPolicy::Reject),
+            ..Default::default()
+        };
+        let output = custom_fair_matching_with_config(&input, 1.0, &grid_fee_matrix, &config);
+        // The ask is only cheap enough with the capped grid fee
+        assert_eq!(output.matches.len(), 1);
+        assert!((output.matches[0].price_euro_per_kwh - 0.25).abs() < 1e-9);
+        assert_eq!(output.diagnostics.len(), 2);
+        assert!(output
+            .diagnostics
+            .iter()
+            .all(|d| matches!(d, Diagnostic::GridFeeCapped { .. })));
+    }
+}
@@ -0,0 +1,390 @@
+//! McAfee's double auction with trade reduction. It is truthful for single-unit orders.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::with_matching_config;
+use crate::{
+    energy_units, is_valid_energy_unit, round_energy_value, MarketInput, MarketOutput, Match,
+    MatchingConfig, Order, OrderType,
+};
+
+/// The result of the McAfee double auction.
+///
+/// The matches are serialized next to the prices, so the JSON output can also be read as a
+/// [`MarketOutput`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct McAfeeOutput {
+    #[serde(flatten)]
+    pub market_output: MarketOutput,
+    /// The price in € / kWh that all buyers pay, `None` if nothing is traded
+    pub buyer_price_euro_per_kwh: Option<f64>,
+    /// The price in € / kWh that all sellers receive, `None` if nothing is traded
+    pub seller_price_euro_per_kwh: Option<f64>,
+    /// The number of energy units that are traded in the efficient allocation
+    pub efficient_units: u64,
+    /// The number of energy units that are actually traded
+    pub traded_units: u64,
+    /// The difference between the payments of the buyers and the revenue of the sellers in €.
+    /// It goes to the market operator.
+    pub budget_surplus_euro: f64,
+}
+
+/// An order with the number of energy units it contains.
+struct UnitOrder<'a> {
+    order: &'a Order,
+    units: u64,
+}
+
+/// McAfee's double auction.
+///
+/// Orders are divided into units of `energy_unit_kwh` like in the
+/// [`custom_fair_matching`](crate::custom_fair_matching); every unit is treated as a single-unit
+/// order. Bid units are sorted by price descending (b_1 ≥ b_2 ≥ …) and ask units by price
+/// ascending (s_1 ≤ s_2 ≤ …). The efficient trade count k is the largest number with b_k ≥ s_k.
+///
+/// If the units k + 1 exist on both sides and the price p = (b_(k+1) + s_(k+1)) / 2 lies within
+/// [s_k, b_k], all k units are traded at p. Otherwise only k - 1 units are traded (trade
+/// reduction): buyers pay b_k, sellers receive s_k and the difference goes to the market
+/// operator. If every order contains a single unit, no participant can gain by misreporting
+/// their price in both cases.
+///
+/// This doesn't hold for divisible orders with multiple units. An order can then influence the
+/// price of its own traded units, e.g. a buyer whose last unit sets the price can lower it by
+/// bidding for fewer units (demand reduction). The auction is not strategy-proof for them.
+///
+/// The price of a match is the buyer price. Grid fees and clusters are ignored. Orders whose
+/// [`FillConstraint`](crate::FillConstraint) can't be met are removed before the auction.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let order = |id, order_type, price| Order {
+///     id,
+///     order_type,
+///     time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+///     actor_id: format!("actor_{id}"),
+///     cluster_index: None,
+///     energy_kwh: 1.0,
+///     price_euro_per_kwh: price,
+///     ..Default::default()
+/// };
+/// let market_input = MarketInput {
+///     orders: vec![
+///         order(1, OrderType::Bid, 10.0),
+///         order(2, OrderType::Bid, 8.0),
+///         order(3, OrderType::Ask, 2.0),
+///         order(4, OrderType::Ask, 9.0),
+///     ],
+///     curve_orders: vec![],
+/// };
+/// let output = mcafee_matching(&market_input, 1.0);
+/// // One unit is traded at the mean of the first bid and ask that are not traded
+/// assert_eq!(output.traded_units, 1);
+/// assert_eq!(output.buyer_price_euro_per_kwh, Some(8.5));
+/// assert_eq!(output.seller_price_euro_per_kwh, Some(8.5));
+/// ```
+pub fn mcafee_matching(input: &MarketInput, energy_unit_kwh: f64) -> McAfeeOutput {
+    mcafee_matching_with_config(input, energy_unit_kwh, &MatchingConfig::default())
+}
+
+/// McAfee's double auction with explicit settings. See [`mcafee_matching`].
+///
+/// The tie-break rule decides which of the units with equal prices are traded. The allocation
+/// rule is not used. Unless self-trades are allowed, the traded units of an actor are not paired
+/// with each other; units that can't be paired otherwise are not traded and not counted in
+/// [`McAfeeOutput::traded_units`].
+pub fn mcafee_matching_with_config(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    config: &MatchingConfig,
+) -> McAfeeOutput {
+    with_matching_config(
+        input,
+        config,
+        |input| trade_reduction(input, energy_unit_kwh, config),
+        |output| &mut output.market_output,
+    )
+}
+
+/// McAfee's double auction without the repetitions for fill constraints and the price limits.
+fn trade_reduction(
+    input: &MarketInput,
+    energy_unit_kwh: f64,
+    config: &MatchingConfig,
+) -> McAfeeOutput {
+    let mut output = McAfeeOutput {
+        market_output: MarketOutput {
+            matches: vec![],
+            residual_book: vec![],
+            diagnostics: vec![],
+        },
+        buyer_price_euro_per_kwh: None,
+        seller_price_euro_per_kwh: None,
+        efficient_units: 0,
+        traded_units: 0,
+        budget_surplus_euro: 0.0,
+    };
+    if !is_valid_energy_unit(energy_unit_kwh) {
+        return output;
+    }
+
+    let unit_orders = |order_type: OrderType| -> Vec<UnitOrder> {
+        input
+            .orders
+            .iter()
+            .filter(|order| order.order_type == order_type && order.price_euro_per_kwh.is_finite())
+            .map(|order| UnitOrder {
+                order,
+                units: energy_units(order.energy_kwh, energy_unit_kwh),
+            })
+            .filter(|unit_order| unit_order.units > 0)
+            .collect()
+    };
+
+    // Highest price first
+    let mut bids = unit_orders(OrderType::Bid);
+    bids.sort_by(|a, b| {
+        let (a, b) = (a.order, b.order);
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .reverse()
+            .then_with(|| config.tie_break.compare(a, b))
+    });
+
+    // Lowest price first
+    let mut asks = unit_orders(OrderType::Ask);
+    asks.sort_by(|a, b| {
+        let (a, b) = (a.order, b.order);
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .then_with(|| config.tie_break.compare(a, b))
+    });
+
+    let efficient_units = efficient_units(&bids, &asks);
+    if efficient_units == 0 {
+        return output;
+    }
+    output.efficient_units = efficient_units;
+
+    // Unit k exists on both sides because k units are traded efficiently
+    let bid_k = unit_price(&bids, efficient_units - 1).unwrap_or(f64::NAN);
+    let ask_k = unit_price(&asks, efficient_units - 1).unwrap_or(f64::NAN);
+    let candidate_price = match (
+        unit_price(&bids, efficient_units),
+        unit_price(&asks, efficient_units),
+    ) {
+        (Some(bid_next), Some(ask_next)) => Some((bid_next + ask_next) / 2.0),
+        _ => None,
+    };
+
+    let (traded_units, buyer_price, seller_price) = match candidate_price {
+        Some(price) if ask_k <= price && price <= bid_k => (efficient_units, price, price),
+        _ => (efficient_units - 1, bid_k, ask_k),
+    };
+    if traded_units == 0 {
+        return output;
+    }
+
+    // Pair the traded bid units with the traded ask units in their sort order. All of them accept
+    // the prices, so pairs that the self-trade prevention doesn't allow can be skipped.
+    limit_units(&mut bids, traded_units);
+    limit_units(&mut asks, traded_units);
+    let mut paired_units = 0;
+    // Asks before this position are completely paired
+    let mut first_ask = 0;
+    for b in &mut bids {
+        for a in asks[first_ask..].iter_mut() {
+            if b.units == 0 {
+                break;
+            }
+            if a.units == 0 || config.self_trade_prevention.prevents(b.order, a.order) {
+                continue;
+            }
+            let units = b.units.min(a.units);
+            output.market_output.matches.push(Match {
+                bid_id: b.order.id,
+                ask_id: a.order.id,
+                energy_kwh: round_energy_value(units as f64 * energy_unit_kwh),
+                price_euro_per_kwh: buyer_price,
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            });
+            b.units -= units;
+            a.units -= units;
+            paired_units += units;
+        }
+        while first_ask < asks.len() && asks[first_ask].units == 0 {
+            first_ask += 1;
+        }
+    }
+    if paired_units == 0 {
+        return output;
+    }
+
+    output.traded_units = paired_units;
+    output.buyer_price_euro_per_kwh = Some(buyer_price);
+    output.seller_price_euro_per_kwh = Some(seller_price);
+    output.budget_surplus_euro =
+        paired_units as f64 * energy_unit_kwh * (buyer_price - seller_price);
+    output
+}
+
+/// Keep only the first `units` units of the sorted orders.
+fn limit_units(orders: &mut [UnitOrder], mut units: u64) {
+    for unit_order in orders {
+        unit_order.units = unit_order.units.min(units);
+        units -= unit_order.units;
+    }
+}
+
+/// Count the units k with b_k ≥ s_k, given bids sorted descending and asks sorted ascending.
+fn efficient_units(bids: &[UnitOrder], asks: &[UnitOrder]) -> u64 {
+    let mut count: u64 = 0;
+    let (mut bid_idx, mut ask_idx) = (0, 0);
+    // Units of the current orders that are already counted
+    let (mut bid_used, mut ask_used) = (0, 0);
+    while let (Some(bid), Some(ask)) = (bids.get(bid_idx), asks.get(ask_idx)) {
+        if bid.order.price_euro_per_kwh < ask.order.price_euro_per_kwh {
+            break;
+        }
+        let units = (bid.units - bid_used).min(ask.units - ask_used);
+        count = count.saturating_add(units);
+        bid_used += units;
+        ask_used += units;
+        if bid_used == bid.units {
+            bid_idx += 1;
+            bid_used = 0;
+        }
+        if ask_used == ask.units {
+            ask_idx += 1;
+            ask_used = 0;
+        }
+    }
+    count
+}
+
+/// Return the price of the unit with the given zero-based index.
+fn unit_price(orders: &[UnitOrder], mut index: u64) -> Option<f64> {
+    for unit_order in orders {
+        if index < unit_order.units {
+            return Some(unit_order.order.price_euro_per_kwh);
+        }
+        index -= unit_order.units;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, energy_kwh: f64, price_euro_per_kwh: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: format!("actor_{id}"),
+            cluster_index: None,
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    fn market(bid_prices: &[f64], ask_prices: &[f64]) -> MarketInput {
+        let bids = bid_prices
+            .iter()
+            .enumerate()
+            .map(|(idx, &price)| order(idx as u64 + 1, OrderType::Bid, 1.0, price));
+        let asks = ask_prices
+            .iter()
+            .enumerate()
+            .map(|(idx, &price)| order(idx as u64 + 101, OrderType::Ask, 1.0, price));
+        MarketInput {
+            orders: bids.chain(asks).collect(),
+            curve_orders: vec![],
+        }
+    }
+
+    #[test]
+    fn test_mcafee_without_trade_reduction() {
+        let output = mcafee_matching(&market(&[10.0, 8.0, 6.0, 4.0], &[2.0, 3.0, 5.0, 7.0]), 1.0);
+        assert_eq!(output.efficient_units, 3);
+        assert_eq!(output.traded_units, 3);
+        assert_eq!(output.buyer_price_euro_per_kwh, Some(5.5));
+        assert_eq!(output.seller_price_euro_per_kwh, Some(5.5));
+        assert_eq!(output.budget_surplus_euro, 0.0);
+        let pairs: Vec<(u64, u64)> = output
+            .market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id))
+            .collect();
+        assert_eq!(pairs, vec![(1, 101), (2, 102), (3, 103)]);
+    }
+
+    #[test]
+    fn test_mcafee_with_trade_reduction() {
+        let output = mcafee_matching(&market(&[10.0, 8.0, 6.0, 4.0], &[2.0, 3.0, 5.9, 6.5]), 1.0);
+        assert_eq!(output.efficient_units, 3);
+        assert_eq!(output.traded_units, 2);
+        assert_eq!(output.buyer_price_euro_per_kwh, Some(6.0));
+        assert_eq!(output.seller_price_euro_per_kwh, Some(5.9));
+        assert!((output.budget_surplus_euro - 0.2).abs() < 1e-9);
+        assert_eq!(output.market_output.matches.len(), 2);
+        assert!(output
+            .market_output
+            .matches
+            .iter()
+            .all(|m| m.price_euro_per_kwh == 6.0 && m.energy_kwh == 1.0));
+    }
+
+    #[test]
+    fn test_mcafee_energy_units() {
+        // Like the first market, but with divisible orders of several units
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, 2.0, 10.0),
+                order(2, OrderType::Bid, 2.5, 6.0),
+                order(3, OrderType::Bid, 0.4, 20.0),
+                order(4, OrderType::Ask, 1.0, 2.0),
+                order(5, OrderType::Ask, 3.0, 5.0),
+                order(6, OrderType::Ask, 1.0, 7.0),
+            ],
+            curve_orders: vec![],
+        };
+        let output = mcafee_matching(&market_input, 1.0);
+        // Bid units: 10, 10, 6, 6 and ask units: 2, 5, 5, 5, 7
+        assert_eq!(output.efficient_units, 4);
+        // The fifth bid unit does not exist, so the trade is reduced
+        assert_eq!(output.traded_units, 3);
+        assert_eq!(output.buyer_price_euro_per_kwh, Some(6.0));
+        assert_eq!(output.seller_price_euro_per_kwh, Some(5.0));
+        assert!((output.budget_surplus_euro - 3.0).abs() < 1e-9);
+        let matches: Vec<(u64, u64, f64)> = output
+            .market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.energy_kwh))
+            .collect();
+        assert_eq!(matches, vec![(1, 4, 1.0), (1, 5, 1.0), (2, 5, 1.0)]);
+
+        let output = mcafee_matching(&market_input, 0.0);
+        assert_eq!(output.traded_units, 0);
+        assert!(output.market_output.matches.is_empty());
+    }
+
+    #[test]
+    fn test_mcafee_no_trade() {
+        let output = mcafee_matching(&market(&[1.0, 2.0], &[3.0, 4.0]), 1.0);
+        assert_eq!(output.efficient_units, 0);
+        assert_eq!(output.buyer_price_euro_per_kwh, None);
+        assert!(output.market_output.matches.is_empty());
+    }
+}
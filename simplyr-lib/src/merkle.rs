@@ -0,0 +1,242 @@
+//! Merkle trees over the matches of a market output.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::canonical::canonical_bytes;
+use crate::{Digest, MarketOutput, Match};
+
+/// Leaves and inner nodes are hashed with different prefixes, so an inner node can't be passed
+/// off as a match.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// A Merkle tree over the matches of a market output.
+///
+/// The leaves are the SHA-256 digests of the byte 0 followed by the canonical encoding of a match
+/// (see [`MarketInput::canonical_bytes`](crate::MarketInput::canonical_bytes)), in the order of
+/// the matches. Every inner node is the SHA-256 digest of the byte 1 followed by its two
+/// children. If a level has an odd number of nodes, the last one is moved up to the next level
+/// unchanged. The root of a tree without matches is the SHA-256 digest of no bytes.
+///
+/// The market operator publishes the root and hands every participant the [`MerkleProof`]s of
+/// their matches. The proofs are checked against the root without the other matches.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 3.0, "price_euro_per_kwh": 0.1},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.3},
+///     {"id": 3, "order_type": "bid", "time_slot": "t", "actor_id": "c", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.2}
+/// ]}"#).unwrap();
+/// let output = pay_as_bid_matching(&input);
+/// let tree = MerkleTree::new(&output.matches);
+/// let root = tree.root();
+/// assert_eq!(root, output.merkle_root());
+///
+/// // The buyer with order 3 only needs its match and the proof
+/// let index = output.matches.iter().position(|m| m.bid_id == 3).unwrap();
+/// let proof = tree.proof(index).unwrap();
+/// assert!(proof.verify(&output.matches[index], &root));
+///
+/// let mut changed = output.matches[index].clone();
+/// changed.price_euro_per_kwh = 0.15;
+/// assert!(!proof.verify(&changed, &root));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// The levels of the tree, from the leaves to the root
+    levels: Vec<Vec<Digest>>,
+}
+
+impl MerkleTree {
+    /// Build the tree over the matches.
+    pub fn new(matches: &[Match]) -> Self {
+        let leaves: Vec<Digest> = matches.iter().map(leaf_hash).collect();
+        let mut levels = vec![leaves];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    _ => pair[0],
+                })
+                .collect();
+            levels.push(parents);
+        }
+        MerkleTree { levels }
+    }
+
+    /// Return the root of the tree.
+    pub fn root(&self) -> Digest {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => Digest::of(&[]),
+        }
+    }
+
+    /// Return the proof that the match with the index is part of the tree, or `None` if there is
+    /// no such match.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        let leaf_count = self.levels[0].len();
+        if index >= leaf_count {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            // The last node of a level with an odd length has no sibling
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index: index as u64,
+            leaf_count: leaf_count as u64,
+            siblings,
+        })
+    }
+}
+
+/// The proof that a match is part of a [`MerkleTree`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// The index of the match in the market output
+    pub index: u64,
+    /// The number of matches in the market output
+    pub leaf_count: u64,
+    /// The siblings of the nodes on the path from the match to the root, from the bottom up
+    pub siblings: Vec<Digest>,
+}
+
+impl MerkleProof {
+    /// Return `true` if the proof shows that the match is part of the tree with the root.
+    pub fn verify(&self, matched: &Match, root: &Digest) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = leaf_hash(matched);
+        let mut position = self.index;
+        let mut level_len = self.leaf_count;
+        while level_len > 1 {
+            if position % 2 == 1 {
+                match siblings.next() {
+                    Some(sibling) => hash = node_hash(sibling, &hash),
+                    None => return false,
+                }
+            } else if position + 1 < level_len {
+                match siblings.next() {
+                    Some(sibling) => hash = node_hash(&hash, sibling),
+                    None => return false,
+                }
+            }
+            position /= 2;
+            level_len = level_len / 2 + level_len % 2;
+        }
+        siblings.next().is_none() && hash == *root
+    }
+}
+
+impl MarketOutput {
+    /// Return the root of the [`MerkleTree`] over the matches.
+    pub fn merkle_root(&self) -> Digest {
+        MerkleTree::new(&self.matches).root()
+    }
+}
+
+fn leaf_hash(matched: &Match) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(canonical_bytes(matched));
+    Digest(hasher.finalize().into())
+}
+
+fn node_hash(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left.0);
+    hasher.update(right.0);
+    Digest(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(count: u64) -> Vec<Match> {
+        (0..count)
+            .map(|id| Match {
+                bid_id: id,
+                ask_id: 100 + id,
+                energy_kwh: 1.0,
+                price_euro_per_kwh: 0.1 * id as f64,
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        assert_eq!(MerkleTree::new(&[]).root(), Digest::of(&[]));
+        assert_eq!(MerkleTree::new(&[]).proof(0), None);
+        for count in 1..=9 {
+            let matches = matches(count);
+            let tree = MerkleTree::new(&matches);
+            let root = tree.root();
+            assert_eq!(tree.proof(count as usize), None);
+            for (index, matched) in matches.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(
+                    proof.verify(matched, &root),
+                    "{count} matches, index {index}"
+                );
+
+                // Other matches, positions, trees and roots
+                let other = &matches[(index + 1) % matches.len()];
+                assert_eq!(proof.verify(other, &root), count == 1);
+                let mut moved = proof.clone();
+                moved.index = (moved.index + 1) % count;
+                assert_eq!(moved.verify(matched, &root), count == 1);
+                let mut grown = proof.clone();
+                grown.leaf_count *= 2;
+                assert!(!grown.verify(matched, &root));
+                let mut extended = proof.clone();
+                extended.siblings.push(root);
+                assert!(!extended.verify(matched, &root));
+                assert!(!proof.verify(matched, &Digest([0; 32])));
+            }
+        }
+    }
+
+    #[test]
+    fn test_inner_node_is_not_a_leaf() {
+        let matches = matches(4);
+        let tree = MerkleTree::new(&matches);
+        // A proof of the inner node over the first two matches must not verify anything
+        let proof = MerkleProof {
+            index: 0,
+            leaf_count: 2,
+            siblings: vec![tree.levels[1][1]],
+        };
+        for matched in &matches {
+            assert!(!proof.verify(matched, &tree.root()));
+        }
+        // The serialized proof contains the digests as hex
+        let json = serde_json::to_string(&tree.proof(2).unwrap()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<MerkleProof>(&json).unwrap(),
+            tree.proof(2).unwrap()
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Welfare and market quality metrics of a matching result.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::curve::orders_by_segment;
+use crate::{GridFeeMatrix, MarketInput, MarketOutput, Order, OrderType, MARKET_MAKER_THRESHOLD};
+
+/// Metrics that describe the outcome of a market.
+///
+/// All amounts of money are in €, energy is in kWh and prices are in € / kWh.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MarketMetrics {
+    /// Number of matches that were evaluated
+    pub match_count: usize,
+    /// Energy of all matches
+    pub traded_volume_kwh: f64,
+    /// Buyer surplus plus seller surplus, i.e. value of the bids minus cost of the asks minus
+    /// grid fees
+    pub social_welfare_euro: f64,
+    /// Price of the bids minus the price paid, summed over all matches
+    pub buyer_surplus_euro: f64,
+    /// Price received after grid fees minus the price of the asks, summed over all matches
+    pub seller_surplus_euro: f64,
+    /// Grid fees of all matches
+    pub grid_fees_euro: f64,
+    /// Statistics of the match prices, `None` if there are no matches
+    pub prices: Option<PriceStatistics>,
+    /// Local supply per cluster, sorted by cluster index
+    pub clusters: Vec<ClusterMetrics>,
+    /// Traded energy and surplus per actor, sorted by actor ID
+    pub actors: Vec<ActorMetrics>,
+    /// Gini coefficient of the surplus of all actors, from 0 (everybody gets the same surplus)
+    /// to almost 1 (one actor gets everything). `None` if the total surplus is not positive.
+    pub surplus_gini: Option<f64>,
+    /// Jain's fairness index of the surplus of all actors, from 1 / number of actors (one actor
+    /// gets everything) to 1 (everybody gets the same surplus). `None` if the total surplus is
+    /// not positive.
+    pub surplus_jain_index: Option<f64>,
+}
+
+/// Statistics of the match prices, weighted by the energy of the matches.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PriceStatistics {
+    pub min_euro_per_kwh: f64,
+    pub max_euro_per_kwh: f64,
+    pub mean_euro_per_kwh: f64,
+    /// Mean absolute deviation from the mean price
+    pub mean_absolute_deviation_euro_per_kwh: f64,
+}
+
+/// How much of the demand of a cluster was met by supply from the same cluster.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClusterMetrics {
+    pub cluster_index: usize,
+    /// Energy of all bids in the cluster, without the market maker
+    pub demand_kwh: f64,
+    /// Energy bought by bids in the cluster, without the market maker
+    pub bought_kwh: f64,
+    /// Energy bought by bids in the cluster from asks in the same cluster, without the market
+    /// maker
+    pub bought_locally_kwh: f64,
+    /// Share of the demand that was met locally (`bought_locally_kwh / demand_kwh`), `None` if
+    /// there is no demand
+    pub self_sufficiency: Option<f64>,
+}
+
+/// Traded energy and surplus of a single actor.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ActorMetrics {
+    pub actor_id: String,
+    pub bought_kwh: f64,
+    pub sold_kwh: f64,
+    /// Buyer surplus plus seller surplus of all orders of the actor
+    pub surplus_euro: f64,
+}
+
+/// Compute welfare and market quality metrics of a market output.
+///
+/// If a grid fee matrix is given, the seller of each match pays the grid fee between the bid
+/// cluster and the ask cluster (like in [`verify_market_output`](crate::verify_market_output)),
+/// except for matches with the market maker. The orders of the market maker are neither part of
+/// the demand nor of the local supply of the clusters. Curve orders count with the limit price of
+/// each segment. Matches that reference unknown orders or have the wrong order types are skipped;
+/// use [`verify_market_output`](crate::verify_market_output) to find them.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 2.0, "price_euro_per_kwh": 0.3},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.4}
+/// ]}"#).unwrap();
+/// let output = pay_as_bid_matching(&input);
+/// let metrics = market_metrics(&input, &output, None);
+/// assert_eq!(metrics.traded_volume_kwh, 1.0);
+/// // Pay-as-Bid gives the whole surplus to the seller
+/// assert_eq!(metrics.buyer_surplus_euro, 0.0);
+/// assert!((metrics.seller_surplus_euro - 0.1).abs() < 1e-9);
+/// assert_eq!(metrics.clusters[0].self_sufficiency, Some(1.0));
+/// ```
+pub fn market_metrics(
+    input: &MarketInput,
+    output: &MarketOutput,
+    grid_fee_matrix: Option<&GridFeeMatrix>,
+) -> MarketMetrics {
+    let (orders, _) = orders_by_segment(input);
+    let mut actors: BTreeMap<&str, ActorMetrics> = BTreeMap::new();
+    let mut clusters: BTreeMap<usize, ClusterMetrics> = BTreeMap::new();
+    let market_maker = |order: &Order| order.energy_kwh >= MARKET_MAKER_THRESHOLD;
+    for order in orders.values() {
+        actors
+            .entry(&order.actor_id)
+            .or_insert_with(|| ActorMetrics {
+                actor_id: order.actor_id.clone(),
+                ..Default::default()
+            });
+        if let (Some(cluster_index), false) = (order.cluster_index, market_maker(order)) {
+            let cluster = clusters
+                .entry(cluster_index)
+                .or_insert_with(|| ClusterMetrics {
+                    cluster_index,
+                    demand_kwh: 0.0,
+                    bought_kwh: 0.0,
+                    bought_locally_kwh: 0.0,
+                    self_sufficiency: None,
+                });
+            if order.order_type == OrderType::Bid {
+                cluster.demand_kwh += order.energy_kwh;
+            }
+        }
+    }
+
+    let mut match_count = 0;
+    let mut traded_volume_kwh = 0.0;
+    let mut buyer_surplus_euro = 0.0;
+    let mut seller_surplus_euro = 0.0;
+    let mut grid_fees_euro = 0.0;
+    // Prices and energy of all evaluated matches
+    let mut prices: Vec<(f64, f64)> = Vec::new();
+    for m in &output.matches {
+        let bid = orders.get(&(m.bid_id, m.bid_segment));
+        let ask = orders.get(&(m.ask_id, m.ask_segment));
+        let (bid, ask) = match (bid, ask) {
+            (Some(bid), Some(ask))
+                if bid.order_type == OrderType::Bid && ask.order_type == OrderType::Ask =>
+            {
+                (bid, ask)
+            }
+            _ => continue,
+        };
+        // Matches with the market maker are not charged grid fees, whatever its cluster
+        let grid_fee = match (grid_fee_matrix, bid.cluster_index, ask.cluster_index) {
+            (Some(matrix), Some(bid_cluster), Some(ask_cluster))
+                if !market_maker(bid) && !market_maker(ask) =>
+            {
+                matrix.get(bid_cluster, ask_cluster).unwrap_or(0.0)
+            }
+            _ => 0.0,
+        };
+        let energy_kwh = m.energy_kwh;
+        let buyer_surplus = energy_kwh * (bid.price_euro_per_kwh - m.price_euro_per_kwh);
+        let seller_surplus =
+            energy_kwh * (m.price_euro_per_kwh - grid_fee - ask.price_euro_per_kwh);
+
+        match_count += 1;
+        traded_volume_kwh += energy_kwh;
+        buyer_surplus_euro += buyer_surplus;
+        seller_surplus_euro += seller_surplus;
+        grid_fees_euro += energy_kwh * grid_fee;
+        prices.push((m.price_euro_per_kwh, energy_kwh));
+
+        if let Some(buyer) = actors.get_mut(bid.actor_id.as_str()) {
+            buyer.bought_kwh += energy_kwh;
+            buyer.surplus_euro += buyer_surplus;
+        }
+        if let Some(seller) = actors.get_mut(ask.actor_id.as_str()) {
+            seller.sold_kwh += energy_kwh;
+            seller.surplus_euro += seller_surplus;
+        }
+        if let (Some(cluster), false) = (
+            bid.cluster_index.and_then(|idx| clusters.get_mut(&idx)),
+            market_maker(bid),
+        ) {
+            cluster.bought_kwh += energy_kwh;
+            if ask.cluster_index == bid.cluster_index && !market_maker(ask) {
+                cluster.bought_locally_kwh += energy_kwh;
+            }
+        }
+    }
+
+    let clusters = clusters
+        .into_values()
+        .map(|mut cluster| {
+            if cluster.demand_kwh > 0.0 {
+                cluster.self_sufficiency = Some(cluster.bought_locally_kwh / cluster.demand_kwh);
+            }
+            cluster
+        })
+        .collect();
+    let actors: Vec<ActorMetrics> = actors.into_values().collect();
+    let surpluses: Vec<f64> = actors.iter().map(|actor| actor.surplus_euro).collect();
+
+    MarketMetrics {
+        match_count,
+        traded_volume_kwh,
+        social_welfare_euro: buyer_surplus_euro + seller_surplus_euro,
+        buyer_surplus_euro,
+        seller_surplus_euro,
+        grid_fees_euro,
+        prices: price_statistics(&prices),
+        clusters,
+        actors,
+        surplus_gini: gini(&surpluses),
+        surplus_jain_index: jain_index(&surpluses),
+    }
+}
+
+/// Energy-weighted statistics of `(price, energy)` pairs.
+fn price_statistics(prices: &[(f64, f64)]) -> Option<PriceStatistics> {
+    let total_energy: f64 = prices.iter().map(|&(_, energy)| energy).sum();
+    if prices.is_empty() || total_energy.is_nan() || total_energy <= 0.0 {
+        return None;
+    }
+    let mean = prices
+        .iter()
+        .map(|&(price, energy)| price * energy)
+        .sum::<f64>()
+        / total_energy;
+    let mean_absolute_deviation = prices
+        .iter()
+        .map(|&(price, energy)| (price - mean).abs() * energy)
+        .sum::<f64>()
+        / total_energy;
+    Some(PriceStatistics {
+        min_euro_per_kwh: prices.iter().map(|&(p, _)| p).fold(f64::INFINITY, f64::min),
+        max_euro_per_kwh: prices
+            .iter()
+            .map(|&(p, _)| p)
+            .fold(f64::NEG_INFINITY, f64::max),
+        mean_euro_per_kwh: mean,
+        mean_absolute_deviation_euro_per_kwh: mean_absolute_deviation,
+    })
+}
+
+/// Gini coefficient: the mean absolute difference of all pairs divided by twice the mean.
+fn gini(values: &[f64]) -> Option<f64> {
+    let total: f64 = values.iter().sum();
+    if total.is_nan() || total <= 0.0 {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    // Equivalent to the sum over all pairs for sorted values
+    let n = sorted.len() as f64;
+    let weighted_sum: f64 = sorted
+        .iter()
+        .enumerate()
+        .map(|(idx, value)| (2.0 * (idx as f64 + 1.0) - n - 1.0) * value)
+        .sum();
+    Some(weighted_sum / (n * total))
+}
+
+/// Jain's fairness index: (Σx)² / (n · Σx²).
+fn jain_index(values: &[f64]) -> Option<f64> {
+    let total: f64 = values.iter().sum();
+    let total_squares: f64 = values.iter().map(|value| value * value).sum();
+    if total.is_nan() || total <= 0.0 {
+        return None;
+    }
+    Some(total * total / (values.len() as f64 * total_squares))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{custom_fair_matching, Match};
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn order(
+        id: u64,
+        order_type: OrderType,
+        actor: &str,
+        cluster_index: usize,
+        energy_kwh: f64,
+        price_euro_per_kwh: f64,
+    ) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: format!("actor_{actor}"),
+            cluster_index: Some(cluster_index),
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn test_market_metrics() {
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, "a", 0, 2.0, 0.5),
+                order(2, OrderType::Bid, "b", 1, 2.0, 0.5),
+                order(3, OrderType::Ask, "c", 0, 3.0, 0.2),
+                order(4, OrderType::Ask, "d", 1, 1.0, 0.4),
+            ],
+            curve_orders: vec![],
+        };
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0, 0.1], vec![0.1, 0.0]]).unwrap();
+        let output = custom_fair_matching(&input, 1.0, &gfm);
+        let metrics = market_metrics(&input, &output, Some(&gfm));
+
+        // Bid 1 buys 2 kWh locally at 0.2, bid 2 buys 1 kWh from cluster 0 at 0.3 and 1 kWh
+        // locally at 0.4
+        assert_eq!(metrics.match_count, 3);
+        assert_close(metrics.traded_volume_kwh, 4.0);
+        assert_close(metrics.buyer_surplus_euro, 2.0 * 0.3 + 0.2 + 0.1);
+        assert_close(metrics.seller_surplus_euro, 0.0);
+        assert_close(metrics.grid_fees_euro, 0.1);
+        assert_close(metrics.social_welfare_euro, 0.9);
+
+        let prices = metrics.prices.unwrap();
+        assert_eq!(prices.min_euro_per_kwh, 0.2);
+        assert_eq!(prices.max_euro_per_kwh, 0.4);
+        assert_close(prices.mean_euro_per_kwh, 0.275);
+
+        assert_eq!(metrics.clusters[0].self_sufficiency, Some(1.0));
+        assert_eq!(metrics.clusters[1].self_sufficiency, Some(0.5));
+        assert_eq!(metrics.actors.len(), 4);
+        assert_close(metrics.actors[0].surplus_euro, 0.6);
+        assert_close(metrics.actors[1].surplus_euro, 0.3);
+    }
+
+    #[test]
+    fn test_market_maker() {
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, "a", 0, 2.0, 0.5),
+                order(2, OrderType::Ask, "b", 0, 1.0, 0.1),
+                order(3, OrderType::Ask, "mm", 1, MARKET_MAKER_THRESHOLD, 0.4),
+                order(4, OrderType::Bid, "mm", 1, MARKET_MAKER_THRESHOLD, 0.05),
+            ],
+            curve_orders: vec![],
+        };
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0, 0.1], vec![0.1, 0.0]]).unwrap();
+        let output = custom_fair_matching(&input, 1.0, &gfm);
+        let metrics = market_metrics(&input, &output, Some(&gfm));
+
+        // Bid 1 buys 1 kWh locally at 0.1 and 1 kWh from the market maker at 0.4 without grid fee
+        assert_eq!(metrics.match_count, 2);
+        assert_close(metrics.traded_volume_kwh, 2.0);
+        assert_close(metrics.grid_fees_euro, 0.0);
+        assert_close(metrics.buyer_surplus_euro, 0.4 + 0.1);
+        assert_close(metrics.seller_surplus_euro, 0.0);
+
+        // The market maker is not part of the clusters
+        assert_eq!(metrics.clusters.len(), 1);
+        assert_close(metrics.clusters[0].demand_kwh, 2.0);
+        assert_close(metrics.clusters[0].bought_kwh, 2.0);
+        assert_eq!(metrics.clusters[0].self_sufficiency, Some(0.5));
+    }
+
+    #[test]
+    fn test_fairness_indices() {
+        assert_eq!(gini(&[1.0, 1.0, 1.0]), Some(0.0));
+        assert_eq!(jain_index(&[1.0, 1.0, 1.0]), Some(1.0));
+        assert_close(gini(&[0.0, 0.0, 0.0, 4.0]).unwrap(), 0.75);
+        assert_close(jain_index(&[0.0, 0.0, 0.0, 4.0]).unwrap(), 0.25);
+        assert_eq!(gini(&[]), None);
+        assert_eq!(jain_index(&[0.0, 0.0]), None);
+    }
+
+    #[test]
+    fn test_unknown_orders_are_skipped() {
+        let input = MarketInput {
+            orders: vec![order(1, OrderType::Bid, "a", 0, 2.0, 0.5)],
+            curve_orders: vec![],
+        };
+        let output = MarketOutput {
+            matches: vec![Match {
+                bid_id: 1,
+                ask_id: 2,
+                energy_kwh: 1.0,
+                price_euro_per_kwh: 0.3,
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            }],
+            residual_book: vec![],
+            diagnostics: vec![],
+        };
+        let metrics = market_metrics(&input, &output, None);
+        assert_eq!(metrics.match_count, 0);
+        assert_eq!(metrics.prices, None);
+        assert_eq!(metrics.clusters[0].self_sufficiency, Some(0.0));
+        assert_eq!(metrics.surplus_gini, None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Batch matching of several time slots with orders that are linked across slots.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fill::fill_violations;
+use crate::{MarketInput, MarketOutput, ResidualOrder};
+
+impl MarketInput {
+    /// Split the orders and curve orders by their time slot.
+    pub fn split_time_slots(&self) -> BTreeMap<String, MarketInput> {
+        let mut slots: BTreeMap<String, MarketInput> = BTreeMap::new();
+        let empty = || MarketInput {
+            orders: vec![],
+            curve_orders: vec![],
+        };
+        for order in &self.orders {
+            slots
+                .entry(order.time_slot.clone())
+                .or_insert_with(empty)
+                .orders
+                .push(order.clone());
+        }
+        for curve_order in &self.curve_orders {
+            slots
+                .entry(curve_order.time_slot.clone())
+                .or_insert_with(empty)
+                .curve_orders
+                .push(curve_order.clone());
+        }
+        slots
+    }
+}
+
+/// Match the orders of every time slot separately and couple the acceptance of linked orders.
+///
+/// `matching` is called with the orders and curve orders of one time slot, e.g.
+/// [`pay_as_bid_matching`](crate::pay_as_bid_matching). `market_output` gives access to the
+/// matches of its result.
+///
+/// Orders with the same [`link_id`](crate::Order::link_id) are accepted together or not at all:
+/// a battery may sell in one slot only if it can buy in another, an industrial load may need
+/// energy in several consecutive slots. If an order of a link is not matched (or violates its
+/// [`FillConstraint`](crate::FillConstraint)), the matched orders of the same link are removed
+/// from the input and all time slots are matched again. Every repetition removes at least one
+/// order. The removed orders are listed in the
+/// [residual book](crate::MarketOutput::residual_book) of their time slot with
+/// [`FillRule::Link`](crate::FillRule::Link).
+///
+/// The result contains the output of every time slot of the input.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "bid", "time_slot": "10:00", "actor_id": "battery",
+///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.1, "link_id": 7},
+///     {"id": 2, "order_type": "ask", "time_slot": "18:00", "actor_id": "battery",
+///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.3, "link_id": 7},
+///     {"id": 3, "order_type": "ask", "time_slot": "10:00", "actor_id": "pv",
+///      "cluster_index": 0, "energy_kwh": 5.0, "price_euro_per_kwh": 0.05},
+///     {"id": 4, "order_type": "bid", "time_slot": "18:00", "actor_id": "home",
+///      "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.25}
+/// ]}"#).unwrap();
+/// let outputs = multi_slot_matching(&input, pay_as_bid_matching, |output| output);
+/// // Nobody buys the energy of the battery in the evening, so it does not charge either
+/// assert!(outputs["10:00"].matches.is_empty());
+/// assert_eq!(outputs["10:00"].residual_book[0].rule, FillRule::Link);
+/// assert!(outputs["18:00"].matches.is_empty());
+/// ```
+pub fn multi_slot_matching<T, F, M>(
+    input: &MarketInput,
+    matching: F,
+    market_output: M,
+) -> BTreeMap<String, T>
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    let time_slots: Vec<String> = input.split_time_slots().into_keys().collect();
+
+    // Removed orders are still checked, so the orders linked to them are removed as well
+    let mut remaining = input.clone();
+    // Orders that were removed because of their links, by time slot
+    let mut residual_books: BTreeMap<String, Vec<ResidualOrder>> = BTreeMap::new();
+    loop {
+        let mut outputs = BTreeMap::new();
+        let mut all_matches = MarketOutput {
+            matches: vec![],
+            residual_book: vec![],
+            diagnostics: vec![],
+        };
+        let mut slot_inputs = remaining.split_time_slots();
+        for time_slot in &time_slots {
+            // All orders of a time slot may have been removed
+            let slot_input = slot_inputs.remove(time_slot).unwrap_or(MarketInput {
+                orders: vec![],
+                curve_orders: vec![],
+            });
+            let mut output = matching(&slot_input);
+            all_matches
+                .matches
+                .extend_from_slice(&market_output(&mut output).matches);
+            outputs.insert(time_slot.clone(), output);
+        }
+
+        // Orders of different time slots are checked at once, so links across slots are found
+        let violations = fill_violations(input, &all_matches);
+        if violations.is_empty() {
+            for (time_slot, output) in outputs.iter_mut() {
+                if let Some(residual_book) = residual_books.remove(time_slot) {
+                    market_output(output).residual_book.extend(residual_book);
+                }
+            }
+            return outputs;
+        }
+        for &(order_id, rule) in &violations {
+            if let Some(order) = input.orders.iter().find(|order| order.id == order_id) {
+                residual_books
+                    .entry(order.time_slot.clone())
+                    .or_default()
+                    .push(ResidualOrder {
+                        order_id,
+                        energy_kwh: order.energy_kwh,
+                        rule,
+                    });
+            }
+        }
+        let removed: BTreeSet<u64> = violations.iter().map(|&(order_id, _)| order_id).collect();
+        remaining
+            .orders
+            .retain(|order| !removed.contains(&order.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pay_as_bid_matching, FillConstraint, FillRule, Order, OrderType};
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, time_slot: &str, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: time_slot.to_string(),
+            actor_id: "actor".to_string() + &id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 2.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_linked_block_order() {
+        // An industrial load needs 2 kWh in each of three slots, but the last slot has too little
+        // supply
+        let block = Some(FillConstraint {
+            all_or_nothing: true,
+            ..Default::default()
+        });
+        let mut orders = vec![];
+        for (idx, time_slot) in ["08:00", "09:00", "10:00"].iter().enumerate() {
+            let mut bid = order(idx as u64 + 1, OrderType::Bid, time_slot, 0.4);
+            bid.fill_constraint = block.clone();
+            bid.link_id = Some(1);
+            orders.push(bid);
+            let mut ask = order(idx as u64 + 11, OrderType::Ask, time_slot, 0.2);
+            if idx == 2 {
+                ask.energy_kwh = 1.0;
+            }
+            orders.push(ask);
+        }
+        let input = MarketInput {
+            orders,
+            curve_orders: vec![],
+        };
+        let outputs = multi_slot_matching(&input, pay_as_bid_matching, |output| output);
+        assert_eq!(outputs.len(), 3);
+        for (time_slot, output) in &outputs {
+            assert!(output.matches.is_empty(), "{time_slot}");
+            assert_eq!(output.residual_book.len(), 1);
+        }
+        assert_eq!(outputs["08:00"].residual_book[0].rule, FillRule::Link);
+        assert_eq!(
+            outputs["10:00"].residual_book[0].rule,
+            FillRule::MinimumFill
+        );
+
+        // With enough supply, the load gets energy in all slots
+        let mut input = input;
+        input.orders[5].energy_kwh = 2.0;
+        let outputs = multi_slot_matching(&input, pay_as_bid_matching, |output| output);
+        for output in outputs.values() {
+            assert_eq!(output.matches.len(), 1);
+            assert_eq!(output.matches[0].energy_kwh, 2.0);
+            assert!(output.residual_book.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_unlinked_orders() {
+        // Without links, every slot is matched on its own
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, "08:00", 0.4),
+                order(2, OrderType::Ask, "08:00", 0.2),
+                order(3, OrderType::Bid, "09:00", 0.1),
+                order(4, OrderType::Ask, "09:00", 0.2),
+            ],
+            curve_orders: vec![],
+        };
+        let outputs = multi_slot_matching(&input, pay_as_bid_matching, |output| output);
+        assert_eq!(outputs["08:00"].matches.len(), 1);
+        assert!(outputs["09:00"].matches.is_empty());
+    }
+}
@@ -0,0 +1,589 @@
+//! A continuous double auction.
+//!
+//! In contrast to the batch algorithms in the crate root, the [`OrderBook`] accepts orders one at
+//! a time and matches them immediately against the resting orders of the opposite side. Each
+//! `time_slot` has its own independent book.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+// We use this instead of [`HashMap`] in `no_std` because we don't have access to a secure source
+// of random numbers to avoid hash collision attacks.
+use alloc::collections::btree_map::BTreeMap;
+
+use crate::counterparty::forbidden;
+use crate::{round_energy_value, Match, Order, OrderType, SelfTradePrevention, ENERGY_EPS};
+
+/// An order that rests in the book together with its time priority.
+#[derive(Clone, Debug)]
+struct RestingOrder {
+    order: Order,
+    /// Increasing counter that is assigned whenever an order (re-)enters the book.
+    sequence: u64,
+}
+
+/// The resting orders of a single time slot.
+///
+/// Both vectors are kept sorted by priority, so the first element is always the best order.
+#[derive(Clone, Debug, Default)]
+struct SlotBook {
+    /// Bids sorted by price (descending), then by sequence (ascending)
+    bids: Vec<RestingOrder>,
+    /// Asks sorted by price (ascending), then by sequence (ascending)
+    asks: Vec<RestingOrder>,
+}
+
+impl SlotBook {
+    fn side_mut(&mut self, order_type: OrderType) -> &mut Vec<RestingOrder> {
+        match order_type {
+            OrderType::Bid => &mut self.bids,
+            OrderType::Ask => &mut self.asks,
+        }
+    }
+
+    /// Insert an order at the position given by price-time priority.
+    fn insert(&mut self, resting: RestingOrder) {
+        let order_type = resting.order.order_type;
+        let side = self.side_mut(order_type);
+        let pos = side.partition_point(|other| {
+            has_priority(
+                &other.order,
+                other.sequence,
+                &resting.order,
+                resting.sequence,
+            )
+        });
+        side.insert(pos, resting);
+    }
+
+    /// Remove an order by its ID.
+    fn remove(&mut self, id: u64) -> Option<RestingOrder> {
+        for side in [&mut self.bids, &mut self.asks] {
+            if let Some(pos) = side.iter().position(|resting| resting.order.id == id) {
+                return Some(side.remove(pos));
+            }
+        }
+        None
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bids.is_empty() && self.asks.is_empty()
+    }
+}
+
+/// Return `true` if order `a` has to be matched before order `b` of the same type.
+fn has_priority(a: &Order, a_sequence: u64, b: &Order, b_sequence: u64) -> bool {
+    let by_price = match a.order_type {
+        OrderType::Bid => b.price_euro_per_kwh.total_cmp(&a.price_euro_per_kwh),
+        OrderType::Ask => a.price_euro_per_kwh.total_cmp(&b.price_euro_per_kwh),
+    };
+    by_price.then(a_sequence.cmp(&b_sequence)).is_lt()
+}
+
+/// All resting orders at the same price aggregated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PriceLevel {
+    /// The price in € / kWh
+    pub price_euro_per_kwh: f64,
+    /// The sum of the remaining energy of all orders at this price in kWh
+    pub energy_kwh: f64,
+    /// The number of orders at this price
+    pub order_count: usize,
+}
+
+/// The aggregated price levels of one time slot, best prices first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Depth {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+/// An order book for continuous trading with price-time priority.
+///
+/// Every incoming order is matched against the best resting orders of the opposite side of its
+/// `time_slot` as long as the prices cross. Matches are executed at the price of the resting
+/// order. Any remaining energy (at least [`ENERGY_EPS`]) rests in the book.
+///
+/// Resting orders that the incoming order forbids as counterparty or that forbid it (see
+/// [`CounterpartyRules`](crate::CounterpartyRules)) are skipped. Orders of the same actor are
+/// handled according to the [`SelfTradePrevention`] of the book, see
+/// [`OrderBook::with_self_trade_prevention`].
+///
+/// ```
+/// # use simplyr_lib::*;
+/// # fn foo() -> Result<(), String> {
+/// let mut book = OrderBook::new();
+/// let ask = Order {
+///     id: 1,
+///     order_type: OrderType::Ask,
+///     time_slot: "2022-03-04T05:06:07+00:00".into(),
+///     actor_id: "actor_1".into(),
+///     cluster_index: Some(0),
+///     energy_kwh: 2.0,
+///     price_euro_per_kwh: 0.25,
+///     ..Default::default()
+/// };
+/// assert!(book.add_order(ask)?.is_empty());
+///
+/// let bid = Order {
+///     id: 2,
+///     order_type: OrderType::Bid,
+///     time_slot: "2022-03-04T05:06:07+00:00".into(),
+///     actor_id: "actor_2".into(),
+///     cluster_index: Some(0),
+///     energy_kwh: 1.5,
+///     price_euro_per_kwh: 0.30,
+///     ..Default::default()
+/// };
+/// let matches = book.add_order(bid)?;
+/// assert_eq!(matches.len(), 1);
+/// assert_eq!(matches[0].energy_kwh, 1.5);
+/// assert_eq!(matches[0].price_euro_per_kwh, 0.25);
+///
+/// let best_ask = book.best_ask("2022-03-04T05:06:07+00:00").unwrap();
+/// assert_eq!(best_ask.energy_kwh, 0.5);
+/// # Ok(())
+/// # }
+/// # foo().unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct OrderBook {
+    /// Map from time slot -> resting orders
+    books: BTreeMap<String, SlotBook>,
+    /// Map from order ID -> time slot of the resting order
+    index: BTreeMap<u64, String>,
+    next_sequence: u64,
+    self_trade_prevention: SelfTradePrevention,
+}
+
+impl OrderBook {
+    /// Create an empty order book that allows self-trades.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty order book that prevents matches between orders of the same actor.
+    ///
+    /// The incoming order is always the newest one, regardless of [`Order::submitted_at`]:
+    /// [`CancelNewest`](SelfTradePrevention::CancelNewest) cancels the rest of the incoming
+    /// order, [`CancelOldest`](SelfTradePrevention::CancelOldest) cancels the resting order and
+    /// continues matching. [`DecrementBoth`](SelfTradePrevention::DecrementBoth) reduces both
+    /// orders by the smaller amount without a match and
+    /// [`SkipPair`](SelfTradePrevention::SkipPair) skips the resting order, so the remainder of
+    /// the incoming order may rest in the book although it crosses it.
+    ///
+    /// ```
+    /// # use simplyr_lib::*;
+    /// # fn foo() -> Result<(), String> {
+    /// let mut book = OrderBook::with_self_trade_prevention(SelfTradePrevention::CancelOldest);
+    /// let order = |id, order_type, price_euro_per_kwh| Order {
+    ///     id,
+    ///     order_type,
+    ///     time_slot: "2022-03-04T05:06:07+00:00".into(),
+    ///     actor_id: "home".into(),
+    ///     energy_kwh: 1.0,
+    ///     price_euro_per_kwh,
+    ///     ..Default::default()
+    /// };
+    /// book.add_order(order(1, OrderType::Ask, 0.25))?;
+    /// // The ask of the same actor is cancelled instead of matched, the bid rests in the book
+    /// assert!(book.add_order(order(2, OrderType::Bid, 0.30))?.is_empty());
+    /// assert!(book.get_order(1).is_none());
+    /// assert!(book.get_order(2).is_some());
+    /// # Ok(())
+    /// # }
+    /// # foo().unwrap();
+    /// ```
+    pub fn with_self_trade_prevention(self_trade_prevention: SelfTradePrevention) -> Self {
+        OrderBook {
+            self_trade_prevention,
+            ..Self::default()
+        }
+    }
+
+    /// Submit a new order.
+    ///
+    /// Returns the matches that were executed immediately, in execution order. Fails if an order
+    /// with the same ID is still resting in the book or if the order values are invalid.
+    pub fn add_order(&mut self, order: Order) -> Result<Vec<Match>, String> {
+        if self.index.contains_key(&order.id) {
+            return Err("an order with this id is already in the book".into());
+        }
+        validate_order(&order)?;
+        Ok(self.execute(order))
+    }
+
+    /// Cancel a resting order and return what was left of it.
+    pub fn cancel_order(&mut self, id: u64) -> Result<Order, String> {
+        let time_slot = match self.index.remove(&id) {
+            Some(time_slot) => time_slot,
+            None => return Err("order not found".into()),
+        };
+        let book = self
+            .books
+            .get_mut(&time_slot)
+            .ok_or_else(|| String::from("order not found"))?;
+        let resting = book
+            .remove(id)
+            .ok_or_else(|| String::from("order not found"))?;
+        if book.is_empty() {
+            self.books.remove(&time_slot);
+        }
+        Ok(resting.order)
+    }
+
+    /// Change the remaining energy and the price of a resting order.
+    ///
+    /// Reducing the energy at an unchanged price keeps the time priority of the order. Any other
+    /// change puts the order at the end of its new price level. If the new price crosses the
+    /// opposite side, the order is matched immediately and the resulting matches are returned.
+    pub fn amend_order(
+        &mut self,
+        id: u64,
+        energy_kwh: f64,
+        price_euro_per_kwh: f64,
+    ) -> Result<Vec<Match>, String> {
+        let resting = match self.resting_order(id) {
+            Some(resting) => resting,
+            None => return Err("order not found".into()),
+        };
+        let mut amended = resting.order.clone();
+        amended.energy_kwh = energy_kwh;
+        amended.price_euro_per_kwh = price_euro_per_kwh;
+        validate_order(&amended)?;
+
+        let keeps_priority = price_euro_per_kwh == resting.order.price_euro_per_kwh
+            && energy_kwh <= resting.order.energy_kwh;
+
+        if keeps_priority {
+            let book = self.books.get_mut(&amended.time_slot);
+            for side in book
+                .into_iter()
+                .flat_map(|book| [&mut book.bids, &mut book.asks])
+            {
+                if let Some(resting) = side.iter_mut().find(|resting| resting.order.id == id) {
+                    resting.order.energy_kwh = energy_kwh;
+                }
+            }
+            return Ok(vec![]);
+        }
+
+        self.cancel_order(id)?;
+        Ok(self.execute(amended))
+    }
+
+    /// Return the remaining part of a resting order.
+    pub fn get_order(&self, id: u64) -> Option<&Order> {
+        self.resting_order(id).map(|resting| &resting.order)
+    }
+
+    /// Return the time slots that have resting orders.
+    pub fn time_slots(&self) -> impl Iterator<Item = &str> {
+        self.books.keys().map(String::as_str)
+    }
+
+    /// Return the highest bid price level of a time slot.
+    pub fn best_bid(&self, time_slot: &str) -> Option<PriceLevel> {
+        let book = self.books.get(time_slot)?;
+        price_levels(&book.bids).next()
+    }
+
+    /// Return the lowest ask price level of a time slot.
+    pub fn best_ask(&self, time_slot: &str) -> Option<PriceLevel> {
+        let book = self.books.get(time_slot)?;
+        price_levels(&book.asks).next()
+    }
+
+    /// Return up to `max_levels` price levels of both sides of a time slot.
+    pub fn depth(&self, time_slot: &str, max_levels: usize) -> Depth {
+        match self.books.get(time_slot) {
+            Some(book) => Depth {
+                bids: price_levels(&book.bids).take(max_levels).collect(),
+                asks: price_levels(&book.asks).take(max_levels).collect(),
+            },
+            None => Depth::default(),
+        }
+    }
+
+    fn resting_order(&self, id: u64) -> Option<&RestingOrder> {
+        let book = self.books.get(self.index.get(&id)?)?;
+        book.bids
+            .iter()
+            .chain(book.asks.iter())
+            .find(|resting| resting.order.id == id)
+    }
+
+    /// Match an order against the opposite side and let the remainder rest in the book.
+    fn execute(&mut self, mut order: Order) -> Vec<Match> {
+        let mut matches = vec![];
+        let book = self.books.entry(order.time_slot.clone()).or_default();
+        let opposite = match order.order_type {
+            OrderType::Bid => &mut book.asks,
+            OrderType::Ask => &mut book.bids,
+        };
+
+        // Position of the best resting order that was not skipped
+        let mut position = 0;
+        while order.energy_kwh >= ENERGY_EPS {
+            let best = match opposite.get_mut(position) {
+                Some(best) => best,
+                None => break,
+            };
+            let crosses = match order.order_type {
+                OrderType::Bid => order.price_euro_per_kwh >= best.order.price_euro_per_kwh,
+                OrderType::Ask => order.price_euro_per_kwh <= best.order.price_euro_per_kwh,
+            };
+            if !crosses {
+                break;
+            }
+
+            let (bid, ask) = match order.order_type {
+                OrderType::Bid => (&order, &best.order),
+                OrderType::Ask => (&best.order, &order),
+            };
+            let prevention = if forbidden(bid, ask) {
+                SelfTradePrevention::SkipPair
+            } else if self.self_trade_prevention.prevents(bid, ask) {
+                self.self_trade_prevention
+            } else {
+                SelfTradePrevention::Allow
+            };
+            match prevention {
+                SelfTradePrevention::Allow => {}
+                SelfTradePrevention::CancelNewest => {
+                    order.energy_kwh = 0.0;
+                    break;
+                }
+                SelfTradePrevention::CancelOldest => {
+                    let cancelled = opposite.remove(position);
+                    self.index.remove(&cancelled.order.id);
+                    continue;
+                }
+                SelfTradePrevention::DecrementBoth => {
+                    let energy = order.energy_kwh.min(best.order.energy_kwh);
+                    order.energy_kwh -= energy;
+                    best.order.energy_kwh -= energy;
+                    if best.order.energy_kwh < ENERGY_EPS {
+                        let removed = opposite.remove(position);
+                        self.index.remove(&removed.order.id);
+                    }
+                    continue;
+                }
+                SelfTradePrevention::SkipPair => {
+                    position += 1;
+                    continue;
+                }
+            }
+
+            let matched_energy = order.energy_kwh.min(best.order.energy_kwh);
+            let (bid_id, ask_id) = match order.order_type {
+                OrderType::Bid => (order.id, best.order.id),
+                OrderType::Ask => (best.order.id, order.id),
+            };
+            matches.push(Match {
+                bid_id,
+                ask_id,
+                energy_kwh: round_energy_value(matched_energy),
+                price_euro_per_kwh: best.order.price_euro_per_kwh,
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            });
+            order.energy_kwh -= matched_energy;
+            best.order.energy_kwh -= matched_energy;
+
+            if best.order.energy_kwh < ENERGY_EPS {
+                let filled = opposite.remove(position);
+                self.index.remove(&filled.order.id);
+            }
+        }
+
+        if order.energy_kwh >= ENERGY_EPS {
+            self.index.insert(order.id, order.time_slot.clone());
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            book.insert(RestingOrder { order, sequence });
+        } else if book.is_empty() {
+            self.books.remove(&order.time_slot);
+        }
+
+        matches
+    }
+}
+
+fn validate_order(order: &Order) -> Result<(), String> {
+    if !order.price_euro_per_kwh.is_finite() {
+        return Err("price must be a finite number".into());
+    }
+    if !order.energy_kwh.is_finite() || order.energy_kwh < ENERGY_EPS {
+        return Err("energy must be a finite number of at least ENERGY_EPS".into());
+    }
+    Ok(())
+}
+
+/// Aggregate consecutive orders with the same price.
+fn price_levels(side: &[RestingOrder]) -> impl Iterator<Item = PriceLevel> + '_ {
+    let mut remaining = side;
+    core::iter::from_fn(move || {
+        let price = remaining.first()?.order.price_euro_per_kwh;
+        let count = remaining
+            .iter()
+            .take_while(|resting| resting.order.price_euro_per_kwh == price)
+            .count();
+        let (level, rest) = remaining.split_at(count);
+        remaining = rest;
+        Some(PriceLevel {
+            price_euro_per_kwh: price,
+            energy_kwh: round_energy_value(level.iter().map(|r| r.order.energy_kwh).sum()),
+            order_count: count,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CounterpartyRules;
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, energy_kwh: f64, price_euro_per_kwh: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor_1".to_string(),
+            cluster_index: Some(0),
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    const SLOT: &str = "2022-03-04T05:06:07+00:00";
+
+    #[test]
+    fn test_price_time_priority() {
+        let mut book = OrderBook::new();
+        book.add_order(order(1, OrderType::Ask, 1.0, 0.30)).unwrap();
+        book.add_order(order(2, OrderType::Ask, 1.0, 0.20)).unwrap();
+        book.add_order(order(3, OrderType::Ask, 1.0, 0.20)).unwrap();
+
+        let matches = book.add_order(order(4, OrderType::Bid, 2.5, 0.30)).unwrap();
+        assert_eq!(matches.len(), 3);
+        assert_eq!(
+            (matches[0].ask_id, matches[0].price_euro_per_kwh),
+            (2, 0.20)
+        );
+        assert_eq!(
+            (matches[1].ask_id, matches[1].price_euro_per_kwh),
+            (3, 0.20)
+        );
+        assert_eq!((matches[2].ask_id, matches[2].energy_kwh), (1, 0.5));
+
+        assert_eq!(book.get_order(1).unwrap().energy_kwh, 0.5);
+        assert!(book.get_order(2).is_none());
+        assert!(book.get_order(4).is_none());
+        assert!(book.best_bid(SLOT).is_none());
+    }
+
+    #[test]
+    fn test_resting_and_depth() {
+        let mut book = OrderBook::new();
+        book.add_order(order(1, OrderType::Bid, 1.0, 0.20)).unwrap();
+        book.add_order(order(2, OrderType::Bid, 2.0, 0.20)).unwrap();
+        book.add_order(order(3, OrderType::Bid, 1.0, 0.15)).unwrap();
+        book.add_order(order(4, OrderType::Ask, 1.0, 0.25)).unwrap();
+
+        let depth = book.depth(SLOT, 10);
+        assert_eq!(depth.bids.len(), 2);
+        assert_eq!(depth.bids[0].energy_kwh, 3.0);
+        assert_eq!(depth.bids[0].order_count, 2);
+        assert_eq!(depth.bids[1].price_euro_per_kwh, 0.15);
+        assert_eq!(book.best_ask(SLOT).unwrap().price_euro_per_kwh, 0.25);
+        assert_eq!(book.depth("other slot", 10), Depth::default());
+
+        assert!(book.add_order(order(1, OrderType::Ask, 1.0, 0.1)).is_err());
+    }
+
+    #[test]
+    fn test_cancel_and_amend() {
+        let mut book = OrderBook::new();
+        book.add_order(order(1, OrderType::Ask, 1.0, 0.20)).unwrap();
+        book.add_order(order(2, OrderType::Ask, 1.0, 0.20)).unwrap();
+        book.add_order(order(3, OrderType::Bid, 1.0, 0.10)).unwrap();
+
+        // Increasing the energy loses time priority
+        assert!(book.amend_order(1, 2.0, 0.20).unwrap().is_empty());
+        // Reducing the energy keeps time priority
+        assert!(book.amend_order(2, 0.5, 0.20).unwrap().is_empty());
+
+        // A crossing amendment is matched immediately
+        let matches = book.amend_order(3, 1.0, 0.20).unwrap();
+        assert_eq!(matches.len(), 2);
+        assert_eq!((matches[0].ask_id, matches[0].energy_kwh), (2, 0.5));
+        assert_eq!((matches[1].ask_id, matches[1].energy_kwh), (1, 0.5));
+
+        let cancelled = book.cancel_order(1).unwrap();
+        assert_eq!(cancelled.energy_kwh, 1.5);
+        assert!(book.cancel_order(1).is_err());
+        assert_eq!(book.time_slots().count(), 0);
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        // Ask 1 and bid 3 belong to the same actor
+        let run = |book: &mut OrderBook, forbid: bool| {
+            let mut other = order(2, OrderType::Ask, 1.0, 0.25);
+            other.actor_id = "actor_2".to_string();
+            if forbid {
+                other.counterparties = Some(CounterpartyRules {
+                    forbidden: vec!["actor_1".to_string()],
+                    ..Default::default()
+                });
+            }
+            book.add_order(order(1, OrderType::Ask, 1.0, 0.20)).unwrap();
+            book.add_order(other).unwrap();
+            let matches: Vec<_> = book
+                .add_order(order(3, OrderType::Bid, 1.5, 0.30))
+                .unwrap()
+                .iter()
+                .map(|m| (m.ask_id, m.energy_kwh))
+                .collect();
+            let remaining = |id| book.get_order(id).map(|order| order.energy_kwh);
+            (matches, [remaining(1), remaining(2), remaining(3)])
+        };
+        let with = |self_trade_prevention| {
+            run(
+                &mut OrderBook::with_self_trade_prevention(self_trade_prevention),
+                false,
+            )
+        };
+
+        assert_eq!(
+            with(SelfTradePrevention::Allow),
+            (vec![(1, 1.0), (2, 0.5)], [None, Some(0.5), None])
+        );
+        assert_eq!(
+            with(SelfTradePrevention::CancelNewest),
+            (vec![], [Some(1.0), Some(1.0), None])
+        );
+        assert_eq!(
+            with(SelfTradePrevention::CancelOldest),
+            (vec![(2, 1.0)], [None, None, Some(0.5)])
+        );
+        assert_eq!(
+            with(SelfTradePrevention::DecrementBoth),
+            (vec![(2, 0.5)], [None, Some(0.5), None])
+        );
+        assert_eq!(
+            with(SelfTradePrevention::SkipPair),
+            (vec![(2, 1.0)], [Some(1.0), None, Some(0.5)])
+        );
+        // Forbidden counterparties are skipped
+        assert_eq!(
+            run(&mut OrderBook::new(), true),
+            (vec![(1, 1.0)], [None, Some(1.0), Some(0.5)])
+        );
+    }
+}
@@ -0,0 +1,205 @@
+//! Pseudonyms for the actors of published market data.
+
+use alloc::collections::btree_map::BTreeMap;
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
+
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::digest::parse_hex;
+use crate::MarketInput;
+
+/// The pseudonyms start with this prefix.
+const PSEUDONYM_PREFIX: &str = "p_";
+
+/// Pseudonyms contain this many bytes of the keyed hash.
+const PSEUDONYM_BYTES: usize = 16;
+
+/// A secret key for actor pseudonyms, parsed from 64 hex digits.
+///
+/// The pseudonym of an actor is the HMAC-SHA256 of the `actor_id` with this key, truncated to
+/// 128 bits and written as hex digits after the prefix `p_`. So an actor gets the same pseudonym
+/// in all data that is pseudonymized with the same key, but without the key the pseudonyms can't
+/// be linked to the actors. The key is not printed by `Debug`.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let key: PseudonymKey =
+///     "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".parse().unwrap();
+/// let pseudonym = key.pseudonym("household_17");
+/// assert!(pseudonym.starts_with("p_"));
+/// assert_eq!(pseudonym, key.pseudonym("household_17"));
+/// assert_ne!(pseudonym, key.pseudonym("household_18"));
+/// ```
+#[derive(Clone)]
+pub struct PseudonymKey(pub [u8; 32]);
+
+impl PseudonymKey {
+    /// Return the pseudonym of an actor.
+    pub fn pseudonym(&self, actor_id: &str) -> String {
+        // HMAC pads shorter keys with zeros to the block size of SHA-256
+        let mut key = [0; 64];
+        key[..self.0.len()].copy_from_slice(&self.0);
+        let mut mac = <Hmac<Sha256> as KeyInit>::new(&key.into());
+        mac.update(actor_id.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        let mut pseudonym = String::from(PSEUDONYM_PREFIX);
+        for byte in &hash[..PSEUDONYM_BYTES] {
+            pseudonym.push_str(&alloc::format!("{byte:02x}"));
+        }
+        pseudonym
+    }
+}
+
+impl fmt::Debug for PseudonymKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PseudonymKey(..)")
+    }
+}
+
+impl FromStr for PseudonymKey {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        parse_hex(hex).map(PseudonymKey)
+    }
+}
+
+/// Replace every actor ID of the market input with its pseudonym.
+///
+/// The actors of orders, curve orders and [`CounterpartyRules`](crate::CounterpartyRules) are
+/// replaced, so the pseudonymized input is matched exactly like the original one. Matches only
+/// reference order IDs, so the outputs of the pseudonymized input can be published as well.
+///
+/// The returned map from the pseudonyms to the actor IDs has to be kept private; it reverses the
+/// pseudonymization with [`depseudonymize`].
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "pv_plant",
+///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.1},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "household_17",
+///      "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.3,
+///      "counterparties": {"forbidden": ["pv_plant"]}}
+/// ]}"#).unwrap();
+/// let key: PseudonymKey =
+///     "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f".parse().unwrap();
+/// let (pseudonymized, pseudonyms) = pseudonymize(&input, &key);
+/// let seller = &pseudonymized.orders[0].actor_id;
+/// assert_eq!(pseudonyms[seller], "pv_plant");
+/// let rules = pseudonymized.orders[1].counterparties.as_ref().unwrap();
+/// assert_eq!(rules.forbidden, [seller.clone()]);
+/// assert_eq!(depseudonymize(&pseudonymized, &pseudonyms).orders[0].actor_id, "pv_plant");
+/// ```
+pub fn pseudonymize(
+    input: &MarketInput,
+    key: &PseudonymKey,
+) -> (MarketInput, BTreeMap<String, String>) {
+    let mut pseudonyms = BTreeMap::new();
+    let output = rename_actors(input, |actor_id| {
+        let pseudonym = key.pseudonym(actor_id);
+        pseudonyms.insert(pseudonym.clone(), String::from(actor_id));
+        pseudonym
+    });
+    (output, pseudonyms)
+}
+
+/// Replace the pseudonyms of a market input with the actor IDs of the map that was returned by
+/// [`pseudonymize`]. Unknown actors are kept.
+pub fn depseudonymize(input: &MarketInput, pseudonyms: &BTreeMap<String, String>) -> MarketInput {
+    rename_actors(input, |pseudonym| match pseudonyms.get(pseudonym) {
+        Some(actor_id) => actor_id.clone(),
+        None => String::from(pseudonym),
+    })
+}
+
+/// Replace every actor ID of the market input.
+fn rename_actors(input: &MarketInput, mut rename: impl FnMut(&str) -> String) -> MarketInput {
+    let mut output = input.clone();
+    for order in &mut output.orders {
+        order.actor_id = rename(&order.actor_id);
+        if let Some(rules) = &mut order.counterparties {
+            rules.preferred = rules
+                .preferred
+                .iter()
+                .map(|(actor_id, &weight)| (rename(actor_id), weight))
+                .collect();
+            for actor_id in &mut rules.forbidden {
+                *actor_id = rename(actor_id);
+            }
+        }
+    }
+    for curve_order in &mut output.curve_orders {
+        curve_order.actor_id = rename(&curve_order.actor_id);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pay_as_bid_matching, CounterpartyRules, Order, OrderType};
+    use alloc::string::ToString;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn order(id: u64, order_type: OrderType, actor_id: &str, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: actor_id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 1.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pseudonymize() {
+        let mut bid = order(3, OrderType::Bid, "home", 0.3);
+        bid.counterparties = Some(CounterpartyRules {
+            preferred: [("neighbour".to_string(), 1.0)].into_iter().collect(),
+            forbidden: vec!["utility".to_string()],
+        });
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, "utility", 0.1),
+                order(2, OrderType::Ask, "neighbour", 0.2),
+                bid,
+            ],
+            curve_orders: vec![],
+        };
+        let key = PseudonymKey([7; 32]);
+        let (pseudonymized, pseudonyms) = pseudonymize(&input, &key);
+        assert_eq!(pseudonyms.len(), 3);
+        for order in &pseudonymized.orders {
+            assert_eq!(order.actor_id.len(), 2 + 2 * PSEUDONYM_BYTES);
+            assert!(pseudonyms.contains_key(&order.actor_id));
+        }
+        // Other keys give other pseudonyms
+        let (other, _) = pseudonymize(&input, &PseudonymKey([8; 32]));
+        assert_ne!(other.orders[0].actor_id, pseudonymized.orders[0].actor_id);
+
+        // The counterparty rules are pseudonymized as well, so the matches don't change
+        let matches = |input: &MarketInput| {
+            let output = pay_as_bid_matching(input);
+            let pairs: Vec<(u64, u64)> = output
+                .matches
+                .iter()
+                .map(|m| (m.bid_id, m.ask_id))
+                .collect();
+            pairs
+        };
+        assert_eq!(matches(&input), vec![(3, 2)]);
+        assert_eq!(matches(&pseudonymized), matches(&input));
+
+        let restored = depseudonymize(&pseudonymized, &pseudonyms);
+        assert_eq!(restored.digest(), input.digest());
+    }
+}
@@ -0,0 +1,476 @@
+//! Synthetic market scenarios with households, PV producers and a market maker.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::rng::SplitMix64;
+use crate::{GridFeeMatrixRaw, MarketInput, Order, OrderType, ENERGY_EPS, MARKET_MAKER_THRESHOLD};
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// How the prices of the generated orders are distributed (in € / kWh).
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum PriceDistribution {
+    /// Every price between `min` and `max` is equally likely
+    Uniform { min: f64, max: f64 },
+    /// An approximately normal distribution, cut off at 0
+    Normal { mean: f64, std_dev: f64 },
+}
+
+impl PriceDistribution {
+    fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            PriceDistribution::Uniform { min, max } => {
+                min.is_finite() && max.is_finite() && min <= max
+            }
+            PriceDistribution::Normal { mean, std_dev } => {
+                mean.is_finite() && std_dev.is_finite() && std_dev >= 0.0
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid price distribution {self:?}"))
+        }
+    }
+
+    fn sample(&self, rng: &mut SplitMix64) -> f64 {
+        match *self {
+            PriceDistribution::Uniform { min, max } => min + (max - min) * rng.next_f64(),
+            PriceDistribution::Normal { mean, std_dev } => {
+                // The sum of 12 uniform values minus 6 has a mean of 0 and a variance of 1
+                let standard: f64 = (0..12).map(|_| rng.next_f64()).sum::<f64>() - 6.0;
+                (mean + std_dev * standard).max(0.0)
+            }
+        }
+    }
+}
+
+/// The settings of [`generate_scenario`].
+///
+/// All fields have defaults, so a JSON file only needs to contain the fields that differ.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScenarioConfig {
+    /// The number of households
+    pub households: usize,
+    /// The number of clusters; the households are spread randomly over them
+    pub clusters: usize,
+    /// The share of households with a PV system (between 0 and 1)
+    pub pv_share: f64,
+    /// The peak power of a PV system in kW
+    pub pv_peak_kw: f64,
+    /// The average load of a household in kW
+    pub household_load_kw: f64,
+    /// The date of the first time slot (`YYYY-MM-DD`); the time slots start at midnight UTC
+    pub start_date: String,
+    /// The number of consecutive time slots
+    pub time_slots: usize,
+    /// The length of a time slot in minutes
+    pub slot_minutes: u32,
+    /// The limit prices of the households' bids
+    pub bid_prices: PriceDistribution,
+    /// The limit prices of the households' asks
+    pub ask_prices: PriceDistribution,
+    /// The price of the market maker's ask in every time slot (e.g. the retail tariff), no ask if
+    /// `None`
+    pub market_maker_ask_euro_per_kwh: Option<f64>,
+    /// The price of the market maker's bid in every time slot (e.g. the feed-in tariff), no bid
+    /// if `None`
+    pub market_maker_bid_euro_per_kwh: Option<f64>,
+    /// The grid fee between neighbouring clusters in € / kWh. The clusters are arranged in a
+    /// line, so the fee grows with the distance between two clusters.
+    pub grid_fee_euro_per_kwh: f64,
+}
+
+impl Default for ScenarioConfig {
+    fn default() -> Self {
+        ScenarioConfig {
+            households: 20,
+            clusters: 3,
+            pv_share: 0.4,
+            pv_peak_kw: 5.0,
+            household_load_kw: 0.5,
+            start_date: "2022-03-04".into(),
+            time_slots: 96,
+            slot_minutes: 15,
+            // Some households pay the retail tariff of the market maker and some sell at its
+            // feed-in tariff, so every time slot has matches
+            bid_prices: PriceDistribution::Uniform {
+                min: 0.2,
+                max: 0.45,
+            },
+            ask_prices: PriceDistribution::Uniform {
+                min: 0.05,
+                max: 0.25,
+            },
+            market_maker_ask_euro_per_kwh: Some(0.4),
+            market_maker_bid_euro_per_kwh: Some(0.06),
+            grid_fee_euro_per_kwh: 0.01,
+        }
+    }
+}
+
+impl ScenarioConfig {
+    /// Check that all values are finite and within their ranges.
+    pub fn validate(&self) -> Result<(), String> {
+        let values = [
+            self.pv_share,
+            self.pv_peak_kw,
+            self.household_load_kw,
+            self.grid_fee_euro_per_kwh,
+        ];
+        if !values
+            .iter()
+            .all(|value| value.is_finite() && *value >= 0.0)
+        {
+            return Err("the PV, load and grid fee values have to be finite and positive".into());
+        }
+        if self.pv_share > 1.0 {
+            return Err("the PV share has to be between 0 and 1".into());
+        }
+        if self.clusters == 0 {
+            return Err("a scenario needs at least one cluster".into());
+        }
+        if self.slot_minutes == 0 {
+            return Err("the time slots need a length".into());
+        }
+        let market_maker_prices = [
+            self.market_maker_ask_euro_per_kwh,
+            self.market_maker_bid_euro_per_kwh,
+        ];
+        if market_maker_prices
+            .iter()
+            .flatten()
+            .any(|price| !price.is_finite())
+        {
+            return Err("the prices of the market maker have to be finite".into());
+        }
+        self.bid_prices.validate()?;
+        self.ask_prices.validate()?;
+        parse_date(&self.start_date)?;
+        Ok(())
+    }
+}
+
+/// A generated market input together with the grid fee matrix of its clusters.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scenario {
+    pub market_input: MarketInput,
+    pub grid_fee_matrix: GridFeeMatrixRaw,
+}
+
+/// Generate the orders of households and a market maker over several time slots.
+///
+/// Every household has a load profile with peaks in the morning and in the evening; some of
+/// them also have a PV system that produces around noon. In every time slot, a household with
+/// more production than load sells the difference, the others buy what they need, so the
+/// market is long around noon and short in the evening. The market maker buys and sells any
+/// amount at fixed prices in every time slot (see
+/// [`custom_fair_matching`](crate::custom_fair_matching)).
+///
+/// The same config and seed always give the same scenario. The orders span several time slots,
+/// so they have to be matched with [`multi_slot_matching`](crate::multi_slot_matching).
+///
+/// ```
+/// # use simplyr_lib::*;
+/// // Four time slots of six hours
+/// let config = ScenarioConfig {
+///     households: 10,
+///     time_slots: 4,
+///     slot_minutes: 360,
+///     ..ScenarioConfig::default()
+/// };
+/// let scenario = generate_scenario(&config, 42).unwrap();
+/// assert_eq!(scenario.market_input.split_time_slots().len(), 4);
+/// assert_eq!(scenario.grid_fee_matrix.len(), config.clusters);
+///
+/// let grid_fee_matrix = GridFeeMatrix::from_raw(&scenario.grid_fee_matrix).unwrap();
+/// let outputs = multi_slot_matching(
+///     &scenario.market_input,
+///     |input| custom_fair_matching(input, 0.1, &grid_fee_matrix),
+///     |output| output,
+/// );
+/// for (time_slot, slot_input) in scenario.market_input.split_time_slots() {
+///     let output = &outputs[&time_slot];
+///     let limits = PriceLimits::default();
+///     let report = verify_market_output(&slot_input, output, Some(&grid_fee_matrix), &limits);
+///     assert!(report.is_valid());
+///     assert!(!output.matches.is_empty());
+/// }
+/// ```
+pub fn generate_scenario(config: &ScenarioConfig, seed: u64) -> Result<Scenario, String> {
+    config.validate()?;
+    let start_day = parse_date(&config.start_date)?;
+    let mut rng = SplitMix64::new(seed);
+
+    // The households keep their cluster and PV system over all time slots
+    let households: Vec<(usize, bool)> = (0..config.households)
+        .map(|_| {
+            let cluster_index = (rng.next_u64() % config.clusters as u64) as usize;
+            (cluster_index, rng.next_f64() < config.pv_share)
+        })
+        .collect();
+
+    let slot_hours = f64::from(config.slot_minutes) / 60.0;
+    let mut orders = vec![];
+    let mut next_id = 1;
+    for slot in 0..config.time_slots {
+        let start_minute = slot as u64 * u64::from(config.slot_minutes);
+        let time_slot = format_time_slot(start_day, start_minute);
+        let middle_hour = ((start_minute % MINUTES_PER_DAY) as f64) / 60.0 + slot_hours / 2.0;
+
+        let mut push = |order_type, actor_id, cluster_index, energy_kwh, price_euro_per_kwh| {
+            orders.push(Order {
+                id: next_id,
+                order_type,
+                time_slot: time_slot.clone(),
+                actor_id,
+                cluster_index,
+                energy_kwh,
+                price_euro_per_kwh,
+                ..Default::default()
+            });
+            next_id += 1;
+        };
+        for (index, &(cluster_index, has_pv)) in households.iter().enumerate() {
+            // Clouds reduce the production, the load varies around the profile
+            let production_kw = if has_pv {
+                config.pv_peak_kw * pv_profile(middle_hour) * (0.6 + 0.4 * rng.next_f64())
+            } else {
+                0.0
+            };
+            let load_kw =
+                config.household_load_kw * load_profile(middle_hour) * (0.7 + 0.6 * rng.next_f64());
+            let energy_kwh = round_to_thousandths((production_kw - load_kw).abs() * slot_hours);
+            let (order_type, prices) = if production_kw > load_kw {
+                (OrderType::Ask, &config.ask_prices)
+            } else {
+                (OrderType::Bid, &config.bid_prices)
+            };
+            let price = round_to_thousandths(prices.sample(&mut rng));
+            if energy_kwh >= ENERGY_EPS {
+                let actor_id = format!("household_{index}");
+                push(order_type, actor_id, Some(cluster_index), energy_kwh, price);
+            }
+        }
+        if let Some(price) = config.market_maker_ask_euro_per_kwh {
+            let actor_id = "market_maker".into();
+            push(
+                OrderType::Ask,
+                actor_id,
+                None,
+                MARKET_MAKER_THRESHOLD,
+                price,
+            );
+        }
+        if let Some(price) = config.market_maker_bid_euro_per_kwh {
+            let actor_id = "market_maker".into();
+            push(
+                OrderType::Bid,
+                actor_id,
+                None,
+                MARKET_MAKER_THRESHOLD,
+                price,
+            );
+        }
+    }
+
+    let grid_fee_matrix = (0..config.clusters)
+        .map(|source| {
+            (0..config.clusters)
+                .map(|dest| config.grid_fee_euro_per_kwh * source.abs_diff(dest) as f64)
+                .collect()
+        })
+        .collect();
+    Ok(Scenario {
+        market_input: MarketInput {
+            orders,
+            curve_orders: vec![],
+        },
+        grid_fee_matrix,
+    })
+}
+
+/// The PV production relative to the peak power at an hour of the day (UTC).
+fn pv_profile(hour: f64) -> f64 {
+    let distance_from_noon = (hour - 13.0) / 6.5;
+    (1.0 - distance_from_noon * distance_from_noon).max(0.0)
+}
+
+/// The household load relative to the average load at an hour of the day (UTC).
+fn load_profile(hour: f64) -> f64 {
+    let peak = |center: f64, half_width: f64| (1.0 - (hour - center).abs() / half_width).max(0.0);
+    0.6 + 0.8 * peak(7.5, 2.0) + 1.4 * peak(19.0, 3.0)
+}
+
+/// Round a positive value to three decimal places (Wh or tenths of a cent).
+fn round_to_thousandths(value: f64) -> f64 {
+    (value * 1000.0 + 0.5) as u64 as f64 / 1000.0
+}
+
+/// Parse a date like `2022-03-04` and return the number of days since 1970-01-01.
+fn parse_date(date: &str) -> Result<i64, String> {
+    let invalid = || format!("{date:?} is not a date like 2022-03-04");
+    let mut parts = date.splitn(3, '-');
+    let mut next = |len| match parts.next() {
+        Some(part) if part.len() == len && part.bytes().all(|b| b.is_ascii_digit()) => {
+            part.parse::<i64>().map_err(|_| invalid())
+        }
+        _ => Err(invalid()),
+    };
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+    let days = days_from_civil(year, month, day);
+    if !(1..=12).contains(&month) || civil_from_days(days) != (year, month, day) {
+        return Err(invalid());
+    }
+    Ok(days)
+}
+
+/// Format the start of a time slot like `2022-03-04T05:15:00+00:00`.
+fn format_time_slot(start_day: i64, minute: u64) -> String {
+    let (year, month, day) = civil_from_days(start_day + (minute / MINUTES_PER_DAY) as i64);
+    let minute_of_day = minute % MINUTES_PER_DAY;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:00+00:00",
+        minute_of_day / 60,
+        minute_of_day % 60
+    )
+}
+
+/// The number of days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of a number of days since 1970-01-01, see [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{multi_slot_matching, pay_as_bid_matching};
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2000-03-01"), Ok(11_017));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert!(parse_date("2022-02-29").is_err());
+        assert!(parse_date("2022-13-01").is_err());
+        assert!(parse_date("22-03-04").is_err());
+        assert!(parse_date("2022-03-04T00:00").is_err());
+        let start = parse_date("2024-02-28").unwrap();
+        assert_eq!(format_time_slot(start, 0), "2024-02-28T00:00:00+00:00");
+        assert_eq!(
+            format_time_slot(start, MINUTES_PER_DAY + 75),
+            "2024-02-29T01:15:00+00:00"
+        );
+        assert_eq!(
+            format_time_slot(start, 2 * MINUTES_PER_DAY),
+            "2024-03-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_generate_scenario() {
+        let config = ScenarioConfig {
+            households: 30,
+            time_slots: 24,
+            slot_minutes: 60,
+            bid_prices: PriceDistribution::Normal {
+                mean: 0.3,
+                std_dev: 0.03,
+            },
+            ..ScenarioConfig::default()
+        };
+        let scenario = generate_scenario(&config, 7).unwrap();
+        let orders = &scenario.market_input.orders;
+
+        // Reproducible, but different for other seeds
+        let again = generate_scenario(&config, 7).unwrap();
+        assert_eq!(canonical(&again), canonical(&scenario));
+        let other = generate_scenario(&config, 8).unwrap();
+        assert_ne!(canonical(&other), canonical(&scenario));
+
+        // Unique IDs and one bid and one ask of the market maker per time slot
+        let mut ids: Vec<u64> = orders.iter().map(|order| order.id).collect();
+        ids.dedup();
+        assert_eq!(ids.len(), orders.len());
+        let market_maker = orders
+            .iter()
+            .filter(|order| order.cluster_index.is_none())
+            .count();
+        assert_eq!(market_maker, 2 * 24);
+
+        // Households only sell while the sun shines
+        for order in orders {
+            if order.actor_id.starts_with("household") {
+                assert!(order.cluster_index.unwrap() < config.clusters);
+                assert!(order.energy_kwh >= ENERGY_EPS);
+                if order.order_type == OrderType::Ask {
+                    assert!(("2022-03-04T07".."2022-03-04T19").contains(&order.time_slot.as_str()));
+                }
+            }
+        }
+        assert!(orders
+            .iter()
+            .any(|order| order.order_type == OrderType::Ask && order.cluster_index.is_some()));
+
+        assert_eq!(scenario.grid_fee_matrix[0], vec![0.0, 0.01, 0.02]);
+        assert_eq!(scenario.grid_fee_matrix[2][1], 0.01);
+
+        let invalid = ScenarioConfig {
+            clusters: 0,
+            ..ScenarioConfig::default()
+        };
+        assert!(generate_scenario(&invalid, 0).is_err());
+    }
+
+    #[test]
+    fn test_default_scenario_trades() {
+        // The households trade with the market maker even at night
+        let config = ScenarioConfig {
+            time_slots: 4,
+            ..ScenarioConfig::default()
+        };
+        let scenario = generate_scenario(&config, 0).unwrap();
+        assert!(scenario.market_input.orders[0]
+            .time_slot
+            .starts_with("2022-03-04T00:00"));
+        let outputs =
+            multi_slot_matching(&scenario.market_input, pay_as_bid_matching, |output| output);
+        assert_eq!(outputs.len(), 4);
+        assert!(outputs.values().all(|output| !output.matches.is_empty()));
+    }
+
+    fn canonical(scenario: &Scenario) -> Vec<u8> {
+        scenario.market_input.canonical_bytes()
+    }
+}
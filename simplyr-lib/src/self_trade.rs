@@ -0,0 +1,467 @@
+//! Self-trade prevention by actor.
+//!
+//! The batch algorithms resolve the crossing orders of an actor before matching (see
+//! [`SelfTradePrevention`]). The continuous [`OrderBook`](crate::OrderBook) applies the same rules
+//! whenever an order arrives, see
+//! [`OrderBook::with_self_trade_prevention`](crate::OrderBook::with_self_trade_prevention).
+
+use alloc::borrow::Cow;
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::curve::order_steps;
+use crate::{
+    round_energy_value, Diagnostic, MarketInput, MarketOutput, Order, OrderType, ENERGY_EPS,
+};
+
+/// The bids and the asks of an actor in a time slot.
+type OrderGroup<'a> = (Vec<&'a Order>, Vec<&'a Order>);
+
+/// What happens if a bid and an ask of the same actor could be matched with each other.
+///
+/// A bid and an ask of the same actor cross if they are in the same time slot and the price of
+/// the bid is at least the price of the ask. The crossing pairs are resolved before matching,
+/// starting with the highest bid and the lowest ask of every actor. Of two orders, the one with
+/// the later [`Order::submitted_at`] is the newer one; orders without a submission time are the
+/// oldest and orders with equal times are ordered by their ID.
+///
+/// Unless self-trades are allowed, the batch algorithms never match orders of the same actor,
+/// they skip these pairs while matching. The [`mcafee_matching`](crate::mcafee_matching) then
+/// trades fewer units than the auction determined if the remaining units can't be paired, and
+/// the [`vcg_matching`](crate::vcg_matching) computes the allocation and the payments without
+/// these pairs. [`CurveOrder`](crate::CurveOrder)s are neither cancelled nor decremented; their
+/// crossing pairs are skipped in every mode. All changes are reported in the
+/// [diagnostics](MarketOutput::diagnostics). The [`OrderBook`](crate::OrderBook) checks every
+/// incoming order against the resting orders instead.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "home",
+///      "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.1, "submitted_at": 1},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "home",
+///      "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.3, "submitted_at": 2}
+/// ]}"#).unwrap();
+/// let config = MatchingConfig {
+///     self_trade_prevention: SelfTradePrevention::CancelNewest,
+///     ..Default::default()
+/// };
+/// let output = pay_as_bid_matching_with_config(&input, &config);
+/// assert!(output.matches.is_empty());
+/// assert_eq!(
+///     output.diagnostics,
+///     vec![Diagnostic::SelfTradeCancelled { order_id: 2, other_order_id: 1 }]
+/// );
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTradePrevention {
+    /// Orders of the same actor may be matched with each other
+    #[default]
+    Allow,
+    /// The newer order of a crossing pair is removed
+    CancelNewest,
+    /// The older order of a crossing pair is removed
+    CancelOldest,
+    /// The energy of both orders is reduced by the smaller amount, orders without energy are
+    /// removed
+    DecrementBoth,
+    /// Both orders stay in the market, but they are not matched with each other
+    SkipPair,
+}
+
+impl SelfTradePrevention {
+    /// Return `true` if the bid and the ask may not be matched with each other.
+    pub(crate) fn prevents(self, bid: &Order, ask: &Order) -> bool {
+        self != SelfTradePrevention::Allow && bid.actor_id == ask.actor_id
+    }
+
+    /// Resolve the crossing orders of every actor.
+    ///
+    /// Curve orders are not changed; their crossing pairs are reported as skipped.
+    fn apply<'a>(self, input: &'a MarketInput) -> (Cow<'a, MarketInput>, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        if self == SelfTradePrevention::Allow {
+            return (Cow::Borrowed(input), diagnostics);
+        }
+
+        // Bids and asks that can be matched, by actor and time slot
+        let mut groups: BTreeMap<(&str, &str), OrderGroup> = BTreeMap::new();
+        for order in &input.orders {
+            if !(order.energy_kwh.is_finite() && order.price_euro_per_kwh.is_finite())
+                || order.energy_kwh < ENERGY_EPS
+            {
+                continue;
+            }
+            let group = groups
+                .entry((order.actor_id.as_str(), order.time_slot.as_str()))
+                .or_default();
+            match order.order_type {
+                OrderType::Bid => group.0.push(order),
+                OrderType::Ask => group.1.push(order),
+            }
+        }
+
+        let mut removed = BTreeSet::new();
+        let mut energies: BTreeMap<u64, f64> = BTreeMap::new();
+        for (mut bids, mut asks) in groups.into_values() {
+            bids.sort_by(|a, b| b.price_euro_per_kwh.total_cmp(&a.price_euro_per_kwh));
+            asks.sort_by(|a, b| a.price_euro_per_kwh.total_cmp(&b.price_euro_per_kwh));
+            if self == SelfTradePrevention::SkipPair {
+                for bid in &bids {
+                    for ask in asks
+                        .iter()
+                        .take_while(|ask| ask.price_euro_per_kwh <= bid.price_euro_per_kwh)
+                    {
+                        diagnostics.push(Diagnostic::SelfTradeSkipped {
+                            bid_id: bid.id,
+                            ask_id: ask.id,
+                        });
+                    }
+                }
+                continue;
+            }
+
+            let (mut bid_index, mut ask_index) = (0, 0);
+            while let (Some(&bid), Some(&ask)) = (bids.get(bid_index), asks.get(ask_index)) {
+                if bid.price_euro_per_kwh < ask.price_euro_per_kwh {
+                    break;
+                }
+                if self == SelfTradePrevention::DecrementBoth {
+                    let bid_energy = *energies.entry(bid.id).or_insert(bid.energy_kwh);
+                    let ask_energy = *energies.entry(ask.id).or_insert(ask.energy_kwh);
+                    let energy = bid_energy.min(ask_energy);
+                    energies.insert(bid.id, bid_energy - energy);
+                    energies.insert(ask.id, ask_energy - energy);
+                    diagnostics.push(Diagnostic::SelfTradeDecremented {
+                        bid_id: bid.id,
+                        ask_id: ask.id,
+                        energy_kwh: round_energy_value(energy),
+                    });
+                    if bid_energy - energy < ENERGY_EPS {
+                        removed.insert(bid.id);
+                        bid_index += 1;
+                    }
+                    if ask_energy - energy < ENERGY_EPS {
+                        removed.insert(ask.id);
+                        ask_index += 1;
+                    }
+                    continue;
+                }
+                let bid_is_newer = (bid.submitted_at, bid.id) > (ask.submitted_at, ask.id);
+                let (cancelled, other) =
+                    if bid_is_newer == (self == SelfTradePrevention::CancelNewest) {
+                        bid_index += 1;
+                        (bid, ask)
+                    } else {
+                        ask_index += 1;
+                        (ask, bid)
+                    };
+                removed.insert(cancelled.id);
+                diagnostics.push(Diagnostic::SelfTradeCancelled {
+                    order_id: cancelled.id,
+                    other_order_id: other.id,
+                });
+            }
+        }
+
+        let input = if removed.is_empty() && energies.is_empty() {
+            Cow::Borrowed(input)
+        } else {
+            let mut input = input.clone();
+            input.orders.retain(|order| !removed.contains(&order.id));
+            for order in &mut input.orders {
+                if let Some(&energy_kwh) = energies.get(&order.id) {
+                    order.energy_kwh = energy_kwh;
+                }
+            }
+            Cow::Owned(input)
+        };
+
+        // Curve orders are not changed, so their crossing pairs are skipped in every mode
+        diagnostics.extend(
+            crossing_curve_pairs(&input)
+                .into_iter()
+                .map(|(bid_id, ask_id)| Diagnostic::SelfTradeSkipped { bid_id, ask_id }),
+        );
+        (input, diagnostics)
+    }
+}
+
+/// Return the crossing pairs of the same actor that contain a curve order, sorted by ID.
+fn crossing_curve_pairs(input: &MarketInput) -> BTreeSet<(u64, u64)> {
+    if input.curve_orders.is_empty() {
+        return BTreeSet::new();
+    }
+    let matchable = |order: &Order| {
+        order.energy_kwh.is_finite()
+            && order.price_euro_per_kwh.is_finite()
+            && order.energy_kwh >= ENERGY_EPS
+    };
+
+    // Orders and curve segments that can be matched, by actor and time slot
+    let steps = order_steps(input);
+    let mut groups: BTreeMap<(&str, &str), OrderGroup> = BTreeMap::new();
+    for (order, _) in steps.iter().filter(|(order, _)| matchable(order)) {
+        let group = groups
+            .entry((order.actor_id.as_str(), order.time_slot.as_str()))
+            .or_default();
+        match order.order_type {
+            OrderType::Bid => group.0.push(order),
+            OrderType::Ask => group.1.push(order),
+        }
+    }
+
+    let mut pairs = BTreeSet::new();
+    for (order, _) in steps
+        .iter()
+        .filter(|(order, segment)| segment.is_some() && matchable(order))
+    {
+        let (bids, asks) = match groups.get(&(order.actor_id.as_str(), order.time_slot.as_str())) {
+            Some(group) => group,
+            None => continue,
+        };
+        match order.order_type {
+            OrderType::Bid => {
+                for ask in asks
+                    .iter()
+                    .filter(|ask| ask.price_euro_per_kwh <= order.price_euro_per_kwh)
+                {
+                    pairs.insert((order.id, ask.id));
+                }
+            }
+            OrderType::Ask => {
+                for bid in bids
+                    .iter()
+                    .filter(|bid| bid.price_euro_per_kwh >= order.price_euro_per_kwh)
+                {
+                    pairs.insert((bid.id, order.id));
+                }
+            }
+        }
+    }
+    pairs
+}
+
+/// Run a matching algorithm with self-trade prevention.
+///
+/// The crossing orders are resolved before matching, the algorithm has to skip the pairs that
+/// the self-trade prevention [prevents](SelfTradePrevention::prevents).
+pub(crate) fn with_self_trade_prevention<T, F, M>(
+    input: &MarketInput,
+    self_trade_prevention: SelfTradePrevention,
+    matching: F,
+    market_output: M,
+) -> T
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    if self_trade_prevention == SelfTradePrevention::Allow {
+        return matching(input);
+    }
+    let (input, mut diagnostics) = self_trade_prevention.apply(input);
+    let mut output = matching(&input);
+    let market_output = market_output(&mut output);
+    diagnostics.append(&mut market_output.diagnostics);
+    market_output.diagnostics = diagnostics;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        custom_fair_matching_with_config, mcafee_matching_with_config,
+        pay_as_bid_matching_with_config, uniform_price_matching_with_config,
+        vcg_matching_with_config, CurveOrder, CurvePoint, GridFeeMatrix, Interpolation,
+        MatchingConfig,
+    };
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    fn order(id: u64, order_type: OrderType, actor_id: &str, energy: f64, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: actor_id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: energy,
+            price_euro_per_kwh: price,
+            submitted_at: Some(id),
+            ..Default::default()
+        }
+    }
+
+    fn market() -> MarketInput {
+        MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, "home", 2.0, 0.1),
+                order(2, OrderType::Ask, "pv", 2.0, 0.2),
+                order(3, OrderType::Bid, "home", 3.0, 0.3),
+            ],
+            curve_orders: vec![],
+        }
+    }
+
+    fn config(self_trade_prevention: SelfTradePrevention) -> MatchingConfig {
+        MatchingConfig {
+            self_trade_prevention,
+            ..Default::default()
+        }
+    }
+
+    fn pairs(output: &MarketOutput) -> Vec<(u64, u64, f64)> {
+        output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.energy_kwh))
+            .collect()
+    }
+
+    #[test]
+    fn test_self_trade_prevention() {
+        let input = market();
+        let output = pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::Allow));
+        assert_eq!(pairs(&output), vec![(3, 1, 2.0), (3, 2, 1.0)]);
+        assert!(output.diagnostics.is_empty());
+
+        // The bid is newer than the ask of the same actor
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::CancelNewest));
+        assert!(output.matches.is_empty());
+        assert_eq!(
+            output.diagnostics,
+            vec![Diagnostic::SelfTradeCancelled {
+                order_id: 3,
+                other_order_id: 1
+            }]
+        );
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::CancelOldest));
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert_eq!(
+            output.diagnostics,
+            vec![Diagnostic::SelfTradeCancelled {
+                order_id: 1,
+                other_order_id: 3
+            }]
+        );
+
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::DecrementBoth));
+        assert_eq!(pairs(&output), vec![(3, 2, 1.0)]);
+        assert_eq!(
+            output.diagnostics,
+            vec![Diagnostic::SelfTradeDecremented {
+                bid_id: 3,
+                ask_id: 1,
+                energy_kwh: 2.0
+            }]
+        );
+
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        let skipped = vec![Diagnostic::SelfTradeSkipped {
+            bid_id: 3,
+            ask_id: 1,
+        }];
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::SkipPair));
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert_eq!(output.diagnostics, skipped);
+        let output = custom_fair_matching_with_config(
+            &input,
+            1.0,
+            &grid_fee_matrix,
+            &config(SelfTradePrevention::SkipPair),
+        );
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert_eq!(output.diagnostics, skipped);
+        let output =
+            uniform_price_matching_with_config(&input, &config(SelfTradePrevention::SkipPair));
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert_eq!(output.diagnostics, skipped);
+    }
+
+    #[test]
+    fn test_auctions() {
+        let mut input = market();
+        input
+            .orders
+            .push(order(4, OrderType::Bid, "other", 1.0, 0.3));
+        let config = config(SelfTradePrevention::SkipPair);
+        let skipped = vec![Diagnostic::SelfTradeSkipped {
+            bid_id: 3,
+            ask_id: 1,
+        }];
+
+        // Three units are traded at 0.3 / 0.2, but the two units of the home's ask can't be
+        // paired with the home's bid
+        let output = mcafee_matching_with_config(&input, 1.0, &config);
+        assert_eq!(pairs(&output.market_output), vec![(3, 2, 1.0)]);
+        assert_eq!(output.traded_units, 1);
+        assert!((output.budget_surplus_euro - 0.1).abs() < 1e-9);
+        assert_eq!(output.market_output.diagnostics, skipped);
+
+        // The welfare is maximized without the pair, also for the payments
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0]]").unwrap();
+        let output = vcg_matching_with_config(&input, &grid_fee_matrix, &config);
+        assert_eq!(pairs(&output.market_output), vec![(3, 2, 2.0), (4, 1, 1.0)]);
+        assert!((output.social_welfare_euro - 0.4).abs() < 1e-9);
+        // Without the home, only 1 kWh is traded with a welfare of 0.1
+        assert_eq!(output.payments[0].actor_id, "home");
+        assert!((output.payments[0].utility_euro - 0.3).abs() < 1e-9);
+        assert_eq!(output.market_output.diagnostics, skipped);
+    }
+
+    #[test]
+    fn test_curve_orders() {
+        // A curve bid of the home, the plain orders are not changed because of it
+        let mut input = market();
+        input.orders.remove(2);
+        input.curve_orders.push(CurveOrder {
+            id: 3,
+            order_type: OrderType::Bid,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "home".to_string(),
+            cluster_index: Some(0),
+            interpolation: Interpolation::Step,
+            points: vec![
+                CurvePoint {
+                    energy_kwh: 2.0,
+                    price_euro_per_kwh: 0.3,
+                },
+                CurvePoint {
+                    energy_kwh: 3.0,
+                    price_euro_per_kwh: 0.15,
+                },
+            ],
+            submitted_at: None,
+        });
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::CancelNewest));
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert_eq!(
+            output.diagnostics,
+            vec![Diagnostic::SelfTradeSkipped {
+                bid_id: 3,
+                ask_id: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_other_time_slots() {
+        // Other actors and other time slots are not affected
+        let mut input = market();
+        input.orders[0].time_slot = "2022-03-04T06:06:07+00:00".to_string();
+        let output =
+            pay_as_bid_matching_with_config(&input, &config(SelfTradePrevention::CancelNewest));
+        assert_eq!(pairs(&output), vec![(3, 2, 2.0)]);
+        assert!(output.diagnostics.is_empty());
+    }
+}
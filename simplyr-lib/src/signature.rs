@@ -0,0 +1,192 @@
+//! Market outputs signed by the market operator.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::digest::{hex_bytes, parse_hex};
+use crate::{Digest, MarketInput, MarketOutput};
+
+/// The signed message starts with this, so the signature can't be used for anything else.
+const SIGNATURE_CONTEXT: &[u8] = b"simplyr signed market output v1\0";
+
+/// The secret Ed25519 key of the market operator, parsed from 64 hex digits.
+///
+/// The key is not printed by `Debug` and can't be serialized.
+#[derive(Clone)]
+pub struct SecretKey(pub [u8; 32]);
+
+impl SecretKey {
+    /// Return the public key that belongs to the secret key.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(SigningKey::from_bytes(&self.0).verifying_key().to_bytes())
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        parse_hex(hex).map(SecretKey)
+    }
+}
+
+/// The public Ed25519 key of the market operator, serialized as 64 hex digits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PublicKey(pub [u8; 32]);
+
+hex_bytes!(PublicKey);
+
+/// An Ed25519 signature, serialized as 128 hex digits.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Signature(pub [u8; 64]);
+
+hex_bytes!(Signature);
+
+/// A market output together with the signature of the market operator.
+///
+/// The signature covers the [digest](MarketInput::digest) of the market input and the
+/// [canonical encoding](MarketOutput::canonical_bytes) of the market output, so the result is
+/// bound to exactly the orders it was computed from. Participants can check it offline with the
+/// public key of the operator.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 2.0, "price_euro_per_kwh": 0.1},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.3}
+/// ]}"#).unwrap();
+/// let secret_key: SecretKey =
+///     "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60".parse().unwrap();
+/// let public_key = secret_key.public_key();
+///
+/// let signed = SignedMarketOutput::sign(&input, pay_as_bid_matching(&input), &secret_key);
+/// assert_eq!(signed.verify(&input, &public_key), Ok(()));
+///
+/// // The output doesn't belong to other orders
+/// let mut other_input = input.clone();
+/// other_input.orders[1].price_euro_per_kwh = 0.4;
+/// assert!(signed.verify(&other_input, &public_key).is_err());
+/// ```
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SignedMarketOutput {
+    pub market_output: MarketOutput,
+    /// The digest of the market input that the output was computed from
+    pub input_digest: Digest,
+    pub signature: Signature,
+}
+
+impl SignedMarketOutput {
+    /// Sign a market output that was computed from the market input.
+    pub fn sign(input: &MarketInput, market_output: MarketOutput, secret_key: &SecretKey) -> Self {
+        let input_digest = input.digest();
+        let message = signed_message(&input_digest, &market_output);
+        let signature = SigningKey::from_bytes(&secret_key.0).sign(&message);
+        SignedMarketOutput {
+            market_output,
+            input_digest,
+            signature: Signature(signature.to_bytes()),
+        }
+    }
+
+    /// Check that the output was computed from the market input and signed with the secret key
+    /// that belongs to the public key.
+    pub fn verify(&self, input: &MarketInput, public_key: &PublicKey) -> Result<(), String> {
+        if input.digest() != self.input_digest {
+            return Err("the market output was computed from different orders".into());
+        }
+        let verifying_key = VerifyingKey::from_bytes(&public_key.0)
+            .map_err(|_| "the public key is not a valid Ed25519 key")?;
+        let signature = ed25519_dalek::Signature::from_bytes(&self.signature.0);
+        let message = signed_message(&self.input_digest, &self.market_output);
+        verifying_key
+            .verify_strict(&message, &signature)
+            .map_err(|_| "the signature of the market output is not valid".into())
+    }
+}
+
+fn signed_message(input_digest: &Digest, market_output: &MarketOutput) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend_from_slice(&input_digest.0);
+    message.extend_from_slice(&market_output.canonical_bytes());
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Match, OrderType};
+    use alloc::string::ToString;
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey([byte; 32])
+    }
+
+    fn signed() -> (MarketInput, SignedMarketOutput) {
+        let input: MarketInput = serde_json::from_str(
+            r#"{"orders": [
+                {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a",
+                 "cluster_index": 0, "energy_kwh": 2.0, "price_euro_per_kwh": 0.1},
+                {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b",
+                 "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.3}
+            ]}"#,
+        )
+        .unwrap();
+        let output = crate::pay_as_bid_matching(&input);
+        let signed = SignedMarketOutput::sign(&input, output, &secret_key(1));
+        (input, signed)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let (input, signed) = signed();
+        let public_key = secret_key(1).public_key();
+        assert_eq!(signed.verify(&input, &public_key), Ok(()));
+
+        // The signature survives a round trip through JSON
+        let json = serde_json::to_string(&signed).unwrap();
+        let parsed: SignedMarketOutput = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.verify(&input, &public_key), Ok(()));
+        assert_eq!(public_key.to_string().parse(), Ok(public_key));
+
+        // Another key
+        assert!(signed.verify(&input, &secret_key(2).public_key()).is_err());
+
+        // Changed matches
+        let mut changed = signed.clone();
+        changed.market_output.matches[0].price_euro_per_kwh = 0.2;
+        assert!(changed.verify(&input, &public_key).is_err());
+        let mut changed = signed.clone();
+        changed.market_output.matches.push(Match {
+            bid_id: 2,
+            ask_id: 1,
+            energy_kwh: 1.0,
+            price_euro_per_kwh: 0.3,
+            bid_segment: None,
+            ask_segment: None,
+            stage: None,
+            energy_source: None,
+        });
+        assert!(changed.verify(&input, &public_key).is_err());
+
+        // Other orders, even with a matching digest in the signed output
+        let mut other_input = input.clone();
+        other_input.orders[0].order_type = OrderType::Bid;
+        assert!(signed.verify(&other_input, &public_key).is_err());
+        let mut changed = signed;
+        changed.input_digest = other_input.digest();
+        assert!(changed.verify(&other_input, &public_key).is_err());
+    }
+}
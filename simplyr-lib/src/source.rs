@@ -0,0 +1,323 @@
+//! Energy sources of asks and source preferences of bids.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::curve::order_steps;
+use crate::{
+    min_match_kwh, round_energy_value, with_matching_config, MarketInput, MarketOutput, Match,
+    MatchingConfig, Order, OrderType, ENERGY_EPS,
+};
+
+/// The source of the energy of an ask.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EnergySource {
+    Solar,
+    Wind,
+    /// Energy from the public grid with an unknown mix of sources
+    GridMix,
+}
+
+impl EnergySource {
+    /// Return `true` for solar and wind energy.
+    pub fn is_renewable(self) -> bool {
+        matches!(self, EnergySource::Solar | EnergySource::Wind)
+    }
+}
+
+/// Where the energy of an ask comes from, e.g. for guarantees of origin.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub source: EnergySource,
+    /// The CO2 intensity in g / kWh
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub co2_g_per_kwh: Option<f64>,
+}
+
+/// The energy a bid wants to buy. Asks without a [`SourceInfo`] don't meet any restriction.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SourcePreference {
+    /// Only asks with one of these sources are accepted, all sources if it is empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted_sources: Vec<EnergySource>,
+    /// Only asks with at most this CO2 intensity (in g / kWh) are accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_co2_g_per_kwh: Option<f64>,
+    /// The bid pays up to this much more (in € / kWh) for renewable energy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub renewable_premium_euro_per_kwh: Option<f64>,
+}
+
+impl SourcePreference {
+    /// Return `true` if energy from the source may be bought.
+    pub fn accepts(&self, source: Option<&SourceInfo>) -> bool {
+        if !self.accepted_sources.is_empty()
+            && !matches!(source, Some(info) if self.accepted_sources.contains(&info.source))
+        {
+            return false;
+        }
+        match self.max_co2_g_per_kwh {
+            Some(max_co2) => {
+                matches!(source.and_then(|info| info.co2_g_per_kwh), Some(co2) if co2 <= max_co2)
+            }
+            None => true,
+        }
+    }
+
+    /// Return the premium in € / kWh that the bid pays for energy from the source.
+    pub fn premium_euro_per_kwh(&self, source: Option<&SourceInfo>) -> f64 {
+        match (self.renewable_premium_euro_per_kwh, source) {
+            (Some(premium), Some(info))
+                if info.source.is_renewable() && premium.is_finite() && premium > 0.0 =>
+            {
+                premium
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// Return the premium in € / kWh that the bid pays for the energy of the ask.
+pub(crate) fn renewable_premium(bid: &Order, ask: &Order) -> f64 {
+    bid.source_preference.as_ref().map_or(0.0, |preference| {
+        preference.premium_euro_per_kwh(ask.energy_source.as_ref())
+    })
+}
+
+/// Pay-as-Bid matching that honors the [`SourcePreference`]s of the bids.
+///
+/// The bids are matched one after another, highest price first. Every bid only gets energy from
+/// asks it accepts, and the asks are sorted by their price minus the renewable premium of the
+/// bid, so a bid with a premium buys renewable energy even if other energy is slightly cheaper.
+/// Forbidden counterparties (see [`CounterpartyRules`](crate::CounterpartyRules)) are never
+/// matched.
+/// The price of a match is the price of the bid plus the premium (the premium is not affected by
+/// the [`PriceLimits`](crate::PriceLimits)). Every match records the [`SourceInfo`] of its ask in
+/// [`Match::energy_source`].
+///
+/// [`CurveOrder`](crate::CurveOrder)s have no source attributes. The allocation rule is not used.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "gas", "cluster_index": 0,
+///      "energy_kwh": 5.0, "price_euro_per_kwh": 0.2,
+///      "energy_source": {"source": "grid_mix", "co2_g_per_kwh": 400.0}},
+///     {"id": 2, "order_type": "ask", "time_slot": "t", "actor_id": "pv", "cluster_index": 0,
+///      "energy_kwh": 5.0, "price_euro_per_kwh": 0.25,
+///      "energy_source": {"source": "solar", "co2_g_per_kwh": 40.0}},
+///     {"id": 3, "order_type": "bid", "time_slot": "t", "actor_id": "home", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.3,
+///      "source_preference": {"renewable_premium_euro_per_kwh": 0.1}}
+/// ]}"#).unwrap();
+/// let output = preference_matching(&input);
+/// let m = &output.matches[0];
+/// assert_eq!((m.ask_id, m.price_euro_per_kwh), (2, 0.4));
+/// assert_eq!(m.energy_source.as_ref().unwrap().source, EnergySource::Solar);
+/// ```
+pub fn preference_matching(input: &MarketInput) -> MarketOutput {
+    preference_matching_with_config(input, &MatchingConfig::default())
+}
+
+/// Preference-aware matching with explicit settings. See [`preference_matching`].
+pub fn preference_matching_with_config(
+    input: &MarketInput,
+    config: &MatchingConfig,
+) -> MarketOutput {
+    with_matching_config(
+        input,
+        config,
+        |input| source_aware_matching(input, config),
+        |output| output,
+    )
+}
+
+/// Preference-aware matching without the repetitions for fill constraints and the price limits.
+fn source_aware_matching(input: &MarketInput, config: &MatchingConfig) -> MarketOutput {
+    let mut bids = vec![];
+    let mut asks = vec![];
+    for (order, segment) in order_steps(input)
+        .into_iter()
+        .filter(|(order, _)| order.energy_kwh.is_finite() && order.price_euro_per_kwh.is_finite())
+    {
+        match order.order_type {
+            OrderType::Bid => bids.push((order, segment)),
+            OrderType::Ask => asks.push((order, segment)),
+        }
+    }
+    bids.sort_by(|(a, _), (b, _)| {
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .reverse()
+            .then_with(|| config.tie_break.compare(a, b))
+    });
+    asks.sort_by(|(a, _), (b, _)| {
+        a.price_euro_per_kwh
+            .total_cmp(&b.price_euro_per_kwh)
+            .then_with(|| config.tie_break.compare(a, b))
+    });
+
+    let mut matches = vec![];
+    for (bid, bid_segment) in &bids {
+        let mut remaining_energy = bid.energy_kwh;
+        if remaining_energy < ENERGY_EPS {
+            continue;
+        }
+        let preference = bid.source_preference.clone().unwrap_or_default();
+        // The accepted asks, cheapest for this bid first. The sort is stable, so asks with equal
+        // prices stay in the order of the tie-break rule.
+        let premium = |ask: &Order| preference.premium_euro_per_kwh(ask.energy_source.as_ref());
+        let mut candidates: Vec<usize> = (0..asks.len())
+            .filter(|&ask_idx| {
+                let ask = &asks[ask_idx].0;
+                preference.accepts(ask.energy_source.as_ref()) && config.allows(bid, ask)
+            })
+            .collect();
+        candidates.sort_by(|&a, &b| {
+            let (a, b) = (&asks[a].0, &asks[b].0);
+            (a.price_euro_per_kwh - premium(a)).total_cmp(&(b.price_euro_per_kwh - premium(b)))
+        });
+
+        for ask_idx in candidates {
+            let (ask, ask_segment) = &mut asks[ask_idx];
+            let limit = bid.price_euro_per_kwh + renewable_premium(bid, ask);
+            if ask.price_euro_per_kwh > limit {
+                // All other asks are even more expensive for this bid
+                break;
+            }
+            if ask.energy_kwh <= ENERGY_EPS {
+                continue;
+            }
+            let matched_energy = ask.energy_kwh.min(remaining_energy);
+            // Try the next ask if the match would be too small for one of the orders
+            if matched_energy < min_match_kwh(bid).max(min_match_kwh(ask)) {
+                continue;
+            }
+            matches.push(Match {
+                bid_id: bid.id,
+                ask_id: ask.id,
+                energy_kwh: round_energy_value(matched_energy),
+                price_euro_per_kwh: limit,
+                bid_segment: *bid_segment,
+                ask_segment: *ask_segment,
+                stage: None,
+                energy_source: ask.energy_source.clone(),
+            });
+            ask.energy_kwh -= matched_energy;
+            remaining_energy -= matched_energy;
+            if remaining_energy < ENERGY_EPS {
+                break;
+            }
+        }
+    }
+
+    MarketOutput {
+        matches,
+        residual_book: vec![],
+        diagnostics: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, price: f64, source: Option<EnergySource>) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor".to_string() + &id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 2.0,
+            price_euro_per_kwh: price,
+            energy_source: source.map(|source| SourceInfo {
+                source,
+                co2_g_per_kwh: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_accepts() {
+        let solar = SourceInfo {
+            source: EnergySource::Solar,
+            co2_g_per_kwh: Some(40.0),
+        };
+        let grid_mix = SourceInfo {
+            source: EnergySource::GridMix,
+            co2_g_per_kwh: None,
+        };
+        let any = SourcePreference::default();
+        assert!(any.accepts(None) && any.accepts(Some(&grid_mix)));
+
+        let renewable_only = SourcePreference {
+            accepted_sources: vec![EnergySource::Solar, EnergySource::Wind],
+            ..Default::default()
+        };
+        assert!(renewable_only.accepts(Some(&solar)));
+        assert!(!renewable_only.accepts(Some(&grid_mix)));
+        assert!(!renewable_only.accepts(None));
+
+        // Unknown CO2 intensities are not accepted
+        let low_carbon = SourcePreference {
+            max_co2_g_per_kwh: Some(50.0),
+            ..Default::default()
+        };
+        assert!(low_carbon.accepts(Some(&solar)));
+        assert!(!low_carbon.accepts(Some(&grid_mix)));
+
+        let premium = SourcePreference {
+            renewable_premium_euro_per_kwh: Some(0.05),
+            ..Default::default()
+        };
+        assert_eq!(premium.premium_euro_per_kwh(Some(&solar)), 0.05);
+        assert_eq!(premium.premium_euro_per_kwh(Some(&grid_mix)), 0.0);
+    }
+
+    #[test]
+    fn test_preference_matching() {
+        let mut renewable_bid = order(3, OrderType::Bid, 0.3, None);
+        renewable_bid.source_preference = Some(SourcePreference {
+            accepted_sources: vec![EnergySource::Solar, EnergySource::Wind],
+            ..Default::default()
+        });
+        let mut input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 0.1, Some(EnergySource::GridMix)),
+                order(2, OrderType::Ask, 0.2, Some(EnergySource::Wind)),
+                renewable_bid,
+                order(4, OrderType::Bid, 0.5, None),
+                order(5, OrderType::Bid, 0.4, None),
+            ],
+            curve_orders: vec![],
+        };
+        input.orders[0].energy_kwh = 6.0;
+        let output = preference_matching(&input);
+        let matches: Vec<_> = output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id, m.energy_kwh, m.energy_source.clone()))
+            .collect();
+        let source = |source| {
+            Some(SourceInfo {
+                source,
+                co2_g_per_kwh: None,
+            })
+        };
+        assert_eq!(
+            matches,
+            vec![
+                (4, 1, 2.0, source(EnergySource::GridMix)),
+                (5, 1, 2.0, source(EnergySource::GridMix)),
+                // The remaining grid mix is cheaper, but it is not accepted
+                (3, 2, 2.0, source(EnergySource::Wind)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Closed-loop simulation of a battery that trades in consecutive time slots.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MarketInput, MarketOutput, Order, OrderType, ENERGY_EPS};
+
+/// A battery that buys energy to charge and sells energy when it discharges.
+///
+/// Power limits and energy values are measured at the grid connection: charging with 1 kWh
+/// from the grid stores `charge_efficiency` kWh, discharging 1 kWh into the grid takes
+/// `1 / discharge_efficiency` kWh from the battery.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Battery {
+    pub actor_id: String,
+    pub cluster_index: Option<usize>,
+    /// The usable capacity in kWh
+    pub capacity_kwh: f64,
+    /// The highest charging power in kW
+    pub max_charge_kw: f64,
+    /// The highest discharging power in kW
+    pub max_discharge_kw: f64,
+    /// The share of the bought energy that is stored (between 0 and 1)
+    pub charge_efficiency: f64,
+    /// The share of the stored energy that can be sold (between 0 and 1)
+    pub discharge_efficiency: f64,
+    /// The stored energy in kWh at the beginning of the simulation
+    pub state_of_charge_kwh: f64,
+    /// The battery charges at prices up to this limit (€ / kWh)
+    pub buy_price_euro_per_kwh: f64,
+    /// The battery discharges at prices of at least this limit (€ / kWh)
+    pub sell_price_euro_per_kwh: f64,
+}
+
+impl Battery {
+    /// Check that all values are finite and within their ranges.
+    pub fn validate(&self) -> Result<(), String> {
+        let values = [
+            self.capacity_kwh,
+            self.max_charge_kw,
+            self.max_discharge_kw,
+            self.charge_efficiency,
+            self.discharge_efficiency,
+            self.state_of_charge_kwh,
+            self.buy_price_euro_per_kwh,
+            self.sell_price_euro_per_kwh,
+        ];
+        if !values.iter().all(|value| value.is_finite()) {
+            return Err("all battery values have to be finite".into());
+        }
+        if self.capacity_kwh < 0.0 || self.max_charge_kw < 0.0 || self.max_discharge_kw < 0.0 {
+            return Err("capacity and power limits of the battery may not be negative".into());
+        }
+        for efficiency in [self.charge_efficiency, self.discharge_efficiency] {
+            if !(efficiency > 0.0 && efficiency <= 1.0) {
+                return Err(format!(
+                    "efficiency {efficiency} has to be greater than 0 and at most 1"
+                ));
+            }
+        }
+        if !(0.0..=self.capacity_kwh).contains(&self.state_of_charge_kwh) {
+            return Err("the state of charge has to be between 0 and the capacity".into());
+        }
+        // Otherwise the bid and the ask of the battery would be matched with each other
+        if self.buy_price_euro_per_kwh >= self.sell_price_euro_per_kwh {
+            return Err("the buy price of the battery has to be below its sell price".into());
+        }
+        Ok(())
+    }
+
+    /// Return the bid and the ask that the battery submits in a time slot of the given duration.
+    ///
+    /// Orders with less than [`ENERGY_EPS`] are left out.
+    pub fn orders(&self, time_slot: &str, slot_duration_h: f64, first_id: u64) -> Vec<Order> {
+        let free_kwh = (self.capacity_kwh - self.state_of_charge_kwh) / self.charge_efficiency;
+        let charge_kwh = (self.max_charge_kw * slot_duration_h).min(free_kwh);
+        let stored_kwh = self.state_of_charge_kwh * self.discharge_efficiency;
+        let discharge_kwh = (self.max_discharge_kw * slot_duration_h).min(stored_kwh);
+
+        let order = |id, order_type, energy_kwh, price_euro_per_kwh| Order {
+            id,
+            order_type,
+            time_slot: time_slot.into(),
+            actor_id: self.actor_id.clone(),
+            cluster_index: self.cluster_index,
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        };
+        let mut orders = vec![];
+        if charge_kwh >= ENERGY_EPS {
+            orders.push(order(
+                first_id,
+                OrderType::Bid,
+                charge_kwh,
+                self.buy_price_euro_per_kwh,
+            ));
+        }
+        if discharge_kwh >= ENERGY_EPS {
+            orders.push(order(
+                first_id + 1,
+                OrderType::Ask,
+                discharge_kwh,
+                self.sell_price_euro_per_kwh,
+            ));
+        }
+        orders
+    }
+}
+
+/// What happened to the battery in one time slot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatterySlot {
+    pub time_slot: String,
+    /// The orders of the battery in this time slot
+    pub orders: Vec<Order>,
+    /// The energy in kWh the battery bought
+    pub charged_kwh: f64,
+    /// The energy in kWh the battery sold
+    pub discharged_kwh: f64,
+    /// The price paid for the bought energy in €
+    pub cost_euro: f64,
+    /// The price received for the sold energy in €
+    pub revenue_euro: f64,
+    /// The stored energy in kWh at the end of the time slot
+    pub state_of_charge_kwh: f64,
+    /// The result of the matching algorithm, including the orders of the battery
+    pub market_output: MarketOutput,
+}
+
+/// The result of [`simulate_battery`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatterySimulation {
+    pub slots: Vec<BatterySlot>,
+    /// The stored energy in kWh at the end of the last time slot
+    pub final_state_of_charge_kwh: f64,
+    /// Revenue minus cost of all time slots in €
+    pub profit_euro: f64,
+}
+
+/// Simulate a battery that trades in consecutive time slots.
+///
+/// For every market input in `slots`, the battery submits a bid to charge and an ask to
+/// discharge (see [`Battery::orders`]), the orders are matched with `matching` and the state of
+/// charge is updated from the matches of the battery before the next slot is simulated. The
+/// battery orders get the IDs following the highest ID of the time slot, the time slot is taken
+/// from the first order. Market inputs without orders are skipped.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let battery = Battery {
+///     actor_id: "battery".to_string(),
+///     cluster_index: Some(0),
+///     capacity_kwh: 10.0,
+///     max_charge_kw: 8.0,
+///     max_discharge_kw: 8.0,
+///     charge_efficiency: 1.0,
+///     discharge_efficiency: 0.5,
+///     state_of_charge_kwh: 0.0,
+///     buy_price_euro_per_kwh: 0.1,
+///     sell_price_euro_per_kwh: 0.3,
+/// };
+/// let slot = |time_slot, order_type, price| -> MarketInput {
+///     serde_json::from_value(serde_json::json!({"orders": [{
+///         "id": 1, "order_type": order_type, "time_slot": time_slot, "actor_id": "a",
+///         "cluster_index": 0, "energy_kwh": 100.0, "price_euro_per_kwh": price
+///     }]}))
+///     .unwrap()
+/// };
+/// // Cheap solar power at noon, expensive demand in the evening
+/// let slots = [slot("12:00", "ask", 0.05), slot("18:00", "bid", 0.4)];
+/// let simulation = simulate_battery(&battery, 1.0, &slots, pay_as_bid_matching, |o| o).unwrap();
+/// assert_eq!(simulation.slots[0].charged_kwh, 8.0);
+/// assert_eq!(simulation.slots[0].state_of_charge_kwh, 8.0);
+/// // Half of the stored energy is lost when discharging
+/// assert_eq!(simulation.slots[1].discharged_kwh, 4.0);
+/// assert_eq!(simulation.final_state_of_charge_kwh, 0.0);
+/// ```
+pub fn simulate_battery<T, F, M>(
+    battery: &Battery,
+    slot_duration_h: f64,
+    slots: &[MarketInput],
+    matching: F,
+    market_output: M,
+) -> Result<BatterySimulation, String>
+where
+    F: Fn(&MarketInput) -> T,
+    M: Fn(&mut T) -> &mut MarketOutput,
+{
+    battery.validate()?;
+    if !(slot_duration_h.is_finite() && slot_duration_h > 0.0) {
+        return Err("the duration of a time slot has to be positive".into());
+    }
+
+    let mut battery = battery.clone();
+    let mut simulation = BatterySimulation {
+        slots: vec![],
+        final_state_of_charge_kwh: battery.state_of_charge_kwh,
+        profit_euro: 0.0,
+    };
+    for slot in slots {
+        let time_slot = match slot.orders.first() {
+            Some(order) => order.time_slot.clone(),
+            None => continue,
+        };
+        let first_id = slot
+            .orders
+            .iter()
+            .map(|order| order.id)
+            .chain(slot.curve_orders.iter().map(|curve_order| curve_order.id))
+            .max()
+            .unwrap_or(0)
+            .checked_add(1)
+            .ok_or("no order IDs left for the battery")?;
+        let orders = battery.orders(&time_slot, slot_duration_h, first_id);
+        let mut input = slot.clone();
+        input.orders.extend(orders.iter().cloned());
+
+        let mut output = matching(&input);
+        let market_output = market_output(&mut output).clone();
+        let (mut charged_kwh, mut discharged_kwh, mut cost_euro, mut revenue_euro) =
+            (0.0, 0.0, 0.0, 0.0);
+        for m in &market_output.matches {
+            for order in &orders {
+                match order.order_type {
+                    OrderType::Bid if m.bid_id == order.id => {
+                        charged_kwh += m.energy_kwh;
+                        cost_euro += m.energy_kwh * m.price_euro_per_kwh;
+                    }
+                    OrderType::Ask if m.ask_id == order.id => {
+                        discharged_kwh += m.energy_kwh;
+                        revenue_euro += m.energy_kwh * m.price_euro_per_kwh;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // Matched energy is rounded, so the state of charge could leave its range slightly
+        let state_of_charge_kwh = battery.state_of_charge_kwh
+            + charged_kwh * battery.charge_efficiency
+            - discharged_kwh / battery.discharge_efficiency;
+        battery.state_of_charge_kwh = state_of_charge_kwh.clamp(0.0, battery.capacity_kwh);
+
+        simulation.profit_euro += revenue_euro - cost_euro;
+        simulation.slots.push(BatterySlot {
+            time_slot,
+            orders,
+            charged_kwh,
+            discharged_kwh,
+            cost_euro,
+            revenue_euro,
+            state_of_charge_kwh: battery.state_of_charge_kwh,
+            market_output,
+        });
+    }
+    simulation.final_state_of_charge_kwh = battery.state_of_charge_kwh;
+    Ok(simulation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pay_as_bid_matching;
+    use alloc::string::ToString;
+
+    fn battery() -> Battery {
+        Battery {
+            actor_id: "battery".to_string(),
+            cluster_index: Some(0),
+            capacity_kwh: 10.0,
+            max_charge_kw: 4.0,
+            max_discharge_kw: 2.0,
+            charge_efficiency: 0.9,
+            discharge_efficiency: 0.8,
+            state_of_charge_kwh: 1.0,
+            buy_price_euro_per_kwh: 0.1,
+            sell_price_euro_per_kwh: 0.3,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(battery().validate().is_ok());
+        let invalid = [
+            Battery {
+                state_of_charge_kwh: 11.0,
+                ..battery()
+            },
+            Battery {
+                charge_efficiency: 0.0,
+                ..battery()
+            },
+            Battery {
+                sell_price_euro_per_kwh: 0.1,
+                ..battery()
+            },
+            Battery {
+                max_charge_kw: f64::NAN,
+                ..battery()
+            },
+        ];
+        for battery in invalid {
+            assert!(battery.validate().is_err(), "{battery:?}");
+        }
+    }
+
+    #[test]
+    fn test_orders() {
+        // Limited by the power in a slot of half an hour
+        let orders = battery().orders("t", 0.5, 7);
+        assert_eq!(orders.len(), 2);
+        assert_eq!((orders[0].id, orders[0].energy_kwh), (7, 2.0));
+        assert_eq!(orders[0].order_type, OrderType::Bid);
+        // Limited by the stored energy
+        assert_eq!((orders[1].id, orders[1].energy_kwh), (8, 0.8));
+
+        // An empty battery does not sell
+        let empty = Battery {
+            state_of_charge_kwh: 0.0,
+            ..battery()
+        };
+        assert_eq!(empty.orders("t", 1.0, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_simulation() {
+        let ask = Order {
+            id: 1,
+            order_type: OrderType::Ask,
+            time_slot: "08:00".to_string(),
+            actor_id: "pv".to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 3.0,
+            price_euro_per_kwh: 0.05,
+            ..Default::default()
+        };
+        let bid = Order {
+            id: 1,
+            order_type: OrderType::Bid,
+            time_slot: "09:00".to_string(),
+            actor_id: "home".to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 5.0,
+            price_euro_per_kwh: 0.4,
+            ..Default::default()
+        };
+        let slots = [
+            MarketInput {
+                orders: vec![ask],
+                curve_orders: vec![],
+            },
+            MarketInput {
+                orders: vec![],
+                curve_orders: vec![],
+            },
+            MarketInput {
+                orders: vec![bid],
+                curve_orders: vec![],
+            },
+        ];
+        let simulation =
+            simulate_battery(&battery(), 1.0, &slots, pay_as_bid_matching, |o| o).unwrap();
+        // The empty slot is skipped
+        assert_eq!(simulation.slots.len(), 2);
+
+        // Buys all 3 kWh of the PV system at its bid price and stores 2.7 kWh
+        let first = &simulation.slots[0];
+        assert_eq!(first.orders[0].id, 2);
+        assert_eq!(first.charged_kwh, 3.0);
+        assert!((first.state_of_charge_kwh - 3.7).abs() < 1e-9);
+
+        // Sells 2 kWh (limited by the power) and loses 0.5 kWh
+        let second = &simulation.slots[1];
+        assert_eq!(second.discharged_kwh, 2.0);
+        assert!((second.state_of_charge_kwh - 1.2).abs() < 1e-9);
+        assert!((simulation.profit_euro - (2.0 * 0.4 - 3.0 * 0.1)).abs() < 1e-9);
+        assert_eq!(
+            simulation.final_state_of_charge_kwh,
+            second.state_of_charge_kwh
+        );
+    }
+}
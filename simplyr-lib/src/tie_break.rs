@@ -0,0 +1,49 @@
+//! Deterministic ordering of orders that have the same price.
+
+use core::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::rng::SplitMix64;
+use crate::Order;
+
+/// Decides which of two orders with the same price is matched first.
+///
+/// All algorithms sort orders by price first and use the tie-break rule for orders with equal
+/// prices. Every rule falls back to the order ID, so the outcome does not depend on the order of
+/// the orders in the [`MarketInput`](crate::MarketInput) as long as the order IDs are unique.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TieBreak {
+    /// Orders with an earlier `submitted_at` come first. Orders without a submission time come
+    /// after all orders with one.
+    SubmissionTime,
+    /// Orders with a lower ID come first.
+    #[default]
+    OrderId,
+    /// Orders are shuffled pseudo-randomly. The position of an order only depends on the seed
+    /// and its ID, so the same seed always gives the same result.
+    Random { seed: u64 },
+}
+
+impl TieBreak {
+    /// Compare two orders with equal price. `Ordering::Less` means `a` is matched first.
+    pub fn compare(&self, a: &Order, b: &Order) -> Ordering {
+        let by_rule = match *self {
+            TieBreak::SubmissionTime => match (a.submitted_at, b.submitted_at) {
+                (Some(a_time), Some(b_time)) => a_time.cmp(&b_time),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+            TieBreak::OrderId => Ordering::Equal,
+            TieBreak::Random { seed } => random_key(seed, a.id).cmp(&random_key(seed, b.id)),
+        };
+        by_rule.then(a.id.cmp(&b.id))
+    }
+}
+
+fn random_key(seed: u64, order_id: u64) -> u64 {
+    let mut rng = SplitMix64::new(seed ^ SplitMix64::new(order_id).next_u64());
+    rng.next_u64()
+}
@@ -0,0 +1,522 @@
+//! Welfare-maximizing allocation with Vickrey-Clarke-Groves (VCG) payments.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::with_matching_config;
+use crate::{
+    energy_units, round_energy_value, GridFeeMatrix, MarketInput, MarketOutput, Match,
+    MatchingConfig, Order, OrderType, SelfTradePrevention, ENERGY_EPS,
+};
+
+/// Orders are capped at this number of energy quanta, so no sum of flows overflows and every
+/// amount of quanta can be represented exactly by a `f64`.
+const MAX_QUANTA: u64 = 1 << 53;
+
+/// Paths that improve the welfare by less than this (in € / kWh) are not used.
+const COST_EPS: f64 = 1e-12;
+
+/// The result of [`vcg_matching`].
+///
+/// The matches are serialized next to the payments, so the JSON output can also be read as a
+/// [`MarketOutput`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VcgOutput {
+    #[serde(flatten)]
+    pub market_output: MarketOutput,
+    /// The maximal social welfare in €: value of the bids minus cost of the asks minus grid fees
+    pub social_welfare_euro: f64,
+    /// The grid fees of all matches in €
+    pub grid_fees_euro: f64,
+    /// The VCG payments, one entry per actor sorted by actor ID
+    pub payments: Vec<VcgPayment>,
+    /// The sum of all payments minus the grid fees in €. A negative value is a deficit that has
+    /// to be covered by the market operator.
+    pub budget_balance_euro: f64,
+}
+
+/// The VCG payment of one actor.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VcgPayment {
+    pub actor_id: String,
+    /// The value of the matched bids minus the cost of the matched asks in €, according to the
+    /// prices of the orders
+    pub value_euro: f64,
+    /// The amount the actor pays in €. A negative value is paid to the actor.
+    pub payment_euro: f64,
+    /// The value minus the payment in €. It equals the welfare the actor adds to the market, so it
+    /// is never negative.
+    pub utility_euro: f64,
+}
+
+/// An edge of the flow network. Every edge is stored next to its reverse edge, so the reverse
+/// edge of edge `i` is `i ^ 1`.
+struct Edge {
+    to: usize,
+    /// Remaining capacity in energy quanta
+    capacity: u64,
+    /// Cost in € / kWh
+    cost: f64,
+}
+
+/// The flow network of a transportation problem: source → bids → asks → sink.
+struct FlowNetwork {
+    edges: Vec<Edge>,
+    /// Indices of the outgoing edges per node
+    adjacency: Vec<Vec<usize>>,
+}
+
+impl FlowNetwork {
+    fn new(num_nodes: usize) -> Self {
+        FlowNetwork {
+            edges: vec![],
+            adjacency: vec![vec![]; num_nodes],
+        }
+    }
+
+    /// Add an edge and its reverse edge and return the index of the edge.
+    fn add_edge(&mut self, from: usize, to: usize, capacity: u64, cost: f64) -> usize {
+        let idx = self.edges.len();
+        self.edges.push(Edge { to, capacity, cost });
+        self.edges.push(Edge {
+            to: from,
+            capacity: 0,
+            cost: -cost,
+        });
+        self.adjacency[from].push(idx);
+        self.adjacency[to].push(idx + 1);
+        idx
+    }
+
+    /// Send flow along shortest (cheapest) paths as long as they have a negative cost.
+    ///
+    /// This is the successive shortest path algorithm with Bellman-Ford, which handles the
+    /// negative costs of the edges. The result is a flow with minimal cost among all flows of
+    /// any size.
+    fn min_cost_flow(&mut self, source: usize, sink: usize) {
+        let num_nodes = self.adjacency.len();
+        loop {
+            let mut dist = vec![f64::INFINITY; num_nodes];
+            let mut predecessor: Vec<Option<usize>> = vec![None; num_nodes];
+            dist[source] = 0.0;
+            // At most num_nodes - 1 rounds are needed without negative cycles
+            for _ in 1..num_nodes {
+                let mut changed = false;
+                for node in 0..num_nodes {
+                    if dist[node] == f64::INFINITY {
+                        continue;
+                    }
+                    for &edge_idx in &self.adjacency[node] {
+                        let edge = &self.edges[edge_idx];
+                        let new_dist = dist[node] + edge.cost;
+                        if edge.capacity > 0 && new_dist < dist[edge.to] - COST_EPS {
+                            dist[edge.to] = new_dist;
+                            predecessor[edge.to] = Some(edge_idx);
+                            changed = true;
+                        }
+                    }
+                }
+                if !changed {
+                    break;
+                }
+            }
+            // Also stops if the costs are not finite
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            if !(dist[sink] < -COST_EPS) {
+                return;
+            }
+
+            // Collect the path and its bottleneck
+            let mut path = vec![];
+            let mut node = sink;
+            while node != source {
+                let edge_idx = match predecessor[node] {
+                    Some(edge_idx) => edge_idx,
+                    None => return,
+                };
+                path.push(edge_idx);
+                node = self.edges[edge_idx ^ 1].to;
+                // A path never has more edges than there are nodes
+                if path.len() > num_nodes {
+                    return;
+                }
+            }
+            let bottleneck = path
+                .iter()
+                .map(|&edge_idx| self.edges[edge_idx].capacity)
+                .min()
+                .unwrap_or(0);
+            if bottleneck == 0 {
+                return;
+            }
+            for edge_idx in path {
+                self.edges[edge_idx].capacity -= bottleneck;
+                self.edges[edge_idx ^ 1].capacity += bottleneck;
+            }
+        }
+    }
+
+    /// The flow on an edge in energy quanta.
+    fn flow(&self, edge_idx: usize) -> u64 {
+        self.edges[edge_idx ^ 1].capacity
+    }
+}
+
+/// A match of the welfare-maximizing allocation in energy quanta.
+struct Allocation<'a> {
+    bid: &'a Order,
+    ask: &'a Order,
+    quanta: u64,
+    grid_fee_euro_per_kwh: f64,
+}
+
+/// Find the allocation with maximal social welfare among the given orders. Pairs of orders that
+/// the self-trade prevention doesn't allow are not matched.
+fn max_welfare_allocation<'a>(
+    orders: &[&'a Order],
+    grid_fee_matrix: &GridFeeMatrix,
+    self_trade_prevention: SelfTradePrevention,
+) -> Vec<Allocation<'a>> {
+    let sorted = |order_type: OrderType| {
+        let mut sorted: Vec<&Order> = orders
+            .iter()
+            .copied()
+            .filter(|order| order.order_type == order_type)
+            .collect();
+        // Cheapest ask and highest bid first, so ties are resolved like in the other algorithms
+        sorted.sort_by(|a, b| {
+            let by_price = a.price_euro_per_kwh.total_cmp(&b.price_euro_per_kwh);
+            match order_type {
+                OrderType::Bid => by_price.reverse(),
+                OrderType::Ask => by_price,
+            }
+            .then(a.id.cmp(&b.id))
+        });
+        sorted
+    };
+    let bids = sorted(OrderType::Bid);
+    let asks = sorted(OrderType::Ask);
+    let quanta = |order: &Order| energy_units(order.energy_kwh, ENERGY_EPS).min(MAX_QUANTA);
+
+    // Node 0 is the source, followed by the bids, the asks and the sink
+    let source = 0;
+    let sink = bids.len() + asks.len() + 1;
+    let mut network = FlowNetwork::new(sink + 1);
+    for (bid_idx, bid) in bids.iter().enumerate() {
+        network.add_edge(source, 1 + bid_idx, quanta(bid), 0.0);
+    }
+    for (ask_idx, ask) in asks.iter().enumerate() {
+        network.add_edge(1 + bids.len() + ask_idx, sink, quanta(ask), 0.0);
+    }
+    let mut pairs = vec![];
+    for (bid_idx, bid) in bids.iter().enumerate() {
+        for (ask_idx, ask) in asks.iter().enumerate() {
+            let grid_fee = grid_fee(bid, ask, grid_fee_matrix);
+            let welfare = bid.price_euro_per_kwh - ask.price_euro_per_kwh - grid_fee;
+            // Pairs without gains from trade are never part of an optimal allocation
+            if welfare > 0.0 && !self_trade_prevention.prevents(bid, ask) {
+                let capacity = quanta(bid).min(quanta(ask));
+                let edge_idx =
+                    network.add_edge(1 + bid_idx, 1 + bids.len() + ask_idx, capacity, -welfare);
+                pairs.push((edge_idx, bid, ask, grid_fee));
+            }
+        }
+    }
+
+    network.min_cost_flow(source, sink);
+
+    pairs
+        .into_iter()
+        .filter_map(|(edge_idx, &bid, &ask, grid_fee_euro_per_kwh)| {
+            let quanta = network.flow(edge_idx);
+            (quanta > 0).then_some(Allocation {
+                bid,
+                ask,
+                quanta,
+                grid_fee_euro_per_kwh,
+            })
+        })
+        .collect()
+}
+
+/// The grid fee between the clusters of a bid and an ask. Orders without a cluster (like the
+/// orders of the market maker) don't pay grid fees.
+fn grid_fee(bid: &Order, ask: &Order, grid_fee_matrix: &GridFeeMatrix) -> f64 {
+    match (bid.cluster_index, ask.cluster_index) {
+        (Some(bid_cluster), Some(ask_cluster)) => grid_fee_matrix
+            .get(bid_cluster, ask_cluster)
+            .unwrap_or(f64::NAN),
+        _ => 0.0,
+    }
+}
+
+fn social_welfare(allocations: &[Allocation]) -> f64 {
+    allocations
+        .iter()
+        .map(|a| {
+            let welfare =
+                a.bid.price_euro_per_kwh - a.ask.price_euro_per_kwh - a.grid_fee_euro_per_kwh;
+            welfare * a.quanta as f64 * ENERGY_EPS
+        })
+        .sum()
+}
+
+/// Welfare-maximizing matching with VCG payments.
+///
+/// The energy is allocated such that the social welfare (value of the bids minus cost of the
+/// asks minus grid fees) is maximal over all clusters at once. This is a transportation problem,
+/// which is solved exactly as a min-cost flow problem in multiples of [`ENERGY_EPS`]. Orders
+/// without a cluster don't pay grid fees, orders with a cluster that is not in the grid fee
+/// matrix and orders with non-finite values are ignored.
+///
+/// Every actor pays the welfare loss they cause the other actors (the Clarke pivot rule): the
+/// welfare of the others if the actor had not taken part minus the welfare of the others in the
+/// chosen allocation. This makes reporting the true prices a dominant strategy, but the payments
+/// usually don't cover the revenues of the sellers and the grid fees. The price of a match is
+/// the price of the ask plus the grid fee, like in the
+/// [`custom_fair_matching`](crate::custom_fair_matching); the actual payments are listed in
+/// [`VcgOutput::payments`].
+///
+/// The allocation is computed once for the full market and once for every actor, so this is
+/// meant as a benchmark and not for large markets. Orders whose
+/// [`FillConstraint`](crate::FillConstraint) can't be met are removed and everything is computed
+/// again, so the allocation is not necessarily optimal if there are fill constraints.
+pub fn vcg_matching(input: &MarketInput, grid_fee_matrix: &GridFeeMatrix) -> VcgOutput {
+    vcg_matching_with_config(input, grid_fee_matrix, &MatchingConfig::default())
+}
+
+/// VCG matching with explicit settings. See [`vcg_matching`].
+///
+/// The [`PriceLimits`](crate::PriceLimits) and the
+/// [`SelfTradePrevention`](crate::SelfTradePrevention) are applied; the tie-break rule and the
+/// allocation rule are not used.
+pub fn vcg_matching_with_config(
+    input: &MarketInput,
+    grid_fee_matrix: &GridFeeMatrix,
+    config: &MatchingConfig,
+) -> VcgOutput {
+    let (grid_fee_matrix, grid_fee_diagnostics) =
+        config.price_limits.cap_grid_fees(grid_fee_matrix);
+    let mut output = with_matching_config(
+        input,
+        config,
+        |input| clarke_pivot(input, &grid_fee_matrix, config.self_trade_prevention),
+        |output| &mut output.market_output,
+    );
+    output
+        .market_output
+        .diagnostics
+        .extend(grid_fee_diagnostics);
+    output
+}
+
+/// VCG matching without the repetitions for fill constraints, the price limits and the self-trade
+/// prevention before matching.
+fn clarke_pivot(
+    input: &MarketInput,
+    grid_fee_matrix: &GridFeeMatrix,
+    self_trade_prevention: SelfTradePrevention,
+) -> VcgOutput {
+    let orders: Vec<&Order> = input
+        .orders
+        .iter()
+        .filter(|order| {
+            order.price_euro_per_kwh.is_finite()
+                && order.energy_kwh.is_finite()
+                && !matches!(order.cluster_index,
+                    Some(cluster_index) if cluster_index >= grid_fee_matrix.size)
+        })
+        .collect();
+
+    let allocations = max_welfare_allocation(&orders, grid_fee_matrix, self_trade_prevention);
+    let social_welfare_euro = social_welfare(&allocations);
+    let grid_fees_euro = allocations
+        .iter()
+        .map(|a| a.grid_fee_euro_per_kwh * a.quanta as f64 * ENERGY_EPS)
+        .sum();
+
+    // Value of the matched orders per actor
+    let mut values: BTreeMap<&str, f64> = BTreeMap::new();
+    for order in &orders {
+        values.insert(&order.actor_id, 0.0);
+    }
+    for a in &allocations {
+        let energy_kwh = a.quanta as f64 * ENERGY_EPS;
+        *values.entry(&a.bid.actor_id).or_insert(0.0) += a.bid.price_euro_per_kwh * energy_kwh;
+        *values.entry(&a.ask.actor_id).or_insert(0.0) -= a.ask.price_euro_per_kwh * energy_kwh;
+    }
+
+    let payments: Vec<VcgPayment> = values
+        .into_iter()
+        .map(|(actor_id, value_euro)| {
+            let others: Vec<&Order> = orders
+                .iter()
+                .copied()
+                .filter(|order| order.actor_id != actor_id)
+                .collect();
+            let welfare_without_actor = social_welfare(&max_welfare_allocation(
+                &others,
+                grid_fee_matrix,
+                self_trade_prevention,
+            ));
+            let payment_euro = welfare_without_actor - (social_welfare_euro - value_euro);
+            VcgPayment {
+                actor_id: actor_id.into(),
+                value_euro,
+                payment_euro,
+                utility_euro: value_euro - payment_euro,
+            }
+        })
+        .collect();
+    let budget_balance_euro = payments.iter().map(|p| p.payment_euro).sum::<f64>() - grid_fees_euro;
+
+    let matches = allocations
+        .iter()
+        .map(|a| Match {
+            bid_id: a.bid.id,
+            ask_id: a.ask.id,
+            energy_kwh: round_energy_value(a.quanta as f64 * ENERGY_EPS),
+            price_euro_per_kwh: a.ask.price_euro_per_kwh + a.grid_fee_euro_per_kwh,
+            bid_segment: None,
+            ask_segment: None,
+            stage: None,
+            energy_source: None,
+        })
+        .collect();
+
+    VcgOutput {
+        market_output: MarketOutput {
+            matches,
+            residual_book: vec![],
+            diagnostics: vec![],
+        },
+        social_welfare_euro,
+        grid_fees_euro,
+        payments,
+        budget_balance_euro,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    fn order(
+        id: u64,
+        order_type: OrderType,
+        cluster_index: Option<usize>,
+        energy_kwh: f64,
+        price_euro_per_kwh: f64,
+    ) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: format!("actor_{id}"),
+            cluster_index,
+            energy_kwh,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn test_vcg_single_unit() {
+        // Like a second price auction: the buyer pays the second highest bid, the seller gets
+        // the second lowest ask
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, None, 1.0, 10.0),
+                order(2, OrderType::Bid, None, 1.0, 8.0),
+                order(3, OrderType::Ask, None, 1.0, 2.0),
+                order(4, OrderType::Ask, None, 1.0, 9.0),
+            ],
+            curve_orders: vec![],
+        };
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0]]).unwrap();
+        let output = vcg_matching(&market_input, &gfm);
+        assert_eq!(output.market_output.matches.len(), 1);
+        assert_eq!(output.market_output.matches[0].bid_id, 1);
+        assert_eq!(output.market_output.matches[0].ask_id, 3);
+        assert_close(output.social_welfare_euro, 8.0);
+
+        let payment = |actor_id: &str| {
+            output
+                .payments
+                .iter()
+                .find(|p| p.actor_id == actor_id)
+                .unwrap()
+                .clone()
+        };
+        // Without the buyer, the second bid would buy for 8 - 2 = 6 welfare
+        assert_close(payment("actor_1").payment_euro, 8.0);
+        assert_close(payment("actor_3").payment_euro, -9.0);
+        assert_close(payment("actor_2").payment_euro, 0.0);
+        // The seller gets more than the buyer pays
+        assert_close(output.budget_balance_euro, 8.0 - 9.0);
+        assert!(output.payments.iter().all(|p| p.utility_euro >= -1e-9));
+    }
+
+    #[test]
+    fn test_vcg_grid_fees() {
+        // Custom fair matching processes cluster 0 first, so its bid takes the cheap ask in
+        // cluster 1 and the bid in cluster 1 has to buy the expensive ask in cluster 0. The
+        // welfare is higher if every bid buys within its own cluster.
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, Some(0), 1.0, 10.0),
+                order(2, OrderType::Bid, Some(1), 1.0, 10.0),
+                order(3, OrderType::Ask, Some(0), 1.0, 6.0),
+                order(4, OrderType::Ask, Some(1), 1.0, 1.0),
+            ],
+            curve_orders: vec![],
+        };
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0, 2.0], vec![2.0, 0.0]]).unwrap();
+        let output = vcg_matching(&market_input, &gfm);
+        let mut pairs: Vec<(u64, u64)> = output
+            .market_output
+            .matches
+            .iter()
+            .map(|m| (m.bid_id, m.ask_id))
+            .collect();
+        pairs.sort();
+        assert_eq!(pairs, vec![(1, 3), (2, 4)]);
+        assert_close(output.social_welfare_euro, 4.0 + 9.0);
+        assert_close(output.grid_fees_euro, 0.0);
+    }
+
+    #[test]
+    fn test_vcg_divisible_orders() {
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, Some(0), 3.5, 10.0),
+                order(2, OrderType::Ask, Some(0), 1.25, 2.0),
+                order(3, OrderType::Ask, Some(1), 5.0, 3.0),
+                // Unknown cluster
+                order(4, OrderType::Ask, Some(2), 5.0, 0.0),
+            ],
+            curve_orders: vec![],
+        };
+        let gfm = GridFeeMatrix::from_raw(&vec![vec![0.0, 1.0], vec![1.0, 0.0]]).unwrap();
+        let output = vcg_matching(&market_input, &gfm);
+        let matches: Vec<(u64, f64, f64)> = output
+            .market_output
+            .matches
+            .iter()
+            .map(|m| (m.ask_id, m.energy_kwh, m.price_euro_per_kwh))
+            .collect();
+        assert_eq!(matches, vec![(2, 1.25, 2.0), (3, 2.25, 4.0)]);
+        assert_close(output.grid_fees_euro, 2.25);
+        assert_close(output.social_welfare_euro, 1.25 * 8.0 + 2.25 * 6.0);
+    }
+}
@@ -0,0 +1,760 @@
+//! Checks that a market output is consistent with the orders it was computed from.
+//!
+//! The checks only look at the input and the output, so they can be used to audit the results of
+//! any matching implementation.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+// We use this instead of [`HashMap`] in `no_std` because we don't have access to a secure source
+// of random numbers to avoid hash collision attacks.
+use alloc::collections::btree_map::BTreeMap;
+use alloc::collections::btree_set::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::counterparty::forbidden;
+use crate::curve::orders_by_segment;
+use crate::fill::fill_violations;
+use crate::source::renewable_premium;
+use crate::{
+    Diagnostic, FillRule, GridFeeMatrix, MarketInput, MarketOutput, Order, OrderType, PriceLimits,
+    ENERGY_EPS, MARKET_MAKER_THRESHOLD,
+};
+
+/// Tolerance for comparing prices, to allow for rounding errors when adding grid fees.
+const PRICE_TOLERANCE: f64 = 1e-9;
+
+/// A single problem found in a market output.
+///
+/// `match_index` is the position of the offending match in [`MarketOutput::matches`].
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+    /// Two orders in the input have the same ID
+    DuplicateOrderId { order_id: u64 },
+    /// A match references an order (or a segment of a curve order) that is not part of the input
+    UnknownOrder {
+        match_index: usize,
+        order_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        segment: Option<usize>,
+    },
+    /// A match uses an order as bid that is an ask or vice versa
+    WrongOrderType {
+        match_index: usize,
+        order_id: u64,
+        expected: OrderType,
+    },
+    /// The energy of a match is not a positive number of at least `ENERGY_EPS`
+    InvalidEnergy { match_index: usize, energy_kwh: f64 },
+    /// The price of a match is not a finite number
+    InvalidPrice {
+        match_index: usize,
+        price_euro_per_kwh: f64,
+    },
+    /// The buyer has to pay more than the price of the bid (plus its renewable premium, see
+    /// [`SourcePreference`](crate::SourcePreference))
+    BidPriceExceeded {
+        match_index: usize,
+        bid_id: u64,
+        bid_price_euro_per_kwh: f64,
+        price_euro_per_kwh: f64,
+    },
+    /// The seller gets less than the price of the ask (after paying the grid fee, which is not
+    /// charged if the bid or the ask belongs to the market maker)
+    AskPriceNotReached {
+        match_index: usize,
+        ask_id: u64,
+        ask_price_euro_per_kwh: f64,
+        grid_fee_euro_per_kwh: f64,
+        price_euro_per_kwh: f64,
+    },
+    /// Bid and ask of a match belong to different time slots
+    TimeSlotMismatch {
+        match_index: usize,
+        bid_time_slot: String,
+        ask_time_slot: String,
+    },
+    /// An order is part of a cluster that is not in the grid fee matrix
+    UnknownCluster {
+        match_index: usize,
+        order_id: u64,
+        cluster_index: usize,
+    },
+    /// The matches of an order (or a segment of a curve order) contain more energy than the order
+    OverAllocated {
+        order_id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        segment: Option<usize>,
+        order_energy_kwh: f64,
+        matched_energy_kwh: f64,
+    },
+    /// The matches of an order violate its [`FillConstraint`](crate::FillConstraint)
+    FillConstraintViolated { order_id: u64, rule: FillRule },
+    /// Bid or ask of a match forbid each other as counterparty, see
+    /// [`CounterpartyRules`](crate::CounterpartyRules)
+    ForbiddenCounterparty {
+        match_index: usize,
+        bid_id: u64,
+        ask_id: u64,
+    },
+    /// A match uses an order that was rejected because of the [`PriceLimits`] or the
+    /// commitments, or cancelled by the [`SelfTradePrevention`](crate::SelfTradePrevention)
+    RejectedOrderMatched { match_index: usize, order_id: u64 },
+    /// A bid and an ask are matched although the
+    /// [`SelfTradePrevention`](crate::SelfTradePrevention) skipped the pair
+    SkippedPairMatched {
+        match_index: usize,
+        bid_id: u64,
+        ask_id: u64,
+    },
+    /// The self-trade prevention decremented a bid and an ask that don't belong to the same actor
+    /// and time slot or don't cross, or the amount is not positive or exceeds one of the orders
+    InvalidSelfTradeDecrement {
+        bid_id: u64,
+        ask_id: u64,
+        energy_kwh: f64,
+    },
+    /// The output reports a rejected order, a clipped price or a capped grid fee that doesn't
+    /// follow from the [`PriceLimits`]
+    UnexpectedDiagnostic { diagnostic: Diagnostic },
+    /// A rejected order or a clipped price that follows from the [`PriceLimits`] is not reported
+    /// in the output
+    MissingDiagnostic { diagnostic: Diagnostic },
+}
+
+/// A change that the [`PriceLimits`] make to an order or a grid fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LimitChange {
+    Order(u64),
+    GridFee(usize, usize),
+}
+
+impl LimitChange {
+    /// Return the change a diagnostic reports, `None` if it isn't caused by the price limits.
+    fn of(diagnostic: &Diagnostic) -> Option<LimitChange> {
+        match diagnostic {
+            Diagnostic::OrderRejected { order_id, .. }
+            | Diagnostic::PriceClipped { order_id, .. } => Some(LimitChange::Order(*order_id)),
+            Diagnostic::GridFeeCapped {
+                source_cluster,
+                dest_cluster,
+                ..
+            } => Some(LimitChange::GridFee(*source_cluster, *dest_cluster)),
+            _ => None,
+        }
+    }
+}
+
+/// The result of [`verify_market_output`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct VerificationReport {
+    /// Number of orders in the input
+    pub order_count: usize,
+    /// Number of matches in the output
+    pub match_count: usize,
+    /// All problems that were found, empty if the output is valid
+    pub violations: Vec<Violation>,
+}
+
+impl VerificationReport {
+    /// Return `true` if no violations were found.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Check a market output against the market input it was computed from.
+///
+/// If a grid fee matrix is given, the seller of each match has to get at least the ask price
+/// after the grid fee between the bid cluster and the ask cluster was deducted (like in the custom
+/// fair matching). Orders without a cluster don't pay grid fees. Matched energy is rounded to
+/// [`ENERGY_EPS`] by the algorithms, so an order may be exceeded by half of `ENERGY_EPS` per match.
+/// Matches of a [`CurveOrder`](crate::CurveOrder) are checked against the segment they reference
+/// and the matches of orders with a [`FillConstraint`](crate::FillConstraint) have to meet it.
+///
+/// The prices and grid fees are checked after applying the `price_limits` the output was computed
+/// with. The rejected orders, clipped prices and capped grid fees in the
+/// [diagnostics](MarketOutput::diagnostics) of the output have to be the ones that follow from the
+/// limits. Only orders in the residual book and grid fees (which not all algorithms use) may be
+/// missing from the diagnostics. Without a grid fee matrix, grid fees aren't checked at all. The
+/// energy of an order is reduced by the amounts that the self-trade prevention decremented, which
+/// have to be positive and belong to a crossing bid and ask of the same actor.
+///
+/// ```
+/// # use simplyr_lib::*;
+/// let input: MarketInput = serde_json::from_str(r#"{"orders": [
+///     {"id": 1, "order_type": "ask", "time_slot": "t", "actor_id": "a", "cluster_index": 0,
+///      "energy_kwh": 2.0, "price_euro_per_kwh": 0.3},
+///     {"id": 2, "order_type": "bid", "time_slot": "t", "actor_id": "b", "cluster_index": 0,
+///      "energy_kwh": 1.0, "price_euro_per_kwh": 0.4}
+/// ]}"#).unwrap();
+/// let output = pay_as_bid_matching(&input);
+/// assert!(verify_market_output(&input, &output, None, &PriceLimits::default()).is_valid());
+/// ```
+pub fn verify_market_output(
+    input: &MarketInput,
+    output: &MarketOutput,
+    grid_fee_matrix: Option<&GridFeeMatrix>,
+    price_limits: &PriceLimits,
+) -> VerificationReport {
+    let mut violations = vec![];
+
+    let (orders, duplicates) = orders_by_segment(input);
+    for order_id in duplicates {
+        violations.push(Violation::DuplicateOrderId { order_id });
+    }
+
+    // Recompute the changes that the price limits make
+    let (limited_input, mut limit_diagnostics) = price_limits.apply(input);
+    let (limited_orders, _) = orders_by_segment(&limited_input);
+    let grid_fee_matrix = grid_fee_matrix.map(|grid_fee_matrix| {
+        let (capped, grid_fee_diagnostics) = price_limits.cap_grid_fees(grid_fee_matrix);
+        limit_diagnostics.extend(grid_fee_diagnostics);
+        capped
+    });
+    let mut rejected = BTreeSet::new();
+    let mut expected_changes = BTreeMap::new();
+    for diagnostic in limit_diagnostics {
+        if let Diagnostic::OrderRejected { order_id, .. } = diagnostic {
+            rejected.insert(order_id);
+        }
+        if let Some(change) = LimitChange::of(&diagnostic) {
+            expected_changes.insert(change, diagnostic);
+        }
+    }
+
+    // The changes that the output reports have to match
+    for diagnostic in &output.diagnostics {
+        let change = match LimitChange::of(diagnostic) {
+            Some(LimitChange::GridFee(..)) if grid_fee_matrix.is_none() => continue,
+            Some(change) => change,
+            None => continue,
+        };
+        match expected_changes.remove(&change) {
+            Some(expected) if expected == *diagnostic => {}
+            Some(expected) => {
+                violations.push(Violation::UnexpectedDiagnostic {
+                    diagnostic: diagnostic.clone(),
+                });
+                violations.push(Violation::MissingDiagnostic {
+                    diagnostic: expected,
+                });
+            }
+            None => violations.push(Violation::UnexpectedDiagnostic {
+                diagnostic: diagnostic.clone(),
+            }),
+        }
+    }
+    // Orders in the residual book were removed before the price limits were applied
+    for (change, diagnostic) in expected_changes {
+        let removed = match change {
+            LimitChange::Order(order_id) => output
+                .residual_book
+                .iter()
+                .any(|residual| residual.order_id == order_id),
+            LimitChange::GridFee(..) => true,
+        };
+        if !removed {
+            violations.push(Violation::MissingDiagnostic { diagnostic });
+        }
+    }
+
+    // Changes that were made because of the self-trade prevention and the commitments
+    let mut skipped_pairs = BTreeSet::new();
+    let mut decremented: BTreeMap<u64, f64> = BTreeMap::new();
+    for diagnostic in &output.diagnostics {
+        match diagnostic {
+            // Checked above
+            Diagnostic::OrderRejected { .. }
+            | Diagnostic::PriceClipped { .. }
+            | Diagnostic::GridFeeCapped { .. } => {}
+            Diagnostic::SelfTradeCancelled { order_id, .. }
+            | Diagnostic::CommitmentRejected { order_id, .. } => {
+                rejected.insert(*order_id);
+            }
+            Diagnostic::SelfTradeDecremented {
+                bid_id,
+                ask_id,
+                energy_kwh,
+            } => {
+                // A crossing bid and ask of the same actor, after applying the price limits
+                let bid = limited_orders.get(&(*bid_id, None));
+                let ask = limited_orders.get(&(*ask_id, None));
+                let valid = match (bid, ask) {
+                    (Some(bid), Some(ask)) => {
+                        bid.order_type == OrderType::Bid
+                            && ask.order_type == OrderType::Ask
+                            && bid.actor_id == ask.actor_id
+                            && bid.time_slot == ask.time_slot
+                            && bid.price_euro_per_kwh >= ask.price_euro_per_kwh
+                            && energy_kwh.is_finite()
+                            && *energy_kwh > 0.0
+                            // The amount is rounded to ENERGY_EPS
+                            && *energy_kwh
+                                <= bid.energy_kwh.min(ask.energy_kwh) + ENERGY_EPS / 2.0
+                    }
+                    _ => false,
+                };
+                if !valid {
+                    violations.push(Violation::InvalidSelfTradeDecrement {
+                        bid_id: *bid_id,
+                        ask_id: *ask_id,
+                        energy_kwh: *energy_kwh,
+                    });
+                    continue;
+                }
+                for order_id in [*bid_id, *ask_id] {
+                    *decremented.entry(order_id).or_insert(0.0) += energy_kwh;
+                }
+            }
+            Diagnostic::SelfTradeSkipped { bid_id, ask_id } => {
+                skipped_pairs.insert((*bid_id, *ask_id));
+            }
+        }
+    }
+    let price =
+        |order: &Order, segment: Option<usize>| match limited_orders.get(&(order.id, segment)) {
+            Some(limited) => limited.price_euro_per_kwh,
+            None => order.price_euro_per_kwh,
+        };
+
+    // Map from (order ID, curve segment) -> (matched energy, number of matches)
+    let mut allocated: BTreeMap<(u64, Option<usize>), (f64, usize)> = BTreeMap::new();
+
+    for (match_index, m) in output.matches.iter().enumerate() {
+        if !(m.energy_kwh.is_finite() && m.energy_kwh >= ENERGY_EPS) {
+            violations.push(Violation::InvalidEnergy {
+                match_index,
+                energy_kwh: m.energy_kwh,
+            });
+        }
+        if !m.price_euro_per_kwh.is_finite() {
+            violations.push(Violation::InvalidPrice {
+                match_index,
+                price_euro_per_kwh: m.price_euro_per_kwh,
+            });
+        }
+
+        let mut lookup = |order_id: u64, segment: Option<usize>, expected: OrderType| {
+            let order = match orders.get(&(order_id, segment)) {
+                Some(order) => order,
+                None => {
+                    violations.push(Violation::UnknownOrder {
+                        match_index,
+                        order_id,
+                        segment,
+                    });
+                    return None;
+                }
+            };
+            if order.order_type != expected {
+                violations.push(Violation::WrongOrderType {
+                    match_index,
+                    order_id,
+                    expected,
+                });
+                return None;
+            }
+            let entry = allocated.entry((order_id, segment)).or_insert((0.0, 0));
+            entry.0 += m.energy_kwh;
+            entry.1 += 1;
+            Some(order)
+        };
+        let bid = lookup(m.bid_id, m.bid_segment, OrderType::Bid);
+        let ask = lookup(m.ask_id, m.ask_segment, OrderType::Ask);
+        let (bid, ask) = match (bid, ask) {
+            (Some(bid), Some(ask)) => (bid, ask),
+            _ => continue,
+        };
+
+        for order_id in [bid.id, ask.id] {
+            if rejected.contains(&order_id) {
+                violations.push(Violation::RejectedOrderMatched {
+                    match_index,
+                    order_id,
+                });
+            }
+        }
+
+        if forbidden(bid, ask) {
+            violations.push(Violation::ForbiddenCounterparty {
+                match_index,
+                bid_id: bid.id,
+                ask_id: ask.id,
+            });
+        }
+
+        if skipped_pairs.contains(&(bid.id, ask.id)) {
+            violations.push(Violation::SkippedPairMatched {
+                match_index,
+                bid_id: bid.id,
+                ask_id: ask.id,
+            });
+        }
+
+        if bid.time_slot != ask.time_slot {
+            violations.push(Violation::TimeSlotMismatch {
+                match_index,
+                bid_time_slot: bid.time_slot.clone(),
+                ask_time_slot: ask.time_slot.clone(),
+            });
+        }
+
+        let bid_price = price(bid, m.bid_segment) + renewable_premium(bid, ask);
+        if m.price_euro_per_kwh > bid_price + PRICE_TOLERANCE {
+            violations.push(Violation::BidPriceExceeded {
+                match_index,
+                bid_id: bid.id,
+                bid_price_euro_per_kwh: bid_price,
+                price_euro_per_kwh: m.price_euro_per_kwh,
+            });
+        }
+
+        // Matches with the market maker are not charged grid fees, whatever its cluster
+        let market_maker =
+            bid.energy_kwh >= MARKET_MAKER_THRESHOLD || ask.energy_kwh >= MARKET_MAKER_THRESHOLD;
+        let mut grid_fee = 0.0;
+        if let (Some(grid_fee_matrix), false) = (&grid_fee_matrix, market_maker) {
+            let mut known_clusters = true;
+            for order in [bid, ask] {
+                match order.cluster_index {
+                    Some(cluster_index) if cluster_index >= grid_fee_matrix.size => {
+                        known_clusters = false;
+                        violations.push(Violation::UnknownCluster {
+                            match_index,
+                            order_id: order.id,
+                            cluster_index,
+                        });
+                    }
+                    _ => {}
+                }
+            }
+            if let (true, Some(bid_cluster), Some(ask_cluster)) =
+                (known_clusters, bid.cluster_index, ask.cluster_index)
+            {
+                grid_fee = grid_fee_matrix.get(bid_cluster, ask_cluster).unwrap_or(0.0);
+            }
+        }
+        let ask_price = price(ask, m.ask_segment);
+        if m.price_euro_per_kwh - grid_fee < ask_price - PRICE_TOLERANCE {
+            violations.push(Violation::AskPriceNotReached {
+                match_index,
+                ask_id: ask.id,
+                ask_price_euro_per_kwh: ask_price,
+                grid_fee_euro_per_kwh: grid_fee,
+                price_euro_per_kwh: m.price_euro_per_kwh,
+            });
+        }
+    }
+
+    for ((order_id, segment), (matched_energy, match_count)) in allocated {
+        let order = &orders[&(order_id, segment)];
+        let order_energy = match (segment, decremented.get(&order_id)) {
+            (None, Some(energy)) => order.energy_kwh - energy,
+            _ => order.energy_kwh,
+        };
+        let tolerance = match_count as f64 * ENERGY_EPS / 2.0 + PRICE_TOLERANCE;
+        if matched_energy > order_energy + tolerance {
+            violations.push(Violation::OverAllocated {
+                order_id,
+                segment,
+                order_energy_kwh: order_energy,
+                matched_energy_kwh: matched_energy,
+            });
+        }
+    }
+
+    for (order_id, rule) in fill_violations(input, output) {
+        violations.push(Violation::FillConstraintViolated { order_id, rule });
+    }
+
+    VerificationReport {
+        order_count: input.orders.len(),
+        match_count: output.matches.len(),
+        violations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        custom_fair_matching, custom_fair_matching_with_config, pay_as_bid_matching,
+        pay_as_bid_matching_with_config, FillConstraint, LimitPolicy, Match, MatchingConfig,
+        PriceBounds, SelfTradePrevention,
+    };
+    use alloc::string::ToString;
+
+    fn order(id: u64, order_type: OrderType, cluster_index: usize, price: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: "actor".to_string(),
+            cluster_index: Some(cluster_index),
+            energy_kwh: 2.0,
+            price_euro_per_kwh: price,
+            ..Default::default()
+        }
+    }
+
+    fn market_match(bid_id: u64, ask_id: u64, energy_kwh: f64, price: f64) -> Match {
+        Match {
+            bid_id,
+            ask_id,
+            energy_kwh,
+            price_euro_per_kwh: price,
+            bid_segment: None,
+            ask_segment: None,
+            stage: None,
+            energy_source: None,
+        }
+    }
+
+    #[test]
+    fn test_valid_outputs() {
+        let input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 1, 0.20),
+                order(2, OrderType::Bid, 0, 0.40),
+                order(3, OrderType::Ask, 0, 0.25),
+                order(4, OrderType::Bid, 1, 0.30),
+            ],
+            curve_orders: vec![],
+        };
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.1], [0.1, 0]]").unwrap();
+
+        let output = pay_as_bid_matching(&input);
+        assert!(verify_market_output(&input, &output, None, &PriceLimits::default()).is_valid());
+
+        let output = custom_fair_matching(&input, 0.5, &grid_fee_matrix);
+        let report = verify_market_output(
+            &input,
+            &output,
+            Some(&grid_fee_matrix),
+            &PriceLimits::default(),
+        );
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.order_count, 4);
+        assert_eq!(report.match_count, output.matches.len());
+
+        // The market maker doesn't pay grid fees, even if it has a cluster
+        let mut with_market_maker = input.clone();
+        with_market_maker.orders[1].energy_kwh = MARKET_MAKER_THRESHOLD;
+        with_market_maker.orders[3].price_euro_per_kwh = 0.1;
+        let output = custom_fair_matching(&with_market_maker, 0.5, &grid_fee_matrix);
+        assert!(output
+            .matches
+            .iter()
+            .any(|m| m.ask_id == 1 && m.price_euro_per_kwh == 0.2));
+        let report = verify_market_output(
+            &with_market_maker,
+            &output,
+            Some(&grid_fee_matrix),
+            &PriceLimits::default(),
+        );
+        assert!(report.is_valid(), "{:?}", report);
+
+        // Prices and grid fees that were changed because of the price limits
+        let mut config = MatchingConfig::default();
+        config.price_limits.global.min_price_euro_per_kwh = Some(0.35);
+        config.price_limits.max_grid_fee_euro_per_kwh = Some(0.05);
+        config.price_limits.policy = LimitPolicy::Clip;
+        let output = custom_fair_matching_with_config(&input, 0.5, &grid_fee_matrix, &config);
+        assert!(!output.matches.is_empty());
+        let report = verify_market_output(
+            &input,
+            &output,
+            Some(&grid_fee_matrix),
+            &config.price_limits,
+        );
+        assert!(report.is_valid(), "{:?}", report);
+
+        // Rejected orders may not be matched and have to be reported
+        let price_limits = PriceLimits {
+            global: PriceBounds {
+                min_price_euro_per_kwh: None,
+                max_price_euro_per_kwh: Some(0.35),
+            },
+            ..Default::default()
+        };
+        let rejected = Diagnostic::OrderRejected {
+            order_id: 2,
+            price_euro_per_kwh: 0.4,
+            min_price_euro_per_kwh: None,
+            max_price_euro_per_kwh: Some(0.35),
+        };
+        let mut output = pay_as_bid_matching(&input);
+        let report = verify_market_output(&input, &output, None, &price_limits);
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::MissingDiagnostic {
+                    diagnostic: rejected.clone()
+                },
+                Violation::RejectedOrderMatched {
+                    match_index: 0,
+                    order_id: 2
+                }
+            ]
+        );
+
+        // Diagnostics have to follow from the price limits
+        output.diagnostics.push(rejected.clone());
+        let report = verify_market_output(&input, &output, None, &PriceLimits::default());
+        assert_eq!(
+            report.violations,
+            vec![Violation::UnexpectedDiagnostic {
+                diagnostic: rejected
+            }]
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let mut input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 1, 0.20),
+                order(2, OrderType::Bid, 0, 0.40),
+                order(3, OrderType::Ask, 0, 0.25),
+            ],
+            curve_orders: vec![],
+        };
+        input.orders[2].time_slot = "other".to_string();
+        input.orders[2].fill_constraint = Some(FillConstraint {
+            all_or_nothing: true,
+            ..Default::default()
+        });
+        let grid_fee_matrix = GridFeeMatrix::from_json_str("[[0, 0.1], [0.1, 0]]").unwrap();
+
+        let output = MarketOutput {
+            matches: vec![
+                market_match(2, 1, 1.5, 0.25),
+                market_match(2, 1, 1.0, 0.45),
+                market_match(1, 2, 1.0, 0.30),
+                market_match(2, 7, 0.0, f64::INFINITY),
+                market_match(2, 3, 0.5, 0.30),
+            ],
+            residual_book: vec![],
+            diagnostics: vec![],
+        };
+        let report = verify_market_output(
+            &input,
+            &output,
+            Some(&grid_fee_matrix),
+            &PriceLimits::default(),
+        );
+        assert_eq!(
+            report.violations,
+            vec![
+                // The seller gets 0.25 - 0.1 < 0.2
+                Violation::AskPriceNotReached {
+                    match_index: 0,
+                    ask_id: 1,
+                    ask_price_euro_per_kwh: 0.2,
+                    grid_fee_euro_per_kwh: 0.1,
+                    price_euro_per_kwh: 0.25,
+                },
+                Violation::BidPriceExceeded {
+                    match_index: 1,
+                    bid_id: 2,
+                    bid_price_euro_per_kwh: 0.4,
+                    price_euro_per_kwh: 0.45,
+                },
+                Violation::WrongOrderType {
+                    match_index: 2,
+                    order_id: 1,
+                    expected: OrderType::Bid,
+                },
+                Violation::WrongOrderType {
+                    match_index: 2,
+                    order_id: 2,
+                    expected: OrderType::Ask,
+                },
+                Violation::InvalidEnergy {
+                    match_index: 3,
+                    energy_kwh: 0.0,
+                },
+                Violation::InvalidPrice {
+                    match_index: 3,
+                    price_euro_per_kwh: f64::INFINITY,
+                },
+                Violation::UnknownOrder {
+                    match_index: 3,
+                    order_id: 7,
+                    segment: None,
+                },
+                Violation::TimeSlotMismatch {
+                    match_index: 4,
+                    bid_time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                    ask_time_slot: "other".to_string(),
+                },
+                Violation::OverAllocated {
+                    order_id: 1,
+                    segment: None,
+                    order_energy_kwh: 2.0,
+                    matched_energy_kwh: 2.5,
+                },
+                Violation::OverAllocated {
+                    order_id: 2,
+                    segment: None,
+                    order_energy_kwh: 2.0,
+                    matched_energy_kwh: 3.0,
+                },
+                Violation::FillConstraintViolated {
+                    order_id: 3,
+                    rule: FillRule::MinimumFill,
+                },
+            ]
+        );
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn test_self_trade_decrements() {
+        let mut input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Ask, 0, 0.20),
+                order(2, OrderType::Bid, 0, 0.40),
+                order(3, OrderType::Bid, 0, 0.30),
+            ],
+            curve_orders: vec![],
+        };
+        input.orders[0].energy_kwh = 3.0;
+        input.orders[2].actor_id = "other".to_string();
+        let config = MatchingConfig {
+            self_trade_prevention: SelfTradePrevention::DecrementBoth,
+            ..Default::default()
+        };
+        let mut output = pay_as_bid_matching_with_config(&input, &config);
+        assert_eq!(output.matches.len(), 1);
+        let report = verify_market_output(&input, &output, None, &config.price_limits);
+        assert!(report.is_valid(), "{:?}", report);
+
+        // Negative amounts and orders of different actors are not accepted
+        let decrement = |bid_id, energy_kwh| Diagnostic::SelfTradeDecremented {
+            bid_id,
+            ask_id: 1,
+            energy_kwh,
+        };
+        output.diagnostics = vec![decrement(2, 2.0), decrement(2, -1.0), decrement(3, 1.0)];
+        let report = verify_market_output(&input, &output, None, &config.price_limits);
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::InvalidSelfTradeDecrement {
+                    bid_id: 2,
+                    ask_id: 1,
+                    energy_kwh: -1.0,
+                },
+                Violation::InvalidSelfTradeDecrement {
+                    bid_id: 3,
+                    ask_id: 1,
+                    energy_kwh: 1.0,
+                },
+            ]
+        );
+    }
+}
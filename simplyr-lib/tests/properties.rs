@@ -0,0 +1,983 @@
+//! Property-based tests for the matching algorithms.
+//!
+//! Random order books are checked for conservation of energy, individual rationality and budget
+//! balance. In addition, the algorithms are compared with naive reference implementations that
+//! follow the description of each algorithm as directly as possible.
+
+use std::collections::BTreeMap;
+
+use proptest::prelude::*;
+use simplyr_lib::*;
+
+/// Tolerance for sums of rounded values
+const TOLERANCE: f64 = 1e-6;
+/// Energy value of market maker orders
+const MARKET_MAKER_ENERGY: f64 = 1e12;
+
+fn arb_order_type() -> impl Strategy<Value = OrderType> {
+    prop_oneof![Just(OrderType::Bid), Just(OrderType::Ask)]
+}
+
+/// Orders with few distinct prices and energy values, so there are many ties.
+fn arb_orders(num_clusters: usize) -> impl Strategy<Value = Vec<Order>> {
+    let order = (
+        arb_order_type(),
+        0..num_clusters,
+        1..50_u32,
+        5..40_u32,
+        prop::option::of(0..5_u64),
+    );
+    prop::collection::vec(order, 0..30).prop_map(|orders| {
+        orders
+            .into_iter()
+            .enumerate()
+            .map(
+                |(idx, (order_type, cluster_index, energy, price, submitted_at))| Order {
+                    id: idx as u64 + 1,
+                    order_type,
+                    time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                    actor_id: format!("actor_{}", idx % 7),
+                    cluster_index: Some(cluster_index),
+                    energy_kwh: energy as f64 / 10.0,
+                    price_euro_per_kwh: price as f64 / 100.0,
+                    submitted_at,
+                    ..Default::default()
+                },
+            )
+            .collect()
+    })
+}
+
+/// Grid fee matrices without fees inside a cluster.
+fn arb_grid_fee_matrix(num_clusters: usize) -> impl Strategy<Value = GridFeeMatrix> {
+    prop::collection::vec(0..10_u32, num_clusters * num_clusters).prop_map(move |fees| {
+        let raw: GridFeeMatrixRaw = (0..num_clusters)
+            .map(|source| {
+                (0..num_clusters)
+                    .map(|dest| {
+                        if source == dest {
+                            0.0
+                        } else {
+                            fees[source * num_clusters + dest] as f64 / 100.0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        GridFeeMatrix::from_raw(&raw).unwrap()
+    })
+}
+
+/// Orders of the market maker that don't belong to a cluster.
+fn arb_market_maker(first_id: u64) -> impl Strategy<Value = Vec<Order>> {
+    let order = (arb_order_type(), 5..40_u32);
+    prop::collection::vec(order, 0..3).prop_map(move |orders| {
+        orders
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (order_type, price))| Order {
+                id: first_id + idx as u64,
+                order_type,
+                time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                actor_id: "market_maker".to_string(),
+                cluster_index: None,
+                energy_kwh: MARKET_MAKER_ENERGY,
+                price_euro_per_kwh: price as f64 / 100.0,
+                ..Default::default()
+            })
+            .collect()
+    })
+}
+
+/// A market input together with a grid fee matrix that covers all clusters of the orders.
+fn arb_market() -> impl Strategy<Value = (MarketInput, GridFeeMatrix)> {
+    (1..4_usize).prop_flat_map(|num_clusters| {
+        (
+            arb_orders(num_clusters),
+            arb_market_maker(1000),
+            arb_grid_fee_matrix(num_clusters),
+        )
+            .prop_map(|(mut orders, market_maker, grid_fee_matrix)| {
+                orders.extend(market_maker);
+                (
+                    MarketInput {
+                        orders,
+                        curve_orders: vec![],
+                    },
+                    grid_fee_matrix,
+                )
+            })
+    })
+}
+
+/// Fill constraints with small values, so they often conflict with each other.
+fn arb_fill_constraint() -> impl Strategy<Value = Option<FillConstraint>> {
+    let constraint = (
+        any::<bool>(),
+        prop::option::of(prop_oneof![Just(0.5), Just(0.9)]),
+        prop::option::of(prop_oneof![Just(0.5), Just(1.5)]),
+    );
+    prop::option::of(constraint.prop_map(
+        |(all_or_nothing, min_acceptance_ratio, min_match_kwh)| FillConstraint {
+            all_or_nothing,
+            min_acceptance_ratio,
+            min_match_kwh,
+        },
+    ))
+}
+
+/// A market like [`arb_market`] where some orders have fill constraints.
+fn arb_constrained_market() -> impl Strategy<Value = (MarketInput, GridFeeMatrix)> {
+    arb_market().prop_flat_map(|(market_input, grid_fee_matrix)| {
+        let constraints = prop::collection::vec(arb_fill_constraint(), market_input.orders.len());
+        (Just(market_input), Just(grid_fee_matrix), constraints).prop_map(
+            |(mut market_input, grid_fee_matrix, constraints)| {
+                for (order, fill_constraint) in market_input.orders.iter_mut().zip(constraints) {
+                    order.fill_constraint = fill_constraint;
+                }
+                (market_input, grid_fee_matrix)
+            },
+        )
+    })
+}
+
+/// A market like [`arb_constrained_market`] with two time slots and orders that are linked
+/// within and across the slots.
+fn arb_linked_market() -> impl Strategy<Value = (MarketInput, GridFeeMatrix)> {
+    arb_constrained_market().prop_flat_map(|(market_input, grid_fee_matrix)| {
+        let links = prop::collection::vec(
+            (any::<bool>(), prop::option::of(0..4_u64)),
+            market_input.orders.len(),
+        );
+        (Just(market_input), Just(grid_fee_matrix), links).prop_map(
+            |(mut market_input, grid_fee_matrix, links)| {
+                for (order, (late, link_id)) in market_input.orders.iter_mut().zip(links) {
+                    if late {
+                        order.time_slot = "2022-03-04T05:21:07+00:00".to_string();
+                    }
+                    order.link_id = link_id;
+                }
+                (market_input, grid_fee_matrix)
+            },
+        )
+    })
+}
+
+/// A market like [`arb_constrained_market`] where asks have energy sources and bids have source
+/// preferences.
+fn arb_sourced_market() -> impl Strategy<Value = (MarketInput, GridFeeMatrix)> {
+    let source = prop_oneof![
+        Just(EnergySource::Solar),
+        Just(EnergySource::Wind),
+        Just(EnergySource::GridMix),
+    ];
+    let source_info =
+        prop::option::of((source.clone(), prop::option::of(0..500_u32))).prop_map(|info| {
+            info.map(|(source, co2)| SourceInfo {
+                source,
+                co2_g_per_kwh: co2.map(f64::from),
+            })
+        });
+    let preference = prop::option::of((
+        prop::collection::vec(source, 0..3),
+        prop::option::of(0..500_u32),
+        prop::option::of(0..10_u32),
+    ))
+    .prop_map(|preference| {
+        preference.map(|(accepted_sources, max_co2, premium)| SourcePreference {
+            accepted_sources,
+            max_co2_g_per_kwh: max_co2.map(f64::from),
+            renewable_premium_euro_per_kwh: premium.map(|premium| premium as f64 / 100.0),
+        })
+    });
+    arb_constrained_market().prop_flat_map(move |(market_input, grid_fee_matrix)| {
+        let attributes = prop::collection::vec(
+            (source_info.clone(), preference.clone()),
+            market_input.orders.len(),
+        );
+        (Just(market_input), Just(grid_fee_matrix), attributes).prop_map(
+            |(mut market_input, grid_fee_matrix, attributes)| {
+                for (order, (source_info, preference)) in
+                    market_input.orders.iter_mut().zip(attributes)
+                {
+                    match order.order_type {
+                        OrderType::Ask => order.energy_source = source_info,
+                        OrderType::Bid => order.source_preference = preference,
+                    }
+                }
+                (market_input, grid_fee_matrix)
+            },
+        )
+    })
+}
+
+/// A market like [`arb_constrained_market`] where orders prefer or forbid some of the actors.
+fn arb_counterparty_market() -> impl Strategy<Value = (MarketInput, GridFeeMatrix)> {
+    let actor = prop_oneof![
+        (0..7_u32).prop_map(|idx| format!("actor_{idx}")),
+        Just("market_maker".to_string()),
+    ];
+    let rules = prop::option::of((
+        prop::collection::btree_map(actor.clone(), -1..4_i32, 0..3),
+        prop::collection::vec(actor, 0..3),
+    ))
+    .prop_map(|rules| {
+        rules.map(|(preferred, forbidden)| CounterpartyRules {
+            preferred: preferred
+                .into_iter()
+                .map(|(actor_id, weight)| (actor_id, f64::from(weight)))
+                .collect(),
+            forbidden,
+        })
+    });
+    arb_constrained_market().prop_flat_map(move |(market_input, grid_fee_matrix)| {
+        let rules = prop::collection::vec(rules.clone(), market_input.orders.len());
+        (Just(market_input), Just(grid_fee_matrix), rules).prop_map(
+            |(mut market_input, grid_fee_matrix, rules)| {
+                for (order, counterparties) in market_input.orders.iter_mut().zip(rules) {
+                    order.counterparties = counterparties;
+                }
+                (market_input, grid_fee_matrix)
+            },
+        )
+    })
+}
+
+/// Price limits whose lower bounds are always below the upper bounds of other clusters.
+fn arb_price_limits() -> impl Strategy<Value = PriceLimits> {
+    let bounds =
+        (prop::option::of(0..20_u32), prop::option::of(25..60_u32)).prop_map(|(min, max)| {
+            PriceBounds {
+                min_price_euro_per_kwh: min.map(|min| min as f64 / 100.0),
+                max_price_euro_per_kwh: max.map(|max| max as f64 / 100.0),
+            }
+        });
+    let policy = prop_oneof![Just(LimitPolicy::Reject), Just(LimitPolicy::Clip)];
+    (
+        bounds.clone(),
+        prop::collection::vec(bounds, 0..3),
+        prop::option::of(0..5_u32),
+        policy,
+    )
+        .prop_map(|(global, clusters, max_grid_fee, policy)| PriceLimits {
+            global,
+            clusters,
+            max_grid_fee_euro_per_kwh: max_grid_fee.map(|fee| fee as f64 / 100.0),
+            policy,
+        })
+}
+
+fn arb_config() -> impl Strategy<Value = MatchingConfig> {
+    let tie_break = prop_oneof![
+        Just(TieBreak::OrderId),
+        Just(TieBreak::SubmissionTime),
+        any::<u64>().prop_map(|seed| TieBreak::Random { seed }),
+    ];
+    let allocation = prop_oneof![Just(Allocation::PriceTime), Just(Allocation::ProRata)];
+    (tie_break, allocation).prop_map(|(tie_break, allocation)| MatchingConfig {
+        tie_break,
+        allocation,
+        ..Default::default()
+    })
+}
+
+fn arb_self_trade_prevention() -> impl Strategy<Value = SelfTradePrevention> {
+    prop_oneof![
+        Just(SelfTradePrevention::CancelNewest),
+        Just(SelfTradePrevention::CancelOldest),
+        Just(SelfTradePrevention::DecrementBoth),
+        Just(SelfTradePrevention::SkipPair),
+    ]
+}
+
+/// Sum up the matched energy per (bid ID, ask ID) pair.
+fn energy_by_pair(market_output: &MarketOutput) -> BTreeMap<(u64, u64), f64> {
+    let mut pairs = BTreeMap::new();
+    for m in &market_output.matches {
+        *pairs.entry((m.bid_id, m.ask_id)).or_insert(0.0) += m.energy_kwh;
+    }
+    pairs
+}
+
+fn assert_same_pairs(actual: &MarketOutput, expected: &MarketOutput) -> Result<(), TestCaseError> {
+    let actual = energy_by_pair(actual);
+    let expected = energy_by_pair(expected);
+    prop_assert_eq!(
+        actual.keys().collect::<Vec<_>>(),
+        expected.keys().collect::<Vec<_>>()
+    );
+    for (pair, energy) in actual {
+        prop_assert!(
+            (energy - expected[&pair]).abs() < 2.0 * ENERGY_EPS,
+            "{:?}: {} != {}",
+            pair,
+            energy,
+            expected[&pair]
+        );
+    }
+    Ok(())
+}
+
+/// Check budget balance: the buyers pay exactly what the sellers get plus the grid fees, and
+/// nobody pays more (or gets less) than the price of their order.
+fn assert_budget_balance(
+    market_input: &MarketInput,
+    market_output: &MarketOutput,
+    grid_fee_matrix: Option<&GridFeeMatrix>,
+) -> Result<(), TestCaseError> {
+    let orders: BTreeMap<u64, &Order> = market_input.orders.iter().map(|o| (o.id, o)).collect();
+    let mut buyer_payments = 0.0;
+    let mut seller_revenues = 0.0;
+    let mut grid_fees = 0.0;
+    let mut bid_values = 0.0;
+    let mut ask_costs = 0.0;
+    for m in &market_output.matches {
+        let (bid, ask) = (orders[&m.bid_id], orders[&m.ask_id]);
+        let fee = match (grid_fee_matrix, bid.cluster_index, ask.cluster_index) {
+            (Some(matrix), Some(bid_cluster), Some(ask_cluster)) => {
+                matrix.lookup(bid_cluster, ask_cluster)
+            }
+            _ => 0.0,
+        };
+        buyer_payments += m.energy_kwh * m.price_euro_per_kwh;
+        seller_revenues += m.energy_kwh * (m.price_euro_per_kwh - fee);
+        grid_fees += m.energy_kwh * fee;
+        bid_values += m.energy_kwh * bid.price_euro_per_kwh;
+        ask_costs += m.energy_kwh * ask.price_euro_per_kwh;
+    }
+    let scale = 1.0 + buyer_payments.abs();
+    prop_assert!((buyer_payments - seller_revenues - grid_fees).abs() < TOLERANCE * scale);
+    prop_assert!(buyer_payments <= bid_values + TOLERANCE * scale);
+    prop_assert!(seller_revenues >= ask_costs - TOLERANCE * scale);
+    Ok(())
+}
+
+/// Value of the bids minus cost of the asks minus grid fees of all matches.
+fn social_welfare(
+    market_input: &MarketInput,
+    market_output: &MarketOutput,
+    grid_fee_matrix: &GridFeeMatrix,
+) -> f64 {
+    market_metrics(market_input, market_output, Some(grid_fee_matrix)).social_welfare_euro
+}
+
+/// Total energy of all bids or asks that are not from the market maker.
+fn total_energy(market_input: &MarketInput, order_type: OrderType) -> f64 {
+    market_input
+        .orders
+        .iter()
+        .filter(|o| o.order_type == order_type && o.energy_kwh < MARKET_MAKER_ENERGY)
+        .map(|o| o.energy_kwh)
+        .sum()
+}
+
+/// Naive Pay-as-Bid: repeatedly match the best remaining bid with the cheapest remaining ask.
+fn reference_pay_as_bid(market_input: &MarketInput, config: &MatchingConfig) -> MarketOutput {
+    let mut bids: Vec<Order> = market_input
+        .orders
+        .iter()
+        .filter(|o| o.order_type == OrderType::Bid)
+        .cloned()
+        .collect();
+    let mut asks: Vec<Order> = market_input
+        .orders
+        .iter()
+        .filter(|o| o.order_type == OrderType::Ask)
+        .cloned()
+        .collect();
+    let mut matches = vec![];
+    let mut done = vec![false; bids.len()];
+
+    loop {
+        let best_bid = (0..bids.len())
+            .filter(|&b| !done[b] && bids[b].energy_kwh >= ENERGY_EPS)
+            .min_by(|&a, &b| {
+                let (a, b) = (&bids[a], &bids[b]);
+                b.price_euro_per_kwh
+                    .total_cmp(&a.price_euro_per_kwh)
+                    .then(config.tie_break.compare(a, b))
+            });
+        let bid_idx = match best_bid {
+            Some(bid_idx) => bid_idx,
+            None => break,
+        };
+        let cheapest_ask = (0..asks.len())
+            .filter(|&a| asks[a].energy_kwh > ENERGY_EPS)
+            .min_by(|&a, &b| {
+                let (a, b) = (&asks[a], &asks[b]);
+                a.price_euro_per_kwh
+                    .total_cmp(&b.price_euro_per_kwh)
+                    .then(config.tie_break.compare(a, b))
+            });
+        match cheapest_ask {
+            Some(ask_idx)
+                if asks[ask_idx].price_euro_per_kwh <= bids[bid_idx].price_euro_per_kwh =>
+            {
+                let energy = bids[bid_idx].energy_kwh.min(asks[ask_idx].energy_kwh);
+                matches.push(Match {
+                    bid_id: bids[bid_idx].id,
+                    ask_id: asks[ask_idx].id,
+                    energy_kwh: energy,
+                    price_euro_per_kwh: bids[bid_idx].price_euro_per_kwh,
+                    bid_segment: None,
+                    ask_segment: None,
+                    stage: None,
+                    energy_source: None,
+                });
+                bids[bid_idx].energy_kwh -= energy;
+                asks[ask_idx].energy_kwh -= energy;
+            }
+            _ => done[bid_idx] = true,
+        }
+    }
+    MarketOutput {
+        matches,
+        residual_book: vec![],
+        diagnostics: vec![],
+    }
+}
+
+/// Naive custom fair matching with one entry per energy unit. In every cluster, the sorted bid
+/// units are paired with the sorted ask units as long as the adjusted ask price is not higher.
+fn reference_custom_fair(
+    market_input: &MarketInput,
+    energy_unit_kwh: f64,
+    grid_fee_matrix: &GridFeeMatrix,
+) -> MarketOutput {
+    let is_market_maker = |o: &Order| o.energy_kwh >= MARKET_MAKER_ENERGY;
+    let units = |o: &Order| ((o.energy_kwh + ENERGY_EPS / 2.0) / energy_unit_kwh).floor() as usize;
+    let mut bid_units: Vec<&Order> = vec![];
+    let mut ask_units: Vec<(&Order, bool)> = vec![];
+    for order in market_input.orders.iter().filter(|o| !is_market_maker(o)) {
+        for _ in 0..units(order) {
+            match order.order_type {
+                OrderType::Bid => bid_units.push(order),
+                OrderType::Ask => ask_units.push((order, false)),
+            }
+        }
+    }
+    bid_units.sort_by(|a, b| {
+        b.price_euro_per_kwh
+            .total_cmp(&a.price_euro_per_kwh)
+            .then(a.id.cmp(&b.id))
+    });
+    let mut bid_matched = vec![false; bid_units.len()];
+
+    let mut matches = vec![];
+    for cluster_idx in 0..grid_fee_matrix.size {
+        let adjusted = |ask: &Order| {
+            ask.price_euro_per_kwh + grid_fee_matrix.lookup(cluster_idx, ask.cluster_index.unwrap())
+        };
+        let mut available: Vec<usize> = (0..ask_units.len()).filter(|&a| !ask_units[a].1).collect();
+        available.sort_by(|&a, &b| {
+            let (a, b) = (ask_units[a].0, ask_units[b].0);
+            adjusted(a)
+                .total_cmp(&adjusted(b))
+                .then(b.price_euro_per_kwh.total_cmp(&a.price_euro_per_kwh))
+                .then(a.id.cmp(&b.id))
+        });
+        let local_bids =
+            (0..bid_units.len()).filter(|&b| bid_units[b].cluster_index == Some(cluster_idx));
+        for (bid_idx, ask_idx) in local_bids.zip(available) {
+            let (bid, ask) = (bid_units[bid_idx], ask_units[ask_idx].0);
+            if adjusted(ask) > bid.price_euro_per_kwh {
+                break;
+            }
+            ask_units[ask_idx].1 = true;
+            bid_matched[bid_idx] = true;
+            matches.push(Match {
+                bid_id: bid.id,
+                ask_id: ask.id,
+                energy_kwh: energy_unit_kwh,
+                price_euro_per_kwh: adjusted(ask),
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            });
+        }
+    }
+
+    let market_maker = |order_type: OrderType| {
+        market_input
+            .orders
+            .iter()
+            .filter(|o| is_market_maker(o) && o.order_type == order_type)
+            .min_by(|a, b| {
+                let by_price = a.price_euro_per_kwh.total_cmp(&b.price_euro_per_kwh);
+                match order_type {
+                    OrderType::Ask => by_price,
+                    OrderType::Bid => by_price.reverse(),
+                }
+                .then(a.id.cmp(&b.id))
+            })
+    };
+    if let Some(ask_mm) = market_maker(OrderType::Ask) {
+        for (bid_idx, bid) in bid_units.iter().enumerate() {
+            if !bid_matched[bid_idx] && ask_mm.price_euro_per_kwh <= bid.price_euro_per_kwh {
+                matches.push(Match {
+                    bid_id: bid.id,
+                    ask_id: ask_mm.id,
+                    energy_kwh: energy_unit_kwh,
+                    price_euro_per_kwh: ask_mm.price_euro_per_kwh,
+                    bid_segment: None,
+                    ask_segment: None,
+                    stage: None,
+                    energy_source: None,
+                });
+            }
+        }
+    }
+    if let Some(bid_mm) = market_maker(OrderType::Bid) {
+        for (ask, matched) in &ask_units {
+            if !matched && ask.price_euro_per_kwh <= bid_mm.price_euro_per_kwh {
+                matches.push(Match {
+                    bid_id: bid_mm.id,
+                    ask_id: ask.id,
+                    energy_kwh: energy_unit_kwh,
+                    price_euro_per_kwh: ask.price_euro_per_kwh,
+                    bid_segment: None,
+                    ask_segment: None,
+                    stage: None,
+                    energy_source: None,
+                });
+            }
+        }
+    }
+    MarketOutput {
+        matches,
+        residual_book: vec![],
+        diagnostics: vec![],
+    }
+}
+
+proptest! {
+    #[test]
+    fn pay_as_bid_is_valid((market_input, _) in arb_market(), config in arb_config()) {
+        let market_output = pay_as_bid_matching_with_config(&market_input, &config);
+
+        let report = verify_market_output(&market_input, &market_output, None, &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+        assert_budget_balance(&market_input, &market_output, None)?;
+        for m in &market_output.matches {
+            prop_assert!(m.energy_kwh >= ENERGY_EPS);
+        }
+    }
+
+    #[test]
+    fn uniform_price_is_valid((market_input, _) in arb_market(), config in arb_config()) {
+        let market_output = uniform_price_matching_with_config(&market_input, &config);
+
+        let report = verify_market_output(&market_input, &market_output, None, &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+        assert_budget_balance(&market_input, &market_output, None)?;
+        if let Some(first) = market_output.matches.first() {
+            let price = first.price_euro_per_kwh;
+            prop_assert!(market_output.matches.iter().all(|m| m.price_euro_per_kwh == price));
+        }
+
+        // Only the prices differ from Pay-as-Bid
+        let pay_as_bid = pay_as_bid_matching_with_config(&market_input, &config);
+        assert_same_pairs(&market_output, &pay_as_bid)?;
+    }
+
+    #[test]
+    fn custom_fair_is_valid(
+        (market_input, grid_fee_matrix) in arb_market(),
+        config in arb_config(),
+        energy_unit in prop_oneof![Just(0.1), Just(0.5), Just(1.0)],
+    ) {
+        let market_output =
+            custom_fair_matching_with_config(&market_input, energy_unit, &grid_fee_matrix, &config);
+
+        let report = verify_market_output(&market_input, &market_output, Some(&grid_fee_matrix), &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+        assert_budget_balance(&market_input, &market_output, Some(&grid_fee_matrix))?;
+
+        // Energy is only traded in full units
+        for m in &market_output.matches {
+            let units = m.energy_kwh / energy_unit;
+            prop_assert!((units - units.round()).abs() < TOLERANCE);
+        }
+    }
+
+    #[test]
+    fn local_first_is_valid(
+        (market_input, grid_fee_matrix) in arb_market(),
+        config in arb_config(),
+        energy_unit in prop_oneof![Just(0.1), Just(0.5), Just(1.0)],
+    ) {
+        let market_output =
+            local_first_matching_with_config(&market_input, energy_unit, &grid_fee_matrix, &config);
+
+        let report = verify_market_output(&market_input, &market_output, Some(&grid_fee_matrix), &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+        assert_budget_balance(&market_input, &market_output, Some(&grid_fee_matrix))?;
+
+        let order = |order_id| market_input.orders.iter().find(|order| order.id == order_id).unwrap();
+        for m in &market_output.matches {
+            let (bid, ask) = (order(m.bid_id), order(m.ask_id));
+            match m.stage {
+                Some(MatchStage::Local) => prop_assert_eq!(bid.cluster_index, ask.cluster_index),
+                Some(MatchStage::CrossCluster) => {}
+                Some(MatchStage::MarketMaker) => prop_assert!(
+                    bid.energy_kwh >= MARKET_MAKER_ENERGY || ask.energy_kwh >= MARKET_MAKER_ENERGY
+                ),
+                None => prop_assert!(false, "missing stage"),
+            }
+        }
+        // All local matches are made before the other ones
+        prop_assert!(market_output.matches.windows(2).all(|pair| pair[0].stage <= pair[1].stage));
+    }
+
+    #[test]
+    fn mcafee_is_valid(
+        (market_input, _) in arb_market(),
+        config in arb_config(),
+        energy_unit in prop_oneof![Just(0.1), Just(0.5), Just(1.0)],
+    ) {
+        let output = mcafee_matching_with_config(&market_input, energy_unit, &config);
+
+        let report = verify_market_output(&market_input, &output.market_output, None, &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+        prop_assert!(output.budget_surplus_euro >= 0.0);
+        prop_assert!(output.efficient_units - output.traded_units <= 1);
+
+        // Every trader accepts the uniform prices
+        let orders: BTreeMap<u64, &Order> =
+            market_input.orders.iter().map(|o| (o.id, o)).collect();
+        for m in &output.market_output.matches {
+            let buyer_price = output.buyer_price_euro_per_kwh.unwrap();
+            let seller_price = output.seller_price_euro_per_kwh.unwrap();
+            prop_assert!(seller_price <= buyer_price);
+            prop_assert!(orders[&m.bid_id].price_euro_per_kwh >= buyer_price);
+            prop_assert!(orders[&m.ask_id].price_euro_per_kwh <= seller_price);
+        }
+        let traded: f64 = output.market_output.matches.iter().map(|m| m.energy_kwh).sum();
+        prop_assert!((traded - output.traded_units as f64 * energy_unit).abs() < TOLERANCE * (1.0 + traded));
+    }
+
+    #[test]
+    fn vcg_is_valid_and_optimal(
+        (market_input, grid_fee_matrix) in arb_market(),
+        config in arb_config(),
+    ) {
+        let output = vcg_matching(&market_input, &grid_fee_matrix);
+
+        let report =
+            verify_market_output(&market_input, &output.market_output, Some(&grid_fee_matrix), &PriceLimits::default());
+        prop_assert!(report.is_valid(), "{:?}", report);
+        assert_budget_balance(&market_input, &output.market_output, Some(&grid_fee_matrix))?;
+
+        let scale = 1.0 + output.social_welfare_euro.abs();
+        let welfare = social_welfare(&market_input, &output.market_output, &grid_fee_matrix);
+        prop_assert!((welfare - output.social_welfare_euro).abs() < TOLERANCE * scale);
+        for payment in &output.payments {
+            prop_assert!(payment.utility_euro >= -TOLERANCE * scale);
+        }
+
+        // No other algorithm achieves a higher welfare
+        let custom_fair =
+            custom_fair_matching_with_config(&market_input, 0.1, &grid_fee_matrix, &config);
+        let custom_fair_welfare = social_welfare(&market_input, &custom_fair, &grid_fee_matrix);
+        prop_assert!(custom_fair_welfare <= output.social_welfare_euro + TOLERANCE * scale);
+        let pay_as_bid = pay_as_bid_matching_with_config(&market_input, &config);
+        let pay_as_bid_welfare = social_welfare(&market_input, &pay_as_bid, &grid_fee_matrix);
+        prop_assert!(pay_as_bid_welfare <= output.social_welfare_euro + TOLERANCE * scale);
+    }
+
+    #[test]
+    fn canonical_encoding_survives_json(
+        (market_input, grid_fee_matrix) in arb_constrained_market(),
+        config in arb_config(),
+    ) {
+        let market_output =
+            custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config);
+        let json = serde_json::to_string(&market_input).unwrap();
+        let parsed: MarketInput = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed.digest(), market_input.digest());
+        let json = serde_json::to_string(&market_output).unwrap();
+        let parsed: MarketOutput = serde_json::from_str(&json).unwrap();
+        prop_assert_eq!(parsed.digest(), market_output.digest());
+    }
+
+    #[test]
+    fn every_match_has_an_inclusion_proof(
+        (market_input, grid_fee_matrix) in arb_constrained_market(),
+        config in arb_config(),
+    ) {
+        let market_output =
+            custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config);
+        let tree = MerkleTree::new(&market_output.matches);
+        let root = market_output.merkle_root();
+        prop_assert_eq!(tree.root(), root);
+        for (index, matched) in market_output.matches.iter().enumerate() {
+            let proof = tree.proof(index).unwrap();
+            prop_assert!(proof.verify(matched, &root));
+            let mut changed = matched.clone();
+            changed.energy_kwh += 1.0;
+            prop_assert!(!proof.verify(&changed, &root));
+        }
+    }
+
+    #[test]
+    fn fill_constraints_are_honored(
+        (market_input, grid_fee_matrix) in arb_constrained_market(),
+        config in arb_config(),
+    ) {
+        let outputs = [
+            pay_as_bid_matching_with_config(&market_input, &config),
+            uniform_price_matching_with_config(&market_input, &config),
+            custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+            mcafee_matching_with_config(&market_input, 0.5, &config).market_output,
+            vcg_matching(&market_input, &grid_fee_matrix).market_output,
+        ];
+        for market_output in &outputs {
+            let report = verify_market_output(&market_input, market_output, None, &config.price_limits);
+            prop_assert!(report.is_valid(), "{:?}", report);
+            // Orders in the residual book are not matched at all
+            for residual in &market_output.residual_book {
+                prop_assert!(market_output
+                    .matches
+                    .iter()
+                    .all(|m| m.bid_id != residual.order_id && m.ask_id != residual.order_id));
+            }
+        }
+    }
+
+    #[test]
+    fn price_limits_are_honored(
+        (market_input, grid_fee_matrix) in arb_constrained_market(),
+        config in arb_config(),
+        price_limits in arb_price_limits(),
+    ) {
+        let config = MatchingConfig { price_limits, ..config };
+        let global = &config.price_limits.global;
+        let outputs = [
+            pay_as_bid_matching_with_config(&market_input, &config),
+            uniform_price_matching_with_config(&market_input, &config),
+            custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+            mcafee_matching_with_config(&market_input, 0.5, &config).market_output,
+        ];
+        for (idx, market_output) in outputs.iter().enumerate() {
+            // Only the custom fair matching uses grid fees
+            let grid_fee_matrix = if idx == 2 { Some(&grid_fee_matrix) } else { None };
+            let report = verify_market_output(&market_input, market_output, grid_fee_matrix, &config.price_limits);
+            prop_assert!(report.is_valid(), "{:?}", report);
+            for m in &market_output.matches {
+                let price = m.price_euro_per_kwh;
+                prop_assert!(global.min_price_euro_per_kwh.unwrap_or(price) <= price + TOLERANCE);
+                prop_assert!(global.max_price_euro_per_kwh.unwrap_or(price) >= price - TOLERANCE);
+            }
+        }
+    }
+
+    #[test]
+    fn source_preferences_are_honored(
+        (market_input, _) in arb_sourced_market(),
+        config in arb_config(),
+    ) {
+        let market_output = preference_matching_with_config(&market_input, &config);
+        let report = verify_market_output(&market_input, &market_output, None, &config.price_limits);
+        prop_assert!(report.is_valid(), "{:?}", report);
+
+        let order = |order_id| market_input.orders.iter().find(|order| order.id == order_id).unwrap();
+        for m in &market_output.matches {
+            let (bid, ask) = (order(m.bid_id), order(m.ask_id));
+            prop_assert_eq!(&m.energy_source, &ask.energy_source);
+            if let Some(preference) = &bid.source_preference {
+                prop_assert!(preference.accepts(ask.energy_source.as_ref()));
+            }
+        }
+    }
+
+    #[test]
+    fn counterparty_rules_are_honored(
+        (market_input, grid_fee_matrix) in arb_counterparty_market(),
+        config in arb_config(),
+    ) {
+        let outputs = [
+            (pay_as_bid_matching_with_config(&market_input, &config), None),
+            (uniform_price_matching_with_config(&market_input, &config), None),
+            (preference_matching_with_config(&market_input, &config), None),
+            (
+                custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+                Some(&grid_fee_matrix),
+            ),
+            (
+                local_first_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+                Some(&grid_fee_matrix),
+            ),
+        ];
+        // The verification includes the forbidden counterparties
+        for (market_output, grid_fee_matrix) in &outputs {
+            let report = verify_market_output(&market_input, market_output, *grid_fee_matrix, &config.price_limits);
+            prop_assert!(report.is_valid(), "{:?}", report);
+        }
+    }
+
+    #[test]
+    fn self_trades_are_prevented(
+        (market_input, grid_fee_matrix) in arb_constrained_market(),
+        config in arb_config(),
+        self_trade_prevention in arb_self_trade_prevention(),
+    ) {
+        let config = MatchingConfig { self_trade_prevention, ..config };
+        let outputs = [
+            (pay_as_bid_matching_with_config(&market_input, &config), None),
+            (uniform_price_matching_with_config(&market_input, &config), None),
+            (preference_matching_with_config(&market_input, &config), None),
+            (
+                custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+                Some(&grid_fee_matrix),
+            ),
+            (
+                local_first_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+                Some(&grid_fee_matrix),
+            ),
+            (mcafee_matching_with_config(&market_input, 0.5, &config).market_output, None),
+            (
+                vcg_matching_with_config(&market_input, &grid_fee_matrix, &config).market_output,
+                Some(&grid_fee_matrix),
+            ),
+        ];
+        let actors: BTreeMap<u64, &str> = market_input
+            .orders
+            .iter()
+            .map(|order| (order.id, order.actor_id.as_str()))
+            .collect();
+        for (market_output, grid_fee_matrix) in &outputs {
+            // The verification includes cancelled orders, decremented energy and skipped pairs
+            let report = verify_market_output(&market_input, market_output, *grid_fee_matrix, &config.price_limits);
+            prop_assert!(report.is_valid(), "{:?}", report);
+            for m in &market_output.matches {
+                prop_assert_ne!(actors.get(&m.bid_id), actors.get(&m.ask_id));
+            }
+        }
+    }
+
+    #[test]
+    fn linked_orders_are_accepted_together(
+        (market_input, grid_fee_matrix) in arb_linked_market(),
+        config in arb_config(),
+    ) {
+        let outputs = [
+            multi_slot_matching(
+                &market_input,
+                |input| pay_as_bid_matching_with_config(input, &config),
+                |output| output,
+            ),
+            multi_slot_matching(
+                &market_input,
+                |input| custom_fair_matching_with_config(input, 0.5, &grid_fee_matrix, &config),
+                |output| output,
+            ),
+        ];
+        for slot_outputs in &outputs {
+            let mut matched = std::collections::BTreeSet::new();
+            for (time_slot, market_output) in slot_outputs {
+                let slot_input = MarketInput {
+                    orders: market_input
+                        .orders
+                        .iter()
+                        .filter(|order| &order.time_slot == time_slot)
+                        .cloned()
+                        .collect(),
+                    curve_orders: vec![],
+                };
+                let report = verify_market_output(&slot_input, market_output, None, &config.price_limits);
+                prop_assert!(report.is_valid(), "{:?}", report);
+                for m in &market_output.matches {
+                    matched.insert(m.bid_id);
+                    matched.insert(m.ask_id);
+                }
+            }
+
+            // Either all orders of a link are matched or none
+            let mut links: BTreeMap<u64, Vec<bool>> = BTreeMap::new();
+            for order in &market_input.orders {
+                if let Some(link_id) = order.link_id {
+                    links.entry(link_id).or_default().push(matched.contains(&order.id));
+                }
+            }
+            for accepted in links.values() {
+                prop_assert!(accepted.iter().all(|&a| a) || accepted.iter().all(|&a| !a));
+            }
+        }
+    }
+
+    #[test]
+    fn traded_volume_is_conserved((market_input, grid_fee_matrix) in arb_market()) {
+        // Without the market maker, no more energy can be traded than offered or demanded
+        let market_input = MarketInput {
+            orders: market_input
+                .orders
+                .into_iter()
+                .filter(|o| o.cluster_index.is_some())
+                .collect(),
+         curve_orders: vec![], };
+        let max_volume = total_energy(&market_input, OrderType::Bid)
+            .min(total_energy(&market_input, OrderType::Ask));
+
+        let volume = |market_output: MarketOutput| -> f64 {
+            market_output.matches.iter().map(|m| m.energy_kwh).sum()
+        };
+        let pay_as_bid = volume(pay_as_bid_matching(&market_input));
+        let custom_fair = volume(custom_fair_matching(&market_input, 0.1, &grid_fee_matrix));
+        prop_assert!(pay_as_bid <= max_volume + TOLERANCE);
+        prop_assert!(custom_fair <= max_volume + TOLERANCE);
+
+        // Pro-rata allocation only changes who gets the energy, not how much is traded
+        let config = MatchingConfig {
+            allocation: Allocation::ProRata,
+            ..Default::default()
+        };
+        let pro_rata = volume(pay_as_bid_matching_with_config(&market_input, &config));
+        let num_bids = market_input.orders.len() as f64;
+        prop_assert!((pro_rata - pay_as_bid).abs() <= num_bids * ENERGY_EPS);
+    }
+
+    #[test]
+    fn pay_as_bid_matches_reference((market_input, _) in arb_market(), config in arb_config()) {
+        let config = MatchingConfig {
+            allocation: Allocation::PriceTime,
+            ..config
+        };
+        let market_output = pay_as_bid_matching_with_config(&market_input, &config);
+        let expected = reference_pay_as_bid(&market_input, &config);
+        assert_same_pairs(&market_output, &expected)?;
+    }
+
+    #[test]
+    fn custom_fair_matches_reference(
+        (market_input, grid_fee_matrix) in arb_market(),
+        energy_unit in prop_oneof![Just(0.5), Just(1.0)],
+    ) {
+        let market_output = custom_fair_matching(&market_input, energy_unit, &grid_fee_matrix);
+        let expected = reference_custom_fair(&market_input, energy_unit, &grid_fee_matrix);
+        assert_same_pairs(&market_output, &expected)?;
+    }
+
+    #[test]
+    fn input_order_does_not_matter(
+        (market_input, grid_fee_matrix) in arb_market(),
+        config in arb_config(),
+        seed in any::<u64>(),
+    ) {
+        let mut shuffled = market_input.clone();
+        let mut rng = rng::SplitMix64::new(seed);
+        for i in (1..shuffled.orders.len()).rev() {
+            let j = (rng.next_u64() % (i as u64 + 1)) as usize;
+            shuffled.orders.swap(i, j);
+        }
+
+        prop_assert_eq!(
+            pay_as_bid_matching_with_config(&market_input, &config),
+            pay_as_bid_matching_with_config(&shuffled, &config)
+        );
+        prop_assert_eq!(
+            custom_fair_matching_with_config(&market_input, 0.5, &grid_fee_matrix, &config),
+            custom_fair_matching_with_config(&shuffled, 0.5, &grid_fee_matrix, &config)
+        );
+    }
+}
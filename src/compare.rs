@@ -0,0 +1,507 @@
+//! Side-by-side comparison of the results of several matching algorithms.
+
+use serde::Serialize;
+use simplyr_lib::{
+    market_metrics, GridFeeMatrix, MarketInput, MarketOutput, OrderType, ENERGY_EPS,
+};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Tolerance for comparing average prices
+const PRICE_TOLERANCE: f64 = 1e-9;
+
+/// The comparison of several algorithms on the same market input.
+///
+/// All lists of values contain one entry per algorithm, in the order of `algorithms`.
+#[derive(Serialize)]
+pub struct Comparison {
+    pub algorithms: Vec<AlgorithmSummary>,
+    /// Results per actor, sorted by actor ID
+    pub actors: Vec<ActorComparison>,
+    /// Orders whose matched energy or average price differ between the algorithms, sorted by ID
+    pub orders: Vec<OrderComparison>,
+}
+
+#[derive(Serialize)]
+pub struct AlgorithmSummary {
+    pub algorithm: String,
+    pub match_count: usize,
+    pub traded_volume_kwh: f64,
+    pub social_welfare_euro: f64,
+    pub buyer_surplus_euro: f64,
+    pub seller_surplus_euro: f64,
+    pub grid_fees_euro: f64,
+    pub mean_price_euro_per_kwh: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct ActorComparison {
+    pub actor_id: String,
+    /// Bought plus sold energy
+    pub traded_kwh: Vec<f64>,
+    pub bought_kwh: Vec<f64>,
+    pub sold_kwh: Vec<f64>,
+    /// Bought energy minus the bought energy with the first algorithm
+    pub bought_delta_kwh: Vec<f64>,
+    /// Sold energy minus the sold energy with the first algorithm
+    pub sold_delta_kwh: Vec<f64>,
+    pub surplus_euro: Vec<f64>,
+    /// Surplus minus the surplus with the first algorithm
+    pub surplus_delta_euro: Vec<f64>,
+}
+
+#[derive(Serialize)]
+pub struct OrderComparison {
+    pub order_id: u64,
+    /// The segment if the order is a curve order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segment: Option<usize>,
+    pub order_type: OrderType,
+    pub matched_kwh: Vec<f64>,
+    /// Energy-weighted average price of the matches, `None` if the order is not matched
+    pub average_price_euro_per_kwh: Vec<Option<f64>>,
+}
+
+impl Comparison {
+    /// Compare the outputs of the named algorithms.
+    pub fn new(
+        market_input: &MarketInput,
+        results: &[(String, MarketOutput)],
+        grid_fee_matrix: Option<&GridFeeMatrix>,
+    ) -> Self {
+        let mut algorithms = vec![];
+        let mut actors: BTreeMap<String, ActorComparison> = BTreeMap::new();
+        for (algorithm, market_output) in results {
+            let metrics = market_metrics(market_input, market_output, grid_fee_matrix);
+            algorithms.push(AlgorithmSummary {
+                algorithm: algorithm.clone(),
+                match_count: metrics.match_count,
+                traded_volume_kwh: metrics.traded_volume_kwh,
+                social_welfare_euro: metrics.social_welfare_euro,
+                buyer_surplus_euro: metrics.buyer_surplus_euro,
+                seller_surplus_euro: metrics.seller_surplus_euro,
+                grid_fees_euro: metrics.grid_fees_euro,
+                mean_price_euro_per_kwh: metrics.prices.map(|prices| prices.mean_euro_per_kwh),
+            });
+            // Every algorithm reports the same actors, because they are taken from the input
+            for actor in metrics.actors {
+                let comparison =
+                    actors
+                        .entry(actor.actor_id.clone())
+                        .or_insert_with(|| ActorComparison {
+                            actor_id: actor.actor_id.clone(),
+                            traded_kwh: vec![],
+                            bought_kwh: vec![],
+                            sold_kwh: vec![],
+                            bought_delta_kwh: vec![],
+                            sold_delta_kwh: vec![],
+                            surplus_euro: vec![],
+                            surplus_delta_euro: vec![],
+                        });
+                comparison
+                    .traded_kwh
+                    .push(actor.bought_kwh + actor.sold_kwh);
+                comparison.bought_kwh.push(actor.bought_kwh);
+                comparison.sold_kwh.push(actor.sold_kwh);
+                comparison.surplus_euro.push(actor.surplus_euro);
+            }
+        }
+        for actor in actors.values_mut() {
+            actor.bought_delta_kwh = deltas(&actor.bought_kwh);
+            actor.sold_delta_kwh = deltas(&actor.sold_kwh);
+            actor.surplus_delta_euro = deltas(&actor.surplus_euro);
+        }
+
+        Comparison {
+            algorithms,
+            actors: actors.into_values().collect(),
+            orders: order_differences(market_input, results),
+        }
+    }
+
+    /// Write the comparison as human-readable tables.
+    pub fn write_table(&self, out: &mut impl Write) -> io::Result<()> {
+        let names: Vec<&str> = self
+            .algorithms
+            .iter()
+            .map(|a| a.algorithm.as_str())
+            .collect();
+        let baseline = names.first().copied().unwrap_or_default();
+
+        let summary_rows: Vec<(&str, Vec<String>)> = vec![
+            ("matches", self.column(|a| a.match_count.to_string())),
+            (
+                "traded volume [kWh]",
+                self.column(|a| format!("{:.3}", a.traded_volume_kwh)),
+            ),
+            (
+                "social welfare [€]",
+                self.column(|a| format!("{:.4}", a.social_welfare_euro)),
+            ),
+            (
+                "buyer surplus [€]",
+                self.column(|a| format!("{:.4}", a.buyer_surplus_euro)),
+            ),
+            (
+                "seller surplus [€]",
+                self.column(|a| format!("{:.4}", a.seller_surplus_euro)),
+            ),
+            (
+                "grid fees [€]",
+                self.column(|a| format!("{:.4}", a.grid_fees_euro)),
+            ),
+            (
+                "mean price [€/kWh]",
+                self.column(|a| format_price(a.mean_price_euro_per_kwh)),
+            ),
+        ];
+        write_table(out, "", &names, &summary_rows)?;
+
+        writeln!(out)?;
+        writeln!(out, "Surplus per actor [€] (delta to {baseline})")?;
+        let actor_rows: Vec<(&str, Vec<String>)> = self
+            .actors
+            .iter()
+            .map(|actor| {
+                let cells = actor
+                    .surplus_euro
+                    .iter()
+                    .zip(&actor.surplus_delta_euro)
+                    .enumerate()
+                    .map(|(idx, (surplus, delta))| {
+                        if idx == 0 {
+                            format!("{surplus:.4}")
+                        } else {
+                            format!("{surplus:.4} ({delta:+.4})")
+                        }
+                    })
+                    .collect();
+                (actor.actor_id.as_str(), cells)
+            })
+            .collect();
+        write_table(out, "actor", &names, &actor_rows)?;
+
+        writeln!(out)?;
+        writeln!(
+            out,
+            "Traded energy per actor [kWh bought / sold] (delta to {baseline})"
+        )?;
+        let energy_rows: Vec<(&str, Vec<String>)> = self
+            .actors
+            .iter()
+            .map(|actor| {
+                let cells = (0..actor.bought_kwh.len())
+                    .map(|idx| {
+                        let (bought, sold) = (actor.bought_kwh[idx], actor.sold_kwh[idx]);
+                        if idx == 0 {
+                            format!("{bought:.3} / {sold:.3}")
+                        } else {
+                            format!(
+                                "{bought:.3} / {sold:.3} ({:+.3} / {:+.3})",
+                                actor.bought_delta_kwh[idx], actor.sold_delta_kwh[idx]
+                            )
+                        }
+                    })
+                    .collect();
+                (actor.actor_id.as_str(), cells)
+            })
+            .collect();
+        write_table(out, "actor", &names, &energy_rows)?;
+
+        writeln!(out)?;
+        if self.orders.is_empty() {
+            writeln!(out, "All orders have the same results")?;
+            return Ok(());
+        }
+        writeln!(out, "Orders with different results [kWh @ €/kWh]")?;
+        let order_labels: Vec<String> = self
+            .orders
+            .iter()
+            .map(|order| {
+                let order_type = match order.order_type {
+                    OrderType::Bid => "bid",
+                    OrderType::Ask => "ask",
+                };
+                match order.segment {
+                    Some(segment) => {
+                        format!("{} segment {segment} ({order_type})", order.order_id)
+                    }
+                    None => format!("{} ({order_type})", order.order_id),
+                }
+            })
+            .collect();
+        let order_rows: Vec<(&str, Vec<String>)> = self
+            .orders
+            .iter()
+            .zip(&order_labels)
+            .map(|(order, label)| {
+                let cells = order
+                    .matched_kwh
+                    .iter()
+                    .zip(&order.average_price_euro_per_kwh)
+                    .map(|(energy, price)| format!("{energy:.3} @ {}", format_price(*price)))
+                    .collect();
+                (label.as_str(), cells)
+            })
+            .collect();
+        write_table(out, "order", &names, &order_rows)
+    }
+
+    fn column(&self, cell: impl Fn(&AlgorithmSummary) -> String) -> Vec<String> {
+        self.algorithms.iter().map(cell).collect()
+    }
+}
+
+/// Differences of the values to the first value.
+fn deltas(values: &[f64]) -> Vec<f64> {
+    let baseline = values.first().copied().unwrap_or(0.0);
+    values.iter().map(|value| value - baseline).collect()
+}
+
+/// Matched energy and paid money per order ID and curve segment
+type OrderTotals = BTreeMap<(u64, Option<usize>), (f64, f64)>;
+
+/// Find the orders and curve segments whose matched energy or average price differ between the
+/// results.
+fn order_differences(
+    market_input: &MarketInput,
+    results: &[(String, MarketOutput)],
+) -> Vec<OrderComparison> {
+    // Totals for every algorithm
+    let totals: Vec<OrderTotals> = results
+        .iter()
+        .map(|(_, market_output)| {
+            let mut totals = OrderTotals::new();
+            for m in &market_output.matches {
+                for key in [(m.bid_id, m.bid_segment), (m.ask_id, m.ask_segment)] {
+                    let total = totals.entry(key).or_insert((0.0, 0.0));
+                    total.0 += m.energy_kwh;
+                    total.1 += m.energy_kwh * m.price_euro_per_kwh;
+                }
+            }
+            totals
+        })
+        .collect();
+
+    let plain_orders = market_input
+        .orders
+        .iter()
+        .map(|order| (order.id, None, order.order_type));
+    let curve_segments = market_input.curve_orders.iter().flat_map(|curve_order| {
+        (0..curve_order.points.len())
+            .map(move |segment| (curve_order.id, Some(segment), curve_order.order_type))
+    });
+    let mut orders: Vec<OrderComparison> = plain_orders
+        .chain(curve_segments)
+        .map(|(order_id, segment, order_type)| {
+            let (matched_kwh, average_price_euro_per_kwh) = totals
+                .iter()
+                .map(|totals| match totals.get(&(order_id, segment)) {
+                    Some(&(energy, money)) if energy > 0.0 => (energy, Some(money / energy)),
+                    _ => (0.0, None),
+                })
+                .unzip();
+            OrderComparison {
+                order_id,
+                segment,
+                order_type,
+                matched_kwh,
+                average_price_euro_per_kwh,
+            }
+        })
+        .filter(|order| {
+            let energy_differs = order
+                .matched_kwh
+                .windows(2)
+                .any(|pair| (pair[0] - pair[1]).abs() >= ENERGY_EPS / 2.0);
+            let price_differs =
+                order
+                    .average_price_euro_per_kwh
+                    .windows(2)
+                    .any(|pair| match (pair[0], pair[1]) {
+                        (Some(a), Some(b)) => (a - b).abs() > PRICE_TOLERANCE,
+                        (a, b) => a.is_some() != b.is_some(),
+                    });
+            energy_differs || price_differs
+        })
+        .collect();
+    orders.sort_by_key(|order| (order.order_id, order.segment));
+    orders
+}
+
+fn format_price(price: Option<f64>) -> String {
+    match price {
+        Some(price) => format!("{price:.4}"),
+        None => "-".to_string(),
+    }
+}
+
+/// Write rows with a label and one cell per algorithm, aligned in columns.
+fn write_table(
+    out: &mut impl Write,
+    label_header: &str,
+    names: &[&str],
+    rows: &[(&str, Vec<String>)],
+) -> io::Result<()> {
+    let label_width = rows
+        .iter()
+        .map(|(label, _)| label.chars().count())
+        .chain([label_header.chars().count()])
+        .max()
+        .unwrap_or(0);
+    let widths: Vec<usize> = names
+        .iter()
+        .enumerate()
+        .map(|(idx, name)| {
+            rows.iter()
+                .map(|(_, cells)| cells.get(idx).map_or(0, |cell| cell.chars().count()))
+                .chain([name.chars().count()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    write!(out, "{label_header:label_width$}")?;
+    for (name, width) in names.iter().zip(&widths) {
+        write!(out, "  {name:>width$}")?;
+    }
+    writeln!(out)?;
+    for (label, cells) in rows {
+        write!(out, "{label:label_width$}")?;
+        for (cell, width) in cells.iter().zip(&widths) {
+            write!(out, "  {cell:>width$}")?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simplyr_lib::{CurveOrder, CurvePoint, Interpolation, Match, Order};
+
+    fn order(id: u64, order_type: OrderType, actor_id: &str, price_euro_per_kwh: f64) -> Order {
+        Order {
+            id,
+            order_type,
+            time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+            actor_id: actor_id.to_string(),
+            cluster_index: Some(0),
+            energy_kwh: 1.0,
+            price_euro_per_kwh,
+            ..Default::default()
+        }
+    }
+
+    fn output(price_euro_per_kwh: f64) -> MarketOutput {
+        MarketOutput {
+            matches: vec![Match {
+                bid_id: 1,
+                ask_id: 2,
+                energy_kwh: 1.0,
+                price_euro_per_kwh,
+                bid_segment: None,
+                ask_segment: None,
+                stage: None,
+                energy_source: None,
+            }],
+            residual_book: vec![],
+            diagnostics: vec![],
+        }
+    }
+
+    #[test]
+    fn test_comparison() {
+        let market_input = MarketInput {
+            orders: vec![
+                order(1, OrderType::Bid, "buyer", 0.4),
+                order(2, OrderType::Ask, "seller", 0.2),
+                order(3, OrderType::Ask, "other", 0.5),
+            ],
+            curve_orders: vec![],
+        };
+        let results = vec![
+            ("a".to_string(), output(0.4)),
+            ("b".to_string(), output(0.2)),
+        ];
+        let comparison = Comparison::new(&market_input, &results, None);
+
+        assert_eq!(comparison.algorithms.len(), 2);
+        assert_eq!(comparison.algorithms[1].traded_volume_kwh, 1.0);
+        let buyer = &comparison.actors[0];
+        assert_eq!(buyer.actor_id, "buyer");
+        assert!((buyer.surplus_delta_euro[1] - 0.2).abs() < 1e-9);
+        assert_eq!(buyer.bought_kwh, vec![1.0, 1.0]);
+        assert_eq!(buyer.bought_delta_kwh, vec![0.0, 0.0]);
+        // Only the prices of the matched orders differ
+        let order_ids: Vec<u64> = comparison.orders.iter().map(|o| o.order_id).collect();
+        assert_eq!(order_ids, vec![1, 2]);
+        assert_eq!(
+            comparison.orders[0].average_price_euro_per_kwh,
+            vec![Some(0.4), Some(0.2)]
+        );
+
+        let mut table = vec![];
+        comparison.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.contains("0.2000 (+0.2000)"));
+        assert!(table.contains("1 (bid)"));
+        assert!(table.contains("1.000 / 0.000 (+0.000 / +0.000)"));
+
+        // Without matches, the buyer buys and the seller sells 1 kWh less
+        let mut unmatched = output(0.4);
+        unmatched.matches.clear();
+        let results = vec![("a".to_string(), output(0.4)), ("b".to_string(), unmatched)];
+        let comparison = Comparison::new(&market_input, &results, None);
+        assert_eq!(comparison.actors[0].bought_delta_kwh, vec![0.0, -1.0]);
+        assert_eq!(comparison.actors[2].sold_delta_kwh, vec![0.0, -1.0]);
+        let mut table = vec![];
+        comparison.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.contains("0.000 / 0.000 (-1.000 / +0.000)"));
+    }
+
+    #[test]
+    fn test_curve_order_differences() {
+        let point = |energy_kwh, price_euro_per_kwh| CurvePoint {
+            energy_kwh,
+            price_euro_per_kwh,
+        };
+        let market_input = MarketInput {
+            orders: vec![order(2, OrderType::Ask, "seller", 0.2)],
+            curve_orders: vec![CurveOrder {
+                id: 1,
+                order_type: OrderType::Bid,
+                time_slot: "2022-03-04T05:06:07+00:00".to_string(),
+                actor_id: "buyer".to_string(),
+                cluster_index: Some(0),
+                interpolation: Interpolation::Step,
+                points: vec![point(1.0, 0.4), point(2.0, 0.3)],
+                submitted_at: None,
+            }],
+        };
+        // Both algorithms match 1 kWh with the curve order, but with different segments
+        let mut first = output(0.3);
+        first.matches[0].bid_segment = Some(0);
+        let mut second = output(0.3);
+        second.matches[0].bid_segment = Some(1);
+        let results = vec![("a".to_string(), first), ("b".to_string(), second)];
+        let comparison = Comparison::new(&market_input, &results, None);
+
+        let orders: Vec<_> = comparison
+            .orders
+            .iter()
+            .map(|o| (o.order_id, o.segment, o.matched_kwh.clone()))
+            .collect();
+        assert_eq!(
+            orders,
+            vec![(1, Some(0), vec![1.0, 0.0]), (1, Some(1), vec![0.0, 1.0])]
+        );
+
+        let mut table = vec![];
+        comparison.write_table(&mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        assert!(table.contains("1 segment 1 (bid)"));
+    }
+}
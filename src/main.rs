@@ -1,77 +1,894 @@
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use simplyr_lib::{
-    custom_fair_matching, pay_as_bid_matching, GridFeeMatrix, GridFeeMatrixRaw, MarketInput,
+    custom_fair_matching_with_config, generate_scenario, local_first_matching_with_config,
+    market_metrics, mcafee_matching_with_config, multi_slot_matching,
+    pay_as_bid_matching_with_config, preference_matching_with_config, pseudonymize,
+    simulate_battery, uniform_price_matching_with_config, vcg_matching_with_config,
+    verify_market_output, Allocation, Battery, Digest, GridFeeMatrix, GridFeeMatrixRaw,
+    MarketInput, MarketOutput, Match, MatchingConfig, McAfeeOutput, MerkleProof, MerkleTree,
+    PriceLimits, PseudonymKey, PublicKey, ScenarioConfig, SecretKey, SelfTradePrevention,
+    SignedMarketOutput, TieBreak, VcgOutput,
 };
+use std::collections::BTreeMap;
+use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+mod compare;
+mod replay;
+
+use compare::Comparison;
+use replay::{read_history, ReplaySummary};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum Algorithm {
     PayAsBid,
+    UniformPrice,
     CustomFair,
+    LocalFirst,
+    Preference,
+    Mcafee,
+    Vcg,
+}
+
+/// Rules for prioritizing orders with equal prices
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum TieBreakRule {
+    OrderId,
+    SubmissionTime,
+    Random,
+}
+
+/// Rules for sharing energy among bids at the marginal price
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum AllocationRule {
+    PriceTime,
+    ProRata,
+}
+
+/// Rules for crossing orders of the same actor
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum SelfTradeRule {
+    Allow,
+    CancelNewest,
+    CancelOldest,
+    DecrementBoth,
+    SkipPair,
 }
 
 /// Command line arguments
+///
+/// Without a subcommand, the orders are matched with the selected algorithm.
 #[derive(Parser, Clone, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Which matching algorithm to run
-    #[arg(short, long, value_name = "NAME")]
-    algo: Algorithm,
+    #[arg(short, long, value_name = "NAME", required = true)]
+    algo: Option<Algorithm>,
 
     /// Sets a the JSON file that includes the orders
-    #[arg(short, long, value_name = "FILE.json")]
-    orders: PathBuf,
+    #[arg(short, long, value_name = "FILE.json", required = true)]
+    orders: Option<PathBuf>,
 
-    /// Sets a the JSON file that includes the grid fee matrix (only used in custom fair matching)
+    /// Sets a the JSON file that includes the grid fee matrix (only used in custom fair,
+    /// local-first and VCG matching)
     #[arg(short, long, value_name = "FILE.json")]
     grid_fee_matrix: Option<PathBuf>,
 
-    /// Sets the energy unit (in kWh) that is used to divide Orders in our custom fair matching
-    #[arg(short, long, value_name = "NUM")]
+    /// Sets the energy unit (in kWh) that is used to divide Orders in our custom fair matching,
+    /// the local-first matching and the McAfee auction
+    #[arg(short, long, value_name = "NUM", global = true)]
     energy_unit: Option<f64>,
+
+    /// Sets how orders with equal prices are prioritized
+    #[arg(long, value_name = "RULE", default_value = "order-id", global = true)]
+    tie_break: TieBreakRule,
+
+    /// Sets the seed of the random tie-break rule and of the generated scenarios
+    #[arg(long, value_name = "NUM", default_value_t = 0, global = true)]
+    seed: u64,
+
+    /// Sets how energy is shared among bids at the marginal price
+    #[arg(long, value_name = "RULE", default_value = "price-time", global = true)]
+    allocation: AllocationRule,
+
+    /// Sets the JSON file with regulated price bounds and the maximum grid fee. The verify
+    /// subcommand checks the market output against them.
+    #[arg(long, value_name = "FILE.json", global = true)]
+    price_limits: Option<PathBuf>,
+
+    /// Sets what happens to crossing orders of the same actor
+    #[arg(long, value_name = "RULE", default_value = "allow", global = true)]
+    self_trade_prevention: SelfTradeRule,
+
+    /// Replaces the actor IDs of the orders with keyed-hash pseudonyms wherever the orders are
+    /// read. Sets the file that includes the secret pseudonym key as 64 hex digits.
+    #[arg(long, value_name = "FILE", global = true)]
+    pseudonym_key: Option<PathBuf>,
+
+    /// Writes the private map from the pseudonyms to the actor IDs to this file
+    #[arg(
+        long,
+        value_name = "FILE.json",
+        global = true,
+        requires = "pseudonym_key"
+    )]
+    pseudonym_map: Option<PathBuf>,
+
+    /// Matches every time slot separately and only accepts linked orders together. The output
+    /// contains one result per time slot.
+    #[arg(long)]
+    multi_slot: bool,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Check that a market output is consistent with its orders and print a report
+    Verify {
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the market output
+        #[arg(short, long, value_name = "FILE.json")]
+        matches: PathBuf,
+
+        /// Sets the JSON file that includes the grid fee matrix, if grid fees were charged
+        #[arg(short, long, value_name = "FILE.json")]
+        grid_fee_matrix: Option<PathBuf>,
+    },
+    /// Sign a market output with the secret key of the market operator
+    Sign {
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the market output
+        #[arg(short, long, value_name = "FILE.json")]
+        matches: PathBuf,
+
+        /// Sets the file that includes the secret Ed25519 key as 64 hex digits
+        #[arg(short, long, value_name = "FILE")]
+        key: PathBuf,
+    },
+    /// Check the signature of a signed market output
+    CheckSignature {
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the signed market output
+        #[arg(short, long, value_name = "FILE.json")]
+        signed: PathBuf,
+
+        /// Sets the file that includes the public Ed25519 key of the operator as 64 hex digits
+        #[arg(short, long, value_name = "FILE")]
+        public_key: PathBuf,
+    },
+    /// Print the root of the Merkle tree over the matches of a market output
+    MerkleRoot {
+        /// Sets the JSON file that includes the market output
+        #[arg(short, long, value_name = "FILE.json")]
+        matches: PathBuf,
+    },
+    /// Print the matches of an order together with the proofs that they are part of the market
+    /// output
+    InclusionProof {
+        /// Sets the JSON file that includes the market output
+        #[arg(short, long, value_name = "FILE.json")]
+        matches: PathBuf,
+
+        /// The ID of the bid or ask
+        #[arg(long, value_name = "ID")]
+        order_id: u64,
+    },
+    /// Check that matches are part of the market output with the published Merkle root
+    CheckInclusion {
+        /// Sets the JSON file that includes the matches and proofs of `inclusion-proof`
+        #[arg(short, long, value_name = "FILE.json")]
+        proofs: PathBuf,
+
+        /// The Merkle root of the market output as 64 hex digits
+        #[arg(short, long, value_name = "HEX")]
+        root: Digest,
+    },
+    /// Print the public key that belongs to a secret key
+    PublicKey {
+        /// Sets the file that includes the secret Ed25519 key as 64 hex digits
+        #[arg(short, long, value_name = "FILE")]
+        key: PathBuf,
+    },
+    /// Print the orders with pseudonyms instead of actor IDs (requires --pseudonym-key)
+    Pseudonymize {
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+    },
+    /// Compute welfare and market quality metrics of a market output
+    Stats {
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the market output
+        #[arg(short, long, value_name = "FILE.json")]
+        matches: PathBuf,
+
+        /// Sets the JSON file that includes the grid fee matrix, if grid fees were charged
+        #[arg(short, long, value_name = "FILE.json")]
+        grid_fee_matrix: Option<PathBuf>,
+    },
+    /// Run several algorithms on the same orders and compare their results
+    Compare {
+        /// Which matching algorithms to run, the first one is the baseline for the deltas
+        #[arg(short, long = "algo", value_name = "NAME", required = true)]
+        algos: Vec<Algorithm>,
+
+        /// Sets the JSON file that includes the orders
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the grid fee matrix. It is needed by custom fair,
+        /// local-first and VCG matching and is used to compute the grid fees of all algorithms.
+        #[arg(short, long, value_name = "FILE.json")]
+        grid_fee_matrix: Option<PathBuf>,
+
+        /// Sets the output format
+        #[arg(short, long, value_name = "FORMAT", default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Simulate a battery that trades in every time slot of the orders, one slot after another
+    Simulate {
+        /// Sets the JSON file that includes the battery model
+        #[arg(short, long, value_name = "FILE.json")]
+        battery: PathBuf,
+
+        /// Which matching algorithm to run in every time slot
+        #[arg(short, long, value_name = "NAME")]
+        algo: Algorithm,
+
+        /// Sets the JSON file that includes the orders of all time slots
+        #[arg(short, long, value_name = "FILE.json")]
+        orders: PathBuf,
+
+        /// Sets the JSON file that includes the grid fee matrix (only used in custom fair,
+        /// local-first and VCG matching)
+        #[arg(short, long, value_name = "FILE.json")]
+        grid_fee_matrix: Option<PathBuf>,
+
+        /// Sets the duration of a time slot in hours
+        #[arg(long, value_name = "HOURS", default_value_t = 0.25)]
+        slot_hours: f64,
+    },
+    /// Generate the orders of a synthetic market scenario with households, PV systems and a
+    /// market maker over several time slots. Match them with --multi-slot.
+    Generate {
+        /// Sets the JSON file with the scenario settings; missing settings keep their defaults
+        #[arg(short, long, value_name = "FILE.json")]
+        config: Option<PathBuf>,
+
+        /// Sets the number of households
+        #[arg(long, value_name = "NUM")]
+        households: Option<usize>,
+
+        /// Sets the number of clusters
+        #[arg(long, value_name = "NUM")]
+        clusters: Option<usize>,
+
+        /// Sets the number of time slots
+        #[arg(long, value_name = "NUM")]
+        time_slots: Option<usize>,
+
+        /// Writes the grid fee matrix of the clusters to this file
+        #[arg(short, long, value_name = "FILE.json")]
+        write_grid_fee_matrix: Option<PathBuf>,
+    },
+    /// Match historical orders one time slot after another and print aggregated metrics
+    Replay {
+        /// Which matching algorithm to run in every time slot
+        #[arg(short, long, value_name = "NAME")]
+        algo: Algorithm,
+
+        /// Sets a directory of JSON files or a JSON Lines file with one set of orders per line
+        #[arg(short, long, value_name = "PATH")]
+        inputs: PathBuf,
+
+        /// Sets the JSON file that includes the grid fee matrix (only used in custom fair,
+        /// local-first and VCG matching)
+        #[arg(short, long, value_name = "FILE.json")]
+        grid_fee_matrix: Option<PathBuf>,
+
+        /// Writes the output of every time slot as a line of this JSON Lines file
+        #[arg(short, long, value_name = "FILE.jsonl")]
+        write_outputs: Option<PathBuf>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl Args {
+    fn matching_config(&self) -> Result<MatchingConfig, Box<dyn Error>> {
+        let tie_break = match self.tie_break {
+            TieBreakRule::OrderId => TieBreak::OrderId,
+            TieBreakRule::SubmissionTime => TieBreak::SubmissionTime,
+            TieBreakRule::Random => TieBreak::Random { seed: self.seed },
+        };
+        let allocation = match self.allocation {
+            AllocationRule::PriceTime => Allocation::PriceTime,
+            AllocationRule::ProRata => Allocation::ProRata,
+        };
+        let price_limits: PriceLimits = match &self.price_limits {
+            Some(path) => read_json(path)?,
+            None => PriceLimits::default(),
+        };
+        price_limits.validate()?;
+        let self_trade_prevention = match self.self_trade_prevention {
+            SelfTradeRule::Allow => SelfTradePrevention::Allow,
+            SelfTradeRule::CancelNewest => SelfTradePrevention::CancelNewest,
+            SelfTradeRule::CancelOldest => SelfTradePrevention::CancelOldest,
+            SelfTradeRule::DecrementBoth => SelfTradePrevention::DecrementBoth,
+            SelfTradeRule::SkipPair => SelfTradePrevention::SkipPair,
+        };
+        Ok(MatchingConfig {
+            tie_break,
+            allocation,
+            price_limits,
+            self_trade_prevention,
+        })
+    }
+
+    /// Read the orders and pseudonymize their actors if a pseudonym key is given.
+    fn read_market_input(&self, path: &Path) -> Result<MarketInput, Box<dyn Error>> {
+        let mut market_input: MarketInput = read_json(path)?;
+        self.pseudonymize([&mut market_input])?;
+        Ok(market_input)
+    }
+
+    /// Pseudonymize the actors of the market inputs if a pseudonym key is given.
+    fn pseudonymize<'a>(
+        &self,
+        market_inputs: impl IntoIterator<Item = &'a mut MarketInput>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(key_path) = &self.pseudonym_key else {
+            return Ok(());
+        };
+        let key: PseudonymKey = read_key(key_path)?;
+        let mut all_pseudonyms = BTreeMap::new();
+        for market_input in market_inputs {
+            let (pseudonymized, pseudonyms) = pseudonymize(market_input, &key);
+            *market_input = pseudonymized;
+            all_pseudonyms.extend(pseudonyms);
+        }
+        if let Some(map_path) = &self.pseudonym_map {
+            let mut writer = BufWriter::new(File::create(map_path)?);
+            serde_json::to_writer_pretty(&mut writer, &all_pseudonyms)?;
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, Box<dyn Error>> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+    Ok(serde_json::from_reader(reader)?)
+}
+
+/// Read a key that is stored as hex digits.
+fn read_key<T: FromStr<Err = String>>(path: &Path) -> Result<T, Box<dyn Error>> {
+    Ok(std::fs::read_to_string(path)?.trim().parse()?)
+}
+
+fn read_grid_fee_matrix(path: &Path) -> Result<GridFeeMatrix, Box<dyn Error>> {
+    let raw: GridFeeMatrixRaw = read_json(path)?;
+    Ok(GridFeeMatrix::from_raw(&raw)?)
+}
+
+fn read_optional_grid_fee_matrix(
+    path: Option<&Path>,
+) -> Result<Option<GridFeeMatrix>, Box<dyn Error>> {
+    match path {
+        Some(path) => Ok(Some(read_grid_fee_matrix(path)?)),
+        None => Ok(None),
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
 
-    match args.algo {
-        Algorithm::PayAsBid => {
-            let file = File::open(&args.orders)?;
-            let reader = BufReader::new(file);
-            let market_input: MarketInput = serde_json::from_reader(reader)?;
-            {
-                let market_output = pay_as_bid_matching(&market_input);
-                let mut stdout = std::io::stdout();
-                serde_json::to_writer_pretty(&mut stdout, &market_output)?;
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Verify {
+                orders,
+                matches,
+                grid_fee_matrix,
+            } => verify(&args, orders, matches, grid_fee_matrix.as_deref()),
+            Command::Sign {
+                orders,
+                matches,
+                key,
+            } => sign(&args, orders, matches, key),
+            Command::CheckSignature {
+                orders,
+                signed,
+                public_key,
+            } => check_signature(&args, orders, signed, public_key),
+            Command::MerkleRoot { matches } => {
+                let market_output: MarketOutput = read_json(matches)?;
+                println!("{}", market_output.merkle_root());
+                Ok(())
+            }
+            Command::InclusionProof { matches, order_id } => inclusion_proof(matches, *order_id),
+            Command::CheckInclusion { proofs, root } => check_inclusion(proofs, root),
+            Command::PublicKey { key } => {
+                let secret_key: SecretKey = read_key(key)?;
+                println!("{}", secret_key.public_key());
+                Ok(())
+            }
+            Command::Pseudonymize { orders } => {
+                if args.pseudonym_key.is_none() {
+                    return Err("the orders can't be pseudonymized without --pseudonym-key".into());
+                }
+                let market_input = args.read_market_input(orders)?;
+                serde_json::to_writer_pretty(std::io::stdout(), &market_input)?;
+                println!();
+                Ok(())
             }
+            Command::Stats {
+                orders,
+                matches,
+                grid_fee_matrix,
+            } => stats(&args, orders, matches, grid_fee_matrix.as_deref()),
+            Command::Compare {
+                algos,
+                orders,
+                grid_fee_matrix,
+                format,
+            } => compare(&args, algos, orders, grid_fee_matrix.as_deref(), *format),
+            Command::Simulate {
+                battery,
+                algo,
+                orders,
+                grid_fee_matrix,
+                slot_hours,
+            } => simulate(
+                &args,
+                battery,
+                *algo,
+                orders,
+                grid_fee_matrix.as_deref(),
+                *slot_hours,
+            ),
+            Command::Generate {
+                config,
+                households,
+                clusters,
+                time_slots,
+                write_grid_fee_matrix,
+            } => {
+                let mut config: ScenarioConfig = match config {
+                    Some(path) => read_json(path)?,
+                    None => ScenarioConfig::default(),
+                };
+                config.households = households.unwrap_or(config.households);
+                config.clusters = clusters.unwrap_or(config.clusters);
+                config.time_slots = time_slots.unwrap_or(config.time_slots);
+                generate(&config, args.seed, write_grid_fee_matrix.as_deref())
+            }
+            Command::Replay {
+                algo,
+                inputs,
+                grid_fee_matrix,
+                write_outputs,
+            } => replay(
+                &args,
+                *algo,
+                inputs,
+                grid_fee_matrix.as_deref(),
+                write_outputs.as_deref(),
+            ),
+        };
+    }
+
+    let config = args.matching_config()?;
+    // Both are required by clap if there is no subcommand
+    let algo = args.algo.ok_or("missing algorithm")?;
+    let orders = args.orders.as_deref().ok_or("missing orders")?;
+
+    let market_input = args.read_market_input(orders)?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(args.grid_fee_matrix.as_deref())?;
+    let matching = matching_function(
+        algo,
+        grid_fee_matrix.as_ref(),
+        args.energy_unit.unwrap_or(1.0),
+        &config,
+    )?;
+    let mut stdout = std::io::stdout();
+    if args.multi_slot {
+        let outputs =
+            multi_slot_matching(&market_input, &matching, AlgorithmOutput::market_output_mut);
+        serde_json::to_writer_pretty(&mut stdout, &outputs)?;
+    } else {
+        serde_json::to_writer_pretty(&mut stdout, &matching(&market_input))?;
+    }
+
+    Ok(())
+}
+
+/// The output of a matching algorithm. Some algorithms report more than the matches.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum AlgorithmOutput {
+    Matches(MarketOutput),
+    McAfee(McAfeeOutput),
+    Vcg(VcgOutput),
+}
+
+impl AlgorithmOutput {
+    fn market_output(&self) -> &MarketOutput {
+        match self {
+            AlgorithmOutput::Matches(market_output) => market_output,
+            AlgorithmOutput::McAfee(mcafee_output) => &mcafee_output.market_output,
+            AlgorithmOutput::Vcg(vcg_output) => &vcg_output.market_output,
+        }
+    }
+
+    fn market_output_mut(&mut self) -> &mut MarketOutput {
+        match self {
+            AlgorithmOutput::Matches(market_output) => market_output,
+            AlgorithmOutput::McAfee(mcafee_output) => &mut mcafee_output.market_output,
+            AlgorithmOutput::Vcg(vcg_output) => &mut vcg_output.market_output,
         }
+    }
+}
+
+/// A matching algorithm together with its settings.
+type MatchingFunction<'a> = Box<dyn Fn(&MarketInput) -> AlgorithmOutput + 'a>;
+
+/// Return a function that runs the algorithm with the given settings, or an error if the
+/// algorithm needs a grid fee matrix and none was given.
+fn matching_function<'a>(
+    algo: Algorithm,
+    grid_fee_matrix: Option<&'a GridFeeMatrix>,
+    energy_unit_kwh: f64,
+    config: &'a MatchingConfig,
+) -> Result<MatchingFunction<'a>, Box<dyn Error>> {
+    let matching: MatchingFunction = match algo {
+        Algorithm::PayAsBid => Box::new(move |market_input| {
+            AlgorithmOutput::Matches(pay_as_bid_matching_with_config(market_input, config))
+        }),
+        Algorithm::UniformPrice => Box::new(move |market_input| {
+            AlgorithmOutput::Matches(uniform_price_matching_with_config(market_input, config))
+        }),
         Algorithm::CustomFair => {
-            let market_input: MarketInput = {
-                let file = File::open(&args.orders)?;
-                let reader = BufReader::new(file);
-                serde_json::from_reader(reader)?
-            };
+            let grid_fee_matrix =
+                grid_fee_matrix.ok_or("custom fair matching needs a grid fee matrix")?;
+            Box::new(move |market_input| {
+                AlgorithmOutput::Matches(custom_fair_matching_with_config(
+                    market_input,
+                    energy_unit_kwh,
+                    grid_fee_matrix,
+                    config,
+                ))
+            })
+        }
+        Algorithm::LocalFirst => {
+            let grid_fee_matrix =
+                grid_fee_matrix.ok_or("local-first matching needs a grid fee matrix")?;
+            Box::new(move |market_input| {
+                AlgorithmOutput::Matches(local_first_matching_with_config(
+                    market_input,
+                    energy_unit_kwh,
+                    grid_fee_matrix,
+                    config,
+                ))
+            })
+        }
+        Algorithm::Preference => Box::new(move |market_input| {
+            AlgorithmOutput::Matches(preference_matching_with_config(market_input, config))
+        }),
+        Algorithm::Mcafee => Box::new(move |market_input| {
+            AlgorithmOutput::McAfee(mcafee_matching_with_config(
+                market_input,
+                energy_unit_kwh,
+                config,
+            ))
+        }),
+        Algorithm::Vcg => {
+            let grid_fee_matrix = grid_fee_matrix.ok_or("VCG matching needs a grid fee matrix")?;
+            Box::new(move |market_input| {
+                AlgorithmOutput::Vcg(vcg_matching_with_config(
+                    market_input,
+                    grid_fee_matrix,
+                    config,
+                ))
+            })
+        }
+    };
+    Ok(matching)
+}
 
-            let grid_fee_matrix: GridFeeMatrix = {
-                let file = File::open(args.grid_fee_matrix.unwrap())?;
-                let reader = BufReader::new(file);
-                let raw: GridFeeMatrixRaw = serde_json::from_reader(reader)?;
-                GridFeeMatrix::from_raw(&raw)?
-            };
+/// Print a verification report and exit with an error code if the market output is invalid.
+fn verify(
+    args: &Args,
+    orders: &Path,
+    matches: &Path,
+    grid_fee_matrix: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let market_input = args.read_market_input(orders)?;
+    let market_output: MarketOutput = read_json(matches)?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(grid_fee_matrix)?;
+    let price_limits = args.matching_config()?.price_limits;
 
-            {
-                let market_output = custom_fair_matching(
-                    &market_input,
-                    args.energy_unit.unwrap_or(1.0),
-                    &grid_fee_matrix,
-                );
-                let mut stdout = std::io::stdout();
-                serde_json::to_writer_pretty(&mut stdout, &market_output)?;
-            }
+    let report = verify_market_output(
+        &market_input,
+        &market_output,
+        grid_fee_matrix.as_ref(),
+        &price_limits,
+    );
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &report)?;
+    println!();
+
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Sign a market output and print it together with the signature.
+fn sign(args: &Args, orders: &Path, matches: &Path, key: &Path) -> Result<(), Box<dyn Error>> {
+    let market_input = args.read_market_input(orders)?;
+    let market_output: MarketOutput = read_json(matches)?;
+    let secret_key: SecretKey = read_key(key)?;
+
+    let signed = SignedMarketOutput::sign(&market_input, market_output, &secret_key);
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &signed)?;
+    println!();
+    Ok(())
+}
+
+/// Check that a signed market output was computed from the orders and signed by the operator.
+fn check_signature(
+    args: &Args,
+    orders: &Path,
+    signed: &Path,
+    public_key: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let market_input = args.read_market_input(orders)?;
+    let signed: SignedMarketOutput = read_json(signed)?;
+    let public_key: PublicKey = read_key(public_key)?;
+
+    signed.verify(&market_input, &public_key)?;
+    println!("The signature is valid");
+    Ok(())
+}
+
+/// A match together with the proof that it is part of a market output.
+#[derive(Serialize, Deserialize)]
+struct InclusionProof {
+    #[serde(rename = "match")]
+    matched: Match,
+    proof: MerkleProof,
+}
+
+/// Print the matches of an order together with their inclusion proofs.
+fn inclusion_proof(matches: &Path, order_id: u64) -> Result<(), Box<dyn Error>> {
+    let market_output: MarketOutput = read_json(matches)?;
+    let tree = MerkleTree::new(&market_output.matches);
+
+    let mut proofs = vec![];
+    for (index, matched) in market_output.matches.iter().enumerate() {
+        if matched.bid_id == order_id || matched.ask_id == order_id {
+            let proof = tree
+                .proof(index)
+                .ok_or("the match is not part of the tree")?;
+            proofs.push(InclusionProof {
+                matched: matched.clone(),
+                proof,
+            });
         }
     }
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &proofs)?;
+    println!();
+    Ok(())
+}
+
+/// Check inclusion proofs against the Merkle root of a market output.
+fn check_inclusion(proofs: &Path, root: &Digest) -> Result<(), Box<dyn Error>> {
+    let proofs: Vec<InclusionProof> = read_json(proofs)?;
+    for InclusionProof { matched, proof } in &proofs {
+        if !proof.verify(matched, root) {
+            return Err(format!(
+                "the match of bid {} and ask {} is not part of the market output",
+                matched.bid_id, matched.ask_id
+            )
+            .into());
+        }
+    }
+    println!("Every match is part of the market output");
+    Ok(())
+}
+
+/// Print welfare and market quality metrics of a market output.
+fn stats(
+    args: &Args,
+    orders: &Path,
+    matches: &Path,
+    grid_fee_matrix: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let market_input = args.read_market_input(orders)?;
+    let market_output: MarketOutput = read_json(matches)?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(grid_fee_matrix)?;
+
+    let metrics = market_metrics(&market_input, &market_output, grid_fee_matrix.as_ref());
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &metrics)?;
+    println!();
+    Ok(())
+}
+
+/// Run several algorithms on the same orders and print a comparison.
+fn compare(
+    args: &Args,
+    algos: &[Algorithm],
+    orders: &Path,
+    grid_fee_matrix: Option<&Path>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let market_input = args.read_market_input(orders)?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(grid_fee_matrix)?;
+    let config = args.matching_config()?;
+
+    let mut results = vec![];
+    for &algo in algos {
+        let matching = matching_function(
+            algo,
+            grid_fee_matrix.as_ref(),
+            args.energy_unit.unwrap_or(1.0),
+            &config,
+        )?;
+        let output = matching(&market_input);
+        let name = algo
+            .to_possible_value()
+            .map_or_else(|| format!("{algo:?}"), |value| value.get_name().to_string());
+        results.push((name, output.market_output().clone()));
+    }
+
+    let comparison = Comparison::new(&market_input, &results, grid_fee_matrix.as_ref());
+    let mut stdout = std::io::stdout();
+    match format {
+        OutputFormat::Table => comparison.write_table(&mut stdout)?,
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &comparison)?;
+            println!();
+        }
+    }
+    Ok(())
+}
+
+/// Simulate a battery over all time slots of the orders (in ascending order) and print the result.
+fn simulate(
+    args: &Args,
+    battery: &Path,
+    algo: Algorithm,
+    orders: &Path,
+    grid_fee_matrix: Option<&Path>,
+    slot_hours: f64,
+) -> Result<(), Box<dyn Error>> {
+    let battery: Battery = read_json(battery)?;
+    let market_input = args.read_market_input(orders)?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(grid_fee_matrix)?;
+    let config = args.matching_config()?;
+    let matching = matching_function(
+        algo,
+        grid_fee_matrix.as_ref(),
+        args.energy_unit.unwrap_or(1.0),
+        &config,
+    )?;
+
+    let slots: Vec<MarketInput> = market_input.split_time_slots().into_values().collect();
+    let simulation = simulate_battery(
+        &battery,
+        slot_hours,
+        &slots,
+        &matching,
+        AlgorithmOutput::market_output_mut,
+    )?;
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &simulation)?;
+    println!();
+    Ok(())
+}
+
+/// Generate a scenario, print its orders and write its grid fee matrix.
+fn generate(
+    config: &ScenarioConfig,
+    seed: u64,
+    write_grid_fee_matrix: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let scenario = generate_scenario(config, seed)?;
+    if let Some(path) = write_grid_fee_matrix {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &scenario.grid_fee_matrix)?;
+        writer.flush()?;
+    }
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &scenario.market_input)?;
+    println!();
+    Ok(())
+}
+
+/// A replayed time slot in the JSON Lines file of the outputs.
+#[derive(Serialize)]
+struct ReplayedSlot<'a> {
+    time_slot: &'a str,
+    source: &'a str,
+    output: &'a AlgorithmOutput,
+}
+
+/// Match the historical orders of every time slot (in ascending order) and print the metrics.
+fn replay(
+    args: &Args,
+    algo: Algorithm,
+    inputs: &Path,
+    grid_fee_matrix: Option<&Path>,
+    write_outputs: Option<&Path>,
+) -> Result<(), Box<dyn Error>> {
+    let mut slots = read_history(inputs)?;
+    args.pseudonymize(slots.iter_mut().map(|slot| &mut slot.market_input))?;
+    let grid_fee_matrix = read_optional_grid_fee_matrix(grid_fee_matrix)?;
+    let config = args.matching_config()?;
+    let matching = matching_function(
+        algo,
+        grid_fee_matrix.as_ref(),
+        args.energy_unit.unwrap_or(1.0),
+        &config,
+    )?;
+
+    let mut outputs = match write_outputs {
+        Some(path) => Some(BufWriter::new(File::create(path)?)),
+        None => None,
+    };
+    let mut results = vec![];
+    for slot in &slots {
+        let output = matching(&slot.market_input);
+        if let Some(outputs) = &mut outputs {
+            let replayed = ReplayedSlot {
+                time_slot: &slot.time_slot,
+                source: &slot.source,
+                output: &output,
+            };
+            serde_json::to_writer(&mut *outputs, &replayed)?;
+            writeln!(outputs)?;
+        }
+        let metrics = market_metrics(
+            &slot.market_input,
+            output.market_output(),
+            grid_fee_matrix.as_ref(),
+        );
+        results.push((slot, metrics));
+    }
+    if let Some(mut outputs) = outputs {
+        outputs.flush()?;
+    }
 
+    let summary = ReplaySummary::new(results);
+    let mut stdout = std::io::stdout();
+    serde_json::to_writer_pretty(&mut stdout, &summary)?;
+    println!();
     Ok(())
 }
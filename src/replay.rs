@@ -0,0 +1,271 @@
+//! Replay of historical market inputs with aggregated metrics.
+
+use serde::Serialize;
+use simplyr_lib::{ActorMetrics, MarketInput, MarketMetrics};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+/// The orders of a single time slot from the history.
+pub struct HistoricalSlot {
+    pub time_slot: String,
+    /// The file (and line of a JSON Lines file) that contains the orders
+    pub source: String,
+    pub market_input: MarketInput,
+}
+
+/// Read the market inputs of a directory of JSON files or of a JSON Lines file.
+///
+/// Every market input is split by its time slots. The slots are returned in `time_slot` order;
+/// slots with the same time slot are kept in the order of the files (sorted by name) and lines.
+/// Subdirectories are not read but rejected. The time slots are sorted as strings, so an error is
+/// returned if they don't all have the same UTC offset (`Z` and `+00:00` count as the same).
+pub fn read_history(path: &Path) -> Result<Vec<HistoricalSlot>, Box<dyn Error>> {
+    let mut inputs = vec![];
+    if path.is_dir() {
+        let mut files = vec![];
+        for entry in fs::read_dir(path)? {
+            let file = entry?.path();
+            if file.is_dir() {
+                return Err(format!("{}: subdirectories are not supported", file.display()).into());
+            }
+            if matches!(file.extension(), Some(extension) if extension == "json") {
+                files.push(file);
+            }
+        }
+        files.sort();
+        for file in files {
+            let json = fs::read_to_string(&file)?;
+            let market_input: MarketInput =
+                serde_json::from_str(&json).map_err(|err| format!("{}: {err}", file.display()))?;
+            inputs.push((file.display().to_string(), market_input));
+        }
+    } else {
+        let lines = fs::read_to_string(path)?;
+        for (index, line) in lines.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let source = format!("{}:{}", path.display(), index + 1);
+            let market_input: MarketInput =
+                serde_json::from_str(line).map_err(|err| format!("{source}: {err}"))?;
+            inputs.push((source, market_input));
+        }
+    }
+
+    let mut slots = vec![];
+    for (source, market_input) in inputs {
+        for (time_slot, market_input) in market_input.split_time_slots() {
+            slots.push(HistoricalSlot {
+                time_slot,
+                source: source.clone(),
+                market_input,
+            });
+        }
+    }
+    if let Some(first) = slots.first() {
+        let offset = utc_offset(&first.time_slot);
+        if let Some(slot) = slots
+            .iter()
+            .find(|slot| utc_offset(&slot.time_slot) != offset)
+        {
+            return Err(format!(
+                "{}: the UTC offset of time slot {} differs from time slot {} ({})",
+                slot.source, slot.time_slot, first.time_slot, first.source
+            )
+            .into());
+        }
+    }
+    // The sort is stable, so equal time slots keep the order of the files
+    slots.sort_by(|a, b| a.time_slot.cmp(&b.time_slot));
+    Ok(slots)
+}
+
+/// Return the UTC offset at the end of an RFC 3339 time slot, if it has one.
+fn utc_offset(time_slot: &str) -> Option<&str> {
+    if time_slot.ends_with(['Z', 'z']) {
+        return Some("+00:00");
+    }
+    let offset = time_slot.get(time_slot.len().checked_sub(6)?..)?;
+    let bytes = offset.as_bytes();
+    let is_offset = matches!(bytes[0], b'+' | b'-')
+        && bytes[3] == b':'
+        && [1, 2, 4, 5].iter().all(|&i| bytes[i].is_ascii_digit());
+    match offset {
+        "-00:00" => Some("+00:00"),
+        _ if is_offset => Some(offset),
+        _ => None,
+    }
+}
+
+/// The metrics of all replayed time slots.
+///
+/// All amounts of money are in €, energy is in kWh and prices are in € / kWh.
+#[derive(Serialize, Default)]
+pub struct ReplaySummary {
+    pub slot_count: usize,
+    pub match_count: usize,
+    pub traded_volume_kwh: f64,
+    pub social_welfare_euro: f64,
+    pub buyer_surplus_euro: f64,
+    pub seller_surplus_euro: f64,
+    pub grid_fees_euro: f64,
+    /// Energy-weighted mean price of all matches
+    pub mean_price_euro_per_kwh: Option<f64>,
+    /// Totals per actor, sorted by actor ID
+    pub actors: Vec<ActorMetrics>,
+    /// The metrics of every time slot in replay order
+    pub slots: Vec<SlotSummary>,
+}
+
+#[derive(Serialize)]
+pub struct SlotSummary {
+    pub time_slot: String,
+    pub source: String,
+    pub match_count: usize,
+    pub traded_volume_kwh: f64,
+    pub social_welfare_euro: f64,
+    pub grid_fees_euro: f64,
+    pub mean_price_euro_per_kwh: Option<f64>,
+}
+
+impl ReplaySummary {
+    /// Sum up the metrics of the replayed time slots.
+    pub fn new<'a>(results: impl IntoIterator<Item = (&'a HistoricalSlot, MarketMetrics)>) -> Self {
+        let mut summary = ReplaySummary::default();
+        let mut actors: BTreeMap<String, ActorMetrics> = BTreeMap::new();
+        let mut turnover_euro = 0.0;
+        for (slot, metrics) in results {
+            let mean_price = metrics
+                .prices
+                .as_ref()
+                .map(|prices| prices.mean_euro_per_kwh);
+            if let Some(mean_price) = mean_price {
+                turnover_euro += mean_price * metrics.traded_volume_kwh;
+            }
+            summary.slot_count += 1;
+            summary.match_count += metrics.match_count;
+            summary.traded_volume_kwh += metrics.traded_volume_kwh;
+            summary.social_welfare_euro += metrics.social_welfare_euro;
+            summary.buyer_surplus_euro += metrics.buyer_surplus_euro;
+            summary.seller_surplus_euro += metrics.seller_surplus_euro;
+            summary.grid_fees_euro += metrics.grid_fees_euro;
+            for actor in metrics.actors {
+                let total = actors
+                    .entry(actor.actor_id.clone())
+                    .or_insert_with(|| ActorMetrics {
+                        actor_id: actor.actor_id.clone(),
+                        ..ActorMetrics::default()
+                    });
+                total.bought_kwh += actor.bought_kwh;
+                total.sold_kwh += actor.sold_kwh;
+                total.surplus_euro += actor.surplus_euro;
+            }
+            summary.slots.push(SlotSummary {
+                time_slot: slot.time_slot.clone(),
+                source: slot.source.clone(),
+                match_count: metrics.match_count,
+                traded_volume_kwh: metrics.traded_volume_kwh,
+                social_welfare_euro: metrics.social_welfare_euro,
+                grid_fees_euro: metrics.grid_fees_euro,
+                mean_price_euro_per_kwh: mean_price,
+            });
+        }
+        if summary.traded_volume_kwh > 0.0 {
+            summary.mean_price_euro_per_kwh = Some(turnover_euro / summary.traded_volume_kwh);
+        }
+        summary.actors = actors.into_values().collect();
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use simplyr_lib::{market_metrics, pay_as_bid_matching};
+
+    fn input_json(time_slot: &str, bid_price: f64) -> String {
+        format!(
+            r#"{{"orders": [
+                {{"id": 1, "order_type": "ask", "time_slot": "{time_slot}", "actor_id": "seller",
+                  "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": 0.1}},
+                {{"id": 2, "order_type": "bid", "time_slot": "{time_slot}", "actor_id": "buyer",
+                  "cluster_index": 0, "energy_kwh": 1.0, "price_euro_per_kwh": {bid_price}}}
+            ]}}"#
+        )
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = std::env::temp_dir().join(format!("simplyr-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.json"), input_json("2022-03-04T05:00:00Z", 0.3)).unwrap();
+        fs::write(dir.join("a.json"), input_json("2022-03-04T06:00:00Z", 0.5)).unwrap();
+        fs::write(dir.join("notes.txt"), "not an input").unwrap();
+        let jsonl = format!(
+            "{}\n\n{}\n",
+            input_json("2022-03-04T06:00:00Z", 0.5).replace('\n', ""),
+            input_json("2022-03-04T05:00:00Z", 0.3).replace('\n', "")
+        );
+        let jsonl_path = dir.join("history.jsonl");
+        fs::write(&jsonl_path, jsonl).unwrap();
+
+        let from_dir = read_history(&dir).unwrap();
+        let from_lines = read_history(&jsonl_path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let time_slots: Vec<&str> = from_dir.iter().map(|s| s.time_slot.as_str()).collect();
+        assert_eq!(time_slots, ["2022-03-04T05:00:00Z", "2022-03-04T06:00:00Z"]);
+        assert!(from_dir[0].source.ends_with("b.json"));
+        assert!(from_lines[0].source.ends_with("history.jsonl:3"));
+
+        let summary = ReplaySummary::new(from_lines.iter().map(|slot| {
+            let output = pay_as_bid_matching(&slot.market_input);
+            (slot, market_metrics(&slot.market_input, &output, None))
+        }));
+        assert_eq!(summary.slot_count, 2);
+        assert_eq!(summary.match_count, 2);
+        assert_eq!(summary.traded_volume_kwh, 2.0);
+        assert!((summary.mean_price_euro_per_kwh.unwrap() - 0.4).abs() < 1e-9);
+        assert!((summary.seller_surplus_euro - 0.6).abs() < 1e-9);
+        let sold: Vec<(&str, f64)> = summary
+            .actors
+            .iter()
+            .map(|actor| (actor.actor_id.as_str(), actor.sold_kwh))
+            .collect();
+        assert_eq!(sold, [("buyer", 0.0), ("seller", 2.0)]);
+    }
+
+    #[test]
+    fn test_invalid_history() {
+        let dir = std::env::temp_dir().join(format!("simplyr-history-{}", std::process::id()));
+        fs::create_dir_all(dir.join("older")).unwrap();
+        fs::write(
+            dir.join("a.json"),
+            input_json("2022-03-04T05:00:00+00:00", 0.3),
+        )
+        .unwrap();
+        let subdirectory = read_history(&dir).err().unwrap().to_string();
+        fs::remove_dir(dir.join("older")).unwrap();
+        let same_offset = read_history(&dir).map(|slots| slots.len());
+        fs::write(dir.join("b.json"), input_json("2022-03-04T06:30:00Z", 0.3)).unwrap();
+        let same_offset_z = read_history(&dir).map(|slots| slots.len());
+        fs::write(
+            dir.join("c.json"),
+            input_json("2022-03-04T07:00:00+01:00", 0.3),
+        )
+        .unwrap();
+        let mixed_offsets = read_history(&dir).err().unwrap().to_string();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(subdirectory.contains("subdirectories are not supported"));
+        assert_eq!(same_offset.unwrap(), 1);
+        assert_eq!(same_offset_z.unwrap(), 2);
+        assert!(mixed_offsets.contains("c.json"));
+        assert!(mixed_offsets.contains("2022-03-04T07:00:00+01:00"));
+        assert_eq!(utc_offset("2022-03-04T05:00:00-05:30"), Some("-05:30"));
+        assert_eq!(utc_offset("2022-03-04T05:00:00"), None);
+        assert_eq!(utc_offset("t"), None);
+    }
+}